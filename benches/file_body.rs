@@ -0,0 +1,84 @@
+//! Compares the allocations and time it takes to stream a file as a response
+//! body, reading it with `FramedRead` (as `serve` once did) and with
+//! `buffers::FileStream`. Run with `cargo bench --bench file_body`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::stream::{Stream, StreamExt};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use httpd2::buffers::FileStream;
+
+/// Counts allocations.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const FILE_LEN: usize = 16 * 1024 * 1024;
+const ROUNDS: u32 = 50;
+
+/// Streams the file at `path` `ROUNDS` times through the stream `open` makes,
+/// dropping each chunk before asking for the next, as hyper does. Returns the
+/// allocations and time per round.
+async fn measure<S, B, E>(
+    path: &std::path::Path,
+    open: impl Fn(tokio::fs::File) -> S,
+) -> (usize, Duration)
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::fmt::Debug,
+{
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let mut stream = open(tokio::fs::File::open(path).await.unwrap());
+        let mut len = 0;
+        while let Some(chunk) = stream.next().await {
+            len += chunk.unwrap().as_ref().len();
+        }
+        assert_eq!(len, FILE_LEN);
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    (allocations / ROUNDS as usize, start.elapsed() / ROUNDS)
+}
+
+fn main() {
+    let path = std::env::temp_dir()
+        .join(format!("httpd2-bench-{}", std::process::id()));
+    std::fs::write(&path, vec![b'x'; FILE_LEN]).unwrap();
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        // Once first, so that both start warm.
+        measure(&path, |f| FramedRead::new(f, BytesCodec::new())).await;
+        measure(&path, FileStream::new).await;
+
+        let (allocs, time) =
+            measure(&path, |f| FramedRead::new(f, BytesCodec::new())).await;
+        println!("FramedRead: {} allocations, {:?} per file", allocs, time);
+        let (allocs, time) = measure(&path, FileStream::new).await;
+        println!("FileStream: {} allocations, {:?} per file", allocs, time);
+    });
+
+    std::fs::remove_file(&path).unwrap();
+}
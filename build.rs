@@ -0,0 +1,110 @@
+//! With the `embed` feature, packs the directory named by `HTTPD2_EMBED_DIR`
+//! into a tar archive in `OUT_DIR`, for `archive::embedded` to build in.
+//!
+//! Files keep their modes and modification times, so the picky open rules
+//! apply to them as they would on disk. Symlinks are left out, with a warning,
+//! rather than followed out of the directory. Without `HTTPD2_EMBED_DIR`, the
+//! archive is empty.
+
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if std::env::var_os("CARGO_FEATURE_EMBED").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-env-changed=HTTPD2_EMBED_DIR");
+    let mut tar = vec![];
+    match std::env::var_os("HTTPD2_EMBED_DIR") {
+        Some(dir) => add_dir(&mut tar, Path::new(&dir), ""),
+        // An empty site, so that --all-features builds still work.
+        None => println!(
+            "cargo:warning=HTTPD2_EMBED_DIR isn't set; embedding an empty site"
+        ),
+    }
+    tar.extend_from_slice(&[0; 1024]);
+    let out = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("site.tar"), tar).unwrap();
+}
+
+/// Adds the contents of `dir`, which is at `name` in the archive, to `tar`.
+fn add_dir(tar: &mut Vec<u8>, dir: &Path, name: &str) {
+    // Adding or removing an entry changes the directory.
+    println!("cargo:rerun-if-changed={}", dir.display());
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let meta = fs::symlink_metadata(&path).unwrap();
+        let file_name =
+            path.file_name().unwrap().to_str().unwrap_or_else(|| {
+                panic!("{}: name isn't UTF-8", path.display())
+            });
+        let entry = format!("{}{}", name, file_name);
+        let mode = meta.permissions().mode() & 0o7777;
+        let mtime = meta.mtime().max(0) as u64;
+        if meta.is_dir() {
+            header(tar, &format!("{}/", entry), b'5', mode, mtime, 0);
+            add_dir(tar, &path, &format!("{}/", entry));
+        } else if meta.is_file() {
+            println!("cargo:rerun-if-changed={}", path.display());
+            let data = fs::read(&path).unwrap();
+            header(tar, &entry, b'0', mode, mtime, data.len() as u64);
+            tar.extend_from_slice(&data);
+            pad(tar);
+        } else {
+            println!("cargo:warning=not embedding {}", path.display());
+        }
+    }
+}
+
+/// Appends a ustar header for an entry, preceded by a GNU long name if the
+/// name doesn't fit.
+fn header(
+    tar: &mut Vec<u8>,
+    name: &str,
+    typeflag: u8,
+    mode: u32,
+    mtime: u64,
+    len: u64,
+) {
+    if name.len() > 100 {
+        let mut long = name.as_bytes().to_vec();
+        long.push(0);
+        header(tar, "././@LongLink", b'L', 0o644, 0, long.len() as u64);
+        tar.extend_from_slice(&long);
+        pad(tar);
+    }
+    let mut h = [0; 512];
+    let short = &name.as_bytes()[..name.len().min(100)];
+    h[..short.len()].copy_from_slice(short);
+    octal(&mut h[100..108], mode.into());
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], len);
+    octal(&mut h[136..148], mtime);
+    h[156] = typeflag;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[148..156].fill(b' ');
+    let sum = h.iter().map(|&b| u64::from(b)).sum();
+    octal(&mut h[148..155], sum);
+    tar.extend_from_slice(&h);
+}
+
+/// Writes `n` into `field` as zero-padded octal, ending in a NUL.
+fn octal(field: &mut [u8], n: u64) {
+    let digits = format!("{:0width$o}", n, width = field.len() - 1);
+    assert!(digits.len() < field.len(), "{} is too large for tar", n);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// Pads `tar` to a whole number of blocks.
+fn pad(tar: &mut Vec<u8>) {
+    tar.resize(tar.len().div_ceil(512) * 512, 0);
+}
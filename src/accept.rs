@@ -0,0 +1,110 @@
+//! Keeping the accept loop going when accepting fails.
+//!
+//! The usual reason `accept` fails is that we're out of file descriptors. The
+//! pending connection stays in the kernel's queue, so trying again straight
+//! away fails again, and the loop spins, logging warnings as fast as it can.
+//! Instead, we back off for a while, doubling the delay each time, and to
+//! keep the queue moving, we hold a spare descriptor in reserve: when we run
+//! out, we give it up, accept one connection and close it at once, then take
+//! the reserve back. The unlucky client sees its connection closed, rather
+//! than waiting in a queue that isn't moving.
+
+use std::io;
+use std::os::fd::{AsFd, OwnedFd};
+use std::time::Duration;
+
+use futures::future::FutureExt;
+use tokio::net::TcpListener;
+
+/// Delay after the first failure.
+const MIN_DELAY: Duration = Duration::from_millis(10);
+/// Longest delay between attempts.
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// What happened when recovering from a failed accept.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Recovery {
+    /// Whether a connection was closed unserved, to make room.
+    pub dropped: bool,
+    /// How long we waited before letting the loop go on.
+    pub delay: Duration,
+}
+
+/// Backoff state for an accept loop.
+pub struct AcceptBackoff {
+    reserve: Option<OwnedFd>,
+    delay: Duration,
+}
+
+impl AcceptBackoff {
+    /// Sets up backoff for `listener`, taking a descriptor in reserve.
+    pub fn new(listener: &impl AsFd) -> io::Result<Self> {
+        Ok(AcceptBackoff {
+            reserve: Some(listener.as_fd().try_clone_to_owned()?),
+            delay: Duration::ZERO,
+        })
+    }
+
+    /// Notes a successful accept, ending any backoff.
+    pub fn reset(&mut self) {
+        self.delay = Duration::ZERO;
+    }
+
+    /// Responds to `error` from accepting on `listener`, waiting before
+    /// returning if the error is one that won't clear up by itself.
+    pub async fn recover(
+        &mut self,
+        listener: &TcpListener,
+        error: &io::Error,
+    ) -> Recovery {
+        let mut dropped = false;
+        match error.raw_os_error() {
+            // The connection was gone before we got to it. There may be
+            // others behind it, so carry on.
+            Some(libc::ECONNABORTED) | Some(libc::EINTR) => {
+                return Recovery {
+                    dropped,
+                    delay: Duration::ZERO,
+                };
+            }
+            Some(libc::EMFILE) | Some(libc::ENFILE) => {
+                if let Some(reserve) = self.reserve.take() {
+                    drop(reserve);
+                    // Only take a connection that's already waiting.
+                    if let Some(Ok((socket, _))) = listener.accept().now_or_never() {
+                        drop(socket);
+                        dropped = true;
+                    }
+                    self.reserve = listener.as_fd().try_clone_to_owned().ok();
+                }
+            }
+            _ => (),
+        }
+        self.delay = next_delay(self.delay);
+        tokio::time::sleep(self.delay).await;
+        Recovery {
+            dropped,
+            delay: self.delay,
+        }
+    }
+}
+
+fn next_delay(delay: Duration) -> Duration {
+    (delay * 2).clamp(MIN_DELAY, MAX_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays() {
+        let mut delay = Duration::ZERO;
+        let mut seen = vec![];
+        for _ in 0..9 {
+            delay = next_delay(delay);
+            seen.push(delay.as_millis());
+        }
+        assert_eq!(seen, [10, 20, 40, 80, 160, 320, 640, 1000, 1000]);
+    }
+}
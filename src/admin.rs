@@ -0,0 +1,79 @@
+//! The admin control socket.
+//!
+//! An operator can connect to a Unix socket, created before chroot and usable
+//! only by its owner, and send a single command as a line of text. The server
+//! answers with one or more lines -- `ok`, `error: ...`, or the requested
+//! information -- and hangs up. Being able to connect is the only
+//! authentication, so the socket's permissions matter.
+//!
+//! Commands:
+//!
+//! - `stats` prints counters as `name value` lines.
+//! - `set-log-level LEVEL` changes which log records are kept (`critical`,
+//!   `error`, `warn`, `info`, `debug`, or `trace`).
+//! - `reload-tls` re-reads the private key and certificate chain.
+//! - `reload-user-agents` re-reads the User-Agent blocklist.
+//! - `drain` stops accepting connections, closes the current ones as their
+//!   requests in progress finish, and exits.
+
+use std::str::FromStr;
+
+/// A command received on the admin socket.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Stats,
+    SetLogLevel(slog::Level),
+    ReloadTls,
+    ReloadUserAgents,
+    Drain,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (Some("stats"), None) => Command::Stats,
+            (Some("set-log-level"), Some(level)) => Command::SetLogLevel(
+                level.parse().map_err(|_| format!("bad log level: {}", level))?,
+            ),
+            (Some("reload-tls"), None) => Command::ReloadTls,
+            (Some("reload-user-agents"), None) => Command::ReloadUserAgents,
+            (Some("drain"), None) => Command::Drain,
+            _ => return Err(format!("unknown command: {}", s.trim())),
+        };
+        if words.next().is_some() {
+            return Err("too many arguments".to_string());
+        }
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!("stats\n".parse(), Ok(Command::Stats));
+        assert_eq!(
+            "set-log-level debug".parse(),
+            Ok(Command::SetLogLevel(slog::Level::Debug))
+        );
+        assert_eq!(
+            " set-log-level  WARN ".parse(),
+            Ok(Command::SetLogLevel(slog::Level::Warning))
+        );
+        assert_eq!("reload-tls".parse(), Ok(Command::ReloadTls));
+        assert_eq!("reload-user-agents".parse(), Ok(Command::ReloadUserAgents));
+        assert_eq!("drain".parse(), Ok(Command::Drain));
+
+        assert!("set-log-level".parse::<Command>().is_err());
+        assert!("set-log-level loud".parse::<Command>().is_err());
+        assert!("set-log-level info now".parse::<Command>().is_err());
+        assert!("drain now".parse::<Command>().is_err());
+        assert!("".parse::<Command>().is_err());
+        assert!("restart".parse::<Command>().is_err());
+    }
+}
@@ -0,0 +1,813 @@
+//! Serving a site out of a tar archive.
+//!
+//! When ROOT is a file rather than a directory, it's taken to be a tar
+//! archive, so that a whole site can be deployed as one immutable artifact.
+//! The archive is opened and indexed at startup, before chroot: a map from
+//! each entry's name to where its bytes sit in the archive, its length, mode
+//! and modification time. Requests are then answered with reads at those
+//! offsets, so nothing is ever unpacked.
+//!
+//! Entries get the same scrutiny as files in a directory: only those whose
+//! mode `picky::open` would accept are served, and symlinks, hard links and
+//! device nodes never are. Names with `..` in them are left out of the index.
+//! Directories that only appear in the names of their entries count as
+//! `0755`.
+//!
+//! With the `embed` feature, a site is built into the binary as a tar archive
+//! too, and served the same way from memory.
+//!
+//! Only plain tar is understood -- ustar, with GNU long names and pax `path`,
+//! `size` and `mtime` records -- since a compressed archive can't be read
+//! from the middle. A zip of stored files would work the same way, but one
+//! of deflated files would have to be unpacked to serve, so use tar; and a
+//! squashfs image is better mounted (`mount -o loop,ro`) and served as a
+//! directory, leaving the parsing to the kernel.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::fd::RawFd;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::task::JoinHandle;
+
+use crate::picky::{self, File};
+use crate::source::{ChooseTtl, Content, ContentSource, InferContentType};
+
+/// Most bytes read from the archive at a time.
+const CHUNK: usize = 64 * 1024;
+
+/// A tar archive, indexed and held open.
+pub struct Archive {
+    data: Data,
+    /// The archive's inode number, which with an entry's offset identifies it.
+    /// An archive in memory has none, and uses 0.
+    ino: u64,
+    entries: HashMap<String, Entry>,
+}
+
+/// Where an archive's bytes are.
+enum Data {
+    File(Arc<std::fs::File>),
+    Memory(&'static [u8]),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    kind: Kind,
+    mode: u32,
+    modified: SystemTime,
+    /// Where the entry's bytes start in the archive.
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    File,
+    Directory,
+    /// Links, devices and anything else that isn't served.
+    Other,
+}
+
+impl fmt::Debug for Archive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Archive")
+            .field("ino", &self.ino)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+impl Archive {
+    /// Opens and indexes the tar archive at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let meta = file.metadata()?;
+        let entries = index(&mut io::BufReader::new(&file), meta.len())?;
+        Ok(Archive {
+            data: Data::File(Arc::new(file)),
+            ino: meta.ino(),
+            entries,
+        })
+    }
+
+    /// Indexes the tar archive `data`, which is in memory for good.
+    pub fn from_static(data: &'static [u8]) -> io::Result<Self> {
+        let entries = index(&mut io::Cursor::new(data), data.len() as u64)?;
+        Ok(Archive {
+            data: Data::Memory(data),
+            ino: 0,
+            entries,
+        })
+    }
+
+    /// Looks up `path`, with the errors `picky::open` would give.
+    fn find(&self, path: &Path) -> Result<&Entry, picky::Error> {
+        let raw = path.to_string_lossy();
+        let name = normalize(&raw).ok_or(picky::Error::BadMode(0))?;
+        match self.entries.get(&name) {
+            // A trailing slash after a file, as the kernel would see it.
+            Some(entry)
+                if entry.kind != Kind::Directory && raw.ends_with('/') =>
+            {
+                Err(picky::Error::NotDirectory)
+            }
+            Some(entry) => Ok(entry),
+            None => {
+                // Some non-final component of the path is a file.
+                let under_file = ancestors(&name).any(|dir| {
+                    self.entries
+                        .get(dir)
+                        .is_some_and(|e| e.kind != Kind::Directory)
+                });
+                if under_file {
+                    Err(picky::Error::NotDirectory)
+                } else {
+                    Err(picky::Error::Io(io::ErrorKind::NotFound.into()))
+                }
+            }
+        }
+    }
+}
+
+impl ContentSource for Archive {
+    fn open<'a>(
+        &'a self,
+        log: &'a slog::Logger,
+        path: &'a Path,
+        infer_content_type: InferContentType<'a>,
+        choose_ttl: ChooseTtl<'a>,
+    ) -> BoxFuture<'a, Result<File, picky::Error>> {
+        Box::pin(async move {
+            slog::debug!(log, "archive open({:?})", path);
+            let entry = self.find(path)?;
+            let mode = entry.mode;
+            if mode & 0o444 != 0o444 || mode & 0o101 == 0o001 {
+                slog::debug!(log, "mode {:#o} is not OK", mode);
+                return Err(picky::Error::BadMode(mode));
+            }
+            let (start, len) = (entry.offset, entry.len);
+            let content: Box<dyn Content> = match &self.data {
+                Data::File(file) => Box::new(Slice::new(file, start, len)),
+                Data::Memory(data) => Box::new(Memory(io::Cursor::new(
+                    &data[start as usize..(start + len) as usize],
+                ))),
+            };
+            match entry.kind {
+                Kind::File => Ok(File {
+                    file: content,
+                    len: entry.len,
+                    modified: entry.modified,
+                    id: (self.ino, entry.offset),
+                    content_type: infer_content_type(path),
+                    ttl: choose_ttl(path),
+                }),
+                Kind::Directory => Err(picky::Error::Directory),
+                Kind::Other => Err(picky::Error::SpecialFile),
+            }
+        })
+    }
+
+    fn list<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Vec<OsString>>> {
+        Box::pin(async move {
+            let dir = normalize(&path.to_string_lossy())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            let mut names: Vec<OsString> = self
+                .entries
+                .keys()
+                .filter(|name| !name.is_empty() && parent(name) == dir)
+                .map(|name| name.rsplit('/').next().unwrap_or(name).into())
+                .collect();
+            names.sort();
+            Ok(names)
+        })
+    }
+}
+
+/// Turns an entry's or a request's path into an index key: components
+/// separated by single slashes, without `.` or leading and trailing slashes.
+/// The archive's root is the empty string. Returns `None` for paths with `..`
+/// in them.
+fn normalize(path: &str) -> Option<String> {
+    let mut parts = vec![];
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => return None,
+            part => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// The key of the directory holding `name`.
+fn parent(name: &str) -> &str {
+    name.rfind('/').map_or("", |i| &name[..i])
+}
+
+/// The keys of the directories above `name`, nearest first, ending with the
+/// root.
+fn ancestors(name: &str) -> impl Iterator<Item = &str> {
+    let mut rest = Some(name);
+    std::iter::from_fn(move || {
+        let name = rest?;
+        if name.is_empty() {
+            rest = None;
+            return None;
+        }
+        let dir = parent(name);
+        rest = Some(dir);
+        Some(dir)
+    })
+}
+
+/// Reads the headers of the tar archive in `r`, which is `size` bytes long,
+/// into an index.
+fn index<R: Read + Seek>(
+    r: &mut R,
+    size: u64,
+) -> io::Result<HashMap<String, Entry>> {
+    let bad = |why: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad tar archive: {}", why),
+        )
+    };
+    let mut entries = HashMap::new();
+    entries.insert(String::new(), directory());
+    // Names and sizes for the next entry, from GNU and pax extensions.
+    let mut long_name = None;
+    let mut pax = Pax::default();
+    let mut offset = 0;
+    // Some writers leave off the zeroed blocks that mark the end.
+    while offset < size {
+        let mut header = [0; 512];
+        r.read_exact(&mut header).map_err(|_| bad("truncated"))?;
+        offset += 512;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let checksum =
+            octal(&header[148..156]).ok_or_else(|| bad("checksum"))?;
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    u64::from(b' ')
+                } else {
+                    u64::from(b)
+                }
+            })
+            .sum();
+        if sum != checksum {
+            return Err(bad("checksum"));
+        }
+        let len = match pax.size.take() {
+            Some(len) => len,
+            None => number(&header[124..136]).ok_or_else(|| bad("size"))?,
+        };
+        if size - offset < len {
+            return Err(bad("truncated"));
+        }
+        let padded = len.div_ceil(512) * 512;
+        if size - offset < padded {
+            return Err(bad("truncated"));
+        }
+        let typeflag = header[156];
+        match typeflag {
+            // The name, or the attributes, of the next entry.
+            b'L' | b'x' => {
+                let mut data = vec![0; len as usize];
+                r.read_exact(&mut data)?;
+                r.seek(SeekFrom::Current((padded - len) as i64))?;
+                if typeflag == b'L' {
+                    long_name = Some(string(&data));
+                } else {
+                    pax = Pax::parse(&data);
+                }
+            }
+            // Global attributes and long link names say nothing we use.
+            b'g' | b'K' => {
+                r.seek(SeekFrom::Current(padded as i64))?;
+            }
+            _ => {
+                let name = match (pax.path.take(), long_name.take()) {
+                    (Some(name), _) | (None, Some(name)) => name,
+                    (None, None) => {
+                        let name = string(&header[0..100]);
+                        let prefix = string(&header[345..500]);
+                        if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                            format!("{}/{}", prefix, name)
+                        } else {
+                            name
+                        }
+                    }
+                };
+                let mtime = match pax.mtime.take() {
+                    Some(mtime) => mtime,
+                    None => {
+                        number(&header[136..148]).ok_or_else(|| bad("mtime"))?
+                    }
+                };
+                let entry = Entry {
+                    kind: match typeflag {
+                        b'0' | b'\0' | b'7' => Kind::File,
+                        b'5' => Kind::Directory,
+                        _ => Kind::Other,
+                    },
+                    mode: octal(&header[100..108]).ok_or_else(|| bad("mode"))?
+                        as u32,
+                    modified: SystemTime::UNIX_EPOCH
+                        + Duration::from_secs(mtime),
+                    offset,
+                    len: if typeflag == b'5' { 0 } else { len },
+                };
+                if let Some(name) = normalize(&name).filter(|n| !n.is_empty()) {
+                    for dir in ancestors(&name) {
+                        entries
+                            .entry(dir.to_string())
+                            .or_insert_with(directory);
+                    }
+                    // Later entries replace earlier ones, as when unpacking.
+                    entries.insert(name, entry);
+                }
+                r.seek(SeekFrom::Current(padded as i64))?;
+            }
+        }
+        offset += padded;
+    }
+    Ok(entries)
+}
+
+/// A directory that has no entry of its own.
+fn directory() -> Entry {
+    Entry {
+        kind: Kind::Directory,
+        mode: 0o755,
+        modified: SystemTime::UNIX_EPOCH,
+        offset: 0,
+        len: 0,
+    }
+}
+
+/// The pax records for the next entry that we use.
+#[derive(Default)]
+struct Pax {
+    path: Option<String>,
+    size: Option<u64>,
+    mtime: Option<u64>,
+}
+
+impl Pax {
+    /// Reads records of the form `LEN KEY=VALUE\n`.
+    fn parse(mut data: &[u8]) -> Self {
+        let mut pax = Pax::default();
+        while let Some(space) = data.iter().position(|&b| b == b' ') {
+            let len = match std::str::from_utf8(&data[..space])
+                .ok()
+                .and_then(|l| l.parse::<usize>().ok())
+            {
+                Some(len) if len > space && len <= data.len() => len,
+                _ => break,
+            };
+            let record = String::from_utf8_lossy(&data[space + 1..len]);
+            if let Some((key, value)) =
+                record.trim_end_matches('\n').split_once('=')
+            {
+                match key {
+                    "path" => pax.path = Some(value.to_string()),
+                    "size" => pax.size = value.parse().ok(),
+                    // Fractions of a second are dropped.
+                    "mtime" => {
+                        pax.mtime =
+                            value.split('.').next().and_then(|s| s.parse().ok())
+                    }
+                    _ => (),
+                }
+            }
+            data = &data[len..];
+        }
+        pax
+    }
+}
+
+/// Reads a NUL-terminated string field.
+fn string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Reads an octal number field, padded with spaces or NULs.
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = string(field);
+    let digits = digits.trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Reads a number field, which is octal, or for large values in GNU tar,
+/// base-256 with the top bit of the first byte set.
+fn number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 == 0 {
+        return octal(field);
+    }
+    let mut n = u64::from(field[0] & 0x7f);
+    for &b in &field[1..] {
+        n = n.checked_mul(256)?.checked_add(u64::from(b))?;
+    }
+    Some(n)
+}
+
+/// The bytes of one entry, read from the archive at its offset. Reads happen
+/// on the blocking pool, as `tokio::fs::File`'s do, and use `pread`, so that
+/// any number of entries can be read at once.
+#[derive(Debug)]
+struct Slice {
+    file: Arc<std::fs::File>,
+    start: u64,
+    len: u64,
+    /// Where the next byte handed out comes from, relative to `start`.
+    pos: u64,
+    /// Bytes read from `pos` but not yet handed out.
+    buffered: Vec<u8>,
+    reading: Option<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+impl Slice {
+    fn new(file: &Arc<std::fs::File>, start: u64, len: u64) -> Self {
+        Slice {
+            file: Arc::clone(file),
+            start,
+            len,
+            pos: 0,
+            buffered: vec![],
+            reading: None,
+        }
+    }
+}
+
+impl AsyncRead for Slice {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.buffered.is_empty() {
+            let want = (this.len - this.pos).min(CHUNK as u64) as usize;
+            if want == 0 || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let file = &this.file;
+            let at = this.start + this.pos;
+            let reading = this.reading.get_or_insert_with(|| {
+                let file = Arc::clone(file);
+                tokio::task::spawn_blocking(move || {
+                    let mut data = vec![0; want];
+                    let n = file.read_at(&mut data, at)?;
+                    data.truncate(n);
+                    Ok(data)
+                })
+            });
+            let data = futures::ready!(Pin::new(reading).poll(cx));
+            this.reading = None;
+            this.buffered = data??;
+            if this.buffered.is_empty() {
+                // The archive has shrunk since it was indexed.
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        let n = this.buffered.len().min(buf.remaining());
+        buf.put_slice(&this.buffered[..n]);
+        this.buffered.drain(..n);
+        this.pos += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for Slice {
+    fn start_seek(
+        mut self: Pin<&mut Self>,
+        position: SeekFrom,
+    ) -> io::Result<()> {
+        let pos = match position {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        let pos =
+            pos.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.pos = pos.min(self.len);
+        self.buffered.clear();
+        self.reading = None;
+        Ok(())
+    }
+
+    fn poll_complete(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+impl Content for Slice {
+    fn fd(&self) -> Option<RawFd> {
+        // Hints would apply to the whole archive, not the entry.
+        None
+    }
+}
+
+/// The bytes of one entry of an archive in memory.
+struct Memory(io::Cursor<&'static [u8]>);
+
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Memory")
+            .field("len", &self.0.get_ref().len())
+            .finish()
+    }
+}
+
+impl AsyncRead for Memory {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncSeek for Memory {
+    fn start_seek(
+        mut self: Pin<&mut Self>,
+        position: SeekFrom,
+    ) -> io::Result<()> {
+        Pin::new(&mut self.0).start_seek(position)
+    }
+
+    fn poll_complete(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.0).poll_complete(cx)
+    }
+}
+
+impl Content for Memory {
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// The site built into the binary, from the directory `HTTPD2_EMBED_DIR`
+/// names at build time.
+#[cfg(feature = "embed")]
+pub fn embedded() -> io::Result<Archive> {
+    static SITE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/site.tar"));
+    Archive::from_static(SITE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    /// Appends a ustar entry to `tar`.
+    fn add(
+        tar: &mut Vec<u8>,
+        name: &str,
+        typeflag: u8,
+        mode: u32,
+        data: &[u8],
+    ) {
+        let mut header = [0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(format!("{:07o}", mode).as_bytes());
+        header[124..135]
+            .copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[136..147]
+            .copy_from_slice(format!("{:011o}", 1_700_000_000).as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        tar.extend_from_slice(&header);
+        tar.extend_from_slice(data);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+    }
+
+    #[tokio::test]
+    async fn tar_source() {
+        let mut tar = vec![];
+        add(&mut tar, "./index.html", b'0', 0o644, b"<h1>hi</h1>");
+        add(&mut tar, "docs/", b'5', 0o755, b"");
+        add(&mut tar, "docs/guide.txt", b'0', 0o644, b"0123456789");
+        add(&mut tar, "docs/deep/note.txt", b'0', 0o644, b"note");
+        add(&mut tar, "private.txt", b'0', 0o600, b"secret");
+        add(&mut tar, "link", b'2', 0o777, b"");
+        add(&mut tar, "../escape", b'0', 0o644, b"out");
+        let mut pax = vec![];
+        add(
+            &mut pax,
+            "pax",
+            b'x',
+            0o644,
+            b"27 path=long/pax-named.txt\n",
+        );
+        tar.extend_from_slice(&pax);
+        add(&mut tar, "short", b'0', 0o644, b"pax");
+        tar.extend_from_slice(&[0; 1024]);
+        let path = std::env::temp_dir()
+            .join(format!("httpd2-archive-test-{}.tar", std::process::id()));
+        std::fs::write(&path, &tar).unwrap();
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let archive = Archive::open(&path).unwrap();
+        let open = |path: &'static str| {
+            archive.open(&log, Path::new(path), &|_| "text/plain", &|_| None)
+        };
+        let read = |mut file: File| async move {
+            let mut s = String::new();
+            file.file.read_to_string(&mut s).await.unwrap();
+            s
+        };
+
+        let file = open("./index.html").await.unwrap();
+        assert_eq!(file.len, 11);
+        assert_eq!(
+            file.modified,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+        );
+        assert_eq!(read(file).await, "<h1>hi</h1>");
+        assert_eq!(
+            read(open("./long/pax-named.txt").await.unwrap()).await,
+            "pax"
+        );
+
+        // Reads can start anywhere in an entry, and stop at its end.
+        let mut file = open("./docs/guide.txt").await.unwrap();
+        file.file.seek(SeekFrom::Start(7)).await.unwrap();
+        assert_eq!(read(file).await, "789");
+
+        assert!(matches!(
+            open("./docs/").await,
+            Err(picky::Error::Directory)
+        ));
+        // Directories are there even without entries of their own.
+        assert!(matches!(
+            open("./docs/deep").await,
+            Err(picky::Error::Directory)
+        ));
+        assert!(matches!(
+            open("./private.txt").await,
+            Err(picky::Error::BadMode(0o600))
+        ));
+        assert!(matches!(
+            open("./link").await,
+            Err(picky::Error::SpecialFile)
+        ));
+        assert!(matches!(
+            open("./index.html/").await,
+            Err(picky::Error::NotDirectory)
+        ));
+        assert!(matches!(
+            open("./index.html/x").await,
+            Err(picky::Error::NotDirectory)
+        ));
+        assert!(matches!(open("./escape").await, Err(picky::Error::Io(_))));
+        assert!(matches!(open("./missing").await, Err(picky::Error::Io(_))));
+
+        let names = archive.list(Path::new("./docs")).await.unwrap();
+        assert_eq!(names, ["deep", "guide.txt"]);
+
+        // An archive in memory is served the same way.
+        let memory = Box::leak(tar.clone().into_boxed_slice());
+        let memory = Archive::from_static(memory).unwrap();
+        let file = memory
+            .open(&log, Path::new("./docs/guide.txt"), &|_| "", &|_| None)
+            .await
+            .unwrap();
+        assert_eq!(file.file.fd(), None);
+        assert_eq!(read(file).await, "0123456789");
+
+        // Anything else is refused at startup.
+        std::fs::write(&path, &tar[..700]).unwrap();
+        assert!(Archive::open(&path).is_err());
+        std::fs::write(&path, "not a tar file").unwrap();
+        assert!(Archive::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// The sorted keys of the index of `tar`.
+    fn names(tar: &[u8]) -> Vec<String> {
+        let entries = index(&mut io::Cursor::new(tar), tar.len() as u64);
+        let mut names: Vec<_> = entries.unwrap().into_keys().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn escaping_names() {
+        let mut tar = vec![];
+        add(&mut tar, "docs/../../etc/passwd", b'0', 0o644, b"x");
+        add(&mut tar, "docs/./../secret", b'0', 0o644, b"x");
+        add(&mut tar, "..", b'5', 0o755, b"");
+        add(&mut tar, "././@LongLink", b'L', 0o644, b"../long\0");
+        add(&mut tar, "long", b'0', 0o644, b"x");
+        add(&mut tar, "pax", b'x', 0o644, b"15 path=../pax\n");
+        add(&mut tar, "pax", b'0', 0o644, b"x");
+        // Absolute names are taken as under the root, as tar does.
+        add(&mut tar, "/abs//./file", b'0', 0o644, b"x");
+        tar.extend_from_slice(&[0; 1024]);
+        assert_eq!(names(&tar), ["", "abs", "abs/file"]);
+
+        let archive =
+            Archive::from_static(Box::leak(tar.into_boxed_slice())).unwrap();
+        assert!(archive.find(Path::new("./abs/file")).is_ok());
+        assert!(archive.find(Path::new("./abs/../abs/file")).is_err());
+        assert!(archive.find(Path::new("../abs/file")).is_err());
+    }
+
+    #[tokio::test]
+    async fn duplicate_entries() {
+        let mut tar = vec![];
+        add(&mut tar, "a.txt", b'0', 0o644, b"first");
+        add(&mut tar, "./a.txt", b'0', 0o644, b"second");
+        add(&mut tar, "b", b'0', 0o644, b"file");
+        add(&mut tar, "b/", b'5', 0o755, b"");
+        add(&mut tar, "c/", b'5', 0o755, b"");
+        add(&mut tar, "c", b'0', 0o644, b"file");
+        add(&mut tar, "d.txt", b'0', 0o644, b"open");
+        add(&mut tar, "d.txt", b'0', 0o600, b"closed");
+        tar.extend_from_slice(&[0; 1024]);
+        let archive =
+            Archive::from_static(Box::leak(tar.into_boxed_slice())).unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let open = |path: &'static str| {
+            archive.open(&log, Path::new(path), &|_| "text/plain", &|_| None)
+        };
+
+        // The last entry with a name wins, as when unpacking.
+        let mut file = open("./a.txt").await.unwrap();
+        assert_eq!(file.len, 6);
+        let mut s = String::new();
+        file.file.read_to_string(&mut s).await.unwrap();
+        assert_eq!(s, "second");
+        assert!(matches!(open("./b").await, Err(picky::Error::Directory)));
+        assert!(open("./c").await.is_ok());
+        assert!(matches!(
+            open("./d.txt").await,
+            Err(picky::Error::BadMode(0o600))
+        ));
+    }
+
+    #[test]
+    fn truncated_archives() {
+        let bad = |tar: &[u8]| {
+            index(&mut io::Cursor::new(tar), tar.len() as u64)
+                .unwrap_err()
+                .kind()
+        };
+        let mut tar = vec![];
+        add(&mut tar, "a.txt", b'0', 0o644, &[b'a'; 600]);
+
+        // Without the zeroed blocks at the end is fine.
+        assert_eq!(names(&tar), ["", "a.txt"]);
+        // Cut off in the header, or in the data.
+        assert_eq!(bad(&tar[..300]), io::ErrorKind::InvalidData);
+        assert_eq!(bad(&tar[..612]), io::ErrorKind::InvalidData);
+        assert_eq!(bad(&tar[..1024]), io::ErrorKind::InvalidData);
+        // A corrupt header.
+        let mut corrupt = tar.clone();
+        corrupt[0] = b'b';
+        assert_eq!(bad(&corrupt), io::ErrorKind::InvalidData);
+        // A pax size past the end.
+        let mut pax = vec![];
+        add(&mut pax, "pax", b'x', 0o644, b"20 size=99999999999\n");
+        pax.extend_from_slice(&tar);
+        assert_eq!(bad(&pax), io::ErrorKind::InvalidData);
+        // A long name past the end.
+        let mut long = vec![];
+        add(&mut long, "././@LongLink", b'L', 0o644, &[b'x'; 600]);
+        assert_eq!(bad(&long[..1024]), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,12 +1,21 @@
 //! Server argument parsing.
 
+use std::ffi::OsString;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
 
 use clap::{Parser, ValueEnum};
+use hyper::header::HeaderValue;
+use nix::sys::stat::Mode;
 use nix::unistd::{Gid, Uid};
 
+use crate::encoding::Preference;
+use crate::glob::Glob;
+use crate::headers::HeaderRule;
+use crate::image::ImageFormat;
+use crate::proxy::Cidr;
+
 #[derive(Parser)]
 pub struct CommonArgs {
     /// Specifies that the server should chroot into ROOT. You basically always
@@ -21,6 +30,25 @@ pub struct CommonArgs {
         value_name = "ADDR:PORT"
     )]
     pub addr: SocketAddr,
+    /// Makes a listener on an IPv6 address refuse IPv4 connections. By
+    /// default, this is up to the system (on Linux, the
+    /// net.ipv6.bindv6only sysctl).
+    #[clap(long, overrides_with = "dual_stack")]
+    pub ipv6_only: bool,
+    /// Makes a listener on an IPv6 address accept IPv4 connections too, which
+    /// appear to come from IPv4-mapped addresses.
+    #[clap(long, overrides_with = "ipv6_only")]
+    pub dual_stack: bool,
+    /// Only accepts connections that arrive through the network interface
+    /// NAME, such as eth0, whatever address they're sent to (Linux only).
+    /// Before Linux 5.7, this requires root or CAP_NET_RAW.
+    #[clap(long, value_name = "NAME")]
+    pub bind_device: Option<OsString>,
+    /// Accepts TCP Fast Open, letting returning clients send their first
+    /// request along with the handshake. COUNT limits how many such
+    /// connections may be pending at once (Linux only).
+    #[clap(long, value_name = "COUNT")]
+    pub fast_open: Option<u32>,
     /// User to switch to via setuid before serving. Required if the server is
     /// started as root.
     #[clap(
@@ -38,9 +66,52 @@ pub struct CommonArgs {
         value_name = "GID"
     )]
     pub gid: Option<Gid>,
+    /// Supplementary group to keep after switching groups, as well as GID.
+    /// May be repeated.
+    #[clap(
+        long = "group",
+        value_parser = parse_gid,
+        value_name = "GID",
+        requires = "gid"
+    )]
+    pub groups: Vec<Gid>,
+    /// Also keeps the supplementary groups that the group database lists for
+    /// the user given with -U, as login would. The database is read before
+    /// chroot.
+    #[clap(long, requires = "uid", requires = "gid")]
+    pub init_groups: bool,
+    /// File mode creation mask, in octal, set at startup before any files are
+    /// created. If not provided, the mask is inherited.
+    #[clap(long, value_parser = parse_umask, value_name = "MODE")]
+    pub umask: Option<Mode>,
+    /// Niceness to run at, from -20 (least nice) to 19 (most nice). Going
+    /// below the inherited niceness requires root or CAP_SYS_NICE.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(i32).range(-20..=19),
+        allow_negative_numbers = true,
+        value_name = "N"
+    )]
+    pub nice: Option<i32>,
+    /// I/O scheduling class to run in (Linux only).
+    #[clap(long, value_name = "CLASS")]
+    pub io_class: Option<IoClass>,
+    /// Priority within the I/O scheduling class, from 0 (highest) to 7
+    /// (lowest). Ignored by the idle class.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u8).range(0..=7),
+        default_value = "4",
+        value_name = "LEVEL"
+    )]
+    pub io_priority: u8,
     /// Selects a logging backend.
     #[clap(long, default_value = "stderr", value_name = "NAME")]
     pub log: Log,
+    /// Whether to color the log on stderr: always, never, or auto, meaning
+    /// when stderr is a terminal and NO_COLOR isn't set.
+    #[clap(long, default_value = "auto", value_name = "WHEN")]
+    pub log_color: LogColor,
     /// Adds User-Agent header contents, if provided, to request log output.
     #[clap(long)]
     pub log_user_agent: bool,
@@ -51,6 +122,74 @@ pub struct CommonArgs {
     /// timestamped by an external entity such as journald or syslog.
     #[clap(long)]
     pub suppress_log_timestamps: bool,
+    /// How to write log timestamps: short, as in "Oct 15 11:46:45.369" (UTC);
+    /// rfc3339, in UTC; local, RFC 3339 in the local time zone; or epoch,
+    /// seconds since 1970. Defaults to short in text logs and epoch in logfmt.
+    #[clap(long, value_name = "FORMAT")]
+    pub log_timestamps: Option<LogTimestamps>,
+    /// Digits of the seconds' fraction in log timestamps, from 0 to 9.
+    #[clap(
+        long,
+        default_value = "3",
+        value_parser = clap::value_parser!(u8).range(0..=9),
+        value_name = "DIGITS"
+    )]
+    pub log_timestamp_digits: u8,
+    /// Drops server log records less important than LEVEL: one of critical,
+    /// error, warn, info, debug, or trace. The admin socket can change this
+    /// while the server runs. Doesn't apply to records in --access-log.
+    #[clap(
+        long,
+        default_value = "trace",
+        value_name = "LEVEL",
+        value_parser = parse_level
+    )]
+    pub log_level: slog::Level,
+    /// Passes at most COUNT server log warnings and errors with the same
+    /// message in each --log-repeat-window, and then one record saying how
+    /// many more were suppressed. Doesn't apply to records in --access-log.
+    #[clap(
+        long,
+        value_name = "COUNT",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub log_repeat_limit: Option<u32>,
+    /// Length of the window for --log-repeat-limit, in seconds.
+    #[clap(
+        long,
+        default_value = "60",
+        value_parser = seconds,
+        requires = "log_repeat_limit",
+        value_name = "SECS"
+    )]
+    pub log_repeat_window: Duration,
+    /// Writes a record of each request and its response to PATH, appending,
+    /// instead of to the server log. The file is opened at startup.
+    #[clap(long, value_name = "PATH")]
+    pub access_log: Option<PathBuf>,
+    /// How to write --access-log and --host-access-log: text, as the server
+    /// log is, or logfmt, as key=value pairs.
+    #[clap(long, default_value = "text", value_name = "FORMAT")]
+    pub access_log_format: LogFormat,
+    /// Writes the access records of requests addressed to HOST to PATH,
+    /// appending, instead of to --access-log or the server log. May be
+    /// repeated, to give each host a file of its own. The file is opened at
+    /// startup.
+    #[clap(
+        long = "host-access-log",
+        value_parser = parse_host_log,
+        value_name = "HOST=PATH"
+    )]
+    pub host_access_logs: Vec<HostLog>,
+    /// Serves HOST at most BYTES of content a calendar month (UTC), after
+    /// which its requests get a 509 until the next month. Hosts named here or
+    /// with --host have their traffic counted in the stats. May be repeated.
+    #[clap(
+        long = "host-quota",
+        value_parser = parse_host_quota,
+        value_name = "HOST=BYTES"
+    )]
+    pub host_quotas: Vec<HostQuota>,
     /// How long our resources can be cached elsewhere, in seconds.
     #[clap(
         long,
@@ -58,6 +197,10 @@ pub struct CommonArgs {
         value_name = "SECS"
     )]
     pub default_max_age: usize,
+    /// Send an Expires header alongside Cache-Control, for caches that predate
+    /// HTTP/1.1.
+    #[clap(long)]
+    pub expires: bool,
     /// Send the HTTP Strict-Transport-Security header, instructing clients not
     /// to use unencrypted HTTP to access this site.
     #[clap(long)]
@@ -66,9 +209,116 @@ pub struct CommonArgs {
     /// convert http URLs to https.
     #[clap(long)]
     pub upgrade: bool,
+    /// Precompressed alternates to look for, as a comma-separated list of
+    /// encodings (br, zstd, gzip) with optional weights, like
+    /// "br,gzip;q=0.8". Encodings the client accepts are tried in order of
+    /// client preference times weight, with ties going to the earliest
+    /// listed.
+    #[clap(
+        long,
+        default_value = "gzip",
+        value_parser = parse_encoding_preference,
+        value_delimiter = ',',
+        value_name = "LIST"
+    )]
+    pub encodings: Vec<Preference>,
+    /// Image alternates to look for, as a comma-separated list of formats
+    /// (avif, webp): for photo.jpg, photo.jpg.avif and so on. Formats the
+    /// client names in Accept are tried in order of client preference, with
+    /// ties going to the earliest listed. Applies to JPEG, PNG and GIF files.
+    #[clap(long, value_enum, value_delimiter = ',', value_name = "LIST")]
+    pub image_formats: Vec<ImageFormat>,
+    /// Serves files whose URL path matches PATTERN with content type TYPE,
+    /// instead of guessing from the extension. PATTERN may use ?, * (within
+    /// a path component), and ** (across components). May be repeated; the
+    /// first match wins.
+    #[clap(
+        long = "content-type",
+        value_parser = parse_content_type_rule,
+        value_name = "PATTERN=TYPE"
+    )]
+    pub content_types: Vec<ContentTypeRule>,
+    /// Which cache validators to send with files: both (Last-Modified and
+    /// ETag), last-modified, etag, or none.
+    #[clap(long, default_value = "last-modified", value_name = "MODE")]
+    pub validators: Validators,
+    /// Uses validator MODE for files whose URL path matches PATTERN, instead
+    /// of --validators. May be repeated; the first match wins.
+    #[clap(
+        long = "path-validators",
+        value_parser = parse_validator_rule,
+        value_name = "PATTERN=MODE"
+    )]
+    pub path_validators: Vec<ValidatorRule>,
+    /// Changes a header on responses: MATCH=Name: value sets it,
+    /// MATCH=+Name: value adds a value alongside any already there, and
+    /// MATCH=-Name removes it. MATCH is a pattern for the requested path, as
+    /// for --content-type, or type: and a media type, like type:text/html or
+    /// type:image/*. May be repeated; every matching rule applies, in order.
+    #[clap(
+        long = "header",
+        value_parser = parse_header_rule,
+        value_name = "MATCH=ACTION"
+    )]
+    pub header_rules: Vec<HeaderRule>,
+    /// Sends VALUE as the Server header. By default, there's none.
+    #[clap(long, value_parser = parse_header_value, value_name = "VALUE")]
+    pub server_header: Option<HeaderValue>,
+    /// Sends response headers sorted by name, with repeated headers joined
+    /// into one, so the order and shape of a response say less about the
+    /// software that produced it.
+    #[clap(long)]
+    pub normalize_headers: bool,
+    /// Sends a SHA-256 of the file as Repr-Digest or Content-Digest to clients
+    /// that ask for one with Want-Repr-Digest or Want-Content-Digest. Files
+    /// are hashed when first asked for, and the digests remembered.
+    #[clap(long)]
+    pub digests: bool,
+    /// How to compute ETags: metadata (a hash of each file's length and
+    /// modification time, which is cheap but differs between hosts) or
+    /// content (a hash of each file's bytes, computed when first needed and
+    /// remembered, which is the same wherever the file is served).
+    #[clap(long, default_value = "metadata", value_name = "SOURCE")]
+    pub etag_source: EtagSource,
+    /// Hints to the kernel that files of at least BYTES will be read
+    /// sequentially, so it reads further ahead, and asks it to start reading
+    /// them right away.
+    #[clap(long, value_name = "BYTES")]
+    pub fadvise_threshold: Option<u64>,
+    /// Once a file of at least --fadvise-threshold has been sent, tells the
+    /// kernel its pages won't be needed again, so that large downloads don't
+    /// push everything else out of the page cache.
+    #[clap(long, requires = "fadvise_threshold")]
+    pub fadvise_dontneed: bool,
     /// Maximum number of simultaneous connections to allow.
     #[clap(long, default_value = "100000", value_name = "COUNT")]
     pub max_connections: usize,
+    /// Maximum number of simultaneous connections to allow from any one
+    /// client address (or, for IPv6, any one /64 network). Connections over
+    /// the limit are closed as soon as they're accepted.
+    #[clap(long, value_name = "COUNT")]
+    pub max_connections_per_ip: Option<usize>,
+    /// Maximum number of requests to work on at once, across all
+    /// connections. Requests over the limit get an immediate 503 Service
+    /// Unavailable rather than waiting their turn.
+    #[clap(long, value_name = "COUNT")]
+    pub max_requests: Option<usize>,
+    /// Seconds after which clients turned away by --max-requests are invited
+    /// to try again, in the Retry-After header.
+    #[clap(long, default_value = "5", value_name = "SECS")]
+    pub retry_after: u64,
+    /// Maximum number of bodies of at least --bulk-size bytes to send at once.
+    /// Others wait their turn, while smaller bodies go right away.
+    #[clap(long, value_name = "COUNT")]
+    pub max_bulk: Option<usize>,
+    /// Size from which a body counts against --max-bulk.
+    #[clap(
+        long,
+        default_value = "1048576",
+        requires = "max_bulk",
+        value_name = "BYTES"
+    )]
+    pub bulk_size: u64,
     /// Maximum number of concurrent streams (HTTP/2) or pipelined requests
     /// (HTTP/1.1) to allow per connection.
     #[clap(long, default_value = "10", value_name = "COUNT")]
@@ -87,9 +337,242 @@ pub struct CommonArgs {
     /// not provided, this will equal the number of CPUs.
     #[clap(long)]
     pub core_threads: Option<usize>,
+    /// Serves the contents of DIR for URLs beginning with PREFIX, instead of
+    /// looking under ROOT. DIR is opened before chroot, so it may lie outside
+    /// ROOT. May be repeated; the longest matching PREFIX wins.
+    #[clap(
+        long = "mount",
+        value_parser = parse_mount,
+        value_name = "PREFIX=DIR"
+    )]
+    pub mounts: Vec<Mount>,
+    /// Serves paths that ROOT doesn't have, or won't serve, from DIR instead.
+    /// DIR is opened before chroot, so it may lie outside ROOT. May be
+    /// repeated; fallbacks are tried in order.
+    #[clap(long = "fallback-root", value_name = "DIR")]
+    pub fallback_roots: Vec<PathBuf>,
+    /// Serves the site built into the binary, for paths that ROOT and any
+    /// --fallback-root don't have. ROOT can then be an empty directory.
+    #[cfg(feature = "embed")]
+    #[clap(long)]
+    pub embedded: bool,
+    /// Serves each user's directory under /~USER, where TEMPLATE says where
+    /// it is, like /home/{user}/public_html. The users are found at startup,
+    /// by listing the directory before {user}; a directory that isn't owned
+    /// by the same user as the entry it's under is skipped.
+    #[clap(long, value_parser = parse_user_dirs, value_name = "TEMPLATE")]
+    pub user_dirs: Option<UserDirs>,
+    /// Refuses to follow symlinks when opening files, in ROOT or any mount.
+    /// Symlinks that stay inside their directory are otherwise followed.
+    #[clap(long)]
+    pub no_symlinks: bool,
+    /// Removes PREFIX from the front of every request path before looking it
+    /// up, for deployments behind a router that forwards a sub-path. Requests
+    /// outside PREFIX get a 404.
+    #[clap(long, value_parser = parse_prefix, value_name = "PREFIX")]
+    pub strip_prefix: Option<String>,
+    /// What to do with a request for a file that has a trailing slash, like
+    /// /page.html/.
+    #[clap(long, default_value = "not-found", value_name = "POLICY")]
+    pub file_trailing_slash: TrailingSlash,
+    /// What to do with a request that names a directory: serve its
+    /// index.html, or respond as though there were nothing there.
+    #[clap(long, default_value = "index-html", value_name = "POLICY")]
+    pub directory_index: DirectoryIndex,
+    /// Applies the redirect and rewrite rules in _redirects at the root, in
+    /// Netlify's format, rereading the file when it changes.
+    #[clap(long)]
+    pub redirects: bool,
+    /// Adds the response headers in _headers at the root, in Netlify's
+    /// format, rereading the file when it changes. --header rules apply
+    /// after these.
+    #[clap(long)]
+    pub site_headers: bool,
+    /// For a 404, serves the file named NAME (say, 404.html) from the
+    /// nearest directory above the requested path that has one, before
+    /// falling back to errors/404.html.
+    #[clap(long, value_parser = parse_file_name, value_name = "NAME")]
+    pub not_found_page: Option<String>,
+    /// Respond 403 Forbidden, rather than 404 Not Found, to requests for files
+    /// that exist but can't be served because of their permissions or type.
+    /// This tells clients which paths exist, so it's best kept to internal
+    /// deployments.
+    #[clap(long)]
+    pub distinguish_forbidden: bool,
+    /// Developer mode: explains errors in the response body, including the
+    /// sanitized path and why it couldn't be served, instead of sending the
+    /// usual error page. Implies --distinguish-forbidden. Never use this on a
+    /// public server.
+    #[clap(long)]
+    pub dev: bool,
+    /// Treats connections from addresses in CIDR as coming from a reverse
+    /// proxy, and takes the client address, and the host and scheme it asked
+    /// for, from their Forwarded or X-Forwarded-* headers. May be repeated, or
+    /// given a comma-separated list.
+    #[clap(
+        long,
+        value_parser = parse_cidr,
+        value_delimiter = ',',
+        value_name = "CIDR"
+    )]
+    pub trusted_proxies: Vec<Cidr>,
+    /// Expects every connection to begin with a PROXY protocol (version 1 or
+    /// 2) header from a load balancer, giving the real client address.
+    /// Connections without one are dropped.
+    #[clap(long)]
+    pub proxy_protocol: bool,
+    /// Host name this server answers to. May be repeated. If given, requests
+    /// for any other host are handled according to --unknown-host; if not,
+    /// any host is accepted.
+    #[clap(long = "host", value_parser = parse_host, value_name = "NAME")]
+    pub hosts: Vec<String>,
+    /// What to do with requests for a host not named by --host.
+    #[clap(long, default_value = "misdirected", value_name = "POLICY")]
+    pub unknown_host: UnknownHost,
+    /// Permanently redirects requests for host FROM to the same path and
+    /// query on https://TO, where TO may include a port. May be repeated.
+    /// Takes precedence over --host.
+    #[clap(
+        long = "redirect-host",
+        value_parser = parse_host_redirect,
+        value_name = "FROM=TO"
+    )]
+    pub host_redirects: Vec<HostRedirect>,
+    /// Requires a signed link for files whose URL path matches PATTERN: the
+    /// query must carry exp, an expiry time in seconds since the Unix epoch,
+    /// and sig, the HMAC-SHA256 of the path and exp keyed with the contents
+    /// of --signing-key. Other requests get a 403. May be repeated.
+    #[clap(long = "signed", value_name = "PATTERN", requires = "signing_key")]
+    pub signed: Vec<Glob>,
+    /// File holding the secret used to check --signed links. Read before
+    /// chroot.
+    #[clap(long, value_name = "PATH")]
+    pub signing_key: Option<PathBuf>,
+    /// Requires an OpenID Connect login, with --oidc-issuer, for files whose
+    /// URL path matches PATTERN, and everything beneath them. Browsers without
+    /// a session are sent to log in; other requests get a 401. May be
+    /// repeated.
+    #[clap(long, value_name = "PATTERN", requires = "oidc_issuer")]
+    pub oidc_protect: Vec<Glob>,
+    /// The OpenID Connect provider for --oidc-protect. Its configuration is
+    /// fetched from URL/.well-known/openid-configuration, and its hosts
+    /// looked up, at startup.
+    #[clap(
+        long,
+        value_name = "URL",
+        requires_all = ["oidc_client_id", "oidc_client_secret", "oidc_redirect_uri"]
+    )]
+    pub oidc_issuer: Option<String>,
+    /// The client ID this server is registered with at --oidc-issuer.
+    #[clap(long, value_name = "ID")]
+    pub oidc_client_id: Option<String>,
+    /// File holding the client secret for --oidc-client-id. Read before
+    /// chroot.
+    #[clap(long, value_name = "PATH")]
+    pub oidc_client_secret: Option<PathBuf>,
+    /// The URL the provider sends browsers back to after logging in, as
+    /// registered with it. Requests for its path are answered by the server.
+    #[clap(long, value_name = "URL")]
+    pub oidc_redirect_uri: Option<String>,
+    /// CA certificates to trust for --oidc-issuer, in PEM.
+    #[clap(
+        long,
+        default_value = "/etc/ssl/certs/ca-certificates.crt",
+        value_name = "PATH"
+    )]
+    pub oidc_ca: PathBuf,
+    /// How long a login lasts, in seconds.
+    #[clap(long, default_value = "28800", value_name = "SECS")]
+    pub oidc_session_lifetime: u64,
+    /// Requires a bearer token, a JSON Web Token signed with one of
+    /// --jwt-keys, for files whose URL path matches PATTERN, and everything
+    /// beneath them. Requests without a good one get a 401, or a 403 if it's
+    /// for another issuer or audience. May be repeated.
+    #[clap(long, value_name = "PATTERN", requires = "jwt_keys")]
+    pub jwt_protect: Vec<Glob>,
+    /// The keys that sign tokens for --jwt-protect, as a JWK set: either a
+    /// file, read before chroot, or an http or https URL, fetched at startup
+    /// and hourly after that.
+    #[clap(long, value_name = "SOURCE")]
+    pub jwt_keys: Option<String>,
+    /// Only accepts tokens whose iss is ISSUER.
+    #[clap(long, value_name = "ISSUER")]
+    pub jwt_issuer: Option<String>,
+    /// Only accepts tokens whose aud is or includes AUDIENCE.
+    #[clap(long, value_name = "AUDIENCE")]
+    pub jwt_audience: Option<String>,
+    /// CA certificates to trust for an https --jwt-keys, in PEM.
+    #[clap(
+        long,
+        default_value = "/etc/ssl/certs/ca-certificates.crt",
+        value_name = "PATH"
+    )]
+    pub jwt_ca: PathBuf,
+    /// Refuses requests whose User-Agent contains, ignoring case, any line of
+    /// the file at PATH. Blank lines and lines starting with # are ignored.
+    /// The file is opened before chroot, and can be re-read through the admin
+    /// socket.
+    #[clap(long, value_name = "PATH")]
+    pub block_user_agents: Option<PathBuf>,
+    /// How to refuse requests from blocked User-Agents: forbidden (respond
+    /// 403) or close (drop the request without responding).
+    #[clap(long, default_value = "forbidden", value_name = "ACTION")]
+    pub blocked_user_agent_action: BlockAction,
+    /// Refuses, with a 400, requests that are allowed but suspicious: framing
+    /// headers that are repeated or disagree, a Host that's missing,
+    /// repeated, or at odds with the target, absolute-form targets in
+    /// HTTP/1.1, transfer codings other than chunked, and characters in the
+    /// path or query that RFC 3986 doesn't allow there.
+    #[clap(long)]
+    pub strict: bool,
+    /// Stalls requests whose URL path matches PATTERN, such as /wp-login.php
+    /// or /.env, for --tarpit-delay before answering 404, to waste the time
+    /// of vulnerability scanners. May be repeated.
+    #[clap(long = "tarpit", value_name = "PATTERN")]
+    pub tarpit: Vec<Glob>,
+    /// How long to stall --tarpit requests, in seconds.
+    #[clap(
+        long,
+        default_value = "60",
+        value_parser = seconds,
+        value_name = "SECS"
+    )]
+    pub tarpit_delay: Duration,
+    /// Answers requests for /robots.txt, when there's no such file, with one
+    /// telling crawlers to stay out of URLs beginning with PREFIX. May be
+    /// repeated.
+    #[clap(
+        long = "robots-disallow",
+        value_parser = parse_robots_prefix,
+        value_name = "PREFIX"
+    )]
+    pub robots_disallow: Vec<String>,
+    /// Answers requests for /robots.txt, when there's no such file, with one
+    /// asking crawlers to wait SECS between requests.
+    #[clap(long, value_name = "SECS")]
+    pub robots_crawl_delay: Option<u64>,
+    /// Accepts PUT requests for URLs directly under PREFIX, storing the body
+    /// in DIR under the name given in the URL. DIR is opened before chroot,
+    /// and must be writable by the user the server runs as. Requires
+    /// --upload-token.
+    #[clap(
+        long,
+        value_parser = parse_mount,
+        value_name = "PREFIX=DIR",
+        requires = "upload_token"
+    )]
+    pub upload: Option<Mount>,
+    /// File holding the token that uploads must present, as
+    /// "Authorization: Bearer TOKEN". Read before chroot.
+    #[clap(long, value_name = "PATH")]
+    pub upload_token: Option<PathBuf>,
+    /// Largest upload to accept, in bytes.
+    #[clap(long, default_value = "104857600", value_name = "BYTES")]
+    pub upload_max_size: u64,
 
     /// Path of directory to serve (and, if --chroot is provided, the new root
-    /// directory).
+    /// directory). May instead be a tar archive to serve the contents of, in
+    /// which case --chroot uses the directory it's in.
     #[clap(value_name = "ROOT")]
     pub root: PathBuf,
 }
@@ -105,6 +588,307 @@ pub enum Log {
     Journald,
 }
 
+/// When to color the log, from `--log-color`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogColor {
+    Auto,
+    Always,
+    Never,
+}
+
+/// How log records are written to a file, from `--access-log-format`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One line per record, as on stderr.
+    Text,
+    /// One line per record, as `key=value` pairs.
+    Logfmt,
+}
+
+/// How log timestamps are written, from `--log-timestamps`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogTimestamps {
+    /// Month, day and time of day, in UTC.
+    Short,
+    /// RFC 3339, in UTC.
+    Rfc3339,
+    /// RFC 3339, in the local time zone.
+    Local,
+    /// Seconds since 1970.
+    Epoch,
+}
+
+fn parse_level(val: &str) -> Result<slog::Level, String> {
+    val.parse().map_err(|_| format!("bad log level: {}", val))
+}
+
+/// A URL prefix mapped to a content directory, from `--mount`.
+#[derive(Clone, Debug)]
+pub struct Mount {
+    /// Prefix, in sanitized form.
+    pub prefix: String,
+    pub dir: PathBuf,
+}
+
+/// Where users' directories are, from `--user-dirs`: `parent/USER/rest`.
+#[derive(Clone, Debug)]
+pub struct UserDirs {
+    /// The directory with an entry for each user, like `/home`.
+    pub parent: PathBuf,
+    /// The path under each entry to serve, like `public_html`.
+    pub rest: PathBuf,
+}
+
+/// A content type pinned to paths matching a pattern, from `--content-type`.
+#[derive(Clone, Debug)]
+pub struct ContentTypeRule {
+    pub pattern: Glob,
+    pub content_type: &'static str,
+}
+
+fn parse_content_type_rule(val: &str) -> Result<ContentTypeRule, String> {
+    let (pattern, content_type) = val
+        .split_once('=')
+        .ok_or_else(|| "expected PATTERN=TYPE".to_string())?;
+    if HeaderValue::from_str(content_type).is_err() {
+        return Err("bad content type".to_string());
+    }
+    Ok(ContentTypeRule {
+        pattern: pattern.parse()?,
+        // Arguments live as long as the server does, and files carry their
+        // content type as a static string, so leaking this is fine.
+        content_type: Box::leak(content_type.to_string().into_boxed_str()),
+    })
+}
+
+/// Cache validators to send with a file, from `--validators`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Validators {
+    /// Send both Last-Modified and ETag.
+    Both,
+    /// Send only Last-Modified.
+    LastModified,
+    /// Send only ETag.
+    Etag,
+    /// Send neither; every request gets the full response.
+    None,
+}
+
+impl Validators {
+    pub fn last_modified(self) -> bool {
+        matches!(self, Validators::Both | Validators::LastModified)
+    }
+
+    pub fn etag(self) -> bool {
+        matches!(self, Validators::Both | Validators::Etag)
+    }
+}
+
+/// What ETags are computed from, from `--etag-source`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EtagSource {
+    /// Hash the file's length and modification time.
+    Metadata,
+    /// Hash the file's contents.
+    Content,
+}
+
+/// I/O scheduling classes, as for ionice(1).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IoClass {
+    /// Served ahead of everyone else. Requires root or CAP_SYS_ADMIN.
+    Realtime,
+    /// The usual class.
+    BestEffort,
+    /// Only gets disk time when nobody else wants it.
+    Idle,
+}
+
+/// A validator mode applied to paths matching a pattern, from
+/// `--path-validators`.
+#[derive(Clone, Debug)]
+pub struct ValidatorRule {
+    pub pattern: Glob,
+    pub validators: Validators,
+}
+
+fn parse_validator_rule(val: &str) -> Result<ValidatorRule, String> {
+    let (pattern, mode) = val
+        .split_once('=')
+        .ok_or_else(|| "expected PATTERN=MODE".to_string())?;
+    Ok(ValidatorRule {
+        pattern: pattern.parse()?,
+        validators: Validators::from_str(mode, false)?,
+    })
+}
+
+/// How to refuse a request, from `--blocked-user-agent-action`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BlockAction {
+    /// Respond with 403 Forbidden.
+    Forbidden,
+    /// Abandon the request without responding. On HTTP/1.1 this closes the
+    /// connection; on HTTP/2, it resets the stream.
+    Close,
+}
+
+/// Policy for requests naming a file as though it were a directory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TrailingSlash {
+    /// Respond with 404 Not Found.
+    NotFound,
+    /// Redirect to the path without the trailing slash.
+    Redirect,
+}
+
+/// Policy for requests naming a directory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DirectoryIndex {
+    /// Serve the directory's index.html, if it has one.
+    IndexHtml,
+    /// Respond with 404 Not Found.
+    NotFound,
+    /// Respond with 403 Forbidden.
+    Forbidden,
+}
+
+/// Policy for requests addressed to hosts we don't recognize.
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum UnknownHost {
+    /// Serve the request as normal.
+    Serve,
+    /// Respond with 421 Misdirected Request.
+    Misdirected,
+    /// Respond with 404 Not Found.
+    NotFound,
+}
+
+/// A host name that should be redirected to another, from `--redirect-host`.
+#[derive(Clone, Debug)]
+pub struct HostRedirect {
+    /// Host to redirect from, normalized.
+    pub from: String,
+    /// Authority to redirect to.
+    pub to: String,
+}
+
+fn parse_host_redirect(val: &str) -> Result<HostRedirect, String> {
+    let (from, to) = val
+        .split_once('=')
+        .ok_or_else(|| "expected FROM=TO".to_string())?;
+    if crate::host::normalize(to).is_none() {
+        return Err("bad target host".to_string());
+    }
+    Ok(HostRedirect {
+        from: parse_host(from)?,
+        to: to.to_string(),
+    })
+}
+
+/// A host whose access records go to a file of their own, from
+/// `--host-access-log`.
+#[derive(Clone, Debug)]
+pub struct HostLog {
+    /// The host, normalized.
+    pub host: String,
+    /// The file to append its records to.
+    pub path: PathBuf,
+}
+
+fn parse_host_log(val: &str) -> Result<HostLog, String> {
+    let (host, path) = val
+        .split_once('=')
+        .ok_or_else(|| "expected HOST=PATH".to_string())?;
+    Ok(HostLog {
+        host: parse_host(host)?,
+        path: path.into(),
+    })
+}
+
+/// A host's monthly byte limit, from `--host-quota`.
+#[derive(Clone, Debug)]
+pub struct HostQuota {
+    /// The host, normalized.
+    pub host: String,
+    pub bytes: u64,
+}
+
+fn parse_host_quota(val: &str) -> Result<HostQuota, String> {
+    let (host, bytes) = val
+        .split_once('=')
+        .ok_or_else(|| "expected HOST=BYTES".to_string())?;
+    Ok(HostQuota {
+        host: parse_host(host)?,
+        bytes: bytes.parse().map_err(|_| "bad byte count".to_string())?,
+    })
+}
+
+fn parse_host(val: &str) -> Result<String, String> {
+    crate::host::normalize(val).ok_or_else(|| "bad host name".to_string())
+}
+
+fn parse_mount(val: &str) -> Result<Mount, String> {
+    let (prefix, dir) = val
+        .split_once('=')
+        .ok_or_else(|| "expected PREFIX=DIR".to_string())?;
+    Ok(Mount {
+        prefix: parse_prefix(prefix)?,
+        dir: dir.into(),
+    })
+}
+
+fn parse_user_dirs(val: &str) -> Result<UserDirs, String> {
+    let (parent, rest) = val
+        .split_once("/{user}")
+        .filter(|(_, rest)| rest.is_empty() || rest.starts_with('/'))
+        .ok_or_else(|| "expected a {user} path component".to_string())?;
+    if rest.contains("{user}") {
+        return Err("expected one {user}".to_string());
+    }
+    Ok(UserDirs {
+        parent: if parent.is_empty() { "/" } else { parent }.into(),
+        rest: rest.trim_start_matches('/').into(),
+    })
+}
+
+/// Parses a URL prefix into the form produced by `traversal::sanitize_prefix`.
+fn parse_prefix(val: &str) -> Result<String, String> {
+    if !val.starts_with('/') {
+        return Err("prefix must begin with '/'".to_string());
+    }
+    Ok(crate::traversal::sanitize_prefix(val))
+}
+
+fn parse_file_name(val: &str) -> Result<String, String> {
+    if val.is_empty() || val.starts_with('.') || val.contains('/') {
+        return Err("expected a file name".to_string());
+    }
+    Ok(val.to_string())
+}
+
+fn parse_robots_prefix(val: &str) -> Result<String, String> {
+    if !val.starts_with('/') || val.contains(char::is_whitespace) {
+        return Err("expected a path without spaces".to_string());
+    }
+    Ok(val.to_string())
+}
+
+fn parse_header_value(val: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(val).map_err(|_| "bad header value".to_string())
+}
+
+fn parse_header_rule(val: &str) -> Result<HeaderRule, String> {
+    val.parse()
+}
+
+fn parse_encoding_preference(val: &str) -> Result<Preference, String> {
+    val.parse()
+}
+
+fn parse_cidr(val: &str) -> Result<Cidr, String> {
+    val.parse()
+}
+
 fn parse_uid(val: &str) -> Result<Uid, std::num::ParseIntError> {
     val.parse::<libc::uid_t>().map(Uid::from_raw)
 }
@@ -113,6 +897,13 @@ fn parse_gid(val: &str) -> Result<Gid, std::num::ParseIntError> {
     val.parse::<libc::gid_t>().map(Gid::from_raw)
 }
 
+fn parse_umask(val: &str) -> Result<Mode, String> {
+    match libc::mode_t::from_str_radix(val, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(Mode::from_bits_truncate(mode)),
+        _ => Err("expected an octal mode like 027".to_string()),
+    }
+}
+
 fn seconds(val: &str) -> Result<Duration, std::num::ParseFloatError> {
     val.parse::<f64>().map(Duration::from_secs_f64)
 }
@@ -1,4 +1,5 @@
 use std::convert::TryFrom;
+use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
@@ -7,13 +8,15 @@ use std::sync::{
 
 use bytes::Bytes;
 use http_body_util::Empty;
-use httpd2::log::OptionKV;
+use httpd2::log::{self, logger, LevelSwitch, OptionKV, ACCESS};
 use hyper::body::Incoming;
 use hyper::http::HeaderValue;
 use hyper::http::uri::{Scheme, Authority};
-use hyper::server::conn::http1::Builder as ConnBuilder;
+use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response, Method, Uri, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
 
 use nix::unistd::{Gid, Uid};
 
@@ -22,9 +25,18 @@ use tokio::time::timeout;
 
 use clap::Parser;
 
-use httpd2::args::{CommonArgs, Log, HasCommonArgs};
+use httpd2::accept::AcceptBackoff;
+use httpd2::args::{CommonArgs, HasCommonArgs};
+use httpd2::caps::{self, Capabilities};
 use httpd2::err::ServeError;
-use httpd2::sync::SharedSemaphore;
+use httpd2::groups;
+use httpd2::host;
+use httpd2::listen;
+use httpd2::proxy;
+use httpd2::sched;
+use httpd2::stats::Failure;
+use httpd2::strict;
+use httpd2::sync::{PerIpLimit, SharedSemaphore};
 
 #[cfg(feature = "system_allocator")]
 #[global_allocator]
@@ -39,6 +51,11 @@ pub struct Args {
     /// Target for redirects.
     #[structopt(value_name = "HOST")]
     pub default_host: String,
+
+    /// Also speaks cleartext HTTP/2 (h2c) to clients that start with its
+    /// connection preface, as clients with prior knowledge do.
+    #[clap(long)]
+    pub h2c: bool,
 }
 
 impl HasCommonArgs for Args {
@@ -50,35 +67,23 @@ impl HasCommonArgs for Args {
 /// Main server entry point.
 fn main() {
     use futures::future::FutureExt;
-    use slog::Drain;
 
     // Go ahead and parse arguments before dropping privileges, since they
     // control whether we drop privileges, among other things.
     let args = Args::parse();
 
-    let log = match args.common.log {
-        Log::Stderr => {
-            // Produce boring plain text.
-            let decorator = slog_term::PlainDecorator::new(std::io::stderr());
-            // Pack everything onto one line, with the largest scope at left.
-            let mut fmt = slog_term::FullFormat::new(decorator)
-                .use_original_order();
-            if args.common.suppress_log_timestamps {
-                fmt = fmt.use_custom_timestamp(|_| Ok(()));
-            }
-            let drain = fmt.build().fuse();
-            // Don't block the server until a bunch of records have built up.
-            let drain =
-                slog_async::Async::new(drain).chan_size(1024).build().fuse();
-            slog::Logger::root(drain, slog::o!())
-        }
-        #[cfg(feature = "journald")]
-        Log::Journald => {
-            let drain = slog_journald::JournaldDrain.ignore_res();
-            // Don't block the server until a bunch of records have built up.
-            let drain =
-                slog_async::Async::new(drain).chan_size(1024).build().fuse();
-            slog::Logger::root(drain, slog::o!())
+    // Before any threads exist, since they inherit priorities.
+    if let Err(e) = sched::apply(&args.common) {
+        eprintln!("can't set scheduling options: {}", e);
+        std::process::exit(1);
+    }
+
+    let level = LevelSwitch::new(args.common.log_level);
+    let log = match logger(&args.common, &level) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("can't open log: {}", e);
+            std::process::exit(1);
         }
     };
 
@@ -104,13 +109,20 @@ async fn start(args: Args, log: slog::Logger) -> Result<(), ServeError> {
             eprintln!("Provide a lower privileged user ID with -U <uid>");
             std::process::exit(1);
         }
+    } else {
+        let held = Capabilities::current()?;
+        slog::info!(log, "capabilities"; "held" => %held);
+        if let Err(e) = caps::check(&args.common, held) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     }
 
-    // Things that need to get done while root:
+    // Things that need to get done while root (or holding capabilities):
     // - Binding to privileged ports.
     // - Chrooting.
 
-    let listener = tokio::net::TcpListener::bind(&args.common.addr).await?;
+    let listener = listen::bind(&args.common)?;
 
     // Dropping privileges here...
     drop_privs(&log, args.common())?;
@@ -123,9 +135,13 @@ async fn start(args: Args, log: slog::Logger) -> Result<(), ServeError> {
     // Accept loop:
     let connection_counter = AtomicU64::new(0);
     let connection_permits = SharedSemaphore::new(args.common.max_connections);
+    let per_ip = args.common.max_connections_per_ip.map(PerIpLimit::new);
+    let mut backoff = AcceptBackoff::new(&listener)?;
     loop {
         let permit = connection_permits.acquire().await;
-        if let Ok((socket, peer)) = listener.accept().await {
+        let accepted = listener.accept().await;
+        if let Ok((socket, peer)) = accepted {
+            backoff.reset();
             // New connection received. Add metadata to the logger.
             let log = log.new(slog::o!(
                 "cid" => connection_counter.fetch_add(1, Ordering::Relaxed),
@@ -139,17 +155,53 @@ async fn start(args: Args, log: slog::Logger) -> Result<(), ServeError> {
             // into the connection future below.
             let http = http.clone();
             let args = args.clone();
+            let per_ip = per_ip.clone();
             // Spawn the connection future.
             tokio::spawn(async move {
                 let _permit = permit;
+                let mut socket = socket;
+                // Behind a load balancer speaking the PROXY protocol, learn
+                // who the client really is before doing anything else.
+                let peer = if args.common.proxy_protocol {
+                    match proxy::read_proxy_header(&mut socket).await {
+                        Ok(client) => {
+                            let client = client.unwrap_or(peer);
+                            slog::info!(log, "proxied"; "client" => client);
+                            client
+                        }
+                        Err(e) => {
+                            slog::warn!(log, "error in PROXY header: {}", e);
+                            return;
+                        }
+                    }
+                } else {
+                    peer
+                };
+                // Don't let any one client take more than its share.
+                let _per_ip = match &per_ip {
+                    Some(limit) => match limit.try_acquire(peer.ip()) {
+                        Some(permit) => Some(permit),
+                        None => {
+                            slog::info!(log, "closed"; "cause" => "per-ip limit");
+                            return;
+                        }
+                    },
+                    None => None,
+                };
                 // Now that we're in the connection-specific task, do the actual
                 // connection setup process.
-                serve_connection(args, log, http, socket).await
+                serve_connection(args, peer, log, http, socket).await
             });
-        } else {
+        } else if let Err(e) = accepted {
             // Taking the next incoming connection from the socket failed. In
             // practice, this means that the server is out of file descriptors.
-            slog::warn!(log, "error accepting");
+            let recovery = backoff.recover(&listener, &e).await;
+            slog::warn!(
+                log,
+                "error accepting: {}", e;
+                "dropped" => recovery.dropped,
+                "delay" => ?recovery.delay,
+            );
         }
     }
 }
@@ -157,17 +209,33 @@ async fn start(args: Args, log: slog::Logger) -> Result<(), ServeError> {
 /// Connection handler. Returns a future that processes requests on `stream`.
 async fn serve_connection(
     args: Arc<Args>,
+    peer: SocketAddr,
     log: slog::Logger,
-    http: ConnBuilder,
+    http: Http,
     stream: TcpStream,
 ) {
     // Begin handling requests. The request_counter tracks
     // request IDs within this connection.
     let request_counter = AtomicU64::new(0);
-    let connection_server = http.serve_connection(
-        hyper_util::rt::tokio::TokioIo::new(stream),
-        service_fn(|x| handle_request(args.clone(), &log, &request_counter, x)),
-    );
+    let io = TokioIo::new(stream);
+    let service = service_fn(|x| {
+        // Tag the logger with a request ID here, rather than in the handler,
+        // so that the handler doesn't borrow from the connection; HTTP/2
+        // runs it on a task of its own.
+        let log = log.new(slog::o!(
+            "rid" => request_counter.fetch_add(1, Ordering::Relaxed),
+        ));
+        handle_request(args.clone(), peer, log, x)
+    });
+    let connection_server = async {
+        match &http {
+            Http::H1(http) => http
+                .serve_connection(io, service)
+                .await
+                .map_err(Into::into),
+            Http::Auto(http) => http.serve_connection(io, service).await,
+        }
+    };
     match timeout(args.common.connection_time_limit, connection_server).await {
         Err(_) => {
             slog::info!(log, "closed"; "cause" => "timeout");
@@ -175,7 +243,7 @@ async fn serve_connection(
         Ok(conn_result) => match conn_result {
             Ok(_) => slog::info!(log, "closed"),
             Err(e) => {
-                slog::info!(log, "closed"; "cause" => "error");
+                slog::info!(log, "closed"; "cause" => Failure::of_connection(&*e));
                 slog::debug!(log, "error"; "msg" => %e);
             }
         },
@@ -184,16 +252,26 @@ async fn serve_connection(
 
 async fn handle_request(
     args: Arc<Args>,
-    log: &slog::Logger,
-    request_counter: &AtomicU64,
+    peer: SocketAddr,
+    log: slog::Logger,
     req: Request<Incoming>,
 ) -> Result<Response<Empty<Bytes>>, ServeError> {
-    let log = log.new(slog::o!(
-        "rid" => request_counter.fetch_add(1, Ordering::Relaxed),
-    ));
+    let forwarded = proxy::forwarded(
+        &args.common().trusted_proxies,
+        peer.ip(),
+        req.headers(),
+    );
+    let host = forwarded.host.clone().or_else(|| host::request_host(&req));
+    let log = log::for_host(args.common(), log, host);
     // We log all requests, whether or not they will be served.
     let method = req.method();
     let uri = req.uri();
+    let client = if forwarded.client != peer.ip().to_canonical() {
+        Some(slog::o!("client" => forwarded.client.to_string()))
+    } else {
+        None
+    };
+    let proto = forwarded.proto.map(|p| slog::o!("proto" => p));
     let ua = req.headers().get(hyper::header::USER_AGENT).map(|v| {
         slog::o!("user-agent" => format!("{v:?}"))
     });
@@ -207,16 +285,33 @@ async fn handle_request(
     };
     slog::info!(
         log,
+        #ACCESS,
         "{}", method;
         "uri" => %uri,
         "version" => ?req.version(),
+        OptionKV::from(client),
+        OptionKV::from(proto),
         OptionKV::from(ua),
         OptionKV::from(rfr),
     );
+    if args.common().strict {
+        if let Err(reason) = strict::check(&req) {
+            slog::info!(log, #ACCESS, "rejected"; "cause" => reason);
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Empty::new())
+                .unwrap());
+        }
+    }
     match method {
         &Method::GET | &Method::HEAD => {
             let mut https_uri_parts = uri.clone().into_parts();
             https_uri_parts.scheme = Some(Scheme::HTTPS);
+            // The host a proxy says was asked for is the one to send the
+            // client back to.
+            if let Some(host) = forwarded.host.and_then(|h| Authority::from_str(&h).ok()) {
+                https_uri_parts.authority = Some(host);
+            }
             if https_uri_parts.authority.is_none() {
                 https_uri_parts.authority = Some(Authority::from_str(&args.default_host).unwrap());
             }
@@ -242,18 +337,24 @@ async fn handle_request(
 /// Drops the set of privileges requested in `args`. At minimum, this changes
 /// the CWD; at most, it chroots and changes to an unprivileged user.
 fn drop_privs(log: &slog::Logger, args: &CommonArgs) -> Result<(), ServeError> {
+    // This may read the group database, so it has to happen before chroot.
+    let groups = groups::supplementary(args)?;
+
     std::env::set_current_dir(&args.root)?;
 
     if args.should_chroot {
         nix::unistd::chroot(&args.root)?;
     }
-    if let Some(gid) = args.gid {
+    if let (Some(gid), Some(groups)) = (args.gid, &groups) {
         nix::unistd::setgid(gid)?;
-        nix::unistd::setgroups(&[gid])?;
+        nix::unistd::setgroups(groups)?;
     }
     if let Some(uid) = args.uid {
         nix::unistd::setuid(uid)?;
     }
+    // Switching away from root takes our capabilities with it, but a server
+    // that started unprivileged may still have some.
+    caps::drop_all()?;
     slog::info!(
         log,
         "privs";
@@ -261,17 +362,33 @@ fn drop_privs(log: &slog::Logger, args: &CommonArgs) -> Result<(), ServeError> {
         "chroot" => args.should_chroot,
         "setuid" => args.uid.map(Uid::as_raw),
         "setgid" => args.gid.map(Gid::as_raw),
+        "groups" => groups.as_deref().map(groups::display),
     );
 
     Ok(())
 }
 
+/// HTTP settings for new connections.
+#[derive(Clone)]
+enum Http {
+    H1(http1::Builder),
+    /// HTTP/1.1, or HTTP/2 for connections that start with its preface.
+    Auto(auto::Builder<TokioExecutor>),
+}
+
 /// Configure HTTP options for the server.
 fn configure_server_bits(
-    _args: &Args,
-) -> Result<ConnBuilder, ServeError> {
+    args: &Args,
+) -> Result<Http, ServeError> {
     // Configure HTTP.
-    let mut http = ConnBuilder::new();
-    http.max_buf_size(16384);
-    Ok(http)
+    if args.h2c {
+        let mut http = auto::Builder::new(TokioExecutor::new());
+        http.http1().max_buf_size(16384);
+        http.http2().max_concurrent_streams(Some(args.common.max_streams));
+        Ok(Http::Auto(http))
+    } else {
+        let mut http = http1::Builder::new();
+        http.max_buf_size(16384);
+        Ok(Http::H1(http))
+    }
 }
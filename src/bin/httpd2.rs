@@ -1,34 +1,75 @@
 use std::future::Future;
 use std::io;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, RwLock,
 };
+use std::time::{Duration, Instant, SystemTime};
 
-use bytes::Bytes;
-use hyper::body::{Incoming, Body};
-use hyper_util::rt::TokioExecutor;
-use hyper_util::server::conn::auto::Builder as ConnBuilder;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper::server::conn::{http1, http2};
 use hyper::service::service_fn;
 use hyper::{Request, Response};
 
 use nix::unistd::{Gid, Uid};
 
-use rustls::pki_types::{PrivatePkcs8KeyDer, CertificateDer};
+use rustls::pki_types::CertificateDer;
+use rustls::server::ProducesTickets;
+use rustls::sign::SigningKey;
 use rustls::ServerConfig;
 
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
 use tokio::time::timeout;
 use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 
-use httpd2::args::{CommonArgs, Log, HasCommonArgs};
+use httpd2::accept::AcceptBackoff;
+use httpd2::admin::Command;
+use httpd2::args::{CommonArgs, EtagSource, HasCommonArgs};
+use httpd2::blocklist::UserAgentBlocklist;
+use httpd2::caps::{self, Capabilities};
+use httpd2::certs::{self, Identities, Identity};
+use httpd2::config::{self, ConfigFormat};
+use httpd2::daemon::{self, Readiness};
 use httpd2::err::ServeError;
-use httpd2::sync::SharedSemaphore;
-use httpd2::serve;
+use httpd2::etag::TagCache;
+use httpd2::fingerprint::{self, Tap};
+use httpd2::groups;
+use httpd2::handoff;
+use httpd2::headers::SiteHeaders;
+use httpd2::keylog::KeyLogFile;
+use httpd2::listen;
+use httpd2::log::{logger, LevelSwitch, OptionKV};
+use httpd2::jwt::{self, Bearer};
+use httpd2::oidc::Oidc;
+use httpd2::mount::Mounts;
+use httpd2::picky;
+use httpd2::precompress::precompress;
+use httpd2::priority::Schedule;
+use httpd2::proxy;
+use httpd2::redirects::Redirects;
+use httpd2::sched;
+use httpd2::selfsigned;
+use httpd2::sync::{PerIpLimit, RequestLimit, SharedPermit, SharedSemaphore};
+use httpd2::serve::{self, Shared};
+use httpd2::signed::UrlSigner;
+use httpd2::signer::{ExternalKey, KeyType};
+use httpd2::sitefile;
+use httpd2::stats::{Failure, Stats};
+use httpd2::statsd::Pusher;
+use httpd2::tickets::Ticketer;
+use httpd2::traffic::Traffic;
+use httpd2::upload::Spool;
+use httpd2::warm::{self, Limits};
+use httpd2::webhook::{self, Event, Webhooks};
 
 #[cfg(feature = "system_allocator")]
 #[global_allocator]
@@ -40,7 +81,7 @@ pub struct Args {
     #[clap(flatten)]
     common: CommonArgs,
 
-    /// Path to the server private key file.
+    /// Path to the server private key file, in PKCS#8, PKCS#1 or SEC1 PEM.
     #[clap(
         short,
         long,
@@ -58,6 +99,51 @@ pub struct Args {
     )]
     pub cert_path: PathBuf,
 
+    /// Loads the private key and certificate chain from `privkey.pem` and
+    /// `fullchain.pem` in DIR, as in a certbot live directory. Their symlinks
+    /// are followed afresh on each load, so a reload picks up renewals.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["key_path", "cert_path"]
+    )]
+    pub cert_dir: Option<PathBuf>,
+
+    /// Path to a second private key, of a different type from the first --
+    /// typically RSA alongside ECDSA. Each client gets the certificate it
+    /// can verify, preferring ECDSA.
+    #[clap(long, value_name = "PATH", requires = "alt_cert_path")]
+    pub alt_key_path: Option<PathBuf>,
+
+    /// Path to the certificate for --alt-key-path.
+    #[clap(long, value_name = "PATH", requires = "alt_key_path")]
+    pub alt_cert_path: Option<PathBuf>,
+
+    /// Loads a second private key and certificate from a certbot live
+    /// directory, as --cert-dir does for the first.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["alt_key_path", "alt_cert_path"]
+    )]
+    pub alt_cert_dir: Option<PathBuf>,
+
+    /// Leaves the private key to a helper: COMMAND is run by the shell at
+    /// startup, before dropping privileges, and asked over its stdin and stdout
+    /// for each signature. The key never enters this process. Replaces -k for
+    /// the certificate given with -r.
+    #[clap(
+        long,
+        value_name = "COMMAND",
+        requires = "key_signer_type",
+        conflicts_with_all = ["key_path", "cert_dir"]
+    )]
+    pub key_signer: Option<String>,
+
+    /// Type of the key held by --key-signer.
+    #[clap(long, value_name = "TYPE", requires = "key_signer")]
+    pub key_signer_type: Option<KeyType>,
+
     /// Maximum number of worker threads to start, to handle blocking filesystem
     /// operations. Threads are started in response to load, and shut down when
     /// not used. The actual thread count will be above this number, because not
@@ -65,6 +151,240 @@ pub struct Args {
     /// large numbers of concurrent requests, at the expense of RAM.
     #[clap(long, default_value = "10")]
     pub max_threads: usize,
+
+    /// Issues TLS session tickets, which let returning clients skip part of
+    /// the handshake, valid for up to SECS seconds. Unless --session-ticket-keys
+    /// is given, ticket keys are random, and rotated so that none is kept
+    /// longer than SECS.
+    #[clap(long, value_name = "SECS")]
+    pub session_ticket_lifetime: Option<u32>,
+
+    /// Encrypts session tickets with keys read from PATH, so that other
+    /// servers, or this one after a restart, can accept them. The file holds
+    /// one key per line as 64 hex digits; the first encrypts new tickets, and
+    /// all decrypt. It is re-read every minute, so rotate keys by overwriting
+    /// it in place. Implies session tickets, with a default lifetime of six
+    /// hours.
+    #[clap(long, value_name = "PATH")]
+    pub session_ticket_keys: Option<PathBuf>,
+
+    /// Appends TLS session secrets to PATH in NSS key log format, so that
+    /// packet captures can be decrypted with a tool like Wireshark. Anyone who
+    /// can read the file can read the traffic: use this only for debugging.
+    #[clap(long, env = "SSLKEYLOGFILE", value_name = "PATH")]
+    pub key_log_file: Option<PathBuf>,
+
+    /// Adds a JA4 fingerprint of each client's ClientHello to its tls-init
+    /// record, summing up the TLS features it offered.
+    #[clap(long)]
+    pub log_fingerprint: bool,
+
+    /// HTTP versions to offer during the TLS handshake, as a comma-separated
+    /// list in order of preference. Leaving out h2 forces HTTP/1.1, which may
+    /// help with middleboxes that mishandle HTTP/2.
+    #[clap(
+        long,
+        default_value = "h2,http/1.1",
+        value_delimiter = ',',
+        value_name = "LIST"
+    )]
+    pub alpn: Vec<Alpn>,
+
+    /// HTTP/2 flow-control window for each stream, in bytes: how much a
+    /// client may send on a stream before we acknowledge it. Defaults to
+    /// hyper's choice.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..=0x7fff_ffff),
+        value_name = "BYTES"
+    )]
+    pub h2_stream_window: Option<u32>,
+
+    /// HTTP/2 flow-control window for each connection, across all its
+    /// streams, in bytes. Defaults to hyper's choice.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..=0x7fff_ffff),
+        value_name = "BYTES"
+    )]
+    pub h2_connection_window: Option<u32>,
+
+    /// Lets HTTP/2 flow-control windows grow and shrink with the measured
+    /// bandwidth-delay product of each connection. Overrides
+    /// --h2-stream-window and --h2-connection-window.
+    #[clap(long)]
+    pub h2_adaptive_window: bool,
+
+    /// Largest HTTP/2 frame payload we're willing to receive, in bytes.
+    #[clap(
+        long,
+        default_value = "16384",
+        value_parser = clap::value_parser!(u32).range(16384..=16_777_215),
+        value_name = "BYTES"
+    )]
+    pub h2_max_frame_size: u32,
+
+    /// Sends the responses on each HTTP/2 connection in the order their
+    /// Priority headers ask for (RFC 9218): more urgent ones first, so that a
+    /// page's stylesheets and scripts aren't held up behind its images.
+    #[clap(long)]
+    pub h2_priorities: bool,
+
+    /// Asks connections older than SECS to wrap up, however busy they are:
+    /// HTTP/1.1 connections close after the response in progress, and HTTP/2
+    /// ones get a GOAWAY. Lets a load balancer spread long-lived clients
+    /// across backends. Should be well under --connection-time-limit, which
+    /// cuts connections off rather than letting them finish.
+    #[clap(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_connection_age: Option<u64>,
+
+    /// Unix socket used to hand the listening socket to a new server process
+    /// during an upgrade. At startup, if a server is listening on PATH, we
+    /// take its listening socket instead of binding --addr, and it drains
+    /// and exits. Either way, we then listen on PATH for our own successor.
+    #[clap(long, value_name = "PATH")]
+    pub handoff_socket: Option<PathBuf>,
+
+    /// Runs in the background once startup has succeeded. The command exits
+    /// with status 0 when the server is ready to serve, or 1 if it failed to
+    /// start.
+    #[clap(long)]
+    pub daemon: bool,
+
+    /// Writes the server's process ID to PATH at startup, before chroot.
+    #[clap(long, value_name = "PATH")]
+    pub pidfile: Option<PathBuf>,
+
+    /// Accepts admin commands on a Unix socket at PATH, created before chroot
+    /// and usable only by its owner. See the manual for the commands.
+    #[clap(long, value_name = "PATH")]
+    pub admin_socket: Option<PathBuf>,
+
+    /// Pushes the server's counters over UDP to a StatsD server at ADDR, an IP
+    /// address and port.
+    #[clap(long, value_name = "ADDR")]
+    pub statsd: Option<SocketAddr>,
+
+    /// Names StatsD metrics PREFIX.counter.
+    #[clap(long, default_value = "httpd2", value_name = "PREFIX")]
+    pub statsd_prefix: String,
+
+    /// How often to push to StatsD, in seconds.
+    #[clap(
+        long,
+        default_value = "10",
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub statsd_interval: u64,
+
+    /// Attaches TAG, such as env:prod, to every StatsD metric, in the
+    /// DogStatsD style. Can be repeated.
+    #[clap(long, value_name = "TAG", requires = "statsd")]
+    pub statsd_tag: Vec<String>,
+
+    /// At startup, walks the root and each mount, opening up to COUNT files
+    /// and directories as if to serve them, and logs any that can't be
+    /// served. With --etag-source content, also computes their tags.
+    #[clap(long, value_name = "COUNT")]
+    pub warm_up: Option<usize>,
+
+    /// Most bytes of files to read for --warm-up's tag computation.
+    #[clap(
+        long,
+        default_value = "268435456",
+        value_name = "BYTES",
+        requires = "warm_up"
+    )]
+    pub warm_up_bytes: u64,
+
+    /// POSTs a JSON description of significant events -- startup, shutdown,
+    /// TLS reloads, and bursts of errors -- to URL. Can be repeated. Hosts are
+    /// looked up at startup.
+    #[clap(long, value_name = "URL")]
+    pub webhook: Vec<String>,
+
+    /// CA certificates to trust for HTTPS webhooks, as a PEM file read at
+    /// startup.
+    #[clap(
+        long,
+        default_value = "/etc/ssl/certs/ca-certificates.crt",
+        value_name = "PATH"
+    )]
+    pub webhook_ca: PathBuf,
+
+    /// Sends an errors event when at least COUNT requests end in a 5xx or 429
+    /// response within --webhook-error-window.
+    #[clap(long, value_name = "COUNT", requires = "webhook")]
+    pub webhook_error_threshold: Option<u64>,
+
+    /// Length of the window for --webhook-error-threshold, in seconds.
+    #[clap(
+        long,
+        default_value = "60",
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub webhook_error_window: u64,
+
+    /// Checks the configuration and exits, without listening or serving:
+    /// loads the TLS keys and certificates, warning of any that expire within
+    /// 30 days, opens the content directories, and reads the other files the
+    /// options name. Exits with status 1 if anything is wrong.
+    #[clap(short = 't', long)]
+    pub check: bool,
+
+    /// Prints every option, with the value it ends up with after the command
+    /// line, the environment and the defaults have had their say, as toml or
+    /// json, and exits.
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "toml"
+    )]
+    pub print_config: Option<ConfigFormat>,
+
+    /// Writes precompressed alternates, for each of --encodings, of the
+    /// compressible files under ROOT that don't have current ones, and exits.
+    /// Uses the gzip, brotli and zstd commands.
+    #[clap(long, conflicts_with = "check")]
+    pub precompress: bool,
+
+    /// Writes a new private key to --key-path, and a self-signed certificate
+    /// for it naming NAME, a host name or IP address, to --cert-path, and
+    /// exits. Repeat to name more than one. Won't overwrite existing files.
+    #[clap(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["check", "precompress", "cert_dir"]
+    )]
+    pub gen_cert: Vec<String>,
+}
+
+/// An application protocol we can negotiate with ALPN.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Alpn {
+    /// HTTP/2.
+    H2,
+    /// HTTP/1.1.
+    #[value(name = "http/1.1")]
+    Http1,
+}
+
+impl Alpn {
+    /// Protocol ID used in the ALPN extension.
+    fn id(self) -> &'static [u8] {
+        match self {
+            Alpn::H2 => b"h2",
+            Alpn::Http1 => b"http/1.1",
+        }
+    }
 }
 
 impl HasCommonArgs for Args {
@@ -76,35 +396,59 @@ impl HasCommonArgs for Args {
 /// Main server entry point.
 fn main() {
     use futures::future::FutureExt;
-    use slog::Drain;
 
     // Go ahead and parse arguments before dropping privileges, since they
     // control whether we drop privileges, among other things.
-    let args = Args::parse();
-
-    let log = match args.common.log {
-        Log::Stderr => {
-            // Produce boring plain text.
-            let decorator = slog_term::PlainDecorator::new(std::io::stderr());
-            // Pack everything onto one line, with the largest scope at left.
-            let mut fmt = slog_term::FullFormat::new(decorator)
-                .use_original_order();
-            if args.common.suppress_log_timestamps {
-                fmt = fmt.use_custom_timestamp(|_| Ok(()));
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    if let Some(format) = args.print_config {
+        print!("{}", config::render(&Args::command(), &matches, format));
+        std::process::exit(0);
+    }
+    if args.check {
+        let ok = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(check(&args));
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if args.precompress {
+        std::process::exit(if run_precompress(&args) { 0 } else { 1 });
+    }
+    if !args.gen_cert.is_empty() {
+        std::process::exit(if gen_cert(&args) { 0 } else { 1 });
+    }
+
+    // Fork first thing, while we have just the one thread. From here on we're
+    // the child, and the parent is waiting to hear how startup went.
+    let readiness = if args.daemon {
+        // Safety: no threads have been started yet.
+        match unsafe { daemon::fork() } {
+            Ok(readiness) => Some(readiness),
+            Err(e) => {
+                eprintln!("can't daemonize: {}", e);
+                std::process::exit(1);
             }
-            let drain = fmt.build().fuse();
-            // Don't block the server until a bunch of records have built up.
-            let drain =
-                slog_async::Async::new(drain).chan_size(1024).build().fuse();
-            slog::Logger::root(drain, slog::o!())
-        }
-        #[cfg(feature = "journald")]
-        Log::Journald => {
-            let drain = slog_journald::JournaldDrain.ignore_res();
-            // Don't block the server until a bunch of records have built up.
-            let drain =
-                slog_async::Async::new(drain).chan_size(1024).build().fuse();
-            slog::Logger::root(drain, slog::o!())
+        }
+    } else {
+        None
+    };
+
+    // Before any more threads exist, since they inherit priorities.
+    if let Err(e) = sched::apply(&args.common) {
+        eprintln!("can't set scheduling options: {}", e);
+        std::process::exit(1);
+    }
+
+    // The admin socket can change the level at runtime.
+    let level = LevelSwitch::new(args.common.log_level);
+    let log = match logger(&args.common, &level) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("can't open log: {}", e);
+            std::process::exit(1);
         }
     };
 
@@ -115,139 +459,897 @@ fn main() {
         .enable_all()
         .build()
         .unwrap()
-        .block_on(start(args, log).map(Result::unwrap))
+        .block_on(start(args, log, level, readiness).map(Result::unwrap))
 }
 
 /// Starts up a server.
-async fn start(args: Args, log: slog::Logger) -> Result<(), ServeError> {
+async fn start(
+    args: Args,
+    log: slog::Logger,
+    level: LevelSwitch,
+    readiness: Option<Readiness>,
+) -> Result<(), ServeError> {
     // Sanity check configuration.
-    let root = Uid::from_raw(0);
-    if Uid::current() == root {
-        if !args.common.should_chroot {
-            eprintln!("Running as root without chroot?!");
-            std::process::exit(1);
-        }
-        if args.common.uid.is_none() || args.common.uid == Some(root) {
-            eprintln!("Provide a lower privileged user ID with -U <uid>");
-            std::process::exit(1);
-        }
+    if let Err(e) = check_privileges(&log, &args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 
-    // Things that need to get done while root:
+    // Things that need to get done while root (or holding capabilities):
     // - Binding to privileged ports.
     // - Reading SSL private key.
+    // - Opening the root and mounted directories.
+    // - Opening the session ticket key file.
+    // - Opening the TLS key log file.
+    // - Taking over the listening socket from a previous server.
+    // - Writing the PID file.
+    // - Creating the admin socket.
+    // - Looking up webhook hosts and loading their CA certificates.
+    // - Fetching the OIDC provider's configuration, likewise.
     // - Chrooting.
 
-    let (key, cert_chain) = load_key_and_cert(&args.key_path, &args.cert_path)?;
+    let key_signer = match (&args.key_signer, args.key_signer_type) {
+        (Some(command), Some(key_type)) => {
+            let key: Arc<dyn SigningKey> =
+                Arc::new(ExternalKey::spawn(command, key_type)?);
+            Some(key)
+        }
+        _ => None,
+    };
+    let identities = load_identities(&log, &args, &key_signer)?;
+    let mounts = open_mounts(&log, &args)?;
+    for prefix in mounts.prefixes() {
+        slog::info!(log, "mount"; "prefix" => prefix);
+    }
+    let signer = match &args.common.signing_key {
+        Some(path) => Some(UrlSigner::from_file(path)?),
+        None => None,
+    };
+    let spool = match (&args.common.upload, &args.common.upload_token) {
+        (Some(upload), Some(token)) => {
+            slog::info!(log, "uploads"; "prefix" => &upload.prefix);
+            Some(Spool::open(upload, token, args.common.upload_max_size)?)
+        }
+        _ => None,
+    };
+    let user_agents = match &args.common.block_user_agents {
+        Some(path) => Some(Arc::new(UserAgentBlocklist::open(path)?)),
+        None => None,
+    };
+    let oidc = match &args.common.oidc_issuer {
+        Some(issuer) => {
+            let oidc = Oidc::discover(&args.common).await?;
+            slog::info!(log, "oidc"; "issuer" => issuer);
+            Some(oidc)
+        }
+        None => None,
+    };
+    let bearer = match &args.common.jwt_keys {
+        Some(source) => {
+            let bearer = Bearer::load(&args.common).await?;
+            slog::info!(log, "jwt"; "keys" => source);
+            Some(bearer)
+        }
+        None => None,
+    };
+    let ticketer = load_ticketer(&log, &args)?;
+    let key_log = match &args.key_log_file {
+        Some(path) => {
+            slog::warn!(log, "logging TLS secrets"; "path" => %path.display());
+            Some(Arc::new(KeyLogFile::open(path)?))
+        }
+        None => None,
+    };
+    if args.common.dev {
+        slog::warn!(log, "developer mode: error details will be sent to clients");
+    }
 
-    let listener = tokio::net::TcpListener::bind(&args.common.addr).await?;
+    let inherited = match &args.handoff_socket {
+        Some(path) => handoff::receive(path)?,
+        None => None,
+    };
+    let listener = match inherited {
+        Some(listener) => {
+            slog::info!(log, "took over listener"; "addr" => listener.local_addr()?);
+            tokio::net::TcpListener::from_std(listener)?
+        }
+        None => listen::bind(&args.common)?,
+    };
+    let handoff_listener = match &args.handoff_socket {
+        Some(path) => Some(tokio::net::UnixListener::from_std(handoff::listen(path)?)?),
+        None => None,
+    };
+    let admin_listener = match &args.admin_socket {
+        Some(path) => Some(tokio::net::UnixListener::from_std(
+            httpd2::unix::listen_private(path)?,
+        )?),
+        None => None,
+    };
+    if let Some(path) = &args.pidfile {
+        daemon::write_pidfile(path)?;
+    }
+    let webhooks = if args.webhook.is_empty() {
+        None
+    } else {
+        let hooks = Webhooks::new(
+            &args.webhook,
+            &args.webhook_ca,
+            args.common.addr,
+            log.clone(),
+        )
+        .await?;
+        Some(Arc::new(hooks))
+    };
 
     // Dropping privileges here...
     drop_privs(&log, args.common())?;
 
-    let (tls_acceptor, http) = configure_server_bits(&args, key, cert_chain)?;
+    let tls_acceptor = configure_tls(&args, identities, &ticketer, &key_log)?;
     let args = Arc::new(args);
+    let control = Arc::new(Control {
+        tls: RwLock::new(tls_acceptor),
+        ticketer,
+        key_log,
+        key_signer,
+        webhooks: webhooks.clone(),
+        level,
+        drain: Notify::new(),
+        closing: watch::channel(false).0,
+        stats: Arc::new(Stats::with_traffic(Traffic::new(
+            &args.common.hosts,
+            &args.common.host_quotas,
+        ))),
+        permits: SharedSemaphore::new(args.common.max_connections),
+        user_agents: user_agents.clone(),
+    });
+    let shared = Arc::new(Shared {
+        mounts,
+        tags: TagCache::default(),
+        stats: control.stats.clone(),
+        signer,
+        spool,
+        user_agents,
+        requests: RequestLimit::new(args.common.max_requests),
+        bulk: args.common.max_bulk.map(SharedSemaphore::new),
+        redirects: if args.common.redirects {
+            Some(Arc::new(Redirects::default()))
+        } else {
+            None
+        },
+        site_headers: if args.common.site_headers {
+            Some(Arc::new(SiteHeaders::default()))
+        } else {
+            None
+        },
+        oidc,
+        bearer,
+    });
+    if shared.redirects.is_some() || shared.site_headers.is_some() {
+        reload_site_files(&log, &shared).await;
+        let shared = shared.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(sitefile::INTERVAL).await;
+                reload_site_files(&log, &shared).await;
+            }
+        });
+    }
+    if shared.bearer.as_ref().is_some_and(Bearer::refreshes) {
+        let shared = shared.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            let bearer = shared.bearer.as_ref().unwrap();
+            loop {
+                tokio::time::sleep(jwt::REFRESH).await;
+                match bearer.refresh().await {
+                    Ok(keys) => slog::debug!(log, "jwt keys refreshed"; "keys" => keys),
+                    Err(e) => slog::warn!(log, "jwt keys refresh failed"; "error" => %e),
+                }
+            }
+        });
+    }
+    if let Some(files) = args.warm_up {
+        let limits = Limits {
+            files,
+            bytes: args.warm_up_bytes,
+        };
+        let hash = args.common.etag_source == EtagSource::Content;
+        let shared = shared.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            let tags = Some(&shared.tags).filter(|_| hash);
+            warm::warm_up(&log, &shared.mounts, tags, limits).await
+        });
+    }
+    if let Some(listener) = admin_listener {
+        tokio::spawn(admin(args.clone(), control.clone(), log.clone(), listener));
+    }
+    // Log a snapshot of the counters whenever we get SIGUSR2.
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    tokio::spawn({
+        let stats = control.stats.clone();
+        let log = log.clone();
+        async move {
+            while usr2.recv().await.is_some() {
+                slog::info!(log, "stats"; &*stats);
+                stats.traffic.log(&log, SystemTime::now());
+            }
+        }
+    });
+
+    if let Some(addr) = args.statsd {
+        let pusher =
+            Pusher::new(addr, &args.statsd_prefix, &args.statsd_tag).await?;
+        slog::info!(log, "statsd"; "addr" => addr);
+        tokio::spawn(pusher.run(
+            control.stats.clone(),
+            Duration::from_secs(args.statsd_interval),
+            log.clone(),
+        ));
+    }
 
     slog::info!(log, "serving"; "addr" => args.common.addr);
+    if let Some(readiness) = readiness {
+        readiness.notify()?;
+    }
+    if let Some(hooks) = &webhooks {
+        hooks.fire(Event::Startup);
+        if let Some(threshold) = args.webhook_error_threshold {
+            tokio::spawn(webhook::watch_errors(
+                hooks.clone(),
+                control.stats.clone(),
+                threshold,
+                Duration::from_secs(args.webhook_error_window),
+            ));
+        }
+    }
 
     // Accept loop:
-    let connection_counter = AtomicU64::new(0);
-    let connection_permits = SharedSemaphore::new(args.common.max_connections);
+    let server = Arc::new(Server {
+        http: configure_http(&args),
+        per_ip: args.common.max_connections_per_ip.map(PerIpLimit::new),
+        args: args.clone(),
+        shared,
+        control: control.clone(),
+    });
+    let mut backoff = AcceptBackoff::new(&listener)?;
     loop {
-        let permit = connection_permits.acquire().await;
-        if let Ok((socket, peer)) = listener.accept().await {
+        let (permit, accepted) = tokio::select! {
+            r = async {
+                let permit = control.permits.acquire().await;
+                (permit, listener.accept().await)
+            } => r,
+            _ = control.drain.notified() => break,
+            successor = next_successor(&handoff_listener) => {
+                match successor.and_then(|s| s.into_std()).and_then(|s| {
+                    s.set_nonblocking(false)?;
+                    handoff::send(&s, &listener)
+                }) {
+                    Ok(()) => {
+                        slog::info!(log, "handed off listener");
+                        break;
+                    }
+                    Err(e) => {
+                        slog::warn!(log, "error in handoff: {}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+        if let Ok((socket, peer)) = accepted {
+            backoff.reset();
             // New connection received. Add metadata to the logger.
             let log = log.new(slog::o!(
-                "cid" => connection_counter.fetch_add(1, Ordering::Relaxed),
+                "cid" => control.stats.connections.fetch_add(1, Ordering::Relaxed),
             ));
             slog::info!(
                 log,
                 "connect";
                 "peer" => peer,
             );
-            // Clone the acceptor handle and HTTP config so they can be moved
-            // into the connection future below.
-            let tls_acceptor = tls_acceptor.clone();
-            let http = http.clone();
-            let args = args.clone();
-            // Spawn the connection future.
-            tokio::spawn(async move {
-                let _permit = permit;
-                // Now that we're in the connection-specific task, do the actual
-                // TLS accept and connection setup process.
-                match tls_acceptor.accept(socket).await {
-                    Ok(stream) => {
-                        serve_connection(args, log, http, stream).await
-                    }
-                    Err(e) => {
-                        // TLS negotiation failed. In my observations so far,
-                        // this mostly happens when a client speaks HTTP (or
-                        // nonsense) to an HTTPS port.
-                        slog::warn!(log, "error in TLS handshake: {}", e);
-                    }
-                }
-            });
-        } else {
+            tokio::spawn(server.clone().connection(permit, socket, peer, log));
+        } else if let Err(e) = accepted {
             // Taking the next incoming connection from the socket failed. In
             // practice, this means that the server is out of file descriptors.
-            slog::warn!(log, "error accepting");
+            control.stats.accept_errors.fetch_add(1, Ordering::Relaxed);
+            let recovery = backoff.recover(&listener, &e).await;
+            if recovery.dropped {
+                control.stats.accept_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            slog::warn!(
+                log,
+                "error accepting: {}", e;
+                "dropped" => recovery.dropped,
+                "delay" => ?recovery.delay,
+            );
+        }
+    }
+
+    // We've handed our listener to a successor, or been asked to drain. Stop
+    // listening, and leave once the connections we have are done.
+    drop(listener);
+    slog::info!(log, "draining");
+    control.closing.send_replace(true);
+    control.permits.drain(args.common.max_connections).await;
+    slog::info!(log, "drained");
+    if let Some(hooks) = &webhooks {
+        hooks.send(Event::Shutdown).await;
+    }
+    Ok(())
+}
+
+/// Checks that the server, run by the current user with `args`, will end up
+/// with no more privilege than it needs.
+fn check_privileges(log: &slog::Logger, args: &Args) -> Result<(), String> {
+    let root = Uid::from_raw(0);
+    if Uid::current() == root {
+        if !args.common.should_chroot {
+            return Err("Running as root without chroot?!".to_string());
+        }
+        if args.common.uid.is_none() || args.common.uid == Some(root) {
+            return Err("Provide a lower privileged user ID with -U <uid>".to_string());
+        }
+    } else {
+        let held = Capabilities::current().map_err(|e| e.to_string())?;
+        slog::info!(log, "capabilities"; "held" => %held);
+        caps::check(&args.common, held).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Opens the root, the mounts, and any users' directories.
+fn open_mounts(log: &slog::Logger, args: &Args) -> io::Result<Mounts> {
+    let mut mounts = args.common.mounts.clone();
+    if let Some(dirs) = &args.common.user_dirs {
+        let users = Mounts::users(dirs)?;
+        slog::info!(log, "user dirs"; "parent" => %dirs.parent.display(), "users" => users.len());
+        // Explicit mounts come first, to win over a user's at the same prefix.
+        mounts.extend(users);
+    }
+    if !picky::resolves_beneath() {
+        slog::warn!(log, "openat2 unavailable: symlinks may lead out of ROOT and mounts");
+    }
+    let opened = Mounts::open(
+        &args.common.root,
+        &args.common.fallback_roots,
+        &mounts,
+        args.common.no_symlinks,
+    )?;
+    #[cfg(feature = "embed")]
+    let opened = if args.common.embedded {
+        opened.fall_back_to(Box::new(httpd2::archive::embedded()?))
+    } else {
+        opened
+    };
+    Ok(opened)
+}
+
+/// Checks what can be checked of the configuration without listening or
+/// serving, for `--check`, reporting problems on stderr. Returns whether there
+/// were none.
+///
+/// Files are opened as the user running the check, which may not be able to
+/// read the same things as the user the server starts as.
+async fn check(args: &Args) -> bool {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let mut problems = vec![];
+
+    if let Err(e) = check_privileges(&log, args) {
+        problems.push(e);
+    }
+    match credential_paths(args) {
+        Ok(paths) => {
+            for (i, (key_path, cert_path)) in paths.iter().enumerate() {
+                // An external signer stands in for the first key.
+                if i > 0 || args.key_signer.is_none() {
+                    if let Err(e) = load_key(key_path) {
+                        problems.push(format!("{}: {}", key_path.display(), e));
+                    }
+                }
+                match load_cert_chain(cert_path) {
+                    Ok(chain) => check_expiry(cert_path, &chain, &mut problems),
+                    Err(e) => problems.push(format!("{}: {}", cert_path.display(), e)),
+                }
+            }
+        }
+        Err(e) => problems.push(format!("credentials: {}", e)),
+    }
+    if let Err(e) = open_mounts(&log, args) {
+        problems.push(format!("{}: {}", args.common.root.display(), e));
+    }
+    if let Some(path) = &args.common.signing_key {
+        if let Err(e) = UrlSigner::from_file(path) {
+            problems.push(format!("{}: {}", path.display(), e));
+        }
+    }
+    if let (Some(upload), Some(token)) = (&args.common.upload, &args.common.upload_token) {
+        if let Err(e) = Spool::open(upload, token, args.common.upload_max_size) {
+            problems.push(format!("uploads: {}", e));
+        }
+    }
+    if let Some(path) = &args.common.block_user_agents {
+        if let Err(e) = UserAgentBlocklist::open(path) {
+            problems.push(format!("{}: {}", path.display(), e));
+        }
+    }
+    if let Err(e) = load_ticketer(&log, args) {
+        problems.push(format!("session tickets: {}", e));
+    }
+    if !args.webhook.is_empty() {
+        let hooks = Webhooks::new(&args.webhook, &args.webhook_ca, args.common.addr, log).await;
+        if let Err(e) = hooks {
+            problems.push(format!("webhooks: {}", e));
         }
     }
+
+    for problem in &problems {
+        eprintln!("error: {}", problem);
+    }
+    if problems.is_empty() {
+        eprintln!("configuration ok");
+    }
+    problems.is_empty()
 }
 
-/// Connection handler. Returns a future that processes requests on `stream`.
-async fn serve_connection(
+/// Writes precompressed alternates under the root, for `--precompress`,
+/// reporting on stderr. Returns whether every one that was needed was written.
+fn run_precompress(args: &Args) -> bool {
+    let level = LevelSwitch::new(args.common.log_level);
+    let log = match logger(&args.common, &level) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("can't open log: {}", e);
+            return false;
+        }
+    };
+    let encodings = args.common.encodings.iter().map(|p| p.encoding).collect::<Vec<_>>();
+    match precompress(&log, &args.common.root, &encodings, num_cpus::get()) {
+        Ok(summary) => {
+            slog::info!(
+                log,
+                "precompressed";
+                "written" => summary.written,
+                "current" => summary.current,
+                "unhelpful" => summary.unhelpful,
+                "failed" => summary.failed,
+            );
+            summary.failed == 0
+        }
+        Err(e) => {
+            slog::error!(log, "can't precompress"; "err" => %e);
+            false
+        }
+    }
+}
+
+/// Writes a key and self-signed certificate, for `--gen-cert`, reporting on
+/// stderr. Returns whether both were written.
+fn gen_cert(args: &Args) -> bool {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let generated = match selfsigned::generate(&args.gen_cert, SystemTime::now()) {
+        Ok(generated) => generated,
+        Err(_) => {
+            eprintln!("can't generate a key");
+            return false;
+        }
+    };
+    let create = |path: &Path, mode: u32| {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(path)
+            .map_err(|e| eprintln!("can't create {}: {}", path.display(), e))
+    };
+    // Both are created before either is written, so that one already being
+    // there doesn't leave half a pair behind.
+    let key = match create(&args.key_path, 0o600) {
+        Ok(file) => file,
+        Err(()) => return false,
+    };
+    let cert = match create(&args.cert_path, 0o644) {
+        Ok(file) => file,
+        Err(()) => {
+            let _ = std::fs::remove_file(&args.key_path);
+            return false;
+        }
+    };
+    for (mut file, path, pem) in [
+        (key, &args.key_path, &generated.key_pem),
+        (cert, &args.cert_path, &generated.cert_pem),
+    ] {
+        if let Err(e) = file.write_all(pem.as_bytes()) {
+            eprintln!("can't write {}: {}", path.display(), e);
+            return false;
+        }
+        eprintln!("wrote {}", path.display());
+    }
+    true
+}
+
+/// Warns about the first certificate of `chain`, from `path`, if it expires
+/// within 30 days, and records a problem if it already has.
+fn check_expiry(path: &Path, chain: &[CertificateDer<'static>], problems: &mut Vec<String>) {
+    let not_after = match chain.first() {
+        Some(cert) => certs::not_after(cert),
+        None => {
+            problems.push(format!("{}: no certificates", path.display()));
+            return;
+        }
+    };
+    let not_after = match not_after {
+        Some(not_after) => not_after,
+        None => {
+            eprintln!("warning: {}: can't read expiry date", path.display());
+            return;
+        }
+    };
+    let when = httpdate::fmt_http_date(not_after);
+    let now = std::time::SystemTime::now();
+    if not_after <= now {
+        problems.push(format!("{}: expired {}", path.display(), when));
+    } else if not_after <= now + Duration::from_secs(30 * 24 * 60 * 60) {
+        eprintln!("warning: {}: expires {}", path.display(), when);
+    }
+}
+
+/// Rereads whichever of `_redirects` and `_headers` are in use, if they've
+/// changed.
+async fn reload_site_files(log: &slog::Logger, shared: &Shared) {
+    if let Some(redirects) = &shared.redirects {
+        redirects.reload(log, shared.mounts.root()).await;
+    }
+    if let Some(site_headers) = &shared.site_headers {
+        site_headers.reload(log, shared.mounts.root()).await;
+    }
+}
+
+/// Waits for a successor process to connect to the handoff socket, if there is
+/// one; otherwise, never resolves.
+async fn next_successor(
+    listener: &Option<tokio::net::UnixListener>,
+) -> io::Result<tokio::net::UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+        None => futures::future::pending().await,
+    }
+}
+
+/// Server state that can be inspected or changed through the admin socket.
+struct Control {
+    /// TLS configuration for new connections.
+    tls: RwLock<TlsAcceptor>,
+    /// Pieces of the TLS configuration that survive a reload.
+    ticketer: Option<Arc<dyn ProducesTickets>>,
+    key_log: Option<Arc<KeyLogFile>>,
+    key_signer: Option<Arc<dyn SigningKey>>,
+    webhooks: Option<Arc<Webhooks>>,
+    level: LevelSwitch,
+    /// Signaled to make the accept loop stop.
+    drain: Notify,
+    /// Set once the accept loop has stopped, to have connections finish what
+    /// they're doing and close.
+    closing: watch::Sender<bool>,
+    stats: Arc<Stats>,
+    permits: SharedSemaphore,
+    user_agents: Option<Arc<UserAgentBlocklist>>,
+}
+
+/// What each connection needs from the server.
+struct Server {
     args: Arc<Args>,
-    log: slog::Logger,
-    http: ConnBuilder<TokioExecutor>,
-    stream: TlsStream<TcpStream>,
-) {
-    // Announce the connection and record the parameters we have.
-    {
+    shared: Arc<Shared>,
+    control: Arc<Control>,
+    http: Http,
+    per_ip: Option<Arc<PerIpLimit>>,
+}
+
+impl Server {
+    /// Takes a newly accepted connection from `peer` through the PROXY
+    /// header, if any, and the TLS handshake, then serves it.
+    async fn connection(
+        self: Arc<Self>,
+        permit: SharedPermit,
+        mut socket: TcpStream,
+        peer: SocketAddr,
+        log: slog::Logger,
+    ) {
+        let _permit = permit;
+        let stats = &self.control.stats;
+        let _active = ActiveConnection::new(stats.clone());
+        // Behind a load balancer speaking the PROXY protocol, learn who the
+        // client really is before doing anything else. A peer that sends
+        // nothing mustn't hold its slot past the connection's time limit.
+        let peer = if self.args.common.proxy_protocol {
+            let limit = self.args.common.connection_time_limit;
+            match timeout(limit, proxy::read_proxy_header(&mut socket)).await {
+                Err(_) => {
+                    stats.record_failure(Failure::Timeout);
+                    slog::info!(log, "closed"; "cause" => Failure::Timeout);
+                    return;
+                }
+                Ok(Ok(client)) => {
+                    let client = client.unwrap_or(peer);
+                    slog::info!(log, "proxied"; "client" => client);
+                    client
+                }
+                Ok(Err(e)) => {
+                    let failure = Failure::of_io(&e);
+                    stats.record_failure(failure);
+                    slog::warn!(
+                        log,
+                        "error in PROXY header: {}", e;
+                        "cause" => failure,
+                    );
+                    return;
+                }
+            }
+        } else {
+            peer
+        };
+        // Don't let any one client take more than its share.
+        let _per_ip = match &self.per_ip {
+            Some(limit) => match limit.try_acquire(peer.ip()) {
+                Some(permit) => Some(permit),
+                None => {
+                    stats.refused_per_ip.fetch_add(1, Ordering::Relaxed);
+                    slog::info!(log, "closed"; "cause" => "per-ip limit");
+                    return;
+                }
+            },
+            None => None,
+        };
+        // The acceptor is cloned, rather than held, so that a reload can
+        // replace it while this handshake goes on.
+        let tls_acceptor = self.control.tls.read().unwrap().clone();
+        let started = Instant::now();
+        let socket = fingerprint::Tap::new(socket, self.args.log_fingerprint);
+        match tls_acceptor.accept(socket).await {
+            Ok(stream) => {
+                self.serve(peer, log, started.elapsed(), stream).await
+            }
+            Err(e) => {
+                // TLS negotiation failed. In my observations so far, this
+                // mostly happens when a client speaks HTTP (or nonsense) to an
+                // HTTPS port.
+                let failure = Failure::of_handshake(&e);
+                stats.handshake_failures.fetch_add(1, Ordering::Relaxed);
+                stats.record_failure(failure);
+                slog::warn!(
+                    log,
+                    "error in TLS handshake: {}", e;
+                    "cause" => failure,
+                );
+            }
+        }
+    }
+
+    /// Processes requests on `stream`, whose handshake took
+    /// `handshake_time`, with whichever protocol ALPN chose.
+    async fn serve(
+        &self,
+        peer: SocketAddr,
+        log: slog::Logger,
+        handshake_time: Duration,
+        mut stream: TlsStream<Tap<TcpStream>>,
+    ) {
+        let (args, shared) = (&self.args, &self.shared);
+        let ja4 = stream.get_mut().0.ja4().map(|ja4| slog::o!("ja4" => ja4));
+        // Announce the connection and record the parameters we have.
         let session = stream.get_ref().1;
-        let alpn =
-            std::str::from_utf8(session.alpn_protocol().unwrap_or(b"NONE"))
-                .unwrap_or("BOGUS");
+        let handshake = shared.stats.record_handshake(session, handshake_time);
+        let alpn = session.alpn_protocol().map(<[u8]>::to_vec);
         slog::info!(
             log,
             "tls-init";
-            "alpn" => alpn,
+            "alpn" => std::str::from_utf8(alpn.as_deref().unwrap_or(b"NONE"))
+                .unwrap_or("BOGUS"),
             "tls" => ?session.protocol_version().unwrap(),
             "cipher" => ?session.negotiated_cipher_suite().unwrap().suite(),
+            "handshake" => handshake,
+            "handshake_ms" => handshake_time.as_millis() as u64,
+            "sni" => session.server_name(),
+            OptionKV::from(ja4),
         );
+        // A client that doesn't do ALPN at all will speak HTTP/1.1, so only
+        // let it in if that's allowed.
+        if alpn.is_none() && !args.alpn.contains(&Alpn::Http1) {
+            slog::info!(log, "closed"; "cause" => "no alpn");
+            return;
+        }
+
+        // Begin handling requests. The request_counter tracks
+        // request IDs within this connection.
+        let request_counter = AtomicU64::new(0);
+        let h2 = alpn.as_deref() == Some(Alpn::H2.id());
+        let schedule = if h2 && args.h2_priorities {
+            Some(Arc::new(Schedule::default()))
+        } else {
+            None
+        };
+        let io = TokioIo::new(stream);
+        let service = service_fn(|x| {
+            handle_request(
+                args.clone(),
+                shared.clone(),
+                peer,
+                schedule.clone(),
+                &log,
+                &request_counter,
+                x,
+            )
+        });
+        let closing = self.control.closing.subscribe();
+        let max_age = args.max_connection_age.map(Duration::from_secs);
+        let served = async {
+            if h2 {
+                let conn = self.http.h2.serve_connection(io, service);
+                until_closed(conn, closing, max_age, |c| c.graceful_shutdown()).await
+            } else {
+                let conn = self.http.h1.serve_connection(io, service);
+                until_closed(conn, closing, max_age, |c| c.graceful_shutdown()).await
+            }
+        };
+        match timeout(args.common.connection_time_limit, served).await {
+            Err(_) => {
+                shared.stats.record_failure(Failure::Timeout);
+                slog::info!(log, "closed"; "cause" => Failure::Timeout);
+            }
+            Ok(Ok(())) => slog::info!(log, "closed"),
+            Ok(Err(e)) => {
+                let failure = Failure::of_connection(&e);
+                shared.stats.record_failure(failure);
+                slog::info!(log, "closed"; "cause" => failure);
+                slog::debug!(log, "error"; "msg" => %e);
+            }
+        }
     }
+}
 
-    // Begin handling requests. The request_counter tracks
-    // request IDs within this connection.
-    let request_counter = AtomicU64::new(0);
-    let connection_server = http.serve_connection(
-        hyper_util::rt::tokio::TokioIo::new(stream),
-        service_fn(|x| handle_request(args.clone(), &log, &request_counter, x)),
-    );
-    match timeout(args.common.connection_time_limit, connection_server).await {
-        Err(_) => {
-            slog::info!(log, "closed"; "cause" => "timeout");
+/// Drives `conn` to completion, asking it with `shutdown` to finish up once
+/// `closing` is set or it's `max_age` old: HTTP/1.1 closes after the response
+/// in progress, and HTTP/2 sends GOAWAY and lets the streams it has run out.
+async fn until_closed<C: Future>(
+    conn: C,
+    mut closing: watch::Receiver<bool>,
+    max_age: Option<Duration>,
+    shutdown: fn(Pin<&mut C>),
+) -> C::Output {
+    tokio::pin!(conn);
+    let aged = async {
+        match max_age {
+            Some(age) => tokio::time::sleep(age).await,
+            None => std::future::pending().await,
         }
-        Ok(conn_result) => match conn_result {
-            Ok(_) => slog::info!(log, "closed"),
+    };
+    tokio::select! {
+        out = conn.as_mut() => return out,
+        _ = closing.wait_for(|&closing| closing) => shutdown(conn.as_mut()),
+        _ = aged => shutdown(conn.as_mut()),
+    }
+    conn.await
+}
+
+/// Counts a connection as active for as long as it's held.
+struct ActiveConnection(Arc<Stats>);
+
+impl ActiveConnection {
+    fn new(stats: Arc<Stats>) -> Self {
+        stats.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(stats)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serves the admin socket.
+async fn admin(
+    args: Arc<Args>,
+    control: Arc<Control>,
+    log: slog::Logger,
+    listener: tokio::net::UnixListener,
+) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
             Err(e) => {
-                slog::info!(log, "closed"; "cause" => "error");
-                slog::debug!(log, "error"; "msg" => %e);
+                slog::warn!(log, "error accepting admin connection: {}", e);
+                continue;
+            }
+        };
+        let args = args.clone();
+        let control = control.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.split();
+            // Commands are short; don't let a client make us buffer forever.
+            let mut line = String::new();
+            let read = tokio::io::BufReader::new(reader.take(256))
+                .read_line(&mut line)
+                .await;
+            let response = match read.map_err(|e| e.to_string()).and_then(|_| line.parse()) {
+                Ok(command) => {
+                    slog::info!(log, "admin"; "command" => line.trim());
+                    run_admin_command(&args, &control, &log, command)
+                }
+                Err(e) => format!("error: {}\n", e),
+            };
+            let _ = writer.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+/// Carries out an admin command, returning the response to send.
+fn run_admin_command(
+    args: &Args,
+    control: &Control,
+    log: &slog::Logger,
+    command: Command,
+) -> String {
+    match command {
+        Command::Stats => control.stats.to_string(),
+        Command::SetLogLevel(level) => {
+            control.level.set(level);
+            "ok\n".to_string()
+        }
+        Command::ReloadTls => {
+            let result = load_identities(log, args, &control.key_signer)
+                .map_err(ServeError::from)
+                .and_then(|identities| {
+                    configure_tls(args, identities, &control.ticketer, &control.key_log)
+                });
+            let event = match &result {
+                Ok(_) => Event::TlsReloaded,
+                Err(e) => Event::TlsReloadFailed(e.to_string()),
+            };
+            if let Some(hooks) = &control.webhooks {
+                hooks.fire(event);
             }
+            match result {
+                Ok(acceptor) => {
+                    *control.tls.write().unwrap() = acceptor;
+                    "ok\n".to_string()
+                }
+                Err(e) => format!("error: {}\n", e),
+            }
+        }
+        Command::ReloadUserAgents => match &control.user_agents {
+            Some(list) => match list.reload() {
+                Ok(count) => format!("ok: {} entries\n", count),
+                Err(e) => format!("error: {}\n", e),
+            },
+            None => "error: no --block-user-agents list\n".to_string(),
         },
+        Command::Drain => {
+            control.drain.notify_one();
+            "ok\n".to_string()
+        }
     }
 }
 
 /// Request handler. This mostly defers to the `serve` module right now.
 fn handle_request(
     args: Arc<Args>,
+    shared: Arc<Shared>,
+    peer: SocketAddr,
+    schedule: Option<Arc<Schedule>>,
     log: &slog::Logger,
     request_counter: &AtomicU64,
     req: Request<Incoming>,
-) -> impl Future<Output = Result<Response<Pin<Box<dyn Body<Data = Bytes, Error = ServeError> + Send>>>, ServeError>> {
+) -> impl Future<Output = Result<Response<serve::BoxBody>, ServeError>> {
     // Select a request ID and tag our logger with it.
     serve::files(
         args,
+        shared,
+        peer,
+        schedule,
         log.new(slog::o!(
             "rid" => request_counter
             .fetch_add(1, Ordering::Relaxed),
@@ -256,89 +1358,195 @@ fn handle_request(
     )
 }
 
-/// Loads TLS credentials from the filesystem using synchronous operations.
-fn load_key_and_cert(
-    key_path: &Path,
+/// Finds the private key and certificate files named in `args`, one pair for
+/// each identity, resolving symlinks under --cert-dir and --alt-cert-dir,
+/// which certbot points at the latest versions.
+fn credential_paths(args: &Args) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let in_dir = |dir: &Path| -> io::Result<(PathBuf, PathBuf)> {
+        Ok((
+            std::fs::canonicalize(dir.join("privkey.pem"))?,
+            std::fs::canonicalize(dir.join("fullchain.pem"))?,
+        ))
+    };
+    let mut paths = vec![match &args.cert_dir {
+        Some(dir) => in_dir(dir)?,
+        None => (args.key_path.clone(), args.cert_path.clone()),
+    }];
+    if let Some(dir) = &args.alt_cert_dir {
+        paths.push(in_dir(dir)?);
+    } else if let (Some(key), Some(cert)) = (&args.alt_key_path, &args.alt_cert_path) {
+        paths.push((key.clone(), cert.clone()));
+    }
+    Ok(paths)
+}
+
+/// Loads the keys and certificates named in `args`, with `key_signer`, if
+/// any, standing in for the first key.
+fn load_identities(
+    log: &slog::Logger,
+    args: &Args,
+    key_signer: &Option<Arc<dyn SigningKey>>,
+) -> io::Result<Vec<Identity>> {
+    let mut key_signer = key_signer.clone();
+    let mut identities = vec![];
+    for (key_path, cert_path) in credential_paths(args)? {
+        let identity = match key_signer.take() {
+            Some(key) => {
+                slog::info!(
+                    log,
+                    "credentials";
+                    "key" => "--key-signer",
+                    "cert" => %cert_path.display(),
+                );
+                (key, load_cert_chain(&cert_path)?)
+            }
+            None => {
+                slog::info!(
+                    log,
+                    "credentials";
+                    "key" => %key_path.display(),
+                    "cert" => %cert_path.display(),
+                );
+                (load_key(&key_path)?, load_cert_chain(&cert_path)?)
+            }
+        };
+        identities.push(identity);
+    }
+    Ok(identities)
+}
+
+/// Loads a private key from the filesystem using synchronous operations.
+fn load_key(key_path: &Path) -> io::Result<Arc<dyn SigningKey>> {
+    certs::read_private_key(&mut io::BufReader::new(
+        std::fs::File::open(key_path)?,
+    ))
+    .map_err(|e| {
+        io::Error::other(format!("{}: {}", key_path.display(), e))
+    })
+}
+
+/// Loads a certificate chain from the filesystem using synchronous
+/// operations.
+fn load_cert_chain(
     cert_path: &Path,
-) -> io::Result<(PrivatePkcs8KeyDer<'static>, Vec<CertificateDer<'static>>)> {
-    let key = rustls_pemfile::pkcs8_private_keys(
-        &mut io::BufReader::new(std::fs::File::open(key_path)?),
-    )
-    .collect::<Result<Vec<_>, _>>()
-    .map_err(|_| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            "can't load private key (bad file?)",
-        )
-    })?
-    .pop()
-    .ok_or_else(|| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            "no keys found in private key file",
-        )
-    })?;
-    let cert_chain = rustls_pemfile::certs(&mut io::BufReader::new(
+) -> io::Result<Vec<CertificateDer<'static>>> {
+    rustls_pemfile::certs(&mut io::BufReader::new(
         std::fs::File::open(cert_path)?,
     ))
     .collect::<Result<Vec<_>, _>>()
     .map_err(|_| {
-        io::Error::new(io::ErrorKind::Other, "can't load certificate")
-    })?;
-    Ok((key, cert_chain))
+        io::Error::other("can't load certificate")
+    })
 }
 
 /// Drops the set of privileges requested in `args`. At minimum, this changes
 /// the CWD; at most, it chroots and changes to an unprivileged user.
 fn drop_privs(log: &slog::Logger, args: &CommonArgs) -> Result<(), ServeError> {
-    std::env::set_current_dir(&args.root)?;
+    // This may read the group database, so it has to happen before chroot.
+    let groups = groups::supplementary(args)?;
+
+    // An archive, once open, leaves nothing to read from the filesystem, so
+    // the directory it's in does as well as any to chroot into.
+    let root = match args.root.parent() {
+        Some(parent) if args.root.is_file() && parent.as_os_str().is_empty() => {
+            Path::new(".")
+        }
+        Some(parent) if args.root.is_file() => parent,
+        _ => &args.root,
+    };
+    std::env::set_current_dir(root)?;
 
     if args.should_chroot {
-        nix::unistd::chroot(&args.root)?;
+        nix::unistd::chroot(root)?;
     }
     if let Some(gid) = args.gid {
         nix::unistd::setgid(gid)?;
-        nix::unistd::setgroups(&[gid])?;
+    }
+    if let Some(groups) = &groups {
+        nix::unistd::setgroups(groups)?;
     }
     if let Some(uid) = args.uid {
         nix::unistd::setuid(uid)?;
     }
+    // Switching away from root takes our capabilities with it, but a server
+    // that started unprivileged may still have some.
+    caps::drop_all()?;
     slog::info!(
         log,
         "privs";
-        "cwd" => %args.root.display(),
+        "cwd" => %root.display(),
         "chroot" => args.should_chroot,
         "setuid" => args.uid.map(Uid::as_raw),
         "setgid" => args.gid.map(Gid::as_raw),
+        "groups" => groups.as_deref().map(groups::display),
     );
 
     Ok(())
 }
 
-/// Configure TLS and HTTP options for the server.
-fn configure_server_bits(
+/// Sets up session ticket encryption, if enabled.
+fn load_ticketer(
+    log: &slog::Logger,
     args: &Args,
-    private_key: PrivatePkcs8KeyDer<'static>,
-    cert_chain: Vec<CertificateDer<'static>>,
-) -> Result<(TlsAcceptor, ConnBuilder<TokioExecutor>), ServeError> {
-    // Configure TLS and HTTP.
-    let tls_acceptor = {
-        let mut config = ServerConfig::builder()
-            // Don't require authentication.
-            .with_no_client_auth()
-            // We're using only this single identity.
-            .with_single_cert(cert_chain, private_key.into())?;
-        // Prefer HTTP/2 but support 1.1.
-        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
-        TlsAcceptor::from(Arc::new(config))
+) -> io::Result<Option<Arc<dyn ProducesTickets>>> {
+    let ticketer = match (&args.session_ticket_keys, args.session_ticket_lifetime) {
+        (None, None) => return Ok(None),
+        (None, Some(lifetime)) => Ticketer::random(lifetime, log.clone())?,
+        (Some(path), lifetime) => {
+            let file = std::fs::File::open(path)?;
+            Ticketer::from_file(lifetime.unwrap_or(6 * 60 * 60), file, log.clone())?
+        }
     };
-    // Configure Hyper.
-    let mut http = ConnBuilder::new(TokioExecutor::new());
-    http.http2()
-        .max_concurrent_streams(Some(args.common.max_streams))
-        .max_frame_size(16384);
-    http.http1()
-        .max_buf_size(16384); // down from 400kiB default
-
-    Ok((tls_acceptor, http))
+    slog::info!(
+        log,
+        "session tickets";
+        "lifetime" => ticketer.lifetime(),
+        "keys" => args.session_ticket_keys.as_ref().map(|p| p.display().to_string()),
+    );
+    Ok(Some(Arc::new(ticketer)))
+}
+
+/// Configure TLS options for the server.
+fn configure_tls(
+    args: &Args,
+    identities: Vec<Identity>,
+    ticketer: &Option<Arc<dyn ProducesTickets>>,
+    key_log: &Option<Arc<KeyLogFile>>,
+) -> Result<TlsAcceptor, ServeError> {
+    let mut config = ServerConfig::builder()
+        // Don't require authentication.
+        .with_no_client_auth()
+        // Each client gets the best certificate it can verify.
+        .with_cert_resolver(Arc::new(Identities::new(identities)));
+    // By default, prefer HTTP/2 but support 1.1. If the client offers
+    // nothing we support, rustls fails the handshake.
+    config.alpn_protocols =
+        args.alpn.iter().map(|p| p.id().to_vec()).collect();
+    if let Some(ticketer) = ticketer {
+        config.ticketer = ticketer.clone();
+    }
+    if let Some(key_log) = key_log {
+        config.key_log = key_log.clone();
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// HTTP settings for new connections, for each protocol ALPN can choose.
+struct Http {
+    h1: http1::Builder,
+    h2: http2::Builder<TokioExecutor>,
+}
+
+/// Configure HTTP options for the server.
+fn configure_http(args: &Args) -> Http {
+    let mut h2 = http2::Builder::new(TokioExecutor::new());
+    h2.max_concurrent_streams(Some(args.common.max_streams))
+        .initial_stream_window_size(args.h2_stream_window)
+        .initial_connection_window_size(args.h2_connection_window)
+        .adaptive_window(args.h2_adaptive_window)
+        .max_frame_size(args.h2_max_frame_size);
+    let mut h1 = http1::Builder::new();
+    h1.max_buf_size(16384); // down from 400kiB default
+
+    Http { h1, h2 }
 }
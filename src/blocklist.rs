@@ -0,0 +1,90 @@
+//! Refusing requests from unwanted User-Agents.
+//!
+//! The list is a file of User-Agent fragments, one per line; a request is
+//! refused if its User-Agent contains any of them, ignoring ASCII case. Blank
+//! lines and lines starting with `#` are ignored. The file is opened before
+//! chroot and held open, so it can be re-read later through the same
+//! descriptor.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
+/// A reloadable list of User-Agent fragments.
+pub struct UserAgentBlocklist {
+    file: Mutex<File>,
+    fragments: RwLock<Vec<Vec<u8>>>,
+}
+
+impl UserAgentBlocklist {
+    /// Opens and reads the list at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let list = UserAgentBlocklist {
+            file: Mutex::new(File::open(path)?),
+            fragments: RwLock::default(),
+        };
+        list.reload()?;
+        Ok(list)
+    }
+
+    /// Re-reads the file, returning the number of fragments now listed. If
+    /// reading fails, the old list stays in effect.
+    pub fn reload(&self) -> io::Result<usize> {
+        let mut text = String::new();
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_string(&mut text)?;
+        }
+        let fragments = parse(&text);
+        let count = fragments.len();
+        *self.fragments.write().unwrap() = fragments;
+        Ok(count)
+    }
+
+    /// Checks whether `user_agent` contains a listed fragment.
+    pub fn blocks(&self, user_agent: &[u8]) -> bool {
+        let user_agent = user_agent.to_ascii_lowercase();
+        self.fragments
+            .read()
+            .unwrap()
+            .iter()
+            .any(|f| user_agent.windows(f.len()).any(|w| w == &f[..]))
+    }
+}
+
+/// Parses the list into lowercased fragments.
+fn parse(text: &str) -> Vec<Vec<u8>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.as_bytes().to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let path = std::env::temp_dir()
+            .join(format!("httpd2-blocklist-test-{}", std::process::id()));
+        std::fs::write(&path, "# scrapers\nBadBot\n\n  evil-crawler/  \n").unwrap();
+        let list = UserAgentBlocklist::open(&path).unwrap();
+
+        assert!(list.blocks(b"Mozilla/5.0 (compatible; badbot/2.1)"));
+        assert!(list.blocks(b"Evil-Crawler/1.0"));
+        assert!(!list.blocks(b"Mozilla/5.0 (X11; Linux x86_64)"));
+        assert!(!list.blocks(b"evil-crawler"));
+        assert!(!list.blocks(b""));
+
+        // Rewriting the file in place is seen on reload.
+        std::fs::write(&path, "Mozilla\n").unwrap();
+        assert_eq!(list.reload().unwrap(), 1);
+        assert!(list.blocks(b"Mozilla/5.0 (X11; Linux x86_64)"));
+        assert!(!list.blocks(b"BadBot"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+//! Reusable buffers for streaming files.
+//!
+//! Each chunk of a file sent as a response body is read into a `BytesMut` and
+//! handed to hyper frozen. Rather than take a fresh buffer for each chunk, a
+//! `FileStream` keeps reading into the same one: once hyper has written and
+//! dropped the chunks split off it, `BytesMut::reserve` reclaims the space. When
+//! the stream is done, its buffer goes into a pool for the next one, so a
+//! server that's warmed up streams files without allocating for them.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::stream::Stream;
+use tokio::io::AsyncRead;
+
+/// How much is read at a time. Reading a file through `tokio::fs` costs a trip
+/// to the blocking thread pool per read, so this is well above the 8 KiB that
+/// `FramedRead` reads.
+pub const CHUNK: usize = 32 * 1024;
+
+/// How many idle buffers are kept.
+const POOLED: usize = 256;
+
+static POOL: Mutex<Vec<BytesMut>> = Mutex::new(Vec::new());
+
+/// The contents of a file, as a stream of chunks read into pooled buffers.
+pub struct FileStream<R> {
+    file: R,
+    buf: BytesMut,
+}
+
+impl<R> FileStream<R> {
+    pub fn new(file: R) -> Self {
+        let buf = POOL.lock().unwrap().pop().unwrap_or_default();
+        FileStream { file, buf }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for FileStream<R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        this.buf.reserve(CHUNK);
+        let read = tokio_util::io::poll_read_buf(
+            Pin::new(&mut this.file),
+            cx,
+            &mut this.buf,
+        );
+        match futures::ready!(read) {
+            Ok(0) => Poll::Ready(None),
+            Ok(_) => Poll::Ready(Some(Ok(this.buf.split().freeze()))),
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+impl<R> Drop for FileStream<R> {
+    fn drop(&mut self) {
+        // A buffer that chunks still in flight share is fine to pool; the
+        // next stream to reserve space in it gets a new allocation, or
+        // reclaims the old one if they've gone by then.
+        let mut pool = POOL.lock().unwrap();
+        if pool.len() < POOLED {
+            self.buf.clear();
+            pool.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+
+    #[tokio::test]
+    async fn reuse() {
+        let data = (0..CHUNK * 3 + 10).map(|i| i as u8).collect::<Vec<_>>();
+        let mut stream = FileStream::new(&data[..]);
+        let mut read = vec![];
+        let mut first = None;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.unwrap();
+            // Dropping each chunk before the next read lets the buffer be
+            // reclaimed, so every chunk lands in the same place.
+            let at = chunk.as_ptr();
+            assert_eq!(*first.get_or_insert(at), at);
+            read.extend_from_slice(&chunk);
+        }
+        assert_eq!(read, data);
+    }
+}
@@ -0,0 +1,100 @@
+//! Taking turns at sending large bodies.
+//!
+//! A few big downloads can keep every worker busy reading from disk and fill
+//! the uplink, so that a page and its stylesheets, which are small, crawl out
+//! behind them. With `--max-bulk`, bodies of at least `--bulk-size` bytes are
+//! wrapped in `Queued`, which waits for one of a fixed number of turns before
+//! sending anything, and gives it back once it's done. Smaller bodies never
+//! wait, so they keep moving whatever the downloads are doing. A waiting
+//! download has its headers already, and gets its body when a turn comes free.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+
+use crate::err::ServeError;
+use crate::serve::BoxBody;
+use crate::sync::{SharedPermit, SharedSemaphore};
+
+/// A response body that waits its turn.
+pub struct Queued {
+    inner: BoxBody,
+    /// Resolves when it's our turn, until it has.
+    wait: Option<Pin<Box<dyn Future<Output = SharedPermit> + Send>>>,
+    /// Our turn, held until the body is done.
+    _turn: Option<SharedPermit>,
+}
+
+impl Queued {
+    /// Wraps `inner`, which takes one of the turns in `turns` to send.
+    pub fn new(inner: BoxBody, turns: &SharedSemaphore) -> Self {
+        let turns = turns.clone();
+        Queued {
+            inner,
+            wait: Some(Box::pin(async move { turns.acquire().await })),
+            _turn: None,
+        }
+    }
+}
+
+impl Body for Queued {
+    type Data = Bytes;
+    type Error = ServeError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, ServeError>>> {
+        if let Some(wait) = &mut self.wait {
+            let permit = futures::ready!(wait.as_mut().poll(cx));
+            self.wait = None;
+            self._turn = Some(permit);
+        }
+        let frame = futures::ready!(self.inner.as_mut().poll_frame(cx));
+        if frame.is_none() {
+            // Someone else can go now, without waiting for hyper to drop us.
+            self._turn = None;
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn taking_turns() {
+        let body = || -> BoxBody {
+            Box::pin(Full::new(Bytes::from("big")).map_err(|r| match r {}))
+        };
+        let turns = SharedSemaphore::new(1);
+        let mut first = Queued::new(body(), &turns);
+        let mut second = Queued::new(body(), &turns);
+
+        assert!(first.frame().await.is_some());
+        let wait = Duration::from_millis(50);
+        assert!(tokio::time::timeout(wait, second.frame()).await.is_err());
+        // Finishing the first body frees its turn.
+        assert!(first.frame().await.is_none());
+        assert!(second.frame().await.is_some());
+
+        // So does dropping one partway through.
+        let mut third = Queued::new(body(), &turns);
+        drop(second);
+        assert!(third.frame().await.is_some());
+    }
+}
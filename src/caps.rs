@@ -0,0 +1,257 @@
+//! Linux capabilities.
+//!
+//! The server doesn't have to start as root. It can instead start as an
+//! ordinary user that's been granted just the capabilities it needs -- usually
+//! `CAP_NET_BIND_SERVICE`, to bind port 443, either as a file capability
+//! (`setcap cap_net_bind_service=ep httpd2`) or inherited from a service
+//! manager (systemd's `AmbientCapabilities=`). At startup we check that what
+//! we're about to do is possible with what we hold, and once privileged setup
+//! is done, we give up every capability, so that a compromised server holds
+//! nothing worth having.
+//!
+//! On other systems, nobody holds any capabilities, and dropping them does
+//! nothing.
+
+use std::fmt;
+use std::io;
+
+use crate::args::CommonArgs;
+
+/// A capability, by its Linux number.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capability(u32);
+
+pub const SETGID: Capability = Capability(6);
+pub const SETUID: Capability = Capability(7);
+pub const NET_BIND_SERVICE: Capability = Capability(10);
+pub const SYS_CHROOT: Capability = Capability(18);
+
+/// Names of the capabilities, by number, as used by `setcap` and friends.
+const NAMES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+/// A set of capabilities held by this process.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    effective: u64,
+}
+
+impl Capabilities {
+    /// Reads the effective capabilities of this process.
+    pub fn current() -> io::Result<Self> {
+        Ok(Capabilities {
+            effective: sys::effective()?,
+        })
+    }
+
+    /// Checks whether `cap` is in the set.
+    pub fn has(self, cap: Capability) -> bool {
+        self.effective & (1 << cap.0) != 0
+    }
+
+    /// Checks whether the set is empty.
+    pub fn is_empty(self) -> bool {
+        self.effective == 0
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+        let mut first = true;
+        for n in (0..64).filter(|&n| self.has(Capability(n))) {
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            match NAMES.get(n as usize) {
+                Some(name) => f.write_str(name)?,
+                None => write!(f, "cap_{}", n)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that a server started by a user other than root, holding `held`,
+/// can do what `args` asks of it at startup. If not, explains why.
+pub fn check(args: &CommonArgs, held: Capabilities) -> Result<(), String> {
+    if args.should_chroot && !held.has(SYS_CHROOT) {
+        return Err("--chroot requires root or CAP_SYS_CHROOT".to_string());
+    }
+    if args.uid.is_some() && !held.has(SETUID) {
+        return Err("-U requires root or CAP_SETUID".to_string());
+    }
+    if args.gid.is_some() && !held.has(SETGID) {
+        return Err("-G requires root or CAP_SETGID".to_string());
+    }
+    let port = args.addr.port();
+    if port != 0
+        && port < sys::unprivileged_port_start()
+        && !held.has(NET_BIND_SERVICE)
+    {
+        return Err(format!(
+            "binding port {} requires root or CAP_NET_BIND_SERVICE",
+            port
+        ));
+    }
+    Ok(())
+}
+
+/// Gives up all capabilities, for good.
+pub fn drop_all() -> io::Result<()> {
+    sys::drop_all()
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+
+    const VERSION_3: u32 = 0x2008_0522;
+
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Default)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    pub fn effective() -> io::Result<u64> {
+        let mut header = Header {
+            version: VERSION_3,
+            pid: 0,
+        };
+        let mut data = [Data::default(); 2];
+        // Safety: version 3 of the interface reads a header and writes two
+        // data structures, which we've provided.
+        let r = unsafe {
+            libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr())
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(u64::from(data[0].effective) | u64::from(data[1].effective) << 32)
+    }
+
+    pub fn drop_all() -> io::Result<()> {
+        // Ambient capabilities would survive an exec; clear them first. This
+        // fails on kernels before 4.3, which don't have any.
+        // Safety: this takes no pointers.
+        unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_CLEAR_ALL,
+                0,
+                0,
+                0,
+            );
+        }
+        let mut header = Header {
+            version: VERSION_3,
+            pid: 0,
+        };
+        let data = [Data::default(); 2];
+        // Safety: version 3 of the interface reads a header and two data
+        // structures, which we've provided.
+        let r = unsafe {
+            libc::syscall(libc::SYS_capset, &mut header, data.as_ptr())
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The lowest port that anyone can bind.
+    pub fn unprivileged_port_start() -> u16 {
+        std::fs::read_to_string("/proc/sys/net/ipv4/ip_unprivileged_port_start")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(1024)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+
+    pub fn effective() -> io::Result<u64> {
+        Ok(0)
+    }
+
+    pub fn drop_all() -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn unprivileged_port_start() -> u16 {
+        1024
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let caps = |effective| Capabilities { effective };
+        assert_eq!(caps(0).to_string(), "none");
+        assert_eq!(
+            caps(1 << 10 | 1 << 18).to_string(),
+            "cap_net_bind_service,cap_sys_chroot"
+        );
+        assert_eq!(caps(1 << 63).to_string(), "cap_63");
+        assert!(caps(1 << 10).has(NET_BIND_SERVICE));
+        assert!(!caps(1 << 10).has(SYS_CHROOT));
+    }
+}
@@ -0,0 +1,261 @@
+//! Choosing among several certificates for the same name.
+//!
+//! ECDSA keys make for smaller certificates and faster handshakes than RSA,
+//! but some older clients only understand RSA. With one certificate of each
+//! kind, we can give every client the best one it can use: whatever key can
+//! produce a signature scheme from the client's list, trying the others
+//! before RSA.
+//!
+//! This is also where certificates' expiry dates are read, for `--check`.
+
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls::{SignatureAlgorithm, SignatureScheme};
+use rustls_pemfile::Item;
+
+/// A private key and its certificate chain.
+pub type Identity = (Arc<dyn SigningKey>, Vec<CertificateDer<'static>>);
+
+/// Certificates to choose between, in order of preference.
+#[derive(Debug)]
+pub struct Identities {
+    keys: Vec<Arc<CertifiedKey>>,
+}
+
+impl Identities {
+    /// Pairs up each private key with its certificate chain.
+    pub fn new(identities: Vec<Identity>) -> Self {
+        let mut keys = identities
+            .into_iter()
+            .map(|(key, chain)| Arc::new(CertifiedKey::new(chain, key)))
+            .collect::<Vec<_>>();
+        // Stable, so keys of the same kind keep the order they were given in.
+        keys.sort_by_key(|k| k.key.algorithm() == SignatureAlgorithm::RSA);
+        Identities { keys }
+    }
+
+    /// Picks the first key that can sign with one of `schemes`.
+    fn choose(
+        &self,
+        schemes: &[SignatureScheme],
+    ) -> Option<&Arc<CertifiedKey>> {
+        self.keys
+            .iter()
+            .find(|k| k.key.choose_scheme(schemes).is_some())
+    }
+}
+
+/// Reads a private key from PEM, which may be in PKCS#8 ("PRIVATE KEY"),
+/// PKCS#1 ("RSA PRIVATE KEY") or SEC1 ("EC PRIVATE KEY") form. If there are
+/// several, the last wins.
+pub fn read_private_key(
+    rd: &mut dyn io::BufRead,
+) -> io::Result<Arc<dyn SigningKey>> {
+    any_supported_type(&read_private_key_der(rd)?).map_err(io::Error::other)
+}
+
+fn read_private_key_der(
+    rd: &mut dyn io::BufRead,
+) -> io::Result<PrivateKeyDer<'static>> {
+    let mut key = None;
+    let mut others = vec![];
+    for item in rustls_pemfile::read_all(rd) {
+        match item? {
+            Item::Pkcs8Key(k) => key = Some(k.into()),
+            Item::Pkcs1Key(k) => key = Some(k.into()),
+            Item::Sec1Key(k) => key = Some(k.into()),
+            Item::X509Certificate(_) => others.push("certificate"),
+            Item::Crl(_) => others.push("CRL"),
+            _ => others.push("unknown item"),
+        }
+    }
+    key.ok_or_else(|| {
+        let found = if others.is_empty() {
+            // Including encrypted keys, which rustls-pemfile skips.
+            "nothing we can read".to_string()
+        } else {
+            others.join(", ")
+        };
+        io::Error::other(format!(
+            "no PKCS#8, PKCS#1 or SEC1 private key found (found {})",
+            found
+        ))
+    })
+}
+
+impl ResolvesServerCert for Identities {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.choose(client_hello.signature_schemes()).cloned()
+    }
+}
+
+/// Reads when `cert` stops being valid, from its DER, without checking
+/// anything else about it.
+pub fn not_after(cert: &CertificateDer<'_>) -> Option<SystemTime> {
+    let (cert, _) = der_element(cert, 0x30)?;
+    let (mut tbs, _) = der_element(cert, 0x30)?;
+    // The version is optional, and tagged so it can be told apart.
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_skip(tbs)?;
+    }
+    // Then the serial number, signature algorithm, and issuer.
+    for _ in 0..3 {
+        tbs = der_skip(tbs)?;
+    }
+    let (validity, _) = der_element(tbs, 0x30)?;
+    let not_after = der_skip(validity)?;
+    let tag = *not_after.first()?;
+    let (time, _) = der_element(not_after, tag)?;
+    parse_der_time(tag, time)
+}
+
+/// Splits the DER element at the front of `der`, if it has tag `tag`, into its
+/// contents and whatever follows it.
+fn der_element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&t, rest) = der.split_first()?;
+    if t != tag {
+        return None;
+    }
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = if len < 0x80 {
+        (usize::from(len), rest)
+    } else {
+        let n = usize::from(len & 0x7f);
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let (len, rest) = rest.split_at(n);
+        (len.iter().fold(0, |l, &b| l << 8 | usize::from(b)), rest)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+/// Skips the DER element at the front of `der`, whatever it is.
+fn der_skip(der: &[u8]) -> Option<&[u8]> {
+    der_element(der, *der.first()?).map(|(_, rest)| rest)
+}
+
+/// Parses a UTCTime (tag 0x17) or GeneralizedTime (0x18) in the forms
+/// certificates use, like `200207173643Z`.
+fn parse_der_time(tag: u8, time: &[u8]) -> Option<SystemTime> {
+    let time = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+    if !time.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (year, rest) = match (tag, time.len()) {
+        (0x17, 12) => {
+            let year: i64 = time[..2].parse().ok()?;
+            let year = if year < 50 { 2000 + year } else { 1900 + year };
+            (year, &time[2..])
+        }
+        (0x18, 14) => (time[..4].parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| rest[i * 2..i * 2 + 2].parse::<i64>().unwrap();
+    let (month, day) = (field(0), field(1));
+    // Days since the epoch, from Howard Hinnant's days_from_civil.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = days * 86400 + field(2) * 3600 + field(3) * 60 + field(4);
+    let secs = u64::try_from(secs).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    #[test]
+    fn key_formats() {
+        let read = |pem: &str| read_private_key_der(&mut pem.as_bytes());
+        let pem = |label: &str| {
+            format!("-----BEGIN {0}-----\nAAAA\n-----END {0}-----\n", label)
+        };
+        assert!(matches!(
+            read(&pem("PRIVATE KEY")),
+            Ok(PrivateKeyDer::Pkcs8(_))
+        ));
+        assert!(matches!(
+            read(&pem("RSA PRIVATE KEY")),
+            Ok(PrivateKeyDer::Pkcs1(_))
+        ));
+        assert!(matches!(
+            read(&pem("EC PRIVATE KEY")),
+            Ok(PrivateKeyDer::Sec1(_))
+        ));
+        assert_eq!(
+            read(&pem("CERTIFICATE")).unwrap_err().to_string(),
+            "no PKCS#8, PKCS#1 or SEC1 private key found (found certificate)"
+        );
+        assert!(read(&pem("ENCRYPTED PRIVATE KEY"))
+            .unwrap_err()
+            .to_string()
+            .ends_with("(found nothing we can read)"));
+    }
+
+    #[test]
+    fn expiry() {
+        let chain =
+            rustls_pemfile::certs(&mut &include_bytes!("../localhost.crt")[..])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+        assert_eq!(
+            not_after(&chain[0]),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1581097003))
+        );
+        assert_eq!(
+            parse_der_time(0x18, b"20500101000000Z"),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(2524608000))
+        );
+        assert_eq!(not_after(&CertificateDer::from(&[0x30, 0x05][..])), None);
+    }
+
+    #[test]
+    fn ecdsa_preferred() {
+        let ecdsa = EcdsaKeyPair::generate_pkcs8(
+            &ECDSA_P256_SHA256_ASN1_SIGNING,
+            &SystemRandom::new(),
+        )
+        .unwrap();
+        let ecdsa = PrivatePkcs8KeyDer::from(ecdsa.as_ref().to_vec());
+        let ecdsa = any_supported_type(&ecdsa.into()).unwrap();
+        let rsa =
+            read_private_key(&mut &include_bytes!("../localhost.key")[..])
+                .unwrap();
+        // RSA first, to check that order given doesn't win.
+        let ids = Identities::new(vec![(rsa, vec![]), (ecdsa, vec![])]);
+
+        let pick = |schemes: &[SignatureScheme]| {
+            ids.choose(schemes).map(|k| k.key.algorithm())
+        };
+        assert_eq!(
+            pick(&[
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+            ]),
+            Some(SignatureAlgorithm::ECDSA)
+        );
+        assert_eq!(
+            pick(&[SignatureScheme::RSA_PKCS1_SHA256]),
+            Some(SignatureAlgorithm::RSA)
+        );
+        assert_eq!(pick(&[SignatureScheme::ED25519]), None);
+    }
+}
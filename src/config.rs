@@ -0,0 +1,202 @@
+//! Printing the effective configuration.
+//!
+//! An option's value can come from the command line, the environment, or its
+//! default, and with this many options it isn't always clear which won.
+//! `--print-config` writes out every option, with the value it ended up with
+//! and where that came from, as TOML or JSON, so that what a server is running
+//! with can be checked, or diffed against another's.
+
+use std::fmt::Write as _;
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command, ValueEnum};
+
+/// How to print the configuration, from `--print-config`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+/// One option and its value.
+struct Setting {
+    /// The long name, or for a positional argument, its ID.
+    name: String,
+    /// The values as given, or none if the option is unset.
+    values: Option<Vec<String>>,
+    source: &'static str,
+    /// Whether the value is a flag's `true` or `false`.
+    flag: bool,
+    /// Whether the option takes a list of values.
+    list: bool,
+}
+
+/// Renders the options of `command`, with their values in `matches`, as
+/// `format` says.
+pub fn render(
+    command: &Command,
+    matches: &ArgMatches,
+    format: ConfigFormat,
+) -> String {
+    let settings = settings(command, matches);
+    match format {
+        ConfigFormat::Toml => toml(&settings),
+        ConfigFormat::Json => json(&settings),
+    }
+}
+
+fn settings(command: &Command, matches: &ArgMatches) -> Vec<Setting> {
+    let skipped = ["help", "version", "print_config"];
+    command
+        .get_arguments()
+        .filter(|arg| !skipped.contains(&arg.get_id().as_str()))
+        .map(|arg| {
+            let id = arg.get_id().as_str();
+            let values = matches.get_raw(id).map(|values| {
+                values.map(|v| v.to_string_lossy().into_owned()).collect()
+            });
+            let source = match matches.value_source(id) {
+                Some(ValueSource::CommandLine) => "command line",
+                Some(ValueSource::EnvVariable) => "environment",
+                Some(ValueSource::DefaultValue) => "default",
+                _ => "unset",
+            };
+            let action = arg.get_action();
+            Setting {
+                name: arg.get_long().unwrap_or(id).to_string(),
+                values,
+                source,
+                flag: matches!(
+                    action,
+                    ArgAction::SetTrue | ArgAction::SetFalse
+                ),
+                list: matches!(action, ArgAction::Append)
+                    || arg.get_value_delimiter().is_some(),
+            }
+        })
+        .collect()
+}
+
+/// Writes `setting`'s value as TOML or JSON, which agree on everything used
+/// here.
+fn value(out: &mut String, setting: &Setting) {
+    let values = setting.values.as_deref().unwrap_or_default();
+    match values {
+        [flag] if setting.flag => out.push_str(flag),
+        [one] if !setting.list => quote(out, one),
+        _ => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                quote(out, value);
+            }
+            out.push(']');
+        }
+    }
+}
+
+/// Writes `s` as a quoted string, with the escapes TOML and JSON share.
+fn quote(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn toml(settings: &[Setting]) -> String {
+    let mut out = String::new();
+    for setting in settings {
+        if setting.values.is_none() {
+            let _ = writeln!(out, "# {} is unset", setting.name);
+            continue;
+        }
+        let _ = write!(out, "{} = ", setting.name);
+        value(&mut out, setting);
+        let _ = writeln!(out, "  # {}", setting.source);
+    }
+    out
+}
+
+fn json(settings: &[Setting]) -> String {
+    let mut out = String::from("{\n");
+    for (i, setting) in settings.iter().enumerate() {
+        out.push_str("  ");
+        quote(&mut out, &setting.name);
+        out.push_str(": {\"value\": ");
+        if setting.values.is_some() {
+            value(&mut out, setting);
+        } else {
+            out.push_str("null");
+        }
+        let _ = write!(out, ", \"source\": \"{}\"}}", setting.source);
+        out.push_str(if i + 1 < settings.len() { ",\n" } else { "\n" });
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, Parser};
+
+    #[derive(Parser)]
+    struct Args {
+        #[clap(long, default_value = "[::]:8000")]
+        addr: String,
+        #[clap(long)]
+        strict: bool,
+        #[clap(long = "host")]
+        hosts: Vec<String>,
+        #[clap(long)]
+        nice: Option<i32>,
+        root: String,
+    }
+
+    #[test]
+    fn rendering() {
+        let matches = Args::command().get_matches_from([
+            "httpd2",
+            "--strict",
+            "--host=a.com",
+            "--host=b\"c",
+            "/srv",
+        ]);
+        assert_eq!(
+            render(&Args::command(), &matches, ConfigFormat::Toml),
+            "addr = \"[::]:8000\"  # default\n\
+             strict = true  # command line\n\
+             host = [\"a.com\", \"b\\\"c\"]  # command line\n\
+             # nice is unset\n\
+             root = \"/srv\"  # command line\n"
+        );
+        let json = render(&Args::command(), &matches, ConfigFormat::Json);
+        let value = crate::json::parse(&json).unwrap();
+        let get = |name| value.get(name).unwrap();
+        assert_eq!(
+            get("addr").get("source").unwrap().as_str(),
+            Some("default")
+        );
+        assert_eq!(
+            get("host").get("value").unwrap().as_array().unwrap()[1].as_str(),
+            Some("b\"c")
+        );
+        assert_eq!(get("nice").get("value"), Some(&crate::json::Value::Null));
+        assert_eq!(
+            *get("strict").get("value").unwrap(),
+            crate::json::Value::Bool(true)
+        );
+    }
+}
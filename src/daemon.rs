@@ -0,0 +1,82 @@
+//! Running in the background, for init systems that expect it.
+//!
+//! We fork before doing anything else -- in particular, before any threads
+//! exist -- and the parent waits around until the child reports that it's
+//! ready to serve. That way errors during startup (a port that's in use, a
+//! missing key) are still reported by the command that was run, in its exit
+//! status, rather than vanishing into the background.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+use nix::unistd::{self, ForkResult};
+
+/// The daemon's promise to tell its parent when it's ready.
+pub struct Readiness {
+    pipe: File,
+}
+
+impl Readiness {
+    /// Tells the waiting parent process that startup succeeded, so it can
+    /// exit.
+    pub fn notify(mut self) -> io::Result<()> {
+        self.pipe.write_all(b"!")
+    }
+}
+
+/// Forks into the background.
+///
+/// This returns only in the child process. The parent exits when the child
+/// calls `Readiness::notify` (successfully) or exits (unsuccessfully).
+///
+/// # Safety
+///
+/// This must be called while the process has only one thread.
+pub unsafe fn fork() -> io::Result<Readiness> {
+    let (read, write) = unistd::pipe()?;
+    // Safety: these descriptors are brand new, so we're their only owner.
+    let (mut read, write) = (File::from_raw_fd(read), File::from_raw_fd(write));
+
+    match unistd::fork()? {
+        ForkResult::Parent { .. } => {
+            drop(write);
+            let mut byte = [0];
+            let ready = read.read(&mut byte).unwrap_or(0) == 1;
+            std::process::exit(if ready { 0 } else { 1 });
+        }
+        ForkResult::Child => {
+            drop(read);
+            // Leave the parent's session, so we don't receive its terminal's
+            // signals.
+            unistd::setsid()?;
+            // Nothing will be typed at us. Output is left where it is, since
+            // that's where the log goes.
+            let null = File::open("/dev/null")?;
+            unistd::dup2(null.as_raw_fd(), 0)?;
+            Ok(Readiness { pipe: write })
+        }
+    }
+}
+
+/// Records our process ID in the file at `path`.
+pub fn write_pidfile(path: &Path) -> io::Result<()> {
+    std::fs::write(path, format!("{}\n", std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pidfile() {
+        let path = std::env::temp_dir()
+            .join(format!("httpd2-daemon-test-{}.pid", std::process::id()));
+        std::fs::write(&path, "a stale pid that's much longer\n").unwrap();
+        write_pidfile(&path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written, format!("{}\n", std::process::id()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
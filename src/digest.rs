@@ -0,0 +1,104 @@
+//! Integrity digests of responses, as in RFC 9530.
+//!
+//! With `--digests`, a client that asks for them with `Want-Repr-Digest` or
+//! `Want-Content-Digest` gets a SHA-256 of what it's being sent, for checking
+//! a download. The file served is hashed whole, alternate and all, so the
+//! representation digest covers the bytes as encoded; the content digest is
+//! only sent with a full body, when the two are the same. Digests come from
+//! the `TagCache`, so a file is hashed once per version.
+//!
+//! SHA-256 is the only algorithm on offer. A client that says it won't take
+//! that doesn't get a digest.
+
+use base64::Engine;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::etag::Digest;
+
+pub const WANT_REPR_DIGEST: HeaderName =
+    HeaderName::from_static("want-repr-digest");
+pub const WANT_CONTENT_DIGEST: HeaderName =
+    HeaderName::from_static("want-content-digest");
+pub const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+pub const CONTENT_DIGEST: HeaderName =
+    HeaderName::from_static("content-digest");
+
+/// Which digests a request asks for.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Wanted {
+    pub repr: bool,
+    pub content: bool,
+}
+
+impl Wanted {
+    /// Reads the `Want-*-Digest` headers of a request.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Wanted {
+            repr: accepts_sha256(headers, &WANT_REPR_DIGEST),
+            content: accepts_sha256(headers, &WANT_CONTENT_DIGEST),
+        }
+    }
+
+    pub fn any(self) -> bool {
+        self.repr || self.content
+    }
+}
+
+/// Checks whether the `name` headers in `headers` give SHA-256 a nonzero
+/// preference.
+fn accepts_sha256(headers: &HeaderMap, name: &HeaderName) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|item| item.split_once('='))
+        .any(|(alg, pref)| {
+            alg.trim() == "sha-256"
+                && pref.trim().parse::<u8>().is_ok_and(|p| p > 0)
+        })
+}
+
+/// Formats `digest` as a digest field value: `sha-256=:BASE64:`.
+pub fn header_value(digest: &Digest) -> HeaderValue {
+    let value = format!(
+        "sha-256=:{}:",
+        base64::engine::general_purpose::STANDARD.encode(digest)
+    );
+    // Base64 is always a valid header value.
+    HeaderValue::from_str(&value).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Wanted::from_headers(&headers), Wanted::default());
+        headers.insert(
+            &WANT_REPR_DIGEST,
+            HeaderValue::from_static("sha-512=3, sha-256=10"),
+        );
+        headers.insert(
+            &WANT_CONTENT_DIGEST,
+            HeaderValue::from_static("sha-256=0"),
+        );
+        assert_eq!(
+            Wanted::from_headers(&headers),
+            Wanted {
+                repr: true,
+                content: false,
+            }
+        );
+
+        let digest = ring::digest::digest(&ring::digest::SHA256, b"hello\n");
+        let mut bytes = Digest::default();
+        bytes.copy_from_slice(digest.as_ref());
+        assert_eq!(
+            header_value(&bytes),
+            "sha-256=:WJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=:"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Content-encoding negotiation.
+//!
+//! We never compress anything ourselves; instead, the operator provides
+//! precompressed alternates of files (`foo.html.br`, `foo.html.gz`, etc.). When
+//! a client accepts more than one encoding that we might have, the choice is
+//! made by multiplying the client's quality value for each encoding by the
+//! operator's weight for it, and trying alternates from highest to lowest
+//! score. Ties go to whichever encoding the operator listed first.
+
+use std::str::FromStr;
+
+use hyper::header::HeaderValue;
+
+/// A content-coding we know how to serve precompressed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    /// Content-coding token, as used in `Accept-Encoding` and
+    /// `Content-Encoding`.
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Filename extension of precompressed alternates, including the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Gzip => ".gz",
+            Encoding::Brotli => ".br",
+            Encoding::Zstd => ".zst",
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Encoding::Gzip),
+            "br" => Ok(Encoding::Brotli),
+            "zstd" => Ok(Encoding::Zstd),
+            _ => Err(format!("unsupported encoding: {}", s)),
+        }
+    }
+}
+
+impl From<Encoding> for HeaderValue {
+    fn from(e: Encoding) -> Self {
+        HeaderValue::from_static(e.token())
+    }
+}
+
+/// An encoding the operator is willing to serve, with its relative weight.
+/// Written like an `Accept-Encoding` entry: `br` or `gzip;q=0.5`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Preference {
+    pub encoding: Encoding,
+    pub weight: f32,
+}
+
+impl FromStr for Preference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (token, weight) = parse_item(s)
+            .ok_or_else(|| format!("bad encoding preference: {}", s))?;
+        Ok(Preference {
+            encoding: token.parse()?,
+            weight,
+        })
+    }
+}
+
+/// Decides which precompressed alternates are worth trying, given the
+/// operator's `preferences` and the contents of any `Accept-Encoding` headers,
+/// and returns them in the order they should be tried.
+pub fn negotiate<'a>(
+    preferences: &[Preference],
+    accept_encoding: impl Iterator<Item = &'a HeaderValue>,
+) -> Vec<Encoding> {
+    let accepted: Vec<(String, f32)> = accept_encoding
+        .filter_map(|list| list.to_str().ok())
+        .flat_map(|list| list.split(','))
+        .filter_map(parse_item)
+        .map(|(token, q)| (token.to_ascii_lowercase(), q))
+        .collect();
+    let client_q = |token: &str| {
+        let find = |t: &str| {
+            accepted.iter().find(|(a, _)| a == t).map(|&(_, q)| q)
+        };
+        find(token).or_else(|| find("*")).unwrap_or(0.)
+    };
+
+    let mut scored: Vec<(Encoding, f32)> = preferences
+        .iter()
+        .map(|p| (p.encoding, p.weight * client_q(p.encoding.token())))
+        .filter(|&(_, score)| score > 0.)
+        .collect();
+    // Stable, so ties stay in the operator's order.
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.into_iter().map(|(e, _)| e).collect()
+}
+
+/// Parses one `token[;q=value]` list item, ignoring any other parameters. A
+/// missing quality value means 1.
+pub fn parse_item(item: &str) -> Option<(&str, f32)> {
+    let mut parts = item.split(';').map(str::trim);
+    let token = parts.next().filter(|t| !t.is_empty())?;
+    let mut q = 1.;
+    for param in parts {
+        if let Some((name, value)) = param.split_once('=') {
+            if name.trim().eq_ignore_ascii_case("q") {
+                q = value.trim().parse::<f32>().ok()?;
+                if !(0. ..=1.).contains(&q) {
+                    return None;
+                }
+            }
+        }
+    }
+    Some((token, q))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefs(s: &str) -> Vec<Preference> {
+        s.split(',').map(|p| p.parse().unwrap()).collect()
+    }
+
+    fn neg(p: &str, accept: &str) -> Vec<&'static str> {
+        let accept = HeaderValue::from_str(accept).unwrap();
+        negotiate(&prefs(p), std::iter::once(&accept))
+            .into_iter()
+            .map(Encoding::token)
+            .collect()
+    }
+
+    #[test]
+    fn preference_parsing() {
+        assert_eq!(
+            "gzip;q=0.5".parse::<Preference>(),
+            Ok(Preference { encoding: Encoding::Gzip, weight: 0.5 })
+        );
+        assert_eq!(
+            "br".parse::<Preference>(),
+            Ok(Preference { encoding: Encoding::Brotli, weight: 1. })
+        );
+        assert!("br;q=2".parse::<Preference>().is_err());
+        assert!("compress".parse::<Preference>().is_err());
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(neg("gzip", "gzip, deflate"), ["gzip"]);
+        assert_eq!(neg("gzip", "deflate"), Vec::<&str>::new());
+        assert_eq!(neg("br,zstd,gzip", "gzip, br, zstd"), ["br", "zstd", "gzip"]);
+        assert_eq!(neg("br,zstd,gzip", "gzip, br;q=0"), ["gzip"]);
+        assert_eq!(neg("br,gzip", "br;q=0.5, gzip"), ["gzip", "br"]);
+        assert_eq!(neg("br;q=0.4,gzip;q=0.8", "br, gzip;q=0.6"), ["gzip", "br"]);
+        assert_eq!(neg("br,gzip", "*"), ["br", "gzip"]);
+        assert_eq!(neg("br,gzip", "*;q=0, GZIP"), ["gzip"]);
+    }
+}
@@ -0,0 +1,146 @@
+//! Entity tags computed from file contents.
+//!
+//! Hashing a file's bytes gives a strong validator that comes out the same on
+//! every host serving the same file, which the default tag (derived from the
+//! length and modification time) doesn't. It's also much more expensive, so
+//! results are remembered, keyed by the file's identity and modification time:
+//! replacing or rewriting a file changes the key, and stale entries simply go
+//! unused until the cache is next cleared.
+//!
+//! The whole SHA-256 is kept, so that it can also be sent as a digest of the
+//! file (see `digest`).
+
+use std::collections::HashMap;
+use std::io::{self, SeekFrom};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::picky::File;
+
+/// Number of tags to remember before starting over.
+const CAPACITY: usize = 4096;
+
+/// What identifies a particular version of a file.
+type Key = ((u64, u64), SystemTime, u64);
+
+/// A SHA-256 of a file's contents.
+pub type Digest = [u8; 32];
+
+/// Remembers the content hashes of recently served files.
+#[derive(Default)]
+pub struct TagCache {
+    digests: Mutex<HashMap<Key, Digest>>,
+}
+
+impl TagCache {
+    /// Returns the entity tag for `file`'s contents, reading and hashing the
+    /// file if we haven't already. The file is left positioned at its start.
+    pub async fn get(&self, file: &mut File) -> io::Result<String> {
+        // Half of a SHA-256 is plenty to tell versions of a file apart.
+        let hex = self.digest(file).await?[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        Ok(format!("\"{}\"", hex))
+    }
+
+    /// Returns the SHA-256 of `file`'s contents, as for `get`.
+    pub async fn digest(&self, file: &mut File) -> io::Result<Digest> {
+        let key = (file.id, file.modified, file.len);
+        if let Some(digest) = self.digests.lock().unwrap().get(&key) {
+            return Ok(*digest);
+        }
+
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            context.update(&buf[..n]);
+        }
+        file.file.seek(SeekFrom::Start(0)).await?;
+
+        let mut digest = Digest::default();
+        digest.copy_from_slice(context.finish().as_ref());
+
+        let mut digests = self.digests.lock().unwrap();
+        if digests.len() >= CAPACITY {
+            digests.clear();
+        }
+        digests.insert(key, digest);
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn content_tags() {
+        let path = std::env::temp_dir()
+            .join(format!("httpd2-etag-test-{}", std::process::id()));
+        std::fs::write(&path, "hello\n").unwrap();
+        let open = || async {
+            let file = tokio::fs::File::open(&path).await.unwrap();
+            let meta = file.metadata().await.unwrap();
+            File {
+                file: Box::new(file),
+                len: meta.len(),
+                content_type: "text/plain",
+                modified: meta.modified().unwrap(),
+                id: (1, 2),
+                ttl: None,
+            }
+        };
+
+        let cache = TagCache::default();
+        let mut file = open().await;
+        let tag = cache.get(&mut file).await.unwrap();
+        assert_eq!(tag, "\"5891b5b522d5df086d0ff0b110fbd9d2\"");
+        let mut contents = String::new();
+        file.file.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "hello\n");
+
+        // Versions are told apart by metadata alone, so a file rewritten
+        // without changing it keeps its old tag...
+        std::fs::write(&path, "world\n").unwrap();
+        let mut same = File {
+            modified: file.modified,
+            ..open().await
+        };
+        assert_eq!(cache.get(&mut same).await.unwrap(), tag);
+        // ...but an updated timestamp gets a fresh one.
+        let mut changed = File {
+            modified: SystemTime::UNIX_EPOCH,
+            ..open().await
+        };
+        assert_ne!(cache.get(&mut changed).await.unwrap(), tag);
+        // As does another file, or a different length, at the same time.
+        let mut other = File {
+            id: (1, 3),
+            modified: file.modified,
+            ..open().await
+        };
+        assert_ne!(cache.get(&mut other).await.unwrap(), tag);
+        let mut longer = File {
+            len: 7,
+            modified: file.modified,
+            ..open().await
+        };
+        assert_ne!(cache.get(&mut longer).await.unwrap(), tag);
+        // The tag is the first half of the digest.
+        let digest = cache.digest(&mut file).await.unwrap();
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            hex,
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+//! Page cache hints for large files.
+//!
+//! Before streaming a big file we tell the kernel we'll read it front to back,
+//! which lets it read further ahead, and ask it to start on the first stretch
+//! right away. Optionally, once we're done with the file, we tell the kernel
+//! we won't need its pages again, so that a mirror serving many large files
+//! doesn't push everything else out of the page cache. All of this is
+//! advisory; failures are ignored, and on systems without `posix_fadvise`,
+//! nothing happens.
+
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// How much of the file to ask the kernel to read immediately.
+const WILLNEED_WINDOW: u64 = 2 * 1024 * 1024;
+
+/// Tells the kernel that `file` is about to be read sequentially.
+pub fn sequential(file: &impl AsRawFd) {
+    advise(file.as_raw_fd(), 0, 0, Advice::Sequential);
+    advise(file.as_raw_fd(), 0, WILLNEED_WINDOW, Advice::WillNeed);
+}
+
+/// Wraps a file so that its cached pages are dropped when the wrapper is
+/// dropped, whether or not the file was read to the end.
+pub struct DropCache<F> {
+    fd: RawFd,
+    file: F,
+}
+
+impl<F> DropCache<F> {
+    /// Wraps `file`, which reads from `fd`.
+    pub fn new(fd: RawFd, file: F) -> Self {
+        DropCache { fd, file }
+    }
+}
+
+impl<F> Drop for DropCache<F> {
+    fn drop(&mut self) {
+        // The file, and so the descriptor, is still open here; fields are
+        // dropped after this runs.
+        advise(self.fd, 0, 0, Advice::DontNeed);
+    }
+}
+
+impl<F: AsyncRead + Unpin> AsyncRead for DropCache<F> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_read(cx, buf)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Advice {
+    Sequential,
+    WillNeed,
+    DontNeed,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn advise(fd: RawFd, offset: u64, len: u64, advice: Advice) {
+    use std::convert::TryFrom;
+
+    use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+
+    let advice = match advice {
+        Advice::Sequential => PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+        Advice::WillNeed => PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+        Advice::DontNeed => PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+    };
+    let clamp = |n: u64| libc::off_t::try_from(n).unwrap_or(libc::off_t::MAX);
+    let _ = posix_fadvise(fd, clamp(offset), clamp(len), advice);
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn advise(_fd: RawFd, _offset: u64, _len: u64, _advice: Advice) {}
@@ -0,0 +1,247 @@
+//! Fetching JSON from identity providers.
+//!
+//! Just enough of an HTTP/1.1 client to ask a provider for its configuration,
+//! keys and tokens, in the manner of the one for webhooks. Hosts are looked up
+//! when an `Endpoint` is made, at startup, since that can't be done after
+//! chroot; requests later go to the addresses found then.
+
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::Uri;
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::json::{self, Value};
+
+/// How long a request to the provider may take, start to finish.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The most we'll read of a response from the provider.
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+/// Somewhere to send requests.
+pub struct Endpoint {
+    uri: Uri,
+    host: String,
+    addrs: Vec<SocketAddr>,
+    https: bool,
+}
+
+impl Endpoint {
+    /// Looks up the host in `url`.
+    pub async fn lookup(url: &str) -> io::Result<Self> {
+        let bad = |why: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad URL {}: {}", url, why),
+            )
+        };
+        let uri: Uri = url.parse().map_err(|_| bad("can't parse"))?;
+        let https = match uri.scheme_str() {
+            Some("http") => false,
+            Some("https") => true,
+            _ => return Err(bad("not http or https")),
+        };
+        let host = uri.host().ok_or_else(|| bad("no host"))?.to_string();
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let name = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs = tokio::net::lookup_host((name, port)).await?.collect();
+        Ok(Endpoint {
+            uri,
+            host,
+            addrs,
+            https,
+        })
+    }
+
+    /// Whether requests go over HTTPS.
+    pub fn is_https(&self) -> bool {
+        self.https
+    }
+}
+
+/// Sends a request to `endpoint` and parses the JSON it answers with.
+pub async fn call(
+    tls: &Option<TlsConnector>,
+    endpoint: &Endpoint,
+    method: &str,
+    headers: &str,
+    body: &str,
+) -> io::Result<Value> {
+    let path = endpoint.uri.path_and_query().map_or("/", |p| p.as_str());
+    let request = format!(
+        "{} {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         User-Agent: httpd2\r\n\
+         Accept: application/json\r\n\
+         {}\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        method,
+        path,
+        endpoint
+            .uri
+            .authority()
+            .map_or(&endpoint.host[..], |a| a.as_str()),
+        headers,
+        body.len(),
+        body
+    );
+    let exchange = async {
+        let stream = TcpStream::connect(&endpoint.addrs[..]).await?;
+        if let (true, Some(tls)) = (endpoint.https, tls) {
+            let name = ServerName::try_from(endpoint.host.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let stream = tls.connect(name, stream).await?;
+            exchange(stream, &request).await
+        } else {
+            exchange(stream, &request).await
+        }
+    };
+    let response = tokio::time::timeout(TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))??;
+    let (status, body) = parse_response(&response)?;
+    if status != 200 {
+        return Err(io::Error::other(format!(
+            "{} {} got {}",
+            method, endpoint.uri, status
+        )));
+    }
+    std::str::from_utf8(&body)
+        .ok()
+        .and_then(json::parse)
+        .ok_or_else(|| {
+            io::Error::other(format!("bad JSON from {}", endpoint.uri))
+        })
+}
+
+/// Writes `request` to `stream` and reads the response, up to
+/// `MAX_RESPONSE`.
+async fn exchange<S>(mut stream: S, request: &str) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+    let mut response = vec![];
+    match stream.take(MAX_RESPONSE).read_to_end(&mut response).await {
+        // Not every server says goodbye before closing, but what it sent is
+        // still good.
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+        result => {
+            result?;
+        }
+    }
+    Ok(response)
+}
+
+/// Splits an HTTP/1.1 response into its status and body.
+fn parse_response(response: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+    let bad = || io::Error::other("bad HTTP response");
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(bad)?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|_| bad())?;
+    let mut body = &response[end + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(bad)?;
+    let mut chunked = false;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or_else(bad)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            let len = value.parse::<usize>().map_err(|_| bad())?;
+            body = body.get(..len).ok_or_else(bad)?;
+        }
+    }
+    if !chunked {
+        return Ok((status, body.to_vec()));
+    }
+    let mut out = vec![];
+    loop {
+        let line_end =
+            body.windows(2).position(|w| w == b"\r\n").ok_or_else(bad)?;
+        let size = std::str::from_utf8(&body[..line_end]).map_err(|_| bad())?;
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| bad())?;
+        if size == 0 {
+            return Ok((status, out));
+        }
+        // The size is the sender's, so nothing is added to it until it fits.
+        let rest = &body[line_end + 2..];
+        let chunk = rest.get(..size).ok_or_else(bad)?;
+        if rest.get(size..size + 2) != Some(b"\r\n") {
+            return Err(bad());
+        }
+        out.extend_from_slice(chunk);
+        body = &rest[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses() {
+        let parse = |response: &str| parse_response(response.as_bytes()).ok();
+        let ok = |status, body: &str| Some((status, body.as_bytes().to_vec()));
+
+        assert_eq!(parse("HTTP/1.1 200 OK\r\n\r\n{}"), ok(200, "{}"));
+        assert_eq!(
+            parse("HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\n{}xx"),
+            ok(404, "{}")
+        );
+        assert_eq!(
+            parse(
+                "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n\
+                 2;x=y\r\n{\"\r\n1\r\n}\r\n0\r\n\r\n"
+            ),
+            ok(200, "{\"}")
+        );
+
+        for bad in [
+            "",
+            "HTTP/1.1 200 OK\r\n",
+            "HTTP/1.1 OK\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nNo colon\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n{}",
+            "HTTP/1.1 200 OK\r\nContent-Length: -1\r\n\r\n{}",
+            // Chunks that are cut off, too long, or impossibly large.
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\n{}\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             ffffffffffffffff\r\n{}\r\n0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+        ] {
+            assert_eq!(parse(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn urls() {
+        for bad in ["ftp://example.com/", "/path", "http:///x", "not a url"] {
+            let e = Endpoint::lookup(bad).await.err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", bad);
+        }
+        let endpoint = Endpoint::lookup("http://127.0.0.1:8080/x").await;
+        let endpoint = endpoint.unwrap();
+        assert!(!endpoint.is_https());
+        assert_eq!(endpoint.addrs, ["127.0.0.1:8080".parse().unwrap()]);
+        assert!(Endpoint::lookup("https://[::1]/").await.unwrap().is_https());
+    }
+}
@@ -0,0 +1,373 @@
+//! Fingerprinting clients by their ClientHello.
+//!
+//! Which cipher suites, extensions and signature algorithms a client offers,
+//! and in what order, varies between TLS libraries and versions of them, far
+//! more than between the machines running them. A JA4 fingerprint sums that up
+//! in a short string, which `--log-fingerprint` adds to the `tls-init` record:
+//! handy for telling a browser from a script claiming to be one.
+//!
+//! rustls parses the ClientHello but doesn't keep the details JA4 needs, so a
+//! `Tap` under the TLS stream keeps a copy of the bytes rustls reads until the
+//! ClientHello is complete, and it's parsed again here once the handshake is
+//! done. (JA3, the older fingerprint, is an MD5 hash, which we have no
+//! implementation of.)
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use ring::digest::{digest, SHA256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// How much a `Tap` keeps, at most. A ClientHello is usually well under 2 KiB;
+/// one that isn't complete by this point isn't fingerprinted.
+const MAX_HELLO: usize = 16 * 1024;
+
+/// A stream that keeps a copy of the ClientHello read through it.
+pub struct Tap<S> {
+    inner: S,
+    hello: Vec<u8>,
+    capturing: bool,
+}
+
+impl<S> Tap<S> {
+    /// Wraps `inner`, keeping what's read from it if `capture` is set.
+    pub fn new(inner: S, capture: bool) -> Self {
+        Tap {
+            inner,
+            hello: vec![],
+            capturing: capture,
+        }
+    }
+
+    /// Takes the JA4 fingerprint of the ClientHello read so far, if it's all
+    /// there and makes sense.
+    pub fn ja4(&mut self) -> Option<String> {
+        self.capturing = false;
+        ja4(&std::mem::take(&mut self.hello))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tap<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let before = buf.filled().len();
+        futures::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if this.capturing {
+            this.hello.extend_from_slice(&buf.filled()[before..]);
+            if this.hello.len() >= MAX_HELLO {
+                this.capturing = false;
+                this.hello = vec![];
+            } else if handshake_message(&this.hello).is_some() {
+                this.capturing = false;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tap<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+const HANDSHAKE: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+
+const SERVER_NAME: u16 = 0x0000;
+const SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const ALPN: u16 = 0x0010;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// Computes the JA4 fingerprint of the ClientHello at the start of `records`,
+/// which are TLS records as read off the wire.
+pub fn ja4(records: &[u8]) -> Option<String> {
+    let message = handshake_message(records)?;
+    let mut hello = Reader(&message);
+    if hello.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    hello.take(3)?;
+    let legacy_version = hello.u16()?;
+    // random, legacy_session_id
+    hello.take(32)?;
+    hello.vec8()?;
+    let suites = hello.vec16()?.u16s();
+    // legacy_compression_methods
+    hello.vec8()?;
+    let mut extensions = if hello.0.is_empty() {
+        Reader(&[])
+    } else {
+        hello.vec16()?
+    };
+
+    let mut types = vec![];
+    let mut sni = false;
+    let mut alpn: &[u8] = &[];
+    let mut versions = vec![];
+    let mut signature_algorithms = vec![];
+    while !extensions.0.is_empty() {
+        let ty = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+        if grease(ty) {
+            continue;
+        }
+        types.push(ty);
+        match ty {
+            SERVER_NAME => sni = true,
+            ALPN => alpn = data.vec16()?.vec8().map_or(&[], |p| p.0),
+            SIGNATURE_ALGORITHMS => signature_algorithms = data.vec16()?.u16s(),
+            SUPPORTED_VERSIONS => versions = data.vec8()?.u16s(),
+            _ => {}
+        }
+    }
+
+    let version = match versions.into_iter().max().unwrap_or(legacy_version) {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        _ => "00",
+    };
+    let alpn = match (alpn.first(), alpn.last()) {
+        (Some(&first), Some(&last))
+            if first.is_ascii_alphanumeric()
+                && last.is_ascii_alphanumeric() =>
+        {
+            format!("{}{}", first as char, last as char)
+        }
+        (Some(&first), Some(&last)) => {
+            let hex = format!("{:02x}{:02x}", first, last);
+            format!("{}{}", &hex[..1], &hex[3..])
+        }
+        _ => "00".to_string(),
+    };
+    let a = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        if sni { 'd' } else { 'i' },
+        suites.len().min(99),
+        types.len().min(99),
+        alpn,
+    );
+
+    let mut suites = suites;
+    suites.sort_unstable();
+    let b = truncated_hash(&hex_list(&suites));
+
+    // The extensions hash leaves out SNI and ALPN, which are in the first
+    // part already, and takes in the signature algorithms, in their order.
+    types.retain(|&ty| ty != SERVER_NAME && ty != ALPN);
+    types.sort_unstable();
+    let mut c = hex_list(&types);
+    if !c.is_empty() && !signature_algorithms.is_empty() {
+        c.push('_');
+        c.push_str(&hex_list(&signature_algorithms));
+    }
+    let c = truncated_hash(&c);
+
+    Some(format!("{}_{}_{}", a, b, c))
+}
+
+/// Reassembles the first handshake message from `records`, if all of it is
+/// there.
+fn handshake_message(records: &[u8]) -> Option<Vec<u8>> {
+    let mut records = Reader(records);
+    let mut message = vec![];
+    loop {
+        if records.u8()? != HANDSHAKE {
+            return None;
+        }
+        records.take(2)?;
+        message.extend_from_slice(records.vec16()?.0);
+        if let [_, a, b, c, ..] = message[..] {
+            let len = 4 + u32::from_be_bytes([0, a, b, c]) as usize;
+            if message.len() >= len {
+                message.truncate(len);
+                return Some(message);
+            }
+        }
+    }
+}
+
+/// GREASE values (RFC 8701) are reserved, and sent at random by some clients
+/// to keep servers honest; they're left out of fingerprints.
+fn grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn hex_list(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|v| format!("{:04x}", v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The first 12 hex digits of the SHA-256 hash of `s`, or zeros for nothing.
+fn truncated_hash(s: &str) -> String {
+    if s.is_empty() {
+        return "0".repeat(12);
+    }
+    digest(&SHA256, s.as_bytes()).as_ref()[..6]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Reads the big-endian, length-prefixed fields TLS is made of.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()?;
+        Some(Reader(self.take(len.into())?))
+    }
+
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()?;
+        Some(Reader(self.take(len.into())?))
+    }
+
+    /// The rest, as a list of 16-bit values, less any GREASE.
+    fn u16s(mut self) -> Vec<u16> {
+        std::iter::from_fn(|| self.u16())
+            .filter(|&v| !grease(v))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn fingerprints() {
+        // A ClientHello put together by hand, split across two records, with
+        // GREASE in each list.
+        let ext = |ty: u16, data: &[u8]| {
+            [
+                &ty.to_be_bytes()[..],
+                &(data.len() as u16).to_be_bytes(),
+                data,
+            ]
+            .concat()
+        };
+        let extensions = [
+            ext(0x1a1a, &[]),
+            ext(SERVER_NAME, b"\x00\x0c\x00\x00\x09localhost"),
+            ext(ALPN, b"\x00\x0c\x02h2\x08http/1.1"),
+            ext(SIGNATURE_ALGORITHMS, b"\x00\x06\x04\x03\x08\x04\x2a\x2a"),
+            ext(SUPPORTED_VERSIONS, b"\x04\x03\x04\x03\x03"),
+            ext(0x000a, b"\x00\x02\x00\x1d"),
+        ]
+        .concat();
+        let body = [
+            &b"\x03\x03"[..],
+            &[7; 32],
+            b"\x00",
+            b"\x00\x06\x13\x01\x0a\x0a\xc0\x2b",
+            b"\x01\x00",
+            &(extensions.len() as u16).to_be_bytes(),
+            &extensions,
+        ]
+        .concat();
+        let message = [
+            &[CLIENT_HELLO, 0][..],
+            &(body.len() as u16).to_be_bytes(),
+            &body,
+        ]
+        .concat();
+        let (first, second) = message.split_at(40);
+        let record = |fragment: &[u8]| {
+            [
+                &[HANDSHAKE, 3, 1][..],
+                &(fragment.len() as u16).to_be_bytes(),
+                fragment,
+            ]
+            .concat()
+        };
+        let records = [record(first), record(second)].concat();
+        assert_eq!(
+            ja4(&records).unwrap(),
+            format!(
+                "t13d0205h2_{}_{}",
+                truncated_hash("1301,c02b"),
+                truncated_hash("000a,000d,002b_0403,0804"),
+            )
+        );
+        assert_eq!(ja4(&records[..records.len() - 1]), None);
+
+        // One from rustls, read a little at a time through a Tap.
+        let client = rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let mut client = rustls::ClientConnection::new(
+            Arc::new(client),
+            rustls::pki_types::ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let mut hello = vec![];
+        client.write_tls(&mut hello).unwrap();
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut tap = Tap::new(rx, true);
+        let written = hello.clone();
+        let writer = tokio::spawn(async move {
+            tokio::io::AsyncWriteExt::write_all(&mut tx, &written).await
+        });
+        let mut read = vec![0; hello.len()];
+        tap.read_exact(&mut read).await.unwrap();
+        writer.await.unwrap().unwrap();
+        let fingerprint = tap.ja4().unwrap();
+        assert_eq!(Some(&fingerprint), ja4(&hello).as_ref());
+        assert!(fingerprint.starts_with("t13d"), "{}", fingerprint);
+        // No ALPN was offered.
+        assert_eq!(&fingerprint[8..11], "00_", "{}", fingerprint);
+    }
+}
@@ -0,0 +1,86 @@
+//! Shell-style patterns for matching request paths.
+//!
+//! Patterns are written as URL paths (`/feed`, `/downloads/*.iso`) and stored
+//! in sanitized form, so that they're compared against sanitized paths on equal
+//! terms. The wildcards are:
+//!
+//! - `?` matches any one character except `/`.
+//! - `*` matches any run of characters not including `/`.
+//! - `**` matches any run of characters at all, including `/`.
+
+use std::str::FromStr;
+
+use crate::traversal;
+
+/// A compiled path pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Glob {
+    pattern: Vec<char>,
+}
+
+impl Glob {
+    /// Checks whether the sanitized path `path` matches this pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        let path: Vec<char> = path.chars().collect();
+        matches(&self.pattern, &path)
+    }
+}
+
+impl FromStr for Glob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') {
+            return Err("pattern must begin with '/'".to_string());
+        }
+        Ok(Glob {
+            pattern: traversal::sanitize(s).chars().collect(),
+        })
+    }
+}
+
+fn matches(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', rest @ ..] => {
+            (0..=path.len()).any(|i| matches(rest, &path[i..]))
+        }
+        ['*', rest @ ..] => {
+            let run = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+            (0..=run).any(|i| matches(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => match path {
+            [c, tail @ ..] if *c != '/' => matches(rest, tail),
+            _ => false,
+        },
+        [p, rest @ ..] => match path {
+            [c, tail @ ..] if c == p => matches(rest, tail),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(pattern: &str, path: &str) -> bool {
+        pattern.parse::<Glob>().unwrap().matches(path)
+    }
+
+    #[test]
+    fn globbing() {
+        assert!(m("/feed", "./feed"));
+        assert!(!m("/feed", "./feed.xml"));
+        assert!(m("//feed", "./feed"));
+        assert!(m("/bin/*", "./bin/tool"));
+        assert!(!m("/bin/*", "./bin/sub/tool"));
+        assert!(m("/bin/**", "./bin/sub/tool"));
+        assert!(m("/**.iso", "./a/b/c.iso"));
+        assert!(m("/*.htm?", "./x.html"));
+        assert!(!m("/*.htm?", "./x.htm"));
+        assert!(m("/.well-known/*", "./:well-known/x"));
+
+        assert!("feed".parse::<Glob>().is_err());
+    }
+}
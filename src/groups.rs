@@ -0,0 +1,100 @@
+//! Supplementary groups for the user we switch to.
+//!
+//! By default, switching to `-U`/`-G` leaves the server in just the one group
+//! (or, with `-U` alone, no supplementary groups at all). Content trees that
+//! are readable through some other group need more: either an explicit list
+//! with `--group`, or whatever the group database lists for the user, as
+//! `login` would set up. The database has to be read before chroot, so the
+//! list is worked out early.
+
+use std::io;
+
+use nix::unistd::{Gid, Uid};
+
+use crate::args::CommonArgs;
+
+/// Works out the groups to hold after switching to the user and group in
+/// `args`, starting with the primary group. Returns `None` if we're switching
+/// neither.
+pub fn supplementary(args: &CommonArgs) -> io::Result<Option<Vec<Gid>>> {
+    let gid = match (args.gid, args.uid) {
+        (Some(gid), _) => gid,
+        // A new user without a new group still mustn't keep root's groups.
+        (None, Some(_)) => return Ok(Some(vec![])),
+        (None, None) => return Ok(None),
+    };
+    let mut extra = args.groups.clone();
+    if args.init_groups {
+        // Clap ensures that -U comes with --init-groups.
+        let uid = args.uid.unwrap_or_else(Uid::current);
+        extra.extend(database_groups(uid, gid)?);
+    }
+    let mut groups = vec![gid];
+    for g in extra {
+        if !groups.contains(&g) {
+            groups.push(g);
+        }
+    }
+    Ok(Some(groups))
+}
+
+/// Lists the groups that the group database gives the user `uid`.
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+fn database_groups(uid: Uid, gid: Gid) -> io::Result<Vec<Gid>> {
+    use nix::unistd::User;
+    use std::ffi::CString;
+
+    let user = User::from_uid(uid)?.ok_or_else(|| {
+        io::Error::other(format!("no user with ID {} for --init-groups", uid))
+    })?;
+    let name = CString::new(user.name)?;
+    Ok(nix::unistd::getgrouplist(&name, gid)?)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn database_groups(_uid: Uid, _gid: Gid) -> io::Result<Vec<Gid>> {
+    Err(io::Error::other(
+        "--init-groups isn't supported on this system",
+    ))
+}
+
+/// Formats a list of groups for the log.
+pub fn display(groups: &[Gid]) -> String {
+    let groups: Vec<_> = groups.iter().map(|g| g.to_string()).collect();
+    groups.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn explicit_groups() {
+        let parse = |extra: &[&str]| {
+            let argv = ["httpd2", "/srv"].iter().chain(extra);
+            supplementary(&CommonArgs::parse_from(argv)).unwrap()
+        };
+        assert_eq!(parse(&[]), None);
+        assert_eq!(parse(&["-U", "100"]), Some(vec![]));
+        assert_eq!(parse(&["-G", "100"]), Some(vec![Gid::from_raw(100)]));
+        let groups = parse(&["-G", "100", "--group", "33", "--group", "100"]);
+        assert_eq!(display(&groups.unwrap()), "100,33");
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    #[test]
+    fn database_groups() {
+        let argv = ["httpd2", "-U", "0", "-G", "0", "--init-groups"];
+        let args = CommonArgs::parse_from(argv.iter().chain(&["/srv"]));
+        let groups = supplementary(&args).unwrap().unwrap();
+        // The primary group comes first, and only once.
+        assert_eq!(groups[0], Gid::from_raw(0));
+        assert_eq!(groups.iter().filter(|&&g| g == groups[0]).count(), 1);
+
+        // A user the database doesn't have can't have their groups looked up.
+        let argv = ["httpd2", "-U", "3999999999", "-G", "0", "--init-groups"];
+        let args = CommonArgs::parse_from(argv.iter().chain(&["/srv"]));
+        assert!(supplementary(&args).is_err());
+    }
+}
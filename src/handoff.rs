@@ -0,0 +1,104 @@
+//! Handing the listening socket from one server process to its replacement.
+//!
+//! A running server can't re-execute itself -- by the time it's serving, it's
+//! chrooted and unprivileged -- so upgrades work the other way around. The
+//! old process holds a Unix socket open at a well-known path. The new process,
+//! started as root like any other, connects to it during startup, and receives
+//! the old process's listening socket over it (as `SCM_RIGHTS` ancillary data)
+//! instead of binding its own. Having handed the socket over, the old process
+//! stops accepting connections and exits once the ones it has are finished.
+//! Pending connections wait in the listen queue, which both processes share,
+//! so none are refused along the way.
+//!
+//! The Unix socket is created before chroot, readable and writable only by its
+//! owner, which is whoever started the server (generally root). Anyone who can
+//! connect to it can take the listening socket, so don't loosen that.
+
+use std::io::{self, IoSlice, IoSliceMut};
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use nix::sys::socket::{self, ControlMessage, ControlMessageOwned, MsgFlags};
+
+/// Asks a server listening at `path` for its listening socket.
+///
+/// Returns `None` if there's nobody there to ask.
+pub fn receive(path: &Path) -> io::Result<Option<TcpListener>> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == io::ErrorKind::NotFound
+            || e.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut byte = [0];
+    let mut iov = [IoSliceMut::new(&mut byte)];
+    let mut cmsg = nix::cmsg_space!(std::os::fd::RawFd);
+    let msg = socket::recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let [fd] = fds[..] {
+                // Safety: we've just been given this descriptor, so nothing
+                // else in this process owns it.
+                let listener = unsafe { TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                return Ok(Some(listener));
+            }
+        }
+    }
+    Err(io::Error::other("no listening socket received in handoff"))
+}
+
+/// Creates the Unix socket at `path` that a later process will use to request
+/// our listening socket, replacing any socket already there.
+pub fn listen(path: &Path) -> io::Result<UnixListener> {
+    crate::unix::listen_private(path)
+}
+
+/// Sends `listener` to the process on the other end of `stream`.
+pub fn send(stream: &UnixStream, listener: &impl AsRawFd) -> io::Result<()> {
+    let fds = [listener.as_raw_fd()];
+    socket::sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(b"!")],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handoff() {
+        let path = std::env::temp_dir()
+            .join(format!("httpd2-handoff-test-{}", std::process::id()));
+        assert!(receive(&path).unwrap().is_none());
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unix = listen(&path).unwrap();
+        unix.set_nonblocking(false).unwrap();
+        let sender = std::thread::spawn(move || {
+            let (stream, _) = unix.accept().unwrap();
+            send(&stream, &tcp).unwrap();
+            tcp.local_addr().unwrap()
+        });
+
+        let received = receive(&path).unwrap().unwrap();
+        assert_eq!(received.local_addr().unwrap(), sender.join().unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,407 @@
+//! Operator-configured changes to response headers.
+//!
+//! Each `--header` rule picks out responses, by request path or by content
+//! type, and adds, sets, or removes one header on them. Rules are applied in
+//! the order given, once the response is otherwise complete, so they see (and
+//! can undo) everything the server put there itself.
+//!
+//! A site can also carry its own headers, in a `_headers` file at the root in
+//! Netlify's format:
+//!
+//! ```text
+//! # comment
+//! /*
+//!   X-Frame-Options: DENY
+//! /fonts/*
+//!   Cache-Control: public, max-age=31536000, immutable
+//!   Access-Control-Allow-Origin: *
+//! ```
+//!
+//! Each unindented line is a path pattern, as in `_redirects`, and the
+//! indented lines after it are headers for responses to matching requests.
+//! Where several patterns match, values for the same header are joined with
+//! commas. These are set before the `--header` rules run, so the operator has
+//! the last word. The file is read and watched as described in `sitefile`.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::glob::Glob;
+use crate::sitefile::{Pattern, Watched};
+use crate::source::ContentSource;
+
+/// Where the site's headers live, relative to the root.
+pub const SITE_FILE: &str = "./_headers";
+
+/// Which responses a rule applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Matcher {
+    /// Responses to requests for paths matching this pattern.
+    Path(Glob),
+    /// Responses with this media type. A subtype of `*` matches any.
+    ContentType(String),
+}
+
+impl Matcher {
+    fn matches(&self, path: &str, headers: &HeaderMap) -> bool {
+        match self {
+            Matcher::Path(glob) => glob.matches(path),
+            Matcher::ContentType(pattern) => {
+                let media = headers
+                    .get(hyper::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.split(';').next())
+                    .map(|v| v.trim().to_ascii_lowercase());
+                let media = match media {
+                    Some(media) => media,
+                    None => return false,
+                };
+                match pattern.strip_suffix("/*") {
+                    Some(kind) => media.split('/').next() == Some(kind),
+                    None => media == *pattern,
+                }
+            }
+        }
+    }
+}
+
+/// What a rule does to a header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Adds a value, keeping any already there.
+    Add(HeaderName, HeaderValue),
+    /// Replaces any values with this one.
+    Set(HeaderName, HeaderValue),
+    /// Removes all values.
+    Remove(HeaderName),
+}
+
+/// A change to make to matching responses, from `--header`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderRule {
+    pub matcher: Matcher,
+    pub action: Action,
+}
+
+impl FromStr for HeaderRule {
+    type Err = String;
+
+    /// Parses `MATCH=+Name: value` (add), `MATCH=Name: value` (set), or
+    /// `MATCH=-Name` (remove), where `MATCH` is a path pattern or
+    /// `type:` and a media type.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (matcher, action) = s
+            .split_once('=')
+            .ok_or_else(|| "expected MATCH=ACTION".to_string())?;
+        let matcher = match matcher.strip_prefix("type:") {
+            Some(media) => Matcher::ContentType(media.to_ascii_lowercase()),
+            None => Matcher::Path(matcher.parse()?),
+        };
+        let name = |n: &str| {
+            HeaderName::from_str(n.trim())
+                .map_err(|_| format!("bad header name: {}", n.trim()))
+        };
+        let header = |h: &str| {
+            let (n, v) = h
+                .split_once(':')
+                .ok_or_else(|| "expected Name: value".to_string())?;
+            let v = HeaderValue::from_str(v.trim())
+                .map_err(|_| format!("bad header value: {}", v.trim()))?;
+            Ok::<_, String>((name(n)?, v))
+        };
+        let action = if let Some(n) = action.strip_prefix('-') {
+            Action::Remove(name(n)?)
+        } else if let Some(h) = action.strip_prefix('+') {
+            let (n, v) = header(h)?;
+            Action::Add(n, v)
+        } else {
+            let (n, v) = header(action)?;
+            Action::Set(n, v)
+        };
+        Ok(HeaderRule { matcher, action })
+    }
+}
+
+/// Applies each of `rules` that matches, in order, to `headers`, which are
+/// those of the response to a request for the sanitized path `path`.
+pub fn apply(rules: &[HeaderRule], path: &str, headers: &mut HeaderMap) {
+    for rule in rules {
+        if !rule.matcher.matches(path, headers) {
+            continue;
+        }
+        match &rule.action {
+            Action::Add(name, value) => {
+                headers.append(name.clone(), value.clone());
+            }
+            Action::Set(name, value) => {
+                headers.insert(name.clone(), value.clone());
+            }
+            Action::Remove(name) => {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+/// Rebuilds `headers` in order of name, with the values of each repeated
+/// header joined by commas, for `--normalize-headers`. Set-Cookie values
+/// can't be joined, so are left as they are.
+pub fn normalize(headers: &mut HeaderMap) {
+    let mut all = Vec::<(HeaderName, Vec<HeaderValue>)>::new();
+    let mut last = None;
+    for (name, value) in headers.drain() {
+        if let Some(name) = name {
+            last = Some(name.clone());
+            all.push((name, vec![]));
+        }
+        if last.is_some() {
+            all.last_mut().unwrap().1.push(value);
+        }
+    }
+    all.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+    for (name, values) in all {
+        let joined = if values.len() > 1 && name != hyper::header::SET_COOKIE {
+            let values = values.iter().map(HeaderValue::as_bytes);
+            HeaderValue::from_bytes(
+                &values.collect::<Vec<_>>().join(&b", "[..]),
+            )
+            .ok()
+        } else {
+            None
+        };
+        match joined {
+            Some(value) => {
+                headers.insert(name, value);
+            }
+            None => {
+                for value in values {
+                    headers.append(name.clone(), value);
+                }
+            }
+        }
+    }
+}
+
+/// A path pattern from `_headers`, and the headers that go with it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SiteRule {
+    path: Pattern,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Parses a `_headers` file, returning the rules and a complaint about each
+/// line that had to be skipped.
+pub fn parse_site(text: &str) -> (Vec<SiteRule>, Vec<String>) {
+    let mut rules: Vec<SiteRule> = vec![];
+    let mut skipped = vec![];
+    // Whether headers belong to the last rule, rather than to a pattern that
+    // was skipped.
+    let mut current = false;
+    for (n, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let mut skip =
+            |why: String| skipped.push(format!("line {}: {}", n + 1, why));
+        if !line.starts_with(char::is_whitespace) {
+            current = false;
+            if trimmed.contains("://") {
+                skip("patterns with a host aren't supported".to_string());
+                continue;
+            }
+            match trimmed.parse() {
+                Ok(path) => {
+                    rules.push(SiteRule {
+                        path,
+                        headers: vec![],
+                    });
+                    current = true;
+                }
+                Err(why) => skip(why),
+            }
+            continue;
+        }
+        if !current {
+            if rules.is_empty() {
+                skip("header before any path".to_string());
+            }
+            continue;
+        }
+        let header = trimmed
+            .split_once(':')
+            .ok_or_else(|| "expected Name: value".to_string());
+        let header = header.and_then(|(n, v)| {
+            let name = HeaderName::from_str(n.trim())
+                .map_err(|_| format!("bad header name: {}", n.trim()))?;
+            let value = HeaderValue::from_str(v.trim())
+                .map_err(|_| format!("bad header value: {}", v.trim()))?;
+            Ok((name, value))
+        });
+        match header {
+            Ok(header) => rules.last_mut().unwrap().headers.push(header),
+            Err(why) => skip(why),
+        }
+    }
+    (rules, skipped)
+}
+
+/// Sets the headers of each of `rules` that matches `path`, a sanitized request
+/// path, on `headers`. Values for a header from different rules are joined.
+fn apply_site(rules: &[SiteRule], path: &str, headers: &mut HeaderMap) {
+    let mut values = HashMap::<&HeaderName, Vec<&[u8]>>::new();
+    let matching = rules
+        .iter()
+        .filter(|rule| rule.path.matches(path).is_some());
+    for (name, value) in matching.flat_map(|rule| &rule.headers) {
+        values.entry(name).or_default().push(value.as_bytes());
+    }
+    for (name, values) in values {
+        if let Ok(value) = HeaderValue::from_bytes(&values.join(&b", "[..])) {
+            headers.insert(name.clone(), value);
+        }
+    }
+}
+
+/// The `_headers` rules currently in effect.
+pub struct SiteHeaders(Watched<Vec<SiteRule>>);
+
+impl Default for SiteHeaders {
+    fn default() -> Self {
+        SiteHeaders(Watched::new(SITE_FILE))
+    }
+}
+
+impl SiteHeaders {
+    /// Sets the headers for a request for the sanitized path `path` on
+    /// `headers`.
+    pub fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        apply_site(&self.0.get(), path, headers);
+    }
+
+    /// Re-reads the file from `root` if it has changed since last time. If it
+    /// has gone, so do the rules.
+    pub async fn reload(&self, log: &slog::Logger, root: &dyn ContentSource) {
+        let rules = self
+            .0
+            .reload(log, root, |text| {
+                let (rules, skipped) = parse_site(text);
+                for why in skipped {
+                    slog::warn!(log, "skipping site header: {}", why);
+                }
+                rules
+            })
+            .await;
+        if let Some(rules) = rules {
+            slog::info!(log, "site headers"; "rules" => rules.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let rules = [
+            "/private/**=X-Robots-Tag: noindex",
+            "/private/**=-Last-Modified",
+            "type:text/*=+Link: </style.css>; rel=preload",
+            "type:TEXT/HTML=+link: </app.js>; rel=preload",
+        ]
+        .iter()
+        .map(|r| r.parse().unwrap())
+        .collect::<Vec<HeaderRule>>();
+
+        let response = |path: &str, content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                hyper::header::CONTENT_TYPE,
+                HeaderValue::from_str(content_type).unwrap(),
+            );
+            headers.insert(
+                hyper::header::LAST_MODIFIED,
+                HeaderValue::from_static("Mon, 15 Jan 2024 00:00:00 GMT"),
+            );
+            apply(&rules, path, &mut headers);
+            headers
+        };
+
+        let h = response("./private/a.html", "text/html; charset=utf-8");
+        assert_eq!(h["x-robots-tag"], "noindex");
+        assert!(h.get("last-modified").is_none());
+        assert_eq!(h.get_all("link").iter().count(), 2);
+
+        let h = response("./public/a.css", "text/css");
+        assert!(h.get("x-robots-tag").is_none());
+        assert!(h.get("last-modified").is_some());
+        assert_eq!(h.get_all("link").iter().count(), 1);
+
+        assert!("/a=Bad Name: x".parse::<HeaderRule>().is_err());
+        assert!("/a=X-Thing".parse::<HeaderRule>().is_err());
+        assert!("a=-X-Thing".parse::<HeaderRule>().is_err());
+    }
+
+    #[test]
+    fn normalizing() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-b", HeaderValue::from_static("1"));
+        headers.append("vary", HeaderValue::from_static("accept"));
+        headers.insert("content-length", HeaderValue::from_static("5"));
+        headers.append("vary", HeaderValue::from_static("accept-encoding"));
+        headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2"));
+        normalize(&mut headers);
+        let lines = headers
+            .iter()
+            .map(|(n, v)| format!("{}: {}", n, v.to_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "content-length: 5",
+                "set-cookie: a=1",
+                "set-cookie: b=2",
+                "vary: accept, accept-encoding",
+                "x-b: 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn site_rules() {
+        let (rules, skipped) = parse_site(
+            "  X-Early: 1\n\
+             # comment\n\
+             /*\n\
+             \x20 X-Frame-Options: DENY\n\
+             \x20 Link: </a.css>; rel=preload\n\
+             https://example.com/*\n\
+             \x20 X-Other: 1\n\
+             /fonts/:name\n\
+             \x20 Link: </b.css>; rel=preload\n\
+             \x20 Bad\n",
+        );
+        assert_eq!(skipped.len(), 3);
+        assert!(skipped[2].starts_with("line 10: "));
+
+        let response = |path: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                hyper::header::LINK,
+                HeaderValue::from_static("</server.css>"),
+            );
+            apply_site(&rules, path, &mut headers);
+            headers
+        };
+        let h = response("./fonts/a.woff2");
+        assert_eq!(h["x-frame-options"], "DENY");
+        assert_eq!(h["link"], "</a.css>; rel=preload, </b.css>; rel=preload");
+        assert!(h.get("x-other").is_none());
+        let h = response("./fonts/a/b");
+        assert_eq!(h["link"], "</a.css>; rel=preload");
+    }
+}
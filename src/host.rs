@@ -0,0 +1,56 @@
+//! Host name handling.
+//!
+//! The host a request is addressed to comes from the `:authority`
+//! pseudo-header in HTTP/2, or from an absolute-form request target or the
+//! `Host` header in HTTP/1.1. Before comparing it against anything, we
+//! normalize it: the port is dropped, letters are lowercased, and a trailing
+//! dot (as in a fully qualified name) is removed.
+
+use hyper::http::uri::Authority;
+use hyper::Request;
+
+/// Determines the normalized host `req` is addressed to, if it names one and
+/// the name is well-formed.
+pub fn request_host<B>(req: &Request<B>) -> Option<String> {
+    match req.uri().authority() {
+        Some(authority) => normalize(authority.as_str()),
+        None => req
+            .headers()
+            .get(hyper::header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .and_then(normalize),
+    }
+}
+
+/// Normalizes the host part of an authority (`host[:port]`), returning `None`
+/// if it isn't syntactically valid.
+pub fn normalize(authority: &str) -> Option<String> {
+    // Userinfo is never valid here, though `Authority` would accept it.
+    if authority.contains('@') {
+        return None;
+    }
+    let authority = authority.parse::<Authority>().ok()?;
+    let host = authority.host();
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    Some(host.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalization() {
+        assert_eq!(normalize("Example.COM").as_deref(), Some("example.com"));
+        assert_eq!(normalize("example.com:443").as_deref(), Some("example.com"));
+        assert_eq!(normalize("example.com.").as_deref(), Some("example.com"));
+        assert_eq!(normalize("[::1]:8000").as_deref(), Some("[::1]"));
+        assert_eq!(normalize("10.0.0.1").as_deref(), Some("10.0.0.1"));
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("a b"), None);
+        assert_eq!(normalize("user@example.com"), None);
+    }
+}
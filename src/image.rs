@@ -0,0 +1,96 @@
+//! Image format negotiation.
+//!
+//! Like precompressed alternates, but for pictures: next to `photo.jpg`, the
+//! operator can put `photo.jpg.avif` and `photo.jpg.webp`, and clients that
+//! say they can show those formats get them instead, at the same URL. Only
+//! formats the client names outright in `Accept` count -- browsers send
+//! `image/*` and `*/*` whether or not they can decode AVIF, so wildcards
+//! don't tell us anything.
+
+use hyper::header::HeaderValue;
+
+use crate::encoding::parse_item;
+
+/// An image format we can serve in place of the original.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageFormat {
+    Avif,
+    Webp,
+}
+
+impl ImageFormat {
+    /// Media type, as used in `Accept` and `Content-Type`.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+
+    /// Filename extension of alternates in this format, including the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Avif => ".avif",
+            ImageFormat::Webp => ".webp",
+        }
+    }
+}
+
+/// Whether files of `content_type` may have image alternates.
+pub fn convertible(content_type: &str) -> bool {
+    matches!(content_type, "image/jpeg" | "image/png" | "image/gif")
+}
+
+/// Decides which of the operator's `formats` the client will take, given the
+/// contents of any `Accept` headers, and returns them in the order they
+/// should be tried: by the client's quality value, with ties going to
+/// whichever the operator listed first.
+pub fn negotiate<'a>(
+    formats: &[ImageFormat],
+    accept: impl Iterator<Item = &'a HeaderValue>,
+) -> Vec<ImageFormat> {
+    let accepted: Vec<(String, f32)> = accept
+        .filter_map(|list| list.to_str().ok())
+        .flat_map(|list| list.split(','))
+        .filter_map(parse_item)
+        .map(|(media, q)| (media.to_ascii_lowercase(), q))
+        .collect();
+    let mut scored: Vec<(ImageFormat, f32)> = formats
+        .iter()
+        .filter_map(|&f| {
+            accepted
+                .iter()
+                .find(|(media, _)| media == f.content_type())
+                .map(|&(_, q)| (f, q))
+        })
+        .filter(|&(_, q)| q > 0.)
+        .collect();
+    // Stable, so ties stay in the operator's order.
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.into_iter().map(|(f, _)| f).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        let neg = |accept: &str| {
+            let accept = HeaderValue::from_str(accept).unwrap();
+            negotiate(
+                &[ImageFormat::Avif, ImageFormat::Webp],
+                std::iter::once(&accept),
+            )
+        };
+        use ImageFormat::*;
+        assert_eq!(
+            neg("image/avif,image/webp,image/apng,image/*,*/*;q=0.8"),
+            [Avif, Webp]
+        );
+        assert_eq!(neg("image/webp,*/*"), [Webp]);
+        assert_eq!(neg("image/*,*/*;q=0.8"), []);
+        assert_eq!(neg("image/avif;q=0.5, IMAGE/WEBP"), [Webp, Avif]);
+        assert_eq!(neg("image/avif;q=0, image/webp"), [Webp]);
+    }
+}
@@ -0,0 +1,294 @@
+//! Reading JSON.
+//!
+//! Identity providers answer in JSON, and there's no JSON library among our
+//! dependencies, so this is just enough of a parser to pick values out of
+//! their responses. It accepts what RFC 8259 allows and nothing else, and it
+//! limits nesting, since the input comes from over the network.
+
+/// How deep arrays and objects may nest.
+const MAX_DEPTH: usize = 32;
+
+/// A JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Members, in order. A name that appears twice is found the first time.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The member of an object named `name`.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => {
+                members.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value as a whole number of at most 2^53, the most a double holds
+    /// exactly.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Number(n)
+                if n >= 0.0 && n.fract() == 0.0 && n <= (1u64 << 53) as f64 =>
+            {
+                Some(n as u64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Parses `text`, which must be a single JSON value, with nothing but
+/// whitespace around it.
+pub fn parse(text: &str) -> Option<Value> {
+    let mut parser = Parser { rest: text };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.rest.is_empty() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start_matches([' ', '\t', '\n', '\r']);
+    }
+
+    /// Consumes `token` if it comes next.
+    fn eat(&mut self, token: &str) -> bool {
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Option<Value> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        self.skip_whitespace();
+        match self.rest.chars().next()? {
+            '{' => self.object(depth),
+            '[' => self.array(depth),
+            '"' => self.string().map(Value::String),
+            't' if self.eat("true") => Some(Value::Bool(true)),
+            'f' if self.eat("false") => Some(Value::Bool(false)),
+            'n' if self.eat("null") => Some(Value::Null),
+            _ => self.number(),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Option<Value> {
+        self.eat("{");
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.eat("}") {
+            return Some(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let name = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(":") {
+                return None;
+            }
+            members.push((name, self.value(depth + 1)?));
+            self.skip_whitespace();
+            if self.eat("}") {
+                return Some(Value::Object(members));
+            }
+            if !self.eat(",") {
+                return None;
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Option<Value> {
+        self.eat("[");
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.eat("]") {
+            return Some(Value::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            if self.eat("]") {
+                return Some(Value::Array(items));
+            }
+            if !self.eat(",") {
+                return None;
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if !self.eat("\"") {
+            return None;
+        }
+        let mut out = String::new();
+        let mut chars = self.rest.chars();
+        loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => {
+                    let c = match chars.next()? {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => {
+                            let unit = hex4(&mut chars)?;
+                            if (0xd800..0xdc00).contains(&unit) {
+                                // A high surrogate, which must be followed by
+                                // an escaped low one.
+                                if chars.next()? != '\\' || chars.next()? != 'u'
+                                {
+                                    return None;
+                                }
+                                let low = hex4(&mut chars)?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return None;
+                                }
+                                let c = 0x10000
+                                    + ((unit - 0xd800) << 10)
+                                    + (low - 0xdc00);
+                                char::from_u32(c)?
+                            } else {
+                                char::from_u32(unit)?
+                            }
+                        }
+                        _ => return None,
+                    };
+                    out.push(c);
+                }
+                c if c < ' ' => return None,
+                c => out.push(c),
+            }
+        }
+        self.rest = chars.as_str();
+        Some(out)
+    }
+
+    fn number(&mut self) -> Option<Value> {
+        let bytes = self.rest.as_bytes();
+        let digits = |from: usize| {
+            bytes[from..]
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .count()
+        };
+        let mut len = usize::from(bytes.first() == Some(&b'-'));
+        let int = digits(len);
+        // No leading zeros.
+        if int == 0 || (int > 1 && bytes[len] == b'0') {
+            return None;
+        }
+        len += int;
+        if bytes.get(len) == Some(&b'.') {
+            let frac = digits(len + 1);
+            if frac == 0 {
+                return None;
+            }
+            len += 1 + frac;
+        }
+        if let Some(b'e' | b'E') = bytes.get(len) {
+            len += 1;
+            if let Some(b'+' | b'-') = bytes.get(len) {
+                len += 1;
+            }
+            let exp = digits(len);
+            if exp == 0 {
+                return None;
+            }
+            len += exp;
+        }
+        let (number, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        number.parse().ok().map(Value::Number)
+    }
+}
+
+/// Reads the four hex digits of a `\u` escape.
+fn hex4(chars: &mut std::str::Chars) -> Option<u32> {
+    let mut unit = 0;
+    for _ in 0..4 {
+        unit = unit << 4 | chars.next()?.to_digit(16)?;
+    }
+    Some(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let value = parse(
+            r#" {"iss": "https://id.example.com", "aud": ["a", "b"],
+                "exp": 1700000000, "n": -1.5e2, "ok": true, "x": null,
+                "s": "\"\u00e9\ud83d\ude00\n", "iss": "later"} "#,
+        )
+        .unwrap();
+        let get = |name| value.get(name).unwrap();
+        assert_eq!(get("iss").as_str(), Some("https://id.example.com"));
+        assert_eq!(get("aud").as_array().unwrap()[1].as_str(), Some("b"));
+        assert_eq!(get("exp").as_u64(), Some(1_700_000_000));
+        assert_eq!(*get("n"), Value::Number(-150.0));
+        assert_eq!(get("n").as_u64(), None);
+        assert_eq!(*get("ok"), Value::Bool(true));
+        assert_eq!(*get("x"), Value::Null);
+        assert_eq!(get("s").as_str(), Some("\"\u{e9}\u{1f600}\n"));
+        assert_eq!(value.get("missing"), None);
+
+        for bad in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "01",
+            "1.",
+            "\"\\ud800\"",
+            "\"\t\"",
+            "nul",
+            "{} {}",
+            &"[".repeat(100),
+        ] {
+            assert_eq!(parse(bad), None, "{:?}", bad);
+        }
+    }
+}
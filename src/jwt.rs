@@ -0,0 +1,549 @@
+//! Checking bearer tokens.
+//!
+//! Paths matching `--jwt-protect` are only served to requests with an
+//! `Authorization: Bearer` header holding a JSON Web Token (RFC 7519) signed
+//! with one of the keys in `--jwt-keys`, which is either a JWK set (RFC 7517)
+//! in a file, read at startup, or the URL of one, fetched at startup and every
+//! `REFRESH` after that. A failed refresh keeps the keys we had.
+//!
+//! Tokens must be signed with RS256, RS384, RS512, PS256, PS384, PS512,
+//! ES256, ES384 or EdDSA (Ed25519), by a key whose `kid` matches the token's,
+//! if it has one, and whose `alg`, if it names one, is the token's. Unsigned
+//! tokens never pass. A token must have an `exp` that hasn't passed, and may
+//! have an `nbf`, both allowed `LEEWAY` for clocks that disagree; if
+//! `--jwt-issuer` or `--jwt-audience` are given, its `iss` must be that and its
+//! `aud` must include that.
+//!
+//! As RFC 6750 says, a request without a token, or with one we can't accept,
+//! gets a 401 with a `WWW-Authenticate` challenge. A good token meant for
+//! someone else, by its issuer or audience, gets a 403.
+
+use std::fs;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::StatusCode;
+use ring::signature::{
+    self, RsaParameters, RsaPublicKeyComponents, UnparsedPublicKey,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::args::CommonArgs;
+use crate::fetch::{call, Endpoint};
+use crate::json::{self, Value};
+
+/// How often keys from a URL are fetched again.
+pub const REFRESH: Duration = Duration::from_secs(3600);
+/// How far our clock may be from the issuer's, in seconds.
+const LEEWAY: u64 = 60;
+/// The longest token we'll look at.
+const MAX_TOKEN: usize = 8 * 1024;
+
+/// Why a request wasn't let through.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Refusal {
+    pub status: StatusCode,
+    /// What goes in `WWW-Authenticate`, if anything.
+    pub challenge: Option<HeaderValue>,
+    pub reason: &'static str,
+}
+
+impl Refusal {
+    /// A 401, for a request without a token we can accept.
+    fn unauthorized(error: bool, reason: &'static str) -> Self {
+        let challenge = if error {
+            "Bearer error=\"invalid_token\""
+        } else {
+            "Bearer"
+        };
+        Refusal {
+            status: StatusCode::UNAUTHORIZED,
+            challenge: Some(HeaderValue::from_static(challenge)),
+            reason,
+        }
+    }
+
+    /// A 403, for a token meant for someone else.
+    fn forbidden(reason: &'static str) -> Self {
+        Refusal {
+            status: StatusCode::FORBIDDEN,
+            challenge: None,
+            reason,
+        }
+    }
+}
+
+/// A public key from a JWK set.
+#[derive(Debug)]
+struct Key {
+    kid: Option<String>,
+    /// The one algorithm the key may be used with, if it says.
+    alg: Option<String>,
+    material: Material,
+}
+
+#[derive(Debug)]
+enum Material {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// An uncompressed point on P-256 or P-384.
+    P256(Vec<u8>),
+    P384(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+/// Checks bearer tokens against a set of keys.
+pub struct Bearer {
+    keys: RwLock<Arc<Vec<Key>>>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    /// Where the keys came from, if they're to be fetched again.
+    url: Option<Endpoint>,
+    /// Only there if `url` is HTTPS.
+    tls: Option<TlsConnector>,
+}
+
+impl Bearer {
+    /// Reads or fetches the keys, as `args` say.
+    pub async fn load(args: &CommonArgs) -> io::Result<Self> {
+        let source = args
+            .jwt_keys
+            .as_deref()
+            .ok_or_else(|| io::Error::other("no --jwt-keys"))?;
+        let mut bearer = Bearer {
+            keys: RwLock::default(),
+            issuer: args.jwt_issuer.clone(),
+            audience: args.jwt_audience.clone(),
+            url: None,
+            tls: None,
+        };
+        if source.starts_with("http://") || source.starts_with("https://") {
+            let url = Endpoint::lookup(source).await?;
+            if url.is_https() {
+                bearer.tls = Some(crate::webhook::connector(&args.jwt_ca)?);
+            }
+            bearer.url = Some(url);
+            bearer.refresh().await?;
+        } else {
+            let text = fs::read_to_string(source)?;
+            let set = json::parse(&text).ok_or_else(|| {
+                io::Error::other(format!("bad JSON in {}", source))
+            })?;
+            *bearer.keys.write().unwrap() = Arc::new(parse_keys(&set)?);
+        }
+        Ok(bearer)
+    }
+
+    /// Fetches the keys at `url`, using `tls` if it's HTTPS, for checking
+    /// signatures alone, as `verify` does.
+    pub async fn fetch(
+        url: Endpoint,
+        tls: Option<TlsConnector>,
+    ) -> io::Result<Self> {
+        let bearer = Bearer {
+            keys: RwLock::default(),
+            issuer: None,
+            audience: None,
+            url: Some(url),
+            tls,
+        };
+        bearer.refresh().await?;
+        Ok(bearer)
+    }
+
+    /// Whether the keys came from a URL, and so should be refreshed.
+    pub fn refreshes(&self) -> bool {
+        self.url.is_some()
+    }
+
+    /// Fetches the keys again, returning how many there are now. On failure
+    /// the old keys stay.
+    pub async fn refresh(&self) -> io::Result<usize> {
+        let url = match &self.url {
+            Some(url) => url,
+            None => return Ok(self.keys.read().unwrap().len()),
+        };
+        let set = call(&self.tls, url, "GET", "", "").await?;
+        let keys = parse_keys(&set)?;
+        let count = keys.len();
+        *self.keys.write().unwrap() = Arc::new(keys);
+        Ok(count)
+    }
+
+    /// Checks the token in `headers` at `now`, returning its subject, if it
+    /// names one.
+    pub fn check(
+        &self,
+        log: &slog::Logger,
+        headers: &HeaderMap,
+        now: SystemTime,
+    ) -> Result<Option<String>, Refusal> {
+        let token = headers
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                let (scheme, token) = v.split_once(' ')?;
+                scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+            })
+            .ok_or_else(|| Refusal::unauthorized(false, "no bearer token"))?;
+        let claims = self.verify(token)?;
+
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let invalid = |reason| Refusal::unauthorized(true, reason);
+        let exp = claims
+            .get("exp")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| invalid("token has no exp"))?;
+        if exp.saturating_add(LEEWAY) <= now {
+            return Err(invalid("token expired"));
+        }
+        match claims.get("nbf") {
+            None => (),
+            Some(nbf) => match nbf.as_u64() {
+                Some(nbf) if nbf <= now.saturating_add(LEEWAY) => (),
+                _ => return Err(invalid("token not yet valid")),
+            },
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err(Refusal::forbidden("wrong token issuer"));
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let ours = |v: &Value| v.as_str() == Some(audience);
+            let aud = claims.get("aud");
+            let ok = match aud.and_then(Value::as_array) {
+                Some(list) => list.iter().any(ours),
+                None => aud.is_some_and(ours),
+            };
+            if !ok {
+                return Err(Refusal::forbidden("wrong token audience"));
+            }
+        }
+        let sub = claims.get("sub").and_then(Value::as_str).map(String::from);
+        slog::debug!(log, "bearer"; "sub" => &sub);
+        Ok(sub)
+    }
+
+    /// Checks the signature on `token`, returning its claims, which are left
+    /// for the caller to check.
+    pub fn verify(&self, token: &str) -> Result<Value, Refusal> {
+        let invalid = || Refusal::unauthorized(true, "malformed token");
+        if token.len() > MAX_TOKEN {
+            return Err(invalid());
+        }
+        let mut parts = token.split('.');
+        let (header, payload, sig) =
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(h), Some(p), Some(s), None) => (h, p, s),
+                _ => return Err(invalid()),
+            };
+        let decode = |part: &str| {
+            let bytes = URL_SAFE_NO_PAD.decode(part).ok()?;
+            json::parse(std::str::from_utf8(&bytes).ok()?)
+        };
+        let head = decode(header).ok_or_else(invalid)?;
+        let claims = decode(payload).ok_or_else(invalid)?;
+        let sig = URL_SAFE_NO_PAD.decode(sig).map_err(|_| invalid())?;
+        // We understand no extensions, so any that must be understood mean
+        // the token can't be.
+        if head.get("crit").is_some() {
+            return Err(invalid());
+        }
+        let alg = head
+            .get("alg")
+            .and_then(Value::as_str)
+            .ok_or_else(invalid)?;
+        let kid = head.get("kid").and_then(Value::as_str);
+        let signed = &token[..header.len() + 1 + payload.len()];
+
+        let keys = self.keys.read().unwrap().clone();
+        let verified = keys
+            .iter()
+            .filter(|k| kid.is_none() || k.kid.as_deref() == kid)
+            .filter(|k| k.alg.as_deref().is_none_or(|a| a == alg))
+            .any(|k| k.verify(alg, signed.as_bytes(), &sig));
+        if !verified {
+            return Err(Refusal::unauthorized(true, "bad token signature"));
+        }
+        if !matches!(claims, Value::Object(_)) {
+            return Err(invalid());
+        }
+        Ok(claims)
+    }
+}
+
+impl Key {
+    /// Whether `sig` is this key's signature of `msg` with `alg`.
+    fn verify(&self, alg: &str, msg: &[u8], sig: &[u8]) -> bool {
+        let rsa: &RsaParameters = match (alg, &self.material) {
+            ("RS256", Material::Rsa { .. }) => {
+                &signature::RSA_PKCS1_2048_8192_SHA256
+            }
+            ("RS384", Material::Rsa { .. }) => {
+                &signature::RSA_PKCS1_2048_8192_SHA384
+            }
+            ("RS512", Material::Rsa { .. }) => {
+                &signature::RSA_PKCS1_2048_8192_SHA512
+            }
+            ("PS256", Material::Rsa { .. }) => {
+                &signature::RSA_PSS_2048_8192_SHA256
+            }
+            ("PS384", Material::Rsa { .. }) => {
+                &signature::RSA_PSS_2048_8192_SHA384
+            }
+            ("PS512", Material::Rsa { .. }) => {
+                &signature::RSA_PSS_2048_8192_SHA512
+            }
+            ("ES256", Material::P256(point)) => {
+                return UnparsedPublicKey::new(
+                    &signature::ECDSA_P256_SHA256_FIXED,
+                    point,
+                )
+                .verify(msg, sig)
+                .is_ok();
+            }
+            ("ES384", Material::P384(point)) => {
+                return UnparsedPublicKey::new(
+                    &signature::ECDSA_P384_SHA384_FIXED,
+                    point,
+                )
+                .verify(msg, sig)
+                .is_ok();
+            }
+            ("EdDSA", Material::Ed25519(key)) => {
+                return UnparsedPublicKey::new(&signature::ED25519, key)
+                    .verify(msg, sig)
+                    .is_ok();
+            }
+            _ => return false,
+        };
+        match &self.material {
+            Material::Rsa { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(rsa, msg, sig)
+                .is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// The signing keys we can use from the JWK set `set`. Keys of other kinds
+/// are skipped, but a set with none we can use is an error.
+fn parse_keys(set: &Value) -> io::Result<Vec<Key>> {
+    let bad = |why: &str| io::Error::other(format!("bad JWK set: {}", why));
+    let keys = set
+        .get("keys")
+        .and_then(Value::as_array)
+        .ok_or_else(|| bad("no keys"))?;
+    let keys: Vec<Key> = keys.iter().filter_map(parse_key).collect();
+    if keys.is_empty() {
+        return Err(bad("no usable signing keys"));
+    }
+    Ok(keys)
+}
+
+fn parse_key(jwk: &Value) -> Option<Key> {
+    let field = |name| jwk.get(name).and_then(Value::as_str);
+    let bytes = |name| URL_SAFE_NO_PAD.decode(field(name)?).ok();
+    if field("use").is_some_and(|u| u != "sig") {
+        return None;
+    }
+    let material = match (field("kty")?, field("crv")) {
+        ("RSA", _) => {
+            // Big-endian integers, which ring wants without leading zeros.
+            let strip = |mut v: Vec<u8>| {
+                let zeros = v.iter().take_while(|&&b| b == 0).count();
+                v.drain(..zeros);
+                v
+            };
+            Material::Rsa {
+                n: strip(bytes("n")?),
+                e: strip(bytes("e")?),
+            }
+        }
+        ("EC", Some(crv @ ("P-256" | "P-384"))) => {
+            let (x, y) = (bytes("x")?, bytes("y")?);
+            let len = if crv == "P-256" { 32 } else { 48 };
+            if x.len() != len || y.len() != len {
+                return None;
+            }
+            let mut point = vec![4];
+            point.extend_from_slice(&x);
+            point.extend_from_slice(&y);
+            if crv == "P-256" {
+                Material::P256(point)
+            } else {
+                Material::P384(point)
+            }
+        }
+        ("OKP", Some("Ed25519")) => Material::Ed25519(bytes("x")?),
+        _ => return None,
+    };
+    Some(Key {
+        kid: field("kid").map(String::from),
+        alg: field("alg").map(String::from),
+        material,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn checking() {
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let ed = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+        let ec = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng).unwrap();
+        let b64 = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(bytes);
+        let point = ec.public_key().as_ref();
+        let set = format!(
+            r#"{{"keys": [
+                {{"kty": "OKP", "crv": "Ed25519", "kid": "ed", "x": "{}"}},
+                {{"kty": "EC", "crv": "P-256", "kid": "ec", "alg": "ES256",
+                  "x": "{}", "y": "{}"}},
+                {{"kty": "oct", "k": "c2VjcmV0"}}
+            ]}}"#,
+            b64(ed.public_key().as_ref()),
+            b64(&point[1..33]),
+            b64(&point[33..]),
+        );
+        let bearer = Bearer {
+            keys: RwLock::new(Arc::new(
+                parse_keys(&json::parse(&set).unwrap()).unwrap(),
+            )),
+            issuer: Some("https://id.example.com".to_string()),
+            audience: Some("httpd2".to_string()),
+            url: None,
+            tls: None,
+        };
+        assert_eq!(bearer.keys.read().unwrap().len(), 2);
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let sign = |head: &str, claims: &str| {
+            let signed =
+                format!("{}.{}", b64(head.as_bytes()), b64(claims.as_bytes()));
+            let sig = if head.contains("ES256") {
+                ec.sign(&rng, signed.as_bytes()).unwrap().as_ref().to_vec()
+            } else {
+                ed.sign(signed.as_bytes()).as_ref().to_vec()
+            };
+            format!("{}.{}", signed, b64(&sig))
+        };
+        let claims = |exp: u64, iss: &str, aud: &str| {
+            format!(
+                r#"{{"sub": "alice", "exp": {}, "iss": "{}", "aud": {}}}"#,
+                exp, iss, aud
+            )
+        };
+        let good = claims(
+            1_700_000_100,
+            "https://id.example.com",
+            r#"["x", "httpd2"]"#,
+        );
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let check = |token: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(token) = token {
+                let value = format!("Bearer {}", token);
+                headers.insert(
+                    hyper::header::AUTHORIZATION,
+                    HeaderValue::from_str(&value).unwrap(),
+                );
+            }
+            bearer
+                .check(&log, &headers, now)
+                .map_err(|r| (r.status, r.reason))
+        };
+
+        let alice = Ok(Some("alice".to_string()));
+        let ed_head = r#"{"alg": "EdDSA", "kid": "ed"}"#;
+        let ec_head = r#"{"alg": "ES256"}"#;
+        assert_eq!(check(Some(&sign(ed_head, &good))), alice);
+        assert_eq!(check(Some(&sign(ec_head, &good))), alice);
+
+        let unauthorized = |reason| Err((StatusCode::UNAUTHORIZED, reason));
+        assert_eq!(check(None), unauthorized("no bearer token"));
+        assert_eq!(
+            check(Some(&sign(
+                ed_head,
+                &claims(1_699_999_000, "https://id.example.com", "\"httpd2\"")
+            ))),
+            unauthorized("token expired")
+        );
+        // Within the leeway.
+        assert_eq!(
+            check(Some(&sign(
+                ed_head,
+                &claims(1_699_999_990, "https://id.example.com", "\"httpd2\"")
+            ))),
+            alice
+        );
+        assert_eq!(
+            check(Some(&sign(
+                ed_head,
+                r#"{"sub": "alice", "nbf": 1700000100, "exp": 1700000200}"#
+            ))),
+            unauthorized("token not yet valid")
+        );
+        // As far off as a claim can be.
+        assert_eq!(
+            check(Some(&sign(
+                ed_head,
+                &claims(1 << 53, "https://id.example.com", "\"httpd2\"")
+            ))),
+            alice
+        );
+        // The wrong key, a key that's only for another algorithm, and no
+        // signature at all.
+        let wrong_kid = r#"{"alg": "EdDSA", "kid": "ec"}"#;
+        assert_eq!(
+            check(Some(&sign(wrong_kid, &good))),
+            unauthorized("bad token signature")
+        );
+        let mut tampered = sign(ed_head, &good);
+        tampered.push('A');
+        assert!(check(Some(&tampered)).is_err());
+        let none =
+            format!("{}.{}.", b64(br#"{"alg": "none"}"#), b64(good.as_bytes()));
+        assert_eq!(check(Some(&none)), unauthorized("bad token signature"));
+        assert_eq!(check(Some("a.b")), unauthorized("malformed token"));
+        assert_eq!(
+            check(Some(&sign(r#"{"alg": "EdDSA", "crit": ["x"]}"#, &good))),
+            unauthorized("malformed token")
+        );
+
+        let forbidden = |reason| Err((StatusCode::FORBIDDEN, reason));
+        assert_eq!(
+            check(Some(&sign(
+                ed_head,
+                &claims(
+                    1_700_000_100,
+                    "https://evil.example.com",
+                    "\"httpd2\""
+                )
+            ))),
+            forbidden("wrong token issuer")
+        );
+        assert_eq!(
+            check(Some(&sign(
+                ed_head,
+                &claims(1_700_000_100, "https://id.example.com", "\"other\"")
+            ))),
+            forbidden("wrong token audience")
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! TLS key logging, for decrypting packet captures.
+//!
+//! Secrets are written in the NSS key log format understood by Wireshark and
+//! friends, the same format produced by browsers when `SSLKEYLOGFILE` is set.
+//! The file is opened at startup, before chroot, and held open.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use rustls::KeyLog;
+
+/// Appends TLS secrets to a file.
+#[derive(Debug)]
+pub struct KeyLogFile {
+    file: Mutex<File>,
+}
+
+impl KeyLogFile {
+    /// Opens `path` for appending, creating it if necessary. A new file is
+    /// only readable by its owner.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        Ok(KeyLogFile {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        if let Ok(mut file) = self.file.lock() {
+            // There's nobody to report failure to; a missing line just means
+            // one session can't be decrypted.
+            let _ = file.write_all(format_line(label, client_random, secret).as_bytes());
+        }
+    }
+}
+
+/// Formats one key log line, including the newline.
+fn format_line(label: &str, client_random: &[u8], secret: &[u8]) -> String {
+    let hex = |bytes: &[u8]| {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+    };
+    format!("{} {} {}\n", label, hex(client_random), hex(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_format() {
+        assert_eq!(
+            format_line("CLIENT_RANDOM", &[0x00, 0xab], &[0x12, 0x34, 0xff]),
+            "CLIENT_RANDOM 00ab 1234ff\n"
+        );
+    }
+}
@@ -1,8 +1,59 @@
+pub mod accept;
+pub mod admin;
+pub mod archive;
 pub mod args;
+pub mod blocklist;
+pub mod buffers;
+pub mod bulk;
+pub mod caps;
+pub mod certs;
+pub mod config;
+pub mod daemon;
+pub mod date;
+pub mod digest;
+pub mod encoding;
 pub mod err;
+pub mod etag;
+pub mod fadvise;
+pub mod fetch;
+pub mod fingerprint;
+pub mod glob;
+pub mod groups;
+pub mod headers;
+pub mod handoff;
+pub mod host;
+pub mod image;
+pub mod json;
+pub mod jwt;
+pub mod keylog;
+pub mod listen;
 pub mod log;
+pub mod mount;
+pub mod oidc;
 pub mod percent;
 pub mod picky;
+pub mod precompress;
+pub mod priority;
+pub mod proxy;
+pub mod redirects;
+pub mod robots;
+pub mod sched;
+pub mod selfsigned;
+pub mod sent;
 pub mod serve;
+pub mod signed;
+pub mod signer;
+pub mod sitefile;
+pub mod source;
+pub mod stats;
+pub mod statsd;
+pub mod strict;
 pub mod sync;
+pub mod tickets;
+pub mod trace;
+pub mod traffic;
 pub mod traversal;
+pub mod unix;
+pub mod upload;
+pub mod warm;
+pub mod webhook;
@@ -0,0 +1,139 @@
+//! Creating the listening socket.
+//!
+//! This does what `TcpListener::bind` would, plus the socket options that
+//! have to be set before listening: which address families an IPv6 listener
+//! takes, which network interface it's tied to, and TCP Fast Open.
+
+use std::ffi::OsStr;
+use std::io;
+use std::net::SocketAddr;
+
+use nix::sys::socket::{setsockopt, sockopt};
+use tokio::net::{TcpListener, TcpSocket};
+
+use crate::args::CommonArgs;
+
+/// Queue length for connections that haven't been accepted yet, matching
+/// what `TcpListener::bind` uses.
+const BACKLOG: u32 = 1024;
+
+/// Binds and listens on the address in `args`.
+pub fn bind(args: &CommonArgs) -> io::Result<TcpListener> {
+    let socket = match args.addr {
+        SocketAddr::V4(_) => {
+            if args.ipv6_only || args.dual_stack {
+                return Err(io::Error::other(
+                    "--ipv6-only and --dual-stack need an IPv6 --addr",
+                ));
+            }
+            TcpSocket::new_v4()?
+        }
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            // If neither is given, we get the system's default, which varies.
+            if args.ipv6_only || args.dual_stack {
+                setsockopt(&socket, sockopt::Ipv6V6Only, &args.ipv6_only)?;
+            }
+            socket
+        }
+    };
+    socket.set_reuseaddr(true)?;
+    if let Some(device) = &args.bind_device {
+        bind_device(&socket, device)?;
+    }
+    socket.bind(args.addr)?;
+    if let Some(queue) = args.fast_open {
+        fast_open(&socket, queue)?;
+    }
+    socket.listen(BACKLOG)
+}
+
+/// Restricts `socket` to traffic through the network interface `device`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, device: &OsStr) -> io::Result<()> {
+    setsockopt(socket, sockopt::BindToDevice, &device.to_owned())?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, _device: &OsStr) -> io::Result<()> {
+    Err(io::Error::other(
+        "--bind-device isn't supported on this system",
+    ))
+}
+
+/// Accepts TCP Fast Open on `socket`, allowing up to `queue` connections that
+/// are still completing their handshakes.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn fast_open(socket: &TcpSocket, queue: u32) -> io::Result<()> {
+    use std::convert::TryFrom;
+    use std::os::fd::AsRawFd;
+
+    let queue = libc::c_int::try_from(queue).unwrap_or(libc::c_int::MAX);
+    // Safety: the option value is a c_int, and we pass its size.
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            &queue as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn fast_open(_socket: &TcpSocket, _queue: u32) -> io::Result<()> {
+    Err(io::Error::other(
+        "--fast-open isn't supported on this system",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use nix::sys::socket::getsockopt;
+
+    #[tokio::test]
+    async fn v6_only() {
+        let v6_only = |flag: &str| {
+            // Binding a specific IPv6 address implies IPv6-only on Linux, so
+            // this has to be the wildcard.
+            let argv = ["httpd2", "-A", "[::]:0", flag, "/srv"];
+            let listener = bind(&CommonArgs::parse_from(argv)).unwrap();
+            getsockopt(&listener, sockopt::Ipv6V6Only).unwrap()
+        };
+        assert!(v6_only("--ipv6-only"));
+        assert!(!v6_only("--dual-stack"));
+
+        let argv = ["httpd2", "-A", "127.0.0.1:0", "--ipv6-only", "/srv"];
+        assert!(bind(&CommonArgs::parse_from(argv)).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn fast_open_queue() {
+        use std::os::fd::AsRawFd;
+
+        let argv = ["httpd2", "-A", "127.0.0.1:0", "--fast-open", "16", "/srv"];
+        let listener = bind(&CommonArgs::parse_from(argv)).unwrap();
+        let mut queue: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // Safety: the buffer is a c_int, and we pass its size.
+        let r = unsafe {
+            libc::getsockopt(
+                listener.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN,
+                &mut queue as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!((r, queue), (0, 16));
+    }
+}
@@ -1,4 +1,485 @@
 //! Logging support code.
+//!
+//! Records about requests and their responses are tagged `ACCESS`, so that
+//! with `--access-log` they can go to a file of their own, while everything
+//! else -- startup, connections, errors -- goes to the server log, filtered by
+//! its level. With `--host-access-log`, the request logger is tagged with the
+//! host it's addressed to, and its access records go to that host's file.
+//!
+//! On a terminal, the server log is colored, unless `--log-color` says not to;
+//! redirected, it's plain. Timestamps are written as `--log-timestamps` says. The local time zone is
+//! loaded when the logger is set up, since after chroot it can't be found.
+//!
+//! With `--log-repeat-limit`, warnings and errors in the server log that repeat
+//! an earlier message are counted rather than written once there have been
+//! enough of them, so that a scan making thousands of failed handshakes doesn't
+//! bury everything else. When the window ends, a record says how many there
+//! were; it goes out with the next record after that, since nothing runs in
+//! between.
+
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal, Write};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use slog::{Drain, Never};
+
+use crate::args::{CommonArgs, Log, LogColor, LogFormat, LogTimestamps};
+use crate::date;
+
+/// Tag for records that belong in the access log.
+pub const ACCESS: &str = "access";
+
+/// Key for the host a request is addressed to, where it has a log of its own.
+pub const HOST: &str = "host";
+
+type BoxDrain = Box<
+    dyn Drain<Ok = (), Err = Never> + Send + Sync + UnwindSafe + RefUnwindSafe,
+>;
+
+/// Sets up logging as `args` asks, with server records filtered by `level`.
+pub fn logger(
+    args: &CommonArgs,
+    level: &LevelSwitch,
+) -> io::Result<slog::Logger> {
+    let server = match args.log {
+        Log::Stderr => {
+            let timestamps = timestamps(args, LogTimestamps::Short);
+            let color = match args.log_color {
+                LogColor::Always => true,
+                LogColor::Never => false,
+                LogColor::Auto => {
+                    io::stderr().is_terminal()
+                        && std::env::var_os("NO_COLOR").is_none()
+                }
+            };
+            if color {
+                let decorator =
+                    slog_term::TermDecorator::new().stderr().force_color();
+                formatted(decorator.build(), timestamps)
+            } else {
+                text(io::stderr(), timestamps)
+            }
+        }
+        #[cfg(feature = "journald")]
+        Log::Journald => background(slog_journald::JournaldDrain.ignore_res()),
+    };
+    let server = match args.log_repeat_limit {
+        Some(limit) => {
+            Box::new(Repeats::new(server, limit, args.log_repeat_window))
+        }
+        None => server,
+    };
+    let server = SwitchedLevel::new(server, level.clone());
+    let access = match &args.access_log {
+        Some(path) => Some(access_log(args, path)?),
+        None => None,
+    };
+    let mut hosts = HashMap::new();
+    for log in &args.host_access_logs {
+        hosts.insert(log.host.clone(), access_log(args, &log.path)?);
+    }
+    let split = Split {
+        access,
+        hosts,
+        server,
+    };
+    Ok(slog::Logger::root(split, slog::o!()))
+}
+
+/// Opens the access log at `path`, to be written as `args` asks.
+fn access_log(args: &CommonArgs, path: &Path) -> io::Result<BoxDrain> {
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+    Ok(match args.access_log_format {
+        LogFormat::Text => text(file, timestamps(args, LogTimestamps::Short)),
+        LogFormat::Logfmt => background(Logfmt::new(
+            file,
+            timestamps(args, LogTimestamps::Epoch),
+        )),
+    })
+}
+
+/// How `args` says to write timestamps, in a log whose usual format is
+/// `default`, if they're written at all.
+fn timestamps(args: &CommonArgs, default: LogTimestamps) -> Option<Timestamps> {
+    if args.suppress_log_timestamps {
+        return None;
+    }
+    let format = args.log_timestamps.unwrap_or(default);
+    if format == LogTimestamps::Local {
+        // The first call loads the time zone, while we can still see it.
+        local_offset(0);
+    }
+    Some(Timestamps {
+        format,
+        digits: args.log_timestamp_digits,
+    })
+}
+
+/// How to write the time of a record.
+#[derive(Clone, Copy, Debug)]
+pub struct Timestamps {
+    pub format: LogTimestamps,
+    /// Digits of the seconds' fraction, up to 9.
+    pub digits: u8,
+}
+
+impl Timestamps {
+    /// Writes `time` to `out`.
+    pub fn write(&self, out: &mut String, time: SystemTime) {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep",
+            "Oct", "Nov", "Dec",
+        ];
+        let since = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let secs = since.as_secs();
+        let fraction = if self.digits == 0 {
+            String::new()
+        } else {
+            let digits = u32::from(self.digits.min(9));
+            let value = since.subsec_nanos() / 10u32.pow(9 - digits);
+            format!(".{:0width$}", value, width = digits as usize)
+        };
+        let offset = match self.format {
+            LogTimestamps::Epoch => {
+                let _ = write!(out, "{}{}", secs, fraction);
+                return;
+            }
+            LogTimestamps::Local => local_offset(secs),
+            _ => 0,
+        };
+        let local = secs.saturating_add_signed(offset);
+        let (year, month, day) = date::civil_from_days(local / 86_400);
+        let time = local % 86_400;
+        let (hour, minute, second) = (time / 3600, time / 60 % 60, time % 60);
+        if self.format == LogTimestamps::Short {
+            let _ = write!(
+                out,
+                "{} {:02} {:02}:{:02}:{:02}{}",
+                MONTHS[month as usize - 1],
+                day,
+                hour,
+                minute,
+                second,
+                fraction
+            );
+            return;
+        }
+        let _ = write!(
+            out,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}",
+            year, month, day, hour, minute, second, fraction
+        );
+        if offset == 0 {
+            out.push('Z');
+        } else {
+            let sign = if offset < 0 { '-' } else { '+' };
+            let minutes = offset.unsigned_abs() / 60;
+            let _ =
+                write!(out, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60);
+        }
+    }
+}
+
+/// The local time zone's offset from UTC at `secs` after 1970, in seconds.
+fn local_offset(secs: u64) -> i64 {
+    let time = secs as libc::time_t;
+    // Safety: an all-zero `tm` is valid, if meaningless, and localtime_r
+    // writes only to the one it's given.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let r = unsafe { libc::localtime_r(&time, &mut tm) };
+    if r.is_null() {
+        0
+    } else {
+        tm.tm_gmtoff
+    }
+}
+
+/// Tags `log`, for a request addressed to `host`, so that its access records
+/// go to the host's own log, if it has one.
+pub fn for_host(
+    args: &CommonArgs,
+    log: slog::Logger,
+    host: Option<String>,
+) -> slog::Logger {
+    match host {
+        Some(host) if args.host_access_logs.iter().any(|l| l.host == host) => {
+            log.new(slog::o!(HOST => host))
+        }
+        _ => log,
+    }
+}
+
+/// Formats records as plain text lines on `out`, with `timestamps`, if any.
+fn text<W: Write + Send + 'static>(
+    out: W,
+    timestamps: Option<Timestamps>,
+) -> BoxDrain {
+    // Produce boring plain text.
+    formatted(slog_term::PlainDecorator::new(out), timestamps)
+}
+
+/// Formats records as lines decorated by `decorator`, with `timestamps`, if
+/// any.
+fn formatted<D: slog_term::Decorator + Send + 'static>(
+    decorator: D,
+    timestamps: Option<Timestamps>,
+) -> BoxDrain {
+    // Pack everything onto one line, with the largest scope at left.
+    let fmt = slog_term::FullFormat::new(decorator).use_original_order();
+    let fmt = fmt.use_custom_timestamp(move |io: &mut dyn Write| {
+        let mut time = String::new();
+        if let Some(timestamps) = timestamps {
+            timestamps.write(&mut time, SystemTime::now());
+        }
+        io.write_all(time.as_bytes())
+    });
+    background(fmt.build().fuse())
+}
+
+/// Moves `drain` to its own thread, so that logging doesn't block the server
+/// until a bunch of records have built up.
+fn background<D>(drain: D) -> BoxDrain
+where
+    D: Drain<Ok = (), Err = Never> + Send + 'static,
+{
+    Box::new(slog_async::Async::new(drain).chan_size(1024).build().fuse())
+}
+
+/// Drain that sends records tagged `ACCESS` to the drain for their host, or
+/// to one for all hosts, if there is one, and everything else to another.
+struct Split {
+    access: Option<BoxDrain>,
+    hosts: HashMap<String, BoxDrain>,
+    server: SwitchedLevel<BoxDrain>,
+}
+
+impl Drain for Split {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<(), Never> {
+        if record.tag() == ACCESS && !self.hosts.is_empty() {
+            let mut host = FindHost(None);
+            let _ = slog::KV::serialize(values, record, &mut host);
+            if let Some(drain) = host.0.and_then(|h| self.hosts.get(&h)) {
+                return drain.log(record, values);
+            }
+        }
+        match &self.access {
+            Some(access) if record.tag() == ACCESS => {
+                access.log(record, values)
+            }
+            _ => self.server.log(record, values),
+        }
+    }
+}
+
+/// Messages tracked by `Repeats` at most, so that it takes bounded memory.
+const MAX_REPEATS: usize = 1024;
+
+/// Drain that passes the first `limit` warnings and errors with each message
+/// in a window, and counts the rest.
+struct Repeats<D> {
+    drain: D,
+    limit: u32,
+    window: Duration,
+    seen: Mutex<HashMap<String, Seen>>,
+}
+
+/// A message seen by `Repeats` in the current window.
+struct Seen {
+    since: Instant,
+    passed: u32,
+    suppressed: u64,
+}
+
+impl<D> Repeats<D> {
+    fn new(drain: D, limit: u32, window: Duration) -> Self {
+        Repeats {
+            drain,
+            limit,
+            window,
+            seen: Mutex::default(),
+        }
+    }
+}
+
+impl<D: Drain<Ok = (), Err = Never>> Drain for Repeats<D> {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<(), Never> {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        // Say how many went unwritten in windows that are over.
+        seen.retain(|message, s| {
+            if now.duration_since(s.since) < self.window {
+                return true;
+            }
+            if s.suppressed > 0 {
+                let _ = self.drain.log(
+                    &slog::record!(
+                        slog::Level::Warning,
+                        "",
+                        &format_args!(
+                            "{} occurrences suppressed",
+                            s.suppressed
+                        ),
+                        slog::b!("message" => message.as_str())
+                    ),
+                    &slog::OwnedKVList::from(slog::o!()),
+                );
+            }
+            false
+        });
+        if !record.level().is_at_least(slog::Level::Warning) {
+            drop(seen);
+            return self.drain.log(record, values);
+        }
+        let message = record.msg().to_string();
+        let full = seen.len() >= MAX_REPEATS;
+        match seen.get_mut(&message) {
+            Some(s) if s.passed >= self.limit => {
+                s.suppressed += 1;
+                return Ok(());
+            }
+            Some(s) => s.passed += 1,
+            // Once the table is full, new messages just go through.
+            None if full => (),
+            None => {
+                let s = Seen {
+                    since: now,
+                    passed: 1,
+                    suppressed: 0,
+                };
+                seen.insert(message, s);
+            }
+        }
+        drop(seen);
+        self.drain.log(record, values)
+    }
+}
+
+/// Serializer that picks out the value of `HOST`.
+struct FindHost(Option<String>);
+
+impl slog::Serializer for FindHost {
+    fn emit_arguments(
+        &mut self,
+        key: slog::Key,
+        val: &fmt::Arguments,
+    ) -> slog::Result {
+        if key == HOST {
+            self.0 = Some(val.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Drain that writes records in logfmt: a line of `key=value` pairs per
+/// record, beginning with `ts`, if there are timestamps, `level`, and `msg`.
+pub struct Logfmt<W> {
+    out: Mutex<W>,
+    timestamps: Option<Timestamps>,
+}
+
+impl<W: Write> Logfmt<W> {
+    pub fn new(out: W, timestamps: Option<Timestamps>) -> Self {
+        Logfmt {
+            out: Mutex::new(out),
+            timestamps,
+        }
+    }
+
+    fn format(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> String {
+        let mut line = String::new();
+        if let Some(timestamps) = &self.timestamps {
+            let mut time = String::new();
+            timestamps.write(&mut time, SystemTime::now());
+            push_pair(&mut line, "ts", time);
+        }
+        push_pair(&mut line, "level", record.level().as_short_str());
+        push_pair(&mut line, "msg", record.msg());
+        // Pairs come out newest first, from the record and then from each
+        // logger out to the root, so collect them and turn them around.
+        let mut pairs = Pairs(vec![]);
+        let _ = slog::KV::serialize(&record.kv(), record, &mut pairs);
+        let _ = slog::KV::serialize(values, record, &mut pairs);
+        for (key, value) in pairs.0.iter().rev() {
+            push_pair(&mut line, key, value);
+        }
+        line.push('\n');
+        line
+    }
+}
+
+impl<W: Write> Drain for Logfmt<W> {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<(), Never> {
+        let line = self.format(record, values);
+        // There's nowhere to report a failure to write the log.
+        let _ = self.out.lock().unwrap().write_all(line.as_bytes());
+        Ok(())
+    }
+}
+
+/// Appends `key=value` to `line`, after a space if it's not the first pair,
+/// quoting the value if it needs it.
+fn push_pair(line: &mut String, key: &str, value: impl fmt::Display) {
+    let value = value.to_string();
+    let bare = !value.is_empty()
+        && !value.contains(|c: char| {
+            c == ' ' || c == '=' || c == '"' || c.is_control()
+        });
+    if !line.is_empty() {
+        line.push(' ');
+    }
+    if bare {
+        let _ = write!(line, "{}={}", key, value);
+    } else {
+        let _ = write!(line, "{}={:?}", key, value);
+    }
+}
+
+/// Serializer that collects pairs as strings.
+struct Pairs(Vec<(String, String)>);
+
+impl slog::Serializer for Pairs {
+    fn emit_arguments(
+        &mut self,
+        key: slog::Key,
+        val: &fmt::Arguments,
+    ) -> slog::Result {
+        self.0.push((key.to_string(), val.to_string()));
+        Ok(())
+    }
+}
 
 pub struct OptionKV<T>(Option<T>);
 
@@ -20,3 +501,175 @@ impl<T: slog::KV> slog::KV for OptionKV<T> {
         }
     }
 }
+
+/// A log level that can be changed while the server runs, shared between
+/// whoever changes it and the `SwitchedLevel` drains that obey it.
+#[derive(Clone)]
+pub struct LevelSwitch(Arc<AtomicUsize>);
+
+impl LevelSwitch {
+    pub fn new(level: slog::Level) -> Self {
+        LevelSwitch(Arc::new(AtomicUsize::new(level.as_usize())))
+    }
+
+    pub fn get(&self) -> slog::Level {
+        // Only ever set from a valid level.
+        slog::Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap()
+    }
+
+    pub fn set(&self, level: slog::Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+}
+
+/// Drain that discards records less important than the current level of a
+/// `LevelSwitch`.
+pub struct SwitchedLevel<D> {
+    drain: D,
+    level: LevelSwitch,
+}
+
+impl<D> SwitchedLevel<D> {
+    pub fn new(drain: D, level: LevelSwitch) -> Self {
+        SwitchedLevel { drain, level }
+    }
+}
+
+impl<D: slog::Drain> slog::Drain for SwitchedLevel<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<(), D::Err> {
+        if record.level().is_at_least(self.level.get()) {
+            self.drain.log(record, values)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// A buffer that can be checked after the logger has written to it.
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logfmt() {
+        let buf = Buf::default();
+        let log = slog::Logger::root(
+            Logfmt::new(buf.clone(), None),
+            slog::o!("cid" => 7),
+        );
+        let log = log.new(slog::o!("rid" => 0));
+        slog::info!(log, #ACCESS, "GET"; "uri" => "/a b", "empty" => "");
+        assert_eq!(
+            String::from_utf8(buf.0.lock().unwrap().clone()).unwrap(),
+            "level=INFO msg=GET cid=7 rid=0 uri=\"/a b\" empty=\"\"\n"
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        // 2024-02-29T09:05:03.012345678Z
+        let time =
+            SystemTime::UNIX_EPOCH + Duration::new(1_709_197_503, 12_345_678);
+        let format = |format, digits| {
+            let mut out = String::new();
+            Timestamps { format, digits }.write(&mut out, time);
+            out
+        };
+        assert_eq!(format(LogTimestamps::Short, 3), "Feb 29 09:05:03.012");
+        assert_eq!(
+            format(LogTimestamps::Rfc3339, 6),
+            "2024-02-29T09:05:03.012345Z"
+        );
+        assert_eq!(format(LogTimestamps::Rfc3339, 0), "2024-02-29T09:05:03Z");
+        assert_eq!(format(LogTimestamps::Epoch, 9), "1709197503.012345678");
+        assert_eq!(format(LogTimestamps::Epoch, 0), "1709197503");
+    }
+
+    #[test]
+    fn repeats() {
+        let buf = Buf::default();
+        let window = Duration::from_millis(50);
+        let log = slog::Logger::root(
+            Repeats::new(Logfmt::new(buf.clone(), None), 2, window),
+            slog::o!(),
+        );
+        for _ in 0..5 {
+            slog::warn!(log, "error in TLS handshake: {}", "nonsense");
+            slog::info!(log, "connect");
+        }
+        slog::error!(log, "something else");
+        std::thread::sleep(window);
+        slog::info!(log, "connect");
+        let text = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        let count = |line| lines.iter().filter(|&&l| l == line).count();
+        let warning = "level=WARN msg=\"error in TLS handshake: nonsense\"";
+        assert_eq!(count(warning), 2);
+        assert_eq!(count("level=INFO msg=connect"), 6);
+        assert!(lines.contains(&"level=ERRO msg=\"something else\""));
+        assert_eq!(
+            lines[lines.len() - 2],
+            "level=WARN msg=\"3 occurrences suppressed\" \
+             message=\"error in TLS handshake: nonsense\""
+        );
+        assert_eq!(lines.len(), 10);
+    }
+
+    #[test]
+    fn host_logs() {
+        let (all, example, server) =
+            (Buf::default(), Buf::default(), Buf::default());
+        let drain = |buf: &Buf| -> BoxDrain {
+            Box::new(Logfmt::new(buf.clone(), None))
+        };
+        let split = Split {
+            access: Some(drain(&all)),
+            hosts: [("example.com".to_string(), drain(&example))].into(),
+            server: SwitchedLevel::new(
+                drain(&server),
+                LevelSwitch::new(slog::Level::Info),
+            ),
+        };
+        let log = slog::Logger::root(split, slog::o!());
+        let args = CommonArgs::parse_from([
+            "httpd2",
+            "--host-access-log=example.com=/dev/null",
+            "/srv",
+        ]);
+        for host in ["example.com", "example.org"] {
+            let log = for_host(&args, log.clone(), Some(host.to_string()));
+            slog::info!(log, #ACCESS, "GET");
+            slog::info!(log, "closed");
+        }
+        let text = |buf: Buf| String::from_utf8(buf.0.lock().unwrap().clone());
+        assert_eq!(
+            text(example).unwrap(),
+            "level=INFO msg=GET host=example.com\n"
+        );
+        assert_eq!(text(all).unwrap(), "level=INFO msg=GET\n");
+        assert_eq!(
+            text(server).unwrap(),
+            "level=INFO msg=closed host=example.com\nlevel=INFO msg=closed\n"
+        );
+    }
+}
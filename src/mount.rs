@@ -0,0 +1,208 @@
+//! Additional content directories mounted at URL prefixes.
+//!
+//! Each mount maps a URL prefix to a directory that is opened at startup,
+//! before chroot, and held open as a file descriptor. Paths under the prefix
+//! are then resolved relative to that descriptor rather than the root, which
+//! lets a chrooted server reach content that lives outside its root.
+//!
+//! Prefixes are matched against *sanitized* paths, so the tricks that
+//! sanitization defeats (repeated slashes, dot segments) can't be used to
+//! dodge a mount.
+//!
+//! Users' directories, with `--user-dirs`, are mounts too, one per user found
+//! at startup.
+
+use std::cmp::Reverse;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::archive::Archive;
+use crate::args::{Mount, UserDirs};
+use crate::picky::Dir;
+use crate::source::{ContentSource, Layered};
+use crate::traversal;
+
+/// The root directory, plus a table of open mounts, ordered so that the
+/// longest prefix is tried first.
+pub struct Mounts {
+    root: Box<dyn ContentSource>,
+    table: Vec<(String, Box<dyn ContentSource>)>,
+}
+
+impl Mounts {
+    /// Opens the directory `root`, any `fallbacks` for it, and the directory
+    /// named by each of `mounts`. If `no_symlinks` is set, files reached
+    /// through any of them may not involve symlinks. A `root` that's a file
+    /// is opened as a tar archive instead.
+    ///
+    /// Relative directory names are interpreted relative to the current
+    /// working directory, so this should be called before dropping privileges.
+    pub fn open(
+        root: &Path,
+        fallbacks: &[PathBuf],
+        mounts: &[Mount],
+        no_symlinks: bool,
+    ) -> io::Result<Self> {
+        let mut root: Box<dyn ContentSource> = if root.is_file() {
+            Box::new(Archive::open(root)?)
+        } else {
+            Box::new(Dir::open(root, no_symlinks)?)
+        };
+        if !fallbacks.is_empty() {
+            let mut layers = vec![root];
+            for dir in fallbacks {
+                layers.push(Box::new(Dir::open(dir, no_symlinks)?));
+            }
+            root = Box::new(Layered(layers));
+        }
+        let mut table = mounts
+            .iter()
+            .map(|m| {
+                let dir: Box<dyn ContentSource> =
+                    Box::new(Dir::open(&m.dir, no_symlinks)?);
+                Ok((m.prefix.clone(), dir))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        table.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        Ok(Self { root, table })
+    }
+
+    /// Finds each user with a directory where `dirs` says, and returns a
+    /// mount of it at `/~user`, in order of name.
+    ///
+    /// A user's directory has to be owned by the same user as the entry for
+    /// them in the parent (their home, say), so that a symlink can't publish
+    /// someone else's files.
+    pub fn users(dirs: &UserDirs) -> io::Result<Vec<Mount>> {
+        let mut mounts = vec![];
+        for entry in std::fs::read_dir(&dirs.parent)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') => name,
+                _ => continue,
+            };
+            let dir = entry.path().join(&dirs.rest);
+            let owned = match (entry.path().metadata(), dir.metadata()) {
+                (Ok(home), Ok(meta)) => {
+                    meta.is_dir() && meta.uid() == home.uid()
+                }
+                _ => false,
+            };
+            if owned {
+                mounts.push(Mount {
+                    prefix: traversal::sanitize_prefix(&format!("/~{}", name)),
+                    dir,
+                });
+            }
+        }
+        mounts.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+        Ok(mounts)
+    }
+
+    /// The root directory, which serves every path not under a mount.
+    pub fn root(&self) -> &dyn ContentSource {
+        &*self.root
+    }
+
+    /// Checks whether the sanitized path `path` falls under a mount. If so,
+    /// returns the mount's directory and the sanitized path relative to it.
+    pub fn resolve(&self, path: &str) -> Option<(&dyn ContentSource, String)> {
+        self.table.iter().find_map(|(prefix, dir)| {
+            traversal::strip_prefix(prefix, path).map(|rest| (&**dir, rest))
+        })
+    }
+
+    /// Iterates over the root and each mount, with the sanitized prefix it
+    /// serves; the root's is `.`.
+    pub fn sources(&self) -> impl Iterator<Item = (&str, &dyn ContentSource)> {
+        std::iter::once((".", &*self.root)).chain(
+            self.table
+                .iter()
+                .map(|(prefix, dir)| (prefix.as_str(), &**dir)),
+        )
+    }
+
+    /// Adds `source` under the root and its fallbacks, to serve what none of
+    /// them has.
+    pub fn fall_back_to(self, source: Box<dyn ContentSource>) -> Self {
+        Self {
+            root: Box::new(Layered(vec![self.root, source])),
+            ..self
+        }
+    }
+
+    /// Iterates over the sanitized prefixes of each mount, for logging.
+    pub fn prefixes(&self) -> impl Iterator<Item = &str> {
+        self.table.iter().map(|(prefix, _)| prefix.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn confined_to_mounts() {
+        use std::os::unix::fs::symlink;
+
+        let base = std::env::temp_dir()
+            .join(format!("httpd2-mount-test-{}", std::process::id()));
+        for dir in ["root", "assets", "deep"] {
+            std::fs::create_dir_all(base.join(dir)).unwrap();
+        }
+        std::fs::write(base.join("secret.txt"), "").unwrap();
+        std::fs::write(base.join("root/index.txt"), "").unwrap();
+        std::fs::write(base.join("assets/a.txt"), "").unwrap();
+        std::fs::write(base.join("deep/d.txt"), "").unwrap();
+        symlink("../secret.txt", base.join("assets/out")).unwrap();
+        symlink(base.join("secret.txt"), base.join("assets/absolute")).unwrap();
+
+        let mount = |prefix: &str, dir: &str| Mount {
+            prefix: traversal::sanitize_prefix(prefix),
+            dir: base.join(dir),
+        };
+        let mounts = Mounts::open(
+            &base.join("root"),
+            &[],
+            &[mount("/assets", "assets"), mount("/assets/deep", "deep")],
+            false,
+        )
+        .unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let found = |path: &str| {
+            let path = traversal::sanitize(path);
+            let (source, path) = match mounts.resolve(&path) {
+                Some(mounted) => mounted,
+                None => (mounts.root(), path),
+            };
+            let path = PathBuf::from(path);
+            let log = &log;
+            async move {
+                source
+                    .open(log, &path, &|_| "text/plain", &|_| None)
+                    .await
+                    .is_ok()
+            }
+        };
+
+        assert!(found("/index.txt").await);
+        assert!(found("/assets/a.txt").await);
+        assert!(found("/assets//a.txt").await);
+        // The longest prefix wins.
+        assert!(found("/assets/deep/d.txt").await);
+        assert!(!found("/assets/d.txt").await);
+
+        // Nothing under a mount reaches outside its directory.
+        assert!(!found("/assets/../secret.txt").await);
+        assert!(!found("/assets/deep/../a.txt").await);
+        assert!(!found("/assets/out").await);
+        assert!(!found("/assets/absolute").await);
+        // Nor does a name that only starts with the prefix.
+        assert!(!found("/assetsa.txt").await);
+        assert!(!found("/assets/../root/index.txt").await);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
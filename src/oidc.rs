@@ -0,0 +1,756 @@
+//! Logging in with OpenID Connect.
+//!
+//! Paths matching `--oidc-protect` are only served to browsers that have
+//! logged in with the identity provider at `--oidc-issuer`, using the
+//! authorization code flow:
+//!
+//! 1. A request without a session is redirected to the provider, with a
+//!    short-lived cookie remembering where it was going, and the `state` and
+//!    `nonce` that tie the login to this browser.
+//! 2. The provider sends the browser back to `--oidc-redirect-uri`, whose path
+//!    we answer, with a code. We trade the code for an ID token at the
+//!    provider's token endpoint, check that the token is for us, and set a
+//!    session cookie naming the user.
+//! 3. Requests carrying a current session cookie are served as usual.
+//!
+//! The ID token's signature is checked against the provider's `jwks_uri`, as
+//! a bearer token's is, so that it counts for nothing where it came from; its
+//! issuer, audience, expiry and nonce are checked too. If no key fits, the keys
+//! are fetched again, in case the provider has rotated them. Cookies are signed
+//! with a key derived from the client secret, so servers sharing a client
+//! accept each other's sessions, and sessions survive a restart. After a login,
+//! the browser is only sent back to a path on this site.
+//!
+//! As with webhooks, the provider's configuration and keys are fetched, its
+//! hosts looked up, and the CA certificates loaded at startup, since none of
+//! that can be done after chroot.
+
+use std::fs;
+use std::io;
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::{Method, Request, StatusCode, Uri};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio_rustls::TlsConnector;
+
+use crate::args::CommonArgs;
+use crate::fetch::{call, Endpoint};
+use crate::json::Value;
+use crate::jwt::Bearer;
+
+/// The cookie holding a logged-in session.
+const SESSION_COOKIE: &str = "__Host-httpd2-session";
+/// The cookie holding a login in progress.
+const LOGIN_COOKIE: &str = "__Host-httpd2-login";
+/// How long a browser has to log in with the provider and come back.
+const LOGIN_TIME: u64 = 600;
+
+/// What to do with a request, as far as logging in goes.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Gate {
+    /// Serve it as usual.
+    Pass,
+    /// Send the browser to this location, setting these cookies.
+    Redirect(HeaderValue, Vec<HeaderValue>),
+    /// Refuse it with this status, for this reason.
+    Refuse(StatusCode, &'static str),
+}
+
+/// An identity provider, and our registration with it.
+pub struct Oidc {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+    /// The path of `redirect_uri`, which we answer.
+    callback: String,
+    authorization_endpoint: String,
+    token_endpoint: Endpoint,
+    /// The provider's signing keys, for ID tokens.
+    keys: Bearer,
+    session_lifetime: u64,
+    /// Signs cookies.
+    key: hmac::Key,
+    /// Only there if something is reached over HTTPS.
+    tls: Option<TlsConnector>,
+    rng: SystemRandom,
+}
+
+impl Oidc {
+    /// Reads the client secret and fetches the provider's configuration, as
+    /// `args` say.
+    pub async fn discover(args: &CommonArgs) -> io::Result<Self> {
+        let missing = |flag| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--oidc-issuer needs {}", flag),
+            )
+        };
+        let issuer = args
+            .oidc_issuer
+            .clone()
+            .ok_or_else(|| io::Error::other("no --oidc-issuer"))?;
+        let secret_path = args
+            .oidc_client_secret
+            .as_deref()
+            .ok_or_else(|| missing("--oidc-client-secret"))?;
+        let client_secret = fs::read_to_string(secret_path)?;
+        let client_secret = client_secret.trim_end_matches('\n').to_string();
+        if client_secret.is_empty() {
+            return Err(io::Error::other("OIDC client secret file is empty"));
+        }
+        let redirect_uri = args
+            .oidc_redirect_uri
+            .clone()
+            .ok_or_else(|| missing("--oidc-redirect-uri"))?;
+        let callback = redirect_uri
+            .parse::<Uri>()
+            .map_err(|_| missing("a valid --oidc-redirect-uri"))?
+            .path()
+            .to_string();
+        let connector = || crate::webhook::connector(&args.oidc_ca);
+        let mut tls = None;
+        if issuer.starts_with("https:") {
+            tls = Some(connector()?);
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let discovery = Endpoint::lookup(&url).await?;
+        let config = call(&tls, &discovery, "GET", "", "").await?;
+        let field = |name| {
+            config.get(name).and_then(Value::as_str).ok_or_else(|| {
+                io::Error::other(format!("OIDC configuration has no {}", name))
+            })
+        };
+        if field("issuer")? != issuer {
+            return Err(io::Error::other(format!(
+                "OIDC configuration is for issuer {}",
+                field("issuer")?
+            )));
+        }
+        let authorization_endpoint =
+            field("authorization_endpoint")?.to_string();
+        let token_endpoint = Endpoint::lookup(field("token_endpoint")?).await?;
+        let jwks_uri = Endpoint::lookup(field("jwks_uri")?).await?;
+        if (token_endpoint.is_https() || jwks_uri.is_https()) && tls.is_none() {
+            tls = Some(connector()?);
+        }
+        let keys = Bearer::fetch(jwks_uri, tls.clone()).await?;
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, client_secret.as_bytes());
+        let key = hmac::sign(&key, b"httpd2 oidc cookies");
+        Ok(Oidc {
+            issuer,
+            client_id: args
+                .oidc_client_id
+                .clone()
+                .ok_or_else(|| missing("--oidc-client-id"))?,
+            client_secret,
+            redirect_uri,
+            callback,
+            authorization_endpoint,
+            token_endpoint,
+            keys,
+            session_lifetime: args.oidc_session_lifetime,
+            key: hmac::Key::new(hmac::HMAC_SHA256, key.as_ref()),
+            tls,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Decides what to do with `req`, whose path is `protected` or not, at
+    /// `now`. Requests to the callback path are handled here, whether or not
+    /// it's protected.
+    pub async fn gate(
+        &self,
+        log: &slog::Logger,
+        req: &Request<()>,
+        protected: bool,
+        now: SystemTime,
+    ) -> Gate {
+        let now = seconds(now);
+        let read = matches!(*req.method(), Method::GET | Method::HEAD);
+        if req.uri().path() == self.callback && read {
+            return self.finish_login(log, req, now).await;
+        }
+        if !protected {
+            return Gate::Pass;
+        }
+        if let Some(user) = self.session(req.headers(), now) {
+            slog::debug!(log, "logged in"; "sub" => user);
+            return Gate::Pass;
+        }
+        if !read {
+            return Gate::Refuse(StatusCode::UNAUTHORIZED, "not logged in");
+        }
+        self.start_login(req, now)
+    }
+
+    /// The user a current session cookie in `headers` names, if there is one.
+    fn session(&self, headers: &HeaderMap, now: u64) -> Option<String> {
+        let session = self.open(cookie(headers, SESSION_COOKIE)?)?;
+        let (expires, user) = session.split_once('.')?;
+        if expires.parse::<u64>().ok()? <= now {
+            return None;
+        }
+        String::from_utf8(URL_SAFE_NO_PAD.decode(user).ok()?).ok()
+    }
+
+    /// Sends the browser off to the provider to log in, to come back to
+    /// where it was going.
+    fn start_login(&self, req: &Request<()>, now: u64) -> Gate {
+        let (state, nonce) = match (self.random(), self.random()) {
+            (Some(state), Some(nonce)) => (state, nonce),
+            _ => {
+                return Gate::Refuse(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "no randomness",
+                )
+            }
+        };
+        let back = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        let back = on_site(back);
+        let login = self.seal(&format!(
+            "{}.{}.{}.{}",
+            now + LOGIN_TIME,
+            state,
+            nonce,
+            URL_SAFE_NO_PAD.encode(back)
+        ));
+        let location = format!(
+            "{}{}response_type=code&scope=openid&client_id={}\
+             &redirect_uri={}&state={}&nonce={}",
+            self.authorization_endpoint,
+            if self.authorization_endpoint.contains('?') {
+                '&'
+            } else {
+                '?'
+            },
+            form_encode(&self.client_id),
+            form_encode(&self.redirect_uri),
+            state,
+            nonce,
+        );
+        match HeaderValue::from_str(&location) {
+            Ok(location) => Gate::Redirect(
+                location,
+                vec![set_cookie(LOGIN_COOKIE, &login, LOGIN_TIME)],
+            ),
+            Err(_) => Gate::Refuse(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "bad authorization endpoint",
+            ),
+        }
+    }
+
+    /// Handles the browser's return from the provider.
+    async fn finish_login(
+        &self,
+        log: &slog::Logger,
+        req: &Request<()>,
+        now: u64,
+    ) -> Gate {
+        let query = req.uri().query().unwrap_or("");
+        let param = |name: &str| {
+            query.split('&').find_map(|pair| {
+                let value = pair.strip_prefix(name)?.strip_prefix('=')?;
+                let mut decoded = String::new();
+                crate::percent::decode(&value.replace('+', " "), |c| {
+                    decoded.push(c)
+                });
+                Some(decoded)
+            })
+        };
+        if let Some(error) = param("error") {
+            slog::info!(log, "login refused"; "error" => error);
+            return Gate::Refuse(StatusCode::FORBIDDEN, "login refused");
+        }
+        let login =
+            cookie(req.headers(), LOGIN_COOKIE).and_then(|c| self.open(c));
+        let login = login.as_deref().and_then(|login| {
+            let mut fields = login.split('.');
+            let expires = fields.next()?.parse::<u64>().ok()?;
+            let state = fields.next()?;
+            let nonce = fields.next()?;
+            let back = URL_SAFE_NO_PAD.decode(fields.next()?).ok()?;
+            Some((expires, state, nonce, String::from_utf8(back).ok()?))
+        });
+        let (expires, state, nonce, back) = match login {
+            Some(login) => login,
+            None => {
+                return Gate::Refuse(
+                    StatusCode::BAD_REQUEST,
+                    "no login started",
+                )
+            }
+        };
+        if expires <= now {
+            return Gate::Refuse(
+                StatusCode::BAD_REQUEST,
+                "login took too long",
+            );
+        }
+        let code = match param("code") {
+            Some(code) if param("state").as_deref() == Some(state) => code,
+            _ => {
+                return Gate::Refuse(StatusCode::BAD_REQUEST, "bad login state")
+            }
+        };
+
+        let claims = match self.redeem(&code).await {
+            Ok(claims) => claims,
+            Err(e) => {
+                slog::warn!(log, "can't redeem login code: {}", e);
+                return Gate::Refuse(StatusCode::BAD_GATEWAY, "token exchange");
+            }
+        };
+        let user = match self.check_claims(&claims, nonce, now) {
+            Ok(user) => user,
+            Err(why) => {
+                slog::warn!(log, "rejected ID token"; "cause" => why);
+                return Gate::Refuse(StatusCode::FORBIDDEN, "bad ID token");
+            }
+        };
+        slog::info!(log, "login"; "sub" => user);
+
+        let session = self.seal(&format!(
+            "{}.{}",
+            now + self.session_lifetime,
+            URL_SAFE_NO_PAD.encode(user)
+        ));
+        match HeaderValue::from_str(on_site(&back)) {
+            Ok(back) => Gate::Redirect(
+                back,
+                vec![
+                    set_cookie(SESSION_COOKIE, &session, self.session_lifetime),
+                    set_cookie(LOGIN_COOKIE, "", 0),
+                ],
+            ),
+            Err(_) => Gate::Refuse(StatusCode::BAD_REQUEST, "bad return path"),
+        }
+    }
+
+    /// Trades an authorization code for the claims of an ID token, once its
+    /// signature is checked.
+    async fn redeem(&self, code: &str) -> io::Result<Value> {
+        let body = format!(
+            "grant_type=authorization_code&code={}&redirect_uri={}",
+            form_encode(code),
+            form_encode(&self.redirect_uri)
+        );
+        let credentials = format!(
+            "{}:{}",
+            form_encode(&self.client_id),
+            form_encode(&self.client_secret)
+        );
+        let headers = format!(
+            "Authorization: Basic {}\r\n\
+             Content-Type: application/x-www-form-urlencoded\r\n",
+            STANDARD.encode(credentials)
+        );
+        let response =
+            call(&self.tls, &self.token_endpoint, "POST", &headers, &body)
+                .await?;
+        let bad = || io::Error::other("bad token response");
+        let id_token = response
+            .get("id_token")
+            .and_then(Value::as_str)
+            .ok_or_else(bad)?;
+        if let Ok(claims) = self.keys.verify(id_token) {
+            return Ok(claims);
+        }
+        self.keys.refresh().await?;
+        self.keys
+            .verify(id_token)
+            .map_err(|refusal| io::Error::other(refusal.reason))
+    }
+
+    /// Checks that `claims` are from our provider, for us, current at `now`,
+    /// and from the login that used `nonce`, returning the user they name.
+    fn check_claims<'a>(
+        &self,
+        claims: &'a Value,
+        nonce: &str,
+        now: u64,
+    ) -> Result<&'a str, &'static str> {
+        let string = |name| claims.get(name).and_then(Value::as_str);
+        if string("iss") != Some(&self.issuer) {
+            return Err("issuer");
+        }
+        let audience = match claims.get("aud") {
+            Some(Value::String(aud)) => aud == &self.client_id,
+            Some(Value::Array(auds)) => {
+                auds.iter().any(|a| a.as_str() == Some(&self.client_id))
+                    // With other audiences, we must be the one it's for.
+                    && (auds.len() == 1 || string("azp") == Some(&self.client_id))
+            }
+            _ => false,
+        };
+        if !audience {
+            return Err("audience");
+        }
+        match claims.get("exp").and_then(Value::as_u64) {
+            Some(exp) if exp > now => (),
+            _ => return Err("expired"),
+        }
+        if string("nonce") != Some(nonce) {
+            return Err("nonce");
+        }
+        string("sub").ok_or("no subject")
+    }
+
+    /// 16 random bytes, encoded for a URL.
+    fn random(&self) -> Option<String> {
+        let mut bytes = [0; 16];
+        self.rng.fill(&mut bytes).ok()?;
+        Some(URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    /// Appends a signature to `value`.
+    fn seal(&self, value: &str) -> String {
+        let tag = hmac::sign(&self.key, value.as_bytes());
+        format!("{}.{}", value, URL_SAFE_NO_PAD.encode(tag))
+    }
+
+    /// Checks and removes the signature from `sealed`.
+    fn open(&self, sealed: &str) -> Option<String> {
+        let (value, tag) = sealed.rsplit_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        hmac::verify(&self.key, value.as_bytes(), &tag).ok()?;
+        Some(value.to_string())
+    }
+}
+
+/// Finds the cookie `name` among `headers`.
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (n, value) = pair.trim().split_once('=')?;
+            Some(value).filter(|_| n == name)
+        })
+}
+
+/// A `Set-Cookie` value for a cookie only this site, over HTTPS, and not
+/// scripts, can see.
+fn set_cookie(name: &str, value: &str, max_age: u64) -> HeaderValue {
+    // Names and values are base64 and punctuation, valid in a header.
+    HeaderValue::from_str(&format!(
+        "{}={}; Max-Age={}; Path=/; Secure; HttpOnly; SameSite=Lax",
+        name, value, max_age
+    ))
+    .unwrap()
+}
+
+/// `back`, if it's a path on this site, or else the root. Browsers take a path
+/// starting `//`, or `/\`, as the start of a URL on another host.
+fn on_site(back: &str) -> &str {
+    match back.strip_prefix('/') {
+        Some(rest) if !rest.starts_with(['/', '\\']) => back,
+        _ => "/",
+    }
+}
+
+/// Encodes `s` for a query string or form body.
+fn form_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => out.push(char::from(b)),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Reads a request, head and body, from `stream`.
+    async fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = vec![];
+        let mut buf = [0; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("Content-Length: "))
+                    .map_or(0, |l| l.parse().unwrap());
+                if body.len() >= len {
+                    return text;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn login() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let nonce = Arc::new(Mutex::new(String::new()));
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new());
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.unwrap().as_ref()).unwrap();
+        // Whether the provider signs with some other key.
+        let forged = Arc::new(AtomicBool::new(false));
+        let provider = {
+            let (issuer, nonce) = (issuer.clone(), nonce.clone());
+            let forged = forged.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let request = read_request(&mut stream).await;
+                    let body = if request.starts_with("GET /.well-known/") {
+                        format!(
+                            r#"{{"issuer":"{0}","authorization_endpoint":"{0}/auth","token_endpoint":"{0}/token","jwks_uri":"{0}/jwks"}}"#,
+                            issuer
+                        )
+                    } else if request.starts_with("GET /jwks ") {
+                        format!(
+                            r#"{{"keys":[{{"kty":"OKP","crv":"Ed25519","x":"{}"}}]}}"#,
+                            URL_SAFE_NO_PAD.encode(key.public_key())
+                        )
+                    } else {
+                        assert!(request.starts_with("POST /token "));
+                        assert!(request.contains(
+                            "\r\n\r\ngrant_type=authorization_code&code=c%2B1&"
+                        ));
+                        let claims = format!(
+                            r#"{{"iss":"{}","aud":["app"],"sub":"alice","exp":4000000000,"nonce":"{}"}}"#,
+                            issuer,
+                            nonce.lock().unwrap()
+                        );
+                        let signed = format!(
+                            "{}.{}",
+                            URL_SAFE_NO_PAD.encode(r#"{"alg":"EdDSA"}"#),
+                            URL_SAFE_NO_PAD.encode(claims)
+                        );
+                        let mut sig =
+                            key.sign(signed.as_bytes()).as_ref().to_vec();
+                        if forged.load(Ordering::Relaxed) {
+                            sig[0] ^= 1;
+                        }
+                        let token = format!(
+                            "{}.{}",
+                            signed,
+                            URL_SAFE_NO_PAD.encode(sig)
+                        );
+                        format!(r#"{{"id_token":"{}"}}"#, token)
+                    };
+                    // Chunked, in two pieces.
+                    let (a, b) = body.split_at(10);
+                    let response =
+                        format!(
+                        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                         {:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                        a.len(), a, b.len(), b
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            })
+        };
+
+        let secret = std::env::temp_dir()
+            .join(format!("httpd2-oidc-test-{}", std::process::id()));
+        fs::write(&secret, "hunter2\n").unwrap();
+        let args = CommonArgs::parse_from([
+            "httpd2",
+            "--oidc-protect=/private/*",
+            &format!("--oidc-issuer={}", issuer),
+            "--oidc-client-id=app",
+            &format!("--oidc-client-secret={}", secret.display()),
+            "--oidc-redirect-uri=https://example.com/callback",
+            "/srv",
+        ]);
+        let oidc = Oidc::discover(&args).await.unwrap();
+        fs::remove_file(&secret).unwrap();
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let now = SystemTime::now();
+        let get = |uri: &str, cookie: Option<&HeaderValue>| {
+            let mut req = Request::get(uri);
+            if let Some(cookie) = cookie {
+                // The name and value, without the attributes.
+                let cookie =
+                    cookie.to_str().unwrap().split(';').next().unwrap();
+                req = req.header(hyper::header::COOKIE, cookie);
+            }
+            req.body(()).unwrap()
+        };
+        let redirect = |gate| match gate {
+            Gate::Redirect(location, cookies) => {
+                (location.to_str().unwrap().to_string(), cookies)
+            }
+            other => panic!("{:?}", other),
+        };
+
+        // Without a session, off to the provider.
+        let (location, cookies) = redirect(
+            oidc.gate(&log, &get("/private/a?b", None), true, now).await,
+        );
+        assert!(location.starts_with(&format!("{}/auth?", issuer)));
+        let param = |location: &str, name: &str| {
+            location
+                .split_once('?')
+                .unwrap()
+                .1
+                .split('&')
+                .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(param(&location, "client_id"), "app");
+        assert_eq!(
+            param(&location, "redirect_uri"),
+            "https%3A%2F%2Fexample.com%2Fcallback"
+        );
+        *nonce.lock().unwrap() = param(&location, "nonce");
+        let login = &cookies[0];
+        // `cookie`, with its dot-separated field `i` changed to `value`.
+        let tamper = |cookie: &HeaderValue, i: usize, value: &str| {
+            let cookie = cookie.to_str().unwrap().split(';').next().unwrap();
+            let mut fields: Vec<_> = cookie.split('.').collect();
+            fields[i] = value;
+            HeaderValue::from_str(&fields.join(".")).unwrap()
+        };
+
+        // A callback that doesn't match the login is refused.
+        let state = param(&location, "state");
+        let callback = format!("/callback?code=c%2B1&state={}", state);
+        assert_eq!(
+            oidc.gate(
+                &log,
+                &get("/callback?code=c&state=x", Some(login)),
+                false,
+                now
+            )
+            .await,
+            Gate::Refuse(StatusCode::BAD_REQUEST, "bad login state")
+        );
+        assert_eq!(
+            oidc.gate(&log, &get(&callback, None), false, now).await,
+            Gate::Refuse(StatusCode::BAD_REQUEST, "no login started")
+        );
+        // As is one whose login cookie has been changed to match it.
+        let swapped = tamper(login, 1, "x");
+        assert_eq!(
+            oidc.gate(
+                &log,
+                &get("/callback?code=c&state=x", Some(&swapped)),
+                false,
+                now
+            )
+            .await,
+            Gate::Refuse(StatusCode::BAD_REQUEST, "no login started")
+        );
+
+        // So is a token that isn't signed by the provider's key.
+        forged.store(true, Ordering::Relaxed);
+        assert_eq!(
+            oidc.gate(&log, &get(&callback, Some(login)), false, now)
+                .await,
+            Gate::Refuse(StatusCode::BAD_GATEWAY, "token exchange")
+        );
+        forged.store(false, Ordering::Relaxed);
+        // Or that's for some other login.
+        *nonce.lock().unwrap() = "other".to_string();
+        assert_eq!(
+            oidc.gate(&log, &get(&callback, Some(login)), false, now)
+                .await,
+            Gate::Refuse(StatusCode::FORBIDDEN, "bad ID token")
+        );
+        *nonce.lock().unwrap() = param(&location, "nonce");
+
+        // The right one logs in, and goes back where it was going.
+        let (location, cookies) = redirect(
+            oidc.gate(&log, &get(&callback, Some(login)), false, now)
+                .await,
+        );
+        assert_eq!(location, "/private/a?b");
+        let session = &cookies[0];
+        assert!(session.to_str().unwrap().starts_with(SESSION_COOKIE));
+        assert_eq!(
+            oidc.gate(&log, &get("/private/a", Some(session)), true, now)
+                .await,
+            Gate::Pass
+        );
+        // Until it runs out.
+        let later = now + Duration::from_secs(args.oidc_session_lifetime);
+        assert_ne!(
+            oidc.gate(&log, &get("/private/a", Some(session)), true, later)
+                .await,
+            Gate::Pass
+        );
+        // A session can't be changed to another user, or to last longer.
+        let bob = URL_SAFE_NO_PAD.encode("bob");
+        for session in [
+            tamper(session, 1, &bob),
+            tamper(session, 0, &format!("{}=9999999999", SESSION_COOKIE)),
+        ] {
+            assert_ne!(
+                oidc.gate(&log, &get("/private/a", Some(&session)), true, now)
+                    .await,
+                Gate::Pass
+            );
+        }
+        // Other methods aren't redirected.
+        let post = Request::post("/private/a").body(()).unwrap();
+        assert_eq!(
+            oidc.gate(&log, &post, true, now).await,
+            Gate::Refuse(StatusCode::UNAUTHORIZED, "not logged in")
+        );
+
+        // A login started from a path that's a URL on another host comes
+        // back to the root instead.
+        let (location, cookies) = redirect(
+            oidc.gate(&log, &get("//evil.example/x", None), true, now)
+                .await,
+        );
+        *nonce.lock().unwrap() = param(&location, "nonce");
+        let callback =
+            format!("/callback?code=c%2B1&state={}", param(&location, "state"));
+        let (back, _) = redirect(
+            oidc.gate(&log, &get(&callback, Some(&cookies[0])), false, now)
+                .await,
+        );
+        assert_eq!(back, "/");
+        provider.abort();
+
+        // Only ever back to this site.
+        assert_eq!(on_site("/a//b?c"), "/a//b?c");
+        assert_eq!(on_site("//evil.example/"), "/");
+        assert_eq!(on_site("/\\evil.example/"), "/");
+        assert_eq!(on_site("https://evil.example/"), "/");
+    }
+}
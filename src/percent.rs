@@ -1,89 +1,59 @@
-//! URL percent-encoding decoder.
+//! URL percent-encoding decoder, and the encoder that undoes it.
 //!
 //! This decoder interprets the standard somewhat loosely. Correctly encoded
 //! paths are decoded just fine; errors, on the other hand, are literally passed
 //! into the output. Since percent signs are not significant in paths, this is
 //! safe.
 //!
-//! The decoder is expressed as an `Iterator`. Create one using
-//! `decode`.
+//! Each escape decodes to the character with that byte's code point. The
+//! decoder hands characters to a callback as it goes, rather than building a
+//! string, so its output can be fed to the next step directly.
 
-pub fn decode(inner: impl Iterator<Item = char>) -> impl Iterator<Item = char> {
-    PercentDecoder::from(inner)
-}
-
-struct PercentDecoder<I> {
-    inner: I,
-    state: PercentState,
-}
+/// Decodes `s`, passing each resulting character to `emit`.
+pub fn decode(s: &str, mut emit: impl FnMut(char)) {
+    fn hexit(c: char) -> Option<u8> {
+        c.to_digit(16).map(|d| d as u8)
+    }
 
-impl<I> From<I> for PercentDecoder<I> {
-    fn from(inner: I) -> Self {
-        Self {
-            inner,
-            state: PercentState::Normal,
+    let mut rest = s;
+    while let Some(i) = rest.find('%') {
+        rest[..i].chars().for_each(&mut emit);
+        rest = &rest[i + 1..];
+        let mut escape = rest.chars();
+        let (x, y) = (escape.next(), escape.next());
+        if let (Some(x), Some(y)) = (x.and_then(hexit), y.and_then(hexit)) {
+            emit(char::from(x << 4 | y));
+        } else {
+            // An invalid escape, including whatever of its two characters
+            // there are, goes through as is.
+            emit('%');
+            x.into_iter().chain(y).for_each(&mut emit);
         }
+        rest = escape.as_str();
     }
+    rest.chars().for_each(emit);
 }
 
-enum PercentState {
-    /// Haven't seen a percent escape recently.
-    Normal,
-    /// A percent escape was found to be invalid on its final character. We have
-    /// yielded the original '%' and need to yield these additional characters
-    /// in sequence before touching `inner`.
-    Unspool2(char, char),
-    /// A percent escape was found to be invalid. We have yielded some portion
-    /// of it literally, and still need to yield this char before touching
-    /// `inner`.
-    Unspool(char),
-}
-
-impl<I: Iterator<Item = char>> Iterator for PercentDecoder<I> {
-    type Item = char;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        fn hexit(c: char) -> Option<u8> {
-            match c {
-                '0'..='9' => Some(c as u8 - b'0'),
-                'A'..='F' => Some(c as u8 - b'A' + 10),
-                'a'..='f' => Some(c as u8 - b'a' + 10),
-                _ => None,
-            }
-        }
-
-        match self.state {
-            PercentState::Normal => match self.inner.next()? {
-                '%' => {
-                    if let Some(x) = self.inner.next() {
-                        if let Some(y) = self.inner.next() {
-                            if let (Some(x), Some(y)) = (hexit(x), hexit(y)) {
-                                return Some((x << 4 | y) as char);
-                            }
-                            self.state = PercentState::Unspool2(x, y);
-                        } else {
-                            self.state = PercentState::Unspool(x);
-                        }
-                    }
-                    Some('%')
+/// Encodes `s`, as `decode` left it, for use in a URL path. Characters a path
+/// may hold as they are, `/` among them, stay; the rest are escaped, those up
+/// to `\u{ff}` as the byte `decode` took them from, and any past it as UTF-8.
+pub fn encode_path(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut buf = [0; 4];
+    for c in s.chars() {
+        match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' => out.push(c),
+            '-' | '.' | '_' | '~' | '!' | '$' | '&' | '\'' | '(' | ')'
+            | '*' | '+' | ',' | ';' | '=' | ':' | '@' | '/' => out.push(c),
+            '\0'..='\u{ff}' => out.push_str(&format!("%{:02X}", c as u32)),
+            c => {
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    out.push_str(&format!("%{:02X}", b));
                 }
-                c => Some(c),
-            },
-            PercentState::Unspool2(x, y) => {
-                self.state = PercentState::Unspool(y);
-                Some(x)
-            }
-            PercentState::Unspool(y) => {
-                self.state = PercentState::Normal;
-                Some(y)
             }
         }
     }
-
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        let (min, max) = self.inner.size_hint();
-        (min / 3, max)
-    }
+    out
 }
 
 #[cfg(test)]
@@ -91,7 +61,9 @@ mod tests {
     use super::*;
 
     fn decode_str(s: &str) -> String {
-        decode(s.chars()).collect()
+        let mut out = String::new();
+        decode(s, |c| out.push(c));
+        out
     }
 
     #[test]
@@ -105,5 +77,16 @@ mod tests {
         assert_eq!(decode_str("%4A"), "J");
         assert_eq!(decode_str("%4g"), "%4g");
         assert_eq!(decode_str("%2525"), "%25");
+        assert_eq!(decode_str("%%41"), "%%41");
+        assert_eq!(decode_str("a%e9b"), "a\u{e9}b");
+    }
+
+    #[test]
+    fn percent_encode() {
+        assert_eq!(encode_path("/a-b/c:d@e"), "/a-b/c:d@e");
+        assert_eq!(encode_path("a b?c#d%"), "a%20b%3Fc%23d%25");
+        assert_eq!(encode_path("\r\n"), "%0D%0A");
+        assert_eq!(encode_path(&decode_str("caf%C3%A9")), "caf%C3%A9");
+        assert_eq!(encode_path("\u{100}"), "%C4%80");
     }
 }
@@ -1,23 +1,33 @@
 //! Picky filesystem APIs for channeling djb.
 
+use std::ffi::{OsStr, OsString};
 use std::io;
-use std::os::unix::fs::PermissionsExt;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::Path;
+use std::sync::{Arc, OnceLock};
 use std::time::SystemTime;
 
+use nix::fcntl::OFlag;
+use nix::sys::stat::Mode;
+
 use tokio::fs;
 
+use crate::source::Content;
+
 /// Information about an open file, including the file handle.
 #[derive(Debug)]
 pub struct File {
-    /// An async handle to the file, open for read.
-    pub file: fs::File,
+    /// The file's contents, open for read.
+    pub file: Box<dyn Content>,
     /// Length of the file in bytes.
     pub len: u64,
     /// Inferred content type of file.
     pub content_type: &'static str,
     /// Modification timestamp.
     pub modified: SystemTime,
+    /// Device and inode numbers, which together identify the file.
+    pub id: (u64, u64),
     /// Cache TTL in seconds.
     pub ttl: Option<usize>,
 }
@@ -37,17 +47,24 @@ pub struct File {
 ///
 /// If the path turns out to be a directory, returns `Error::Directory` only if
 /// it meets all the above criteria, otherwise you'll get `Error::BadMode`.
+///
+/// `path` is resolved relative to `dir`, and can't lead outside it.
 pub async fn open(
     log: &slog::Logger,
+    dir: &Dir,
     path: &Path,
     infer_content_type: impl FnOnce(&Path) -> &'static str,
     choose_ttl: impl FnOnce(&Path) -> Option<usize>,
 ) -> Result<File, Error> {
     slog::debug!(log, "picky_open({:?})", path);
 
-    let file = fs::File::open(path).await.map_err(|e| {
+    let file = open_at(dir, path).await.map_err(|e| {
         slog::debug!(log, "can't open: {}", e);
-        e
+        match e.raw_os_error() {
+            // Some non-final component of the path is a file.
+            Some(libc::ENOTDIR) => Error::NotDirectory,
+            _ => Error::Io(e),
+        }
     })?;
     let meta = file.metadata().await?;
     let mode = meta.permissions().mode();
@@ -58,9 +75,10 @@ pub async fn open(
     } else if meta.is_file() {
         slog::debug!(log, "opened");
         Ok(File {
-            file,
+            file: Box::new(file),
             len: meta.len(),
             modified: meta.modified().unwrap(),
+            id: (meta.dev(), meta.ino()),
             content_type: infer_content_type(path),
             ttl: choose_ttl(path),
         })
@@ -73,19 +91,182 @@ pub async fn open(
     }
 }
 
+/// A directory that files are served from, held open so that paths can be
+/// resolved relative to it.
+///
+/// On Linux, paths are resolved with `openat2` and `RESOLVE_BENEATH`, so the
+/// kernel refuses to follow `..` or a symlink out of the directory, whatever
+/// the path looks like. This backs up path sanitization, and matters most for
+/// directories outside the chroot, which nothing else confines.
+///
+/// Clones share the open directory.
+#[derive(Clone, Debug)]
+pub struct Dir {
+    fd: Arc<OwnedFd>,
+    /// Whether to refuse to follow symlinks at all.
+    no_symlinks: bool,
+}
+
+impl Dir {
+    /// Opens the directory at `path`. Refusing symlinks takes `openat2`, so
+    /// `no_symlinks` is an error where `resolves_beneath` says it's missing.
+    pub fn open(path: &Path, no_symlinks: bool) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        if no_symlinks && !resolves_beneath() {
+            return Err(io::Error::other(
+                "--no-symlinks needs openat2, which isn't available here",
+            ));
+        }
+        let dir = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(path)?;
+        Ok(Dir {
+            fd: Arc::new(OwnedFd::from(dir)),
+            no_symlinks,
+        })
+    }
+
+    /// Opens `path` for read relative to this directory.
+    fn open_file(&self, path: &Path) -> io::Result<std::fs::File> {
+        let flags = OFlag::O_RDONLY | OFlag::O_CLOEXEC;
+        #[cfg(target_os = "linux")]
+        if resolves_beneath() {
+            let mut resolve =
+                libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+            if self.no_symlinks {
+                resolve |= libc::RESOLVE_NO_SYMLINKS;
+            }
+            return openat2(self.fd.as_raw_fd(), path, flags, resolve);
+        }
+        let fd = nix::fcntl::openat(
+            self.fd.as_raw_fd(),
+            path,
+            flags,
+            Mode::empty(),
+        )?;
+        // Safety: openat has just given us this fd, and nobody else has it.
+        Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+    }
+
+    /// Lists the names in the directory `path`, relative to this one, other
+    /// than `.` and `..`.
+    pub fn list(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = nix::dir::Dir::from_fd(self.open_file(path)?.into_raw_fd())?;
+        let mut names = vec![];
+        for entry in dir.into_iter() {
+            let name = entry?.file_name().to_bytes().to_vec();
+            if name != b"." && name != b".." {
+                names.push(OsStr::from_bytes(&name).to_owned());
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Whether paths can be resolved with `openat2` and `RESOLVE_BENEATH`. Kernels
+/// before 5.6 don't have it, and some sandboxes forbid it; there, and on other
+/// systems, files are opened with plain `openat`. This is checked once, and
+/// the answer holds for every directory, so that confinement never quietly
+/// changes from one request to the next.
+pub fn resolves_beneath() -> bool {
+    static BENEATH: OnceLock<bool> = OnceLock::new();
+    *BENEATH.get_or_init(|| {
+        #[cfg(target_os = "linux")]
+        {
+            let flags = OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+            let probe = openat2(
+                libc::AT_FDCWD,
+                Path::new("."),
+                flags,
+                libc::RESOLVE_BENEATH,
+            );
+            // Anything else means openat2 is there, and the directory was
+            // the problem.
+            !matches!(
+                probe.map_err(|e| e.raw_os_error()),
+                Err(Some(libc::ENOSYS | libc::EPERM))
+            )
+        }
+        #[cfg(not(target_os = "linux"))]
+        false
+    })
+}
+
+/// Opens `path` relative to the directory `dirfd` with `openat2`, resolving
+/// it as `resolve` says.
+#[cfg(target_os = "linux")]
+fn openat2(
+    dirfd: libc::c_int,
+    path: &Path,
+    flags: OFlag,
+    resolve: u64,
+) -> io::Result<std::fs::File> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // Safety: open_how is plain data, for which zero is a valid value.
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = flags.bits() as u64;
+    how.resolve = resolve;
+    // Safety: the pointers are valid for the duration of the call, and the
+    // size is that of the structure pointed to.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dirfd,
+            path.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: openat2 has just given us this fd, and nobody else has it.
+    Ok(unsafe { std::fs::File::from_raw_fd(fd as i32) })
+}
+
+/// Opens `path` for read relative to the directory `dir`.
+async fn open_at(dir: &Dir, path: &Path) -> io::Result<fs::File> {
+    let dir = dir.clone();
+    let path = path.to_owned();
+    let file =
+        tokio::task::spawn_blocking(move || dir.open_file(&path)).await??;
+    Ok(fs::File::from_std(file))
+}
+
 #[derive(Debug)]
 pub enum Error {
     BadMode(u32),
     Directory,
+    NotDirectory,
     SpecialFile,
     Io(io::Error),
 }
 
+impl Error {
+    /// Checks whether this error means the file exists but isn't ours to
+    /// serve, as opposed to not existing at all.
+    pub fn is_forbidden(&self) -> bool {
+        match self {
+            Self::BadMode(_) | Self::SpecialFile => true,
+            Self::Io(e) => e.kind() == io::ErrorKind::PermissionDenied,
+            Self::Directory | Self::NotDirectory => false,
+        }
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::BadMode(x) => write!(f, "mode {:#o}", x),
             Self::Directory => f.write_str("is dir"),
+            Self::NotDirectory => f.write_str("not dir"),
             Self::SpecialFile => f.write_str("is special"),
             Self::Io(e) => e.fmt(f),
         }
@@ -97,3 +278,37 @@ impl From<io::Error> for Error {
         Self::Io(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn confined_to_dir() {
+        use std::os::unix::fs::symlink;
+
+        let base = std::env::temp_dir()
+            .join(format!("httpd2-picky-test-{}", std::process::id()));
+        let root = base.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(base.join("secret"), "").unwrap();
+        std::fs::write(root.join("page"), "").unwrap();
+        symlink("page", root.join("inside")).unwrap();
+        symlink("../secret", root.join("outside")).unwrap();
+        symlink(base.join("secret"), root.join("absolute")).unwrap();
+
+        let dir = Dir::open(&root, false).unwrap();
+        assert!(dir.open_file(Path::new("page")).is_ok());
+        assert!(dir.open_file(Path::new("inside")).is_ok());
+        assert!(dir.open_file(Path::new("outside")).is_err());
+        assert!(dir.open_file(Path::new("absolute")).is_err());
+        assert!(dir.open_file(Path::new("../secret")).is_err());
+
+        let strict = Dir::open(&root, true).unwrap();
+        assert!(strict.open_file(Path::new("page")).is_ok());
+        assert!(strict.open_file(Path::new("inside")).is_err());
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+//! Writing precompressed alternates ahead of time.
+//!
+//! The server never compresses anything itself (see `encoding`), so
+//! `--precompress` is there to produce the alternates it looks for. It walks a
+//! directory, and for each file that would be served with a compressible type,
+//! runs the usual command-line compressor for each encoding -- `gzip`,
+//! `brotli`, `zstd` -- to write `NAME.gz` and so on beside it, with the same
+//! permissions as the original.
+//!
+//! An alternate is only served if it's at least as new as its original, so
+//! those that are already are left alone. One that comes out no smaller than
+//! the original would only waste a lookup, and is thrown away.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::encoding::Encoding;
+use crate::serve::map_content_type;
+
+/// What was done.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Alternates written.
+    pub written: usize,
+    /// Alternates already newer than their originals.
+    pub current: usize,
+    /// Alternates not kept, because they were no smaller.
+    pub unhelpful: usize,
+    /// Alternates that couldn't be written.
+    pub failed: usize,
+}
+
+/// Writes an alternate in each of `encodings` for each compressible file under
+/// `root`, using up to `jobs` compressors at once. Problems with particular
+/// files are logged and counted, rather than stopping the walk.
+pub fn precompress(
+    log: &slog::Logger,
+    root: &Path,
+    encodings: &[Encoding],
+    jobs: usize,
+) -> io::Result<Summary> {
+    let mut files = vec![];
+    walk(root, &mut files)?;
+    let work = files
+        .into_iter()
+        .flat_map(|file| encodings.iter().map(move |&enc| (file.clone(), enc)))
+        .collect::<Vec<_>>();
+
+    let work = Mutex::new(work);
+    let summary = Mutex::new(Summary::default());
+    std::thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            s.spawn(|| loop {
+                let next = work.lock().unwrap().pop();
+                let (file, enc) = match next {
+                    Some(job) => job,
+                    None => break,
+                };
+                let outcome = compress(&file, enc);
+                let mut summary = summary.lock().unwrap();
+                match outcome {
+                    Ok(Outcome::Written) => summary.written += 1,
+                    Ok(Outcome::Current) => summary.current += 1,
+                    Ok(Outcome::Unhelpful) => summary.unhelpful += 1,
+                    Err(e) => {
+                        summary.failed += 1;
+                        slog::warn!(
+                            log,
+                            "can't compress";
+                            "path" => %file.display(),
+                            "enc" => enc.token(),
+                            "err" => %e,
+                        );
+                    }
+                }
+            });
+        }
+    });
+    Ok(summary.into_inner().unwrap())
+}
+
+/// Collects the compressible files under `dir`, skipping dotfiles (which are
+/// never served), symlinks, and alternates.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let kind = entry.file_type()?;
+        let path = entry.path();
+        if kind.is_dir() {
+            walk(&path, files)?;
+        } else if kind.is_file() && compressible(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether the file at `path` is worth compressing, going by the type
+/// it would be served with.
+fn compressible(path: &Path) -> bool {
+    // Alternates, compressed or image, have extensions that would otherwise
+    // be taken for text.
+    let ext = path.extension().and_then(OsStr::to_str);
+    if let Some("gz" | "br" | "zst" | "avif" | "webp") = ext {
+        return false;
+    }
+    let content_type = map_content_type(path);
+    content_type.starts_with("text/")
+        || ["application/xml", "application/wasm"].contains(&content_type)
+}
+
+enum Outcome {
+    Written,
+    Current,
+    Unhelpful,
+}
+
+/// The compressor for `enc`, at its highest setting, writing to stdout.
+fn command(enc: Encoding) -> Command {
+    let (program, args): (_, &[_]) = match enc {
+        Encoding::Gzip => ("gzip", &["-9", "-n", "-c"]),
+        Encoding::Brotli => ("brotli", &["-q", "11", "-c"]),
+        Encoding::Zstd => ("zstd", &["-19", "-q", "-c"]),
+    };
+    let mut command = Command::new(program);
+    command.args(args);
+    command
+}
+
+/// Writes the `enc` alternate of `file`, unless there's already a current one.
+fn compress(file: &Path, enc: Encoding) -> io::Result<Outcome> {
+    let meta = fs::metadata(file)?;
+    let mut alt = file.as_os_str().to_owned();
+    alt.push(enc.extension());
+    let alt = PathBuf::from(alt);
+    if let Ok(alt_meta) = fs::metadata(&alt) {
+        if alt_meta.modified()? >= meta.modified()? {
+            return Ok(Outcome::Current);
+        }
+    }
+
+    // Written under a dotfile name until it's done, so it can't be served.
+    let mut tmp = OsString::from(".");
+    tmp.push(alt.file_name().unwrap());
+    let tmp = alt.with_file_name(tmp);
+    let mut command = command(enc);
+    let status = command
+        .stdin(fs::File::open(file)?)
+        .stdout(fs::File::create(&tmp)?)
+        .stderr(Stdio::null())
+        .status();
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            fs::remove_file(&tmp)?;
+            let program = command.get_program().to_string_lossy();
+            let why = format!("can't run {}: {}", program, e);
+            return Err(io::Error::new(e.kind(), why));
+        }
+    };
+    if !status.success() {
+        fs::remove_file(&tmp)?;
+        return Err(io::Error::other(format!("compressor {}", status)));
+    }
+    if fs::metadata(&tmp)?.len() >= meta.len() {
+        fs::remove_file(&tmp)?;
+        return Ok(Outcome::Unhelpful);
+    }
+    let mode = meta.permissions().mode() & 0o7777;
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+    fs::rename(&tmp, &alt)?;
+    Ok(Outcome::Written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gzip_alternates() {
+        let root = std::env::temp_dir()
+            .join(format!("httpd2-precompress-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/a.html"), "hello ".repeat(100)).unwrap();
+        fs::set_permissions(
+            root.join("sub/a.html"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        fs::write(root.join("tiny.css"), "a").unwrap();
+        fs::write(root.join("b.png"), "not really").unwrap();
+        fs::write(root.join(".hidden.html"), "hello ".repeat(100)).unwrap();
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let run = || precompress(&log, &root, &[Encoding::Gzip], 2).unwrap();
+        assert_eq!(
+            run(),
+            Summary {
+                written: 1,
+                unhelpful: 1,
+                ..Summary::default()
+            }
+        );
+        let alt = fs::metadata(root.join("sub/a.html.gz")).unwrap();
+        assert_eq!(alt.permissions().mode() & 0o777, 0o644);
+        assert_eq!(
+            run(),
+            Summary {
+                current: 1,
+                unhelpful: 1,
+                ..Summary::default()
+            }
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//! Sending HTTP/2 responses in the order the client asked for.
+//!
+//! A browser loading a page over one HTTP/2 connection asks for everything at
+//! once, and left to itself hyper sends the responses side by side, so the
+//! stylesheet that's holding up the page shares the connection with a dozen
+//! images. Browsers say what matters with the `Priority` header of RFC 9218:
+//! an urgency `u` from 0, most urgent, to 7, 3 by default, and whether the
+//! response is of use in pieces (`i`), as an image shown while it loads is. The
+//! older priority tree of RFC 7540, which RFC 9113 deprecates, never gets past
+//! hyper, so it's not considered.
+//!
+//! With `--h2-priorities`, each connection gets a `Schedule`, and the bodies of
+//! its responses are wrapped in `Scheduled`. A body waits while another on the
+//! connection is more urgent, and, if neither is incremental, while an earlier
+//! one of the same urgency is still going. Incremental bodies of the same
+//! urgency share the connection.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::HeaderMap;
+
+use crate::err::ServeError;
+use crate::serve::BoxBody;
+
+/// A response's priority, as the client signaled it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Priority {
+    /// From 0, most urgent, to 7.
+    pub urgency: u8,
+    /// Whether the response is of use before it's all arrived.
+    pub incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority {
+            urgency: 3,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    /// Reads the `Priority` headers in `headers`. Members that aren't
+    /// understood are ignored, as are values out of range, leaving the
+    /// defaults.
+    pub fn of(headers: &HeaderMap) -> Self {
+        let mut priority = Priority::default();
+        let values = headers.get_all("priority").iter();
+        let members = values
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for member in members {
+            // Parameters say nothing we use.
+            let member = member.split(';').next().unwrap_or("").trim();
+            let (key, value) = match member.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (member, None),
+            };
+            // A later member of the same name replaces an earlier one.
+            match (key, value) {
+                ("u", Some(value)) => {
+                    if let Ok(u @ 0..=7) = value.parse() {
+                        priority.urgency = u;
+                    }
+                }
+                ("i", None | Some("?1")) => priority.incremental = true,
+                ("i", Some("?0")) => priority.incremental = false,
+                _ => (),
+            }
+        }
+        priority
+    }
+}
+
+/// The bodies being sent on one connection, and those waiting their turn.
+#[derive(Default)]
+pub struct Schedule {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Numbers bodies in the order they start.
+    next: u64,
+    active: Vec<(u64, Priority)>,
+    waiting: Vec<Waker>,
+}
+
+impl Schedule {
+    /// Counts a body starting with `priority`, returning its number.
+    fn start(&self, priority: Priority) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next;
+        state.next += 1;
+        state.active.push((id, priority));
+        id
+    }
+
+    /// Whether body `id` may send now, arranging for `waker` to be woken when
+    /// that may have changed if not.
+    fn may_send(&self, id: u64, priority: Priority, waker: &Waker) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let blocked = state.active.iter().any(|&(other, p)| {
+            p.urgency < priority.urgency
+                || (p.urgency == priority.urgency
+                    && other < id
+                    && !p.incremental
+                    && !priority.incremental)
+        });
+        if blocked && !state.waiting.iter().any(|w| w.will_wake(waker)) {
+            state.waiting.push(waker.clone());
+        }
+        !blocked
+    }
+
+    /// Counts body `id` as finished, and lets those waiting take another look.
+    fn finish(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.active.retain(|&(other, _)| other != id);
+        for waker in state.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A response body sent when its priority allows.
+pub struct Scheduled {
+    inner: BoxBody,
+    schedule: Arc<Schedule>,
+    priority: Priority,
+    /// The body's number, once it has started.
+    id: Option<u64>,
+    done: bool,
+}
+
+impl Scheduled {
+    /// Wraps `inner`, to be sent on the connection with `schedule` as
+    /// `priority` allows. It's counted from when it's first asked for a frame,
+    /// so a body that's still waiting for something else holds no one up.
+    pub fn new(
+        inner: BoxBody,
+        schedule: &Arc<Schedule>,
+        priority: Priority,
+    ) -> Self {
+        Scheduled {
+            inner,
+            schedule: Arc::clone(schedule),
+            priority,
+            id: None,
+            done: false,
+        }
+    }
+
+    fn finish(&mut self) {
+        if let (Some(id), false) = (self.id, self.done) {
+            self.schedule.finish(id);
+        }
+        self.done = true;
+    }
+}
+
+impl Body for Scheduled {
+    type Data = Bytes;
+    type Error = ServeError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, ServeError>>> {
+        let this = self.get_mut();
+        if !this.done {
+            let (schedule, priority) = (&this.schedule, this.priority);
+            let id = *this.id.get_or_insert_with(|| schedule.start(priority));
+            if !schedule.may_send(id, priority, cx.waker()) {
+                return Poll::Pending;
+            }
+        }
+        let frame = futures::ready!(this.inner.as_mut().poll_frame(cx));
+        if frame.is_none() {
+            this.finish();
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Scheduled {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+    use std::time::Duration;
+
+    /// Whether `body` has nothing to give for a while.
+    async fn blocked(body: &mut Scheduled) -> bool {
+        let wait = Duration::from_millis(50);
+        tokio::time::timeout(wait, body.frame()).await.is_err()
+    }
+
+    #[tokio::test]
+    async fn priorities() {
+        let of = |values: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append("priority", value.parse().unwrap());
+            }
+            Priority::of(&headers)
+        };
+        let p = |urgency, incremental| Priority {
+            urgency,
+            incremental,
+        };
+        assert_eq!(of(&[]), p(3, false));
+        assert_eq!(of(&["u=0, i"]), p(0, true));
+        assert_eq!(of(&["u=5;x=1, i=?0"]), p(5, false));
+        assert_eq!(of(&["u=1", "u=6, i=?1"]), p(6, true));
+        assert_eq!(of(&["u=8, x, i=1"]), p(3, false));
+
+        let body = || -> BoxBody {
+            Box::pin(Full::new(Bytes::from("x")).map_err(|r| match r {}))
+        };
+        let schedule = Arc::new(Schedule::default());
+        let scheduled = |priority| Scheduled::new(body(), &schedule, priority);
+
+        // An image waits for a stylesheet that's started.
+        let mut css = scheduled(p(0, false));
+        let mut image = scheduled(p(5, true));
+        assert!(css.frame().await.is_some());
+        assert!(blocked(&mut image).await);
+        assert!(css.frame().await.is_none());
+        assert!(image.frame().await.is_some());
+
+        // Incremental bodies of one urgency share; others go in order.
+        let (mut a, mut b) = (scheduled(p(3, true)), scheduled(p(3, true)));
+        assert!(a.frame().await.is_some());
+        assert!(b.frame().await.is_some());
+        let (mut c, mut d) = (scheduled(p(2, false)), scheduled(p(2, false)));
+        assert!(c.frame().await.is_some());
+        assert!(blocked(&mut d).await);
+        // Dropping a body partway through also lets others go.
+        drop(c);
+        assert!(d.frame().await.is_some());
+    }
+}
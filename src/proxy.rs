@@ -0,0 +1,570 @@
+//! Support for running behind reverse proxies and load balancers.
+//!
+//! When a connection arrives from a trusted proxy, the address of the actual
+//! client is taken from the `X-Forwarded-For` header. Each proxy appends the
+//! address it received the request from, so the header is read from right to
+//! left, skipping over trusted proxies, and the first untrusted address found
+//! is the client. Anything to the left of that could have been made up by the
+//! client, and is ignored.
+//!
+//! The standard `Forwarded` header (RFC 7239) is read the same way, and
+//! preferred when it's there. Each of its elements is one proxy's account of
+//! the request it received: who sent it (`for`), and the `host` and `proto`
+//! asked for. The element added by the proxy the client connected to says
+//! what the client asked for. With only the older headers, the host and scheme
+//! come from the last `X-Forwarded-Host` and `X-Forwarded-Proto`, those being
+//! the ones our own proxy set.
+//!
+//! Alternatively, a load balancer can announce the client address at the very
+//! start of the connection using HAProxy's PROXY protocol, which works below
+//! TLS. See `read_proxy_header`.
+
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use hyper::header::HeaderMap;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::host;
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`. A
+/// bare address is treated as a network containing only that address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    /// Checks whether `ip` falls within this network.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.len));
+                let mask = mask.unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.len));
+                let mask = mask.unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("bad address: {}", e))?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            None => max,
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max)
+                .ok_or_else(|| format!("bad prefix length: {}", len))?,
+        };
+        Ok(Cidr { addr, len })
+    }
+}
+
+/// What the trusted proxies in front of us say about a request.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Forwarded {
+    /// The client's address, which is the peer's unless a proxy says
+    /// otherwise.
+    pub client: IpAddr,
+    /// The host the client asked for, normalized, if a proxy says.
+    pub host: Option<String>,
+    /// The scheme the client used, `http` or `https`, if a proxy says.
+    pub proto: Option<String>,
+}
+
+/// Determines the address of the client responsible for a request that arrived
+/// from `peer`.
+///
+/// If `peer` isn't within one of the `trusted` networks, it's the client.
+/// Otherwise, the `Forwarded` or `X-Forwarded-For` entries in `headers` are
+/// consulted as described in the module docs. An entry that can't be parsed,
+/// or that hides the address, stops the search, and the last trusted address
+/// is used instead.
+pub fn client_addr(
+    trusted: &[Cidr],
+    peer: IpAddr,
+    headers: &HeaderMap,
+) -> IpAddr {
+    forwarded(trusted, peer, headers).client
+}
+
+/// Finds out what trusted proxies say about a request that arrived from
+/// `peer` with `headers`: the client, as for `client_addr`, and the host and
+/// scheme it asked for. Nothing is believed unless `peer` is trusted.
+pub fn forwarded(
+    trusted: &[Cidr],
+    peer: IpAddr,
+    headers: &HeaderMap,
+) -> Forwarded {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+
+    let mut forwarded = Forwarded {
+        client: peer.to_canonical(),
+        host: None,
+        proto: None,
+    };
+    if !is_trusted(forwarded.client) {
+        return forwarded;
+    }
+
+    if headers.contains_key(hyper::header::FORWARDED) {
+        // Each element we reach was added by a trusted proxy, so what it says
+        // about the request that proxy received is believed; elements further
+        // left are nearer the client.
+        for element in forwarded_elements(headers).into_iter().rev() {
+            let element = match element {
+                Some(element) => element,
+                None => break,
+            };
+            let param = |name| {
+                element.iter().find(|(n, _)| n == name).map(|(_, v)| &v[..])
+            };
+            if let Some(host) = param("host").and_then(host::normalize) {
+                forwarded.host = Some(host);
+            }
+            if let Some(proto) = param("proto").and_then(parse_proto) {
+                forwarded.proto = Some(proto);
+            }
+            match param("for").and_then(parse_forwarded_addr) {
+                Some(ip) => {
+                    forwarded.client = ip;
+                    if !is_trusted(ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        return forwarded;
+    }
+
+    // Multiple X-Forwarded-For headers are equivalent to a single header
+    // with the values joined by commas, so walk them last-to-first too.
+    let entries = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .rev()
+        .flat_map(|value| {
+            let entries = match value.to_str() {
+                Ok(s) => s.split(',').map(parse_forwarded_addr).collect(),
+                Err(_) => vec![None],
+            };
+            entries.into_iter().rev()
+        });
+    for entry in entries {
+        match entry {
+            Some(ip) => {
+                forwarded.client = ip;
+                if !is_trusted(ip) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    let last = |name| {
+        let value = headers.get_all(name).iter().next_back()?.to_str().ok()?;
+        value.rsplit(',').next().map(str::trim)
+    };
+    forwarded.host = last("x-forwarded-host").and_then(host::normalize);
+    forwarded.proto = last("x-forwarded-proto").and_then(parse_proto);
+    forwarded
+}
+
+/// Splits the `Forwarded` headers into their elements, each a list of
+/// parameters with lowercased names and unquoted values. An element that
+/// can't be parsed is `None`.
+fn forwarded_elements(headers: &HeaderMap) -> Vec<Option<Vec<(String, String)>>> {
+    let mut elements = vec![];
+    for value in headers.get_all(hyper::header::FORWARDED) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => {
+                elements.push(None);
+                continue;
+            }
+        };
+        // Commas and semicolons may appear in quoted values, so split by
+        // hand.
+        let mut element = Some(vec![]);
+        let mut pair = String::new();
+        let mut quoted = false;
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    pair.push(c);
+                }
+                '\\' if quoted => {
+                    pair.push(c);
+                    pair.extend(chars.next());
+                }
+                ';' if !quoted => end_param(&mut pair, &mut element),
+                ',' if !quoted => {
+                    end_param(&mut pair, &mut element);
+                    elements.push(element.replace(vec![]));
+                }
+                c => pair.push(c),
+            }
+        }
+        end_param(&mut pair, &mut element);
+        if quoted {
+            element = None;
+        }
+        elements.push(element);
+    }
+    elements
+}
+
+/// Adds the parameter in `pair`, if there is one, to `element`, emptying
+/// `pair`. A bad parameter spoils the element.
+fn end_param(pair: &mut String, element: &mut Option<Vec<(String, String)>>) {
+    let taken = std::mem::take(pair);
+    let taken = taken.trim();
+    if taken.is_empty() {
+        return;
+    }
+    let parsed = taken.split_once('=').and_then(|(name, value)| {
+        let value = match value.strip_prefix('"') {
+            Some(rest) => unquote(rest)?,
+            None => value.to_string(),
+        };
+        Some((name.trim().to_ascii_lowercase(), value))
+    });
+    match (element.as_mut(), parsed) {
+        (Some(params), Some(param)) => params.push(param),
+        _ => *element = None,
+    }
+}
+
+/// Reads a quoted string, after its opening quote, to its closing one, which
+/// must end it.
+fn unquote(s: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push(chars.next()?),
+            '"' => return chars.as_str().is_empty().then_some(out),
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+/// Accepts the schemes we expect to hear of.
+fn parse_proto(s: &str) -> Option<String> {
+    let s = s.to_ascii_lowercase();
+    matches!(&s[..], "http" | "https").then_some(s)
+}
+
+/// Parses a single `X-Forwarded-For` entry or `Forwarded` `for` value, which
+/// is usually a bare address but occasionally includes a port. IPv6 addresses
+/// in `Forwarded` are in brackets, with or without one.
+fn parse_forwarded_addr(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    s.parse::<IpAddr>()
+        .or_else(|_| s.parse::<SocketAddr>().map(|sa| sa.ip()))
+        .or_else(|_| {
+            let bare = s.strip_prefix('[').and_then(|s| s.strip_suffix(']'));
+            bare.unwrap_or(s).parse::<std::net::Ipv6Addr>().map(IpAddr::V6)
+        })
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+/// Signature that begins a version 2 PROXY protocol header.
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible version 1 PROXY protocol header, including the CRLF.
+const PROXY_V1_MAX: usize = 107;
+
+/// Reads a PROXY protocol header, version 1 or 2, from the start of `stream`,
+/// consuming exactly the header and nothing after it.
+///
+/// Returns the client's address, or `None` if the sender declined to provide
+/// one (`UNKNOWN` in version 1, `LOCAL` or an unsupported address family in
+/// version 2), in which case the connection's peer should be used. A missing
+/// or malformed header is an error.
+pub async fn read_proxy_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least this long, which is enough to tell them
+    // apart.
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == PROXY_V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        let [ver_cmd, family, hi, lo] = fixed;
+        let mut rest = vec![0; usize::from(u16::from_be_bytes([hi, lo]))];
+        stream.read_exact(&mut rest).await?;
+        parse_proxy_v2(ver_cmd, family, &rest)
+    } else if start.starts_with(b"PROXY ") {
+        // Version 1 has no length field, so we have to read a byte at a time
+        // to avoid consuming the start of the TLS handshake.
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == PROXY_V1_MAX {
+                return Err(bad_proxy_header());
+            }
+            line.push(stream.read_u8().await?);
+        }
+        line.truncate(line.len() - 2);
+        parse_proxy_v1(&line)
+    } else {
+        Err(bad_proxy_header())
+    }
+}
+
+/// Parses a version 1 header line, minus its CRLF.
+fn parse_proxy_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| bad_proxy_header())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip = src.parse::<IpAddr>().map_err(|_| bad_proxy_header())?;
+            let port = sport.parse::<u16>().map_err(|_| bad_proxy_header())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(bad_proxy_header()),
+    }
+}
+
+/// Parses the body of a version 2 header, following the signature.
+fn parse_proxy_v2(
+    ver_cmd: u8,
+    family: u8,
+    addrs: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(bad_proxy_header());
+    }
+    match ver_cmd & 0xF {
+        // LOCAL: a health check or similar from the proxy itself.
+        0 => return Ok(None),
+        // PROXY
+        1 => (),
+        _ => return Err(bad_proxy_header()),
+    }
+    match family >> 4 {
+        // AF_INET
+        1 if addrs.len() >= 12 => {
+            let ip: [u8; 4] = addrs[0..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        // AF_INET6
+        2 if addrs.len() >= 36 => {
+            let ip: [u8; 16] = addrs[0..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        1 | 2 => Err(bad_proxy_header()),
+        // AF_UNSPEC or AF_UNIX, neither of which tells us anything useful.
+        _ => Ok(None),
+    }
+}
+
+fn bad_proxy_header() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "bad PROXY protocol header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.1.2.3")));
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(cidr("0.0.0.0/0").contains(ip("1.2.3.4")));
+        assert!(cidr("192.168.1.1").contains(ip("192.168.1.1")));
+        assert!(!cidr("192.168.1.1").contains(ip("192.168.1.2")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("10.0.0.1")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("bogus/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn forwarded_for() {
+        let trusted = [cidr("10.0.0.0/8")];
+        let mut headers = HeaderMap::new();
+        headers.append(
+            "x-forwarded-for",
+            "6.6.6.6, 1.2.3.4, 10.0.0.2".parse().unwrap(),
+        );
+
+        // Untrusted peers can say whatever they like.
+        assert_eq!(
+            client_addr(&trusted, ip("5.5.5.5"), &headers),
+            ip("5.5.5.5")
+        );
+        // Trusted peers are believed, up to the first untrusted hop.
+        assert_eq!(
+            client_addr(&trusted, ip("10.0.0.1"), &headers),
+            ip("1.2.3.4")
+        );
+
+        // Later headers come later in the chain.
+        headers.append("x-forwarded-for", "10.0.0.3:1234".parse().unwrap());
+        assert_eq!(
+            client_addr(&trusted, ip("10.0.0.1"), &headers),
+            ip("1.2.3.4")
+        );
+
+        // Garbage stops the search at the last trusted address.
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", "1.2.3.4, junk".parse().unwrap());
+        assert_eq!(
+            client_addr(&trusted, ip("10.0.0.1"), &headers),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let trusted = [cidr("10.0.0.0/8")];
+        let mut headers = HeaderMap::new();
+        headers.append(
+            "forwarded",
+            r#"for=6.6.6.6;host=evil.com, for="[2001:db8::1]:4711";proto=HTTP;host="Example.com:8080""#
+                .parse()
+                .unwrap(),
+        );
+        headers.append("forwarded", "for=10.0.0.2;proto=https".parse().unwrap());
+        // Forwarded is preferred when both are present.
+        headers.append("x-forwarded-for", "1.2.3.4".parse().unwrap());
+        headers.append("x-forwarded-host", "other.com".parse().unwrap());
+
+        assert_eq!(
+            forwarded(&trusted, ip("10.0.0.1"), &headers),
+            Forwarded {
+                client: ip("2001:db8::1"),
+                host: Some("example.com".to_string()),
+                proto: Some("http".to_string()),
+            }
+        );
+        // Untrusted peers get no say.
+        assert_eq!(
+            forwarded(&trusted, ip("5.5.5.5"), &headers),
+            Forwarded {
+                client: ip("5.5.5.5"),
+                host: None,
+                proto: None,
+            }
+        );
+
+        // A hidden client, or garbage, stops the search.
+        for value in [
+            "for=unknown, for=10.0.0.2",
+            "for=_hidden, for=10.0.0.2",
+            r#"for="1.2.3.4"x, for=10.0.0.2"#,
+            "junk, for=10.0.0.2",
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.append("forwarded", value.parse().unwrap());
+            assert_eq!(
+                client_addr(&trusted, ip("10.0.0.1"), &headers),
+                ip("10.0.0.2"),
+                "{}",
+                value
+            );
+        }
+
+        // Without Forwarded, the nearest proxy's X-Forwarded-* are used.
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", "1.2.3.4".parse().unwrap());
+        headers.append("x-forwarded-host", "a.com, b.com".parse().unwrap());
+        headers.append("x-forwarded-proto", "https".parse().unwrap());
+        assert_eq!(
+            forwarded(&trusted, ip("10.0.0.1"), &headers),
+            Forwarded {
+                client: ip("1.2.3.4"),
+                host: Some("b.com".to_string()),
+                proto: Some("https".to_string()),
+            }
+        );
+    }
+
+    async fn read_header(mut input: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let r = read_proxy_header(&mut input).await;
+        (r, input)
+    }
+
+    #[tokio::test]
+    async fn proxy_v1() {
+        let (r, rest) =
+            read_header(b"PROXY TCP4 1.2.3.4 5.6.7.8 1000 443\r\nTLS").await;
+        assert_eq!(r.unwrap(), Some("1.2.3.4:1000".parse().unwrap()));
+        assert_eq!(rest, b"TLS");
+
+        let (r, _) =
+            read_header(b"PROXY TCP6 ::1 ::2 1000 443\r\n").await;
+        assert_eq!(r.unwrap(), Some("[::1]:1000".parse().unwrap()));
+
+        let (r, rest) = read_header(b"PROXY UNKNOWN\r\nTLS").await;
+        assert_eq!(r.unwrap(), None);
+        assert_eq!(rest, b"TLS");
+
+        assert!(read_header(b"PROXY TCP4 1.2.3.4\r\n").await.0.is_err());
+        assert!(read_header(b"GET / HTTP/1.1\r\n").await.0.is_err());
+        assert!(read_header(&[b'P'; 200]).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_v2() {
+        let mut input = PROXY_V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        input.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 0x03, 0xE8, 1, 187]);
+        input.extend_from_slice(b"TLS");
+        let (r, rest) = read_header(&input).await;
+        assert_eq!(r.unwrap(), Some("1.2.3.4:1000".parse().unwrap()));
+        assert_eq!(rest, b"TLS");
+
+        // LOCAL command.
+        let mut input = PROXY_V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&input).await.0.unwrap(), None);
+
+        // Truncated address block.
+        let mut input = PROXY_V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x21, 0, 4, 0, 0, 0, 0]);
+        assert!(read_header(&input).await.0.is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//! Redirects and rewrites from a `_redirects` file, in Netlify's format.
+//!
+//! Each line of the file is a rule:
+//!
+//! ```text
+//! # comment
+//! /old-page       /new-page         301
+//! /blog/*         /posts/:splat     302
+//! /news/:year/:id /archive/:year/:id
+//! /app/*          /app/index.html   200
+//! /shop/*         /shop/404.html    404
+//! /moved          https://example.com/ 301!
+//! ```
+//!
+//! The first field is a path, in which `:name` matches one path segment and a
+//! final `*` matches the rest; the second is where to send the request, which
+//! may use the names and `:splat`. The status defaults to 301. A 3xx status
+//! redirects; 200 serves the destination (a local path) in place of the
+//! original, and 404 does the same with a 404 status. As in Netlify, a rule
+//! only applies to paths that would otherwise be missing, unless its status
+//! ends in `!`. The first matching rule wins.
+//!
+//! Netlify's query parameter matching, proxying to other sites, and
+//! conditions (country, language, role) aren't supported; lines using them
+//! are skipped with a warning.
+//!
+//! The file is read and watched as described in `sitefile`.
+
+use hyper::StatusCode;
+
+use crate::sitefile::{Pattern, Watched};
+use crate::source::ContentSource;
+
+/// Where the rules live, relative to the root.
+pub const FILE: &str = "./_redirects";
+
+/// One line of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    from: Pattern,
+    to: String,
+    status: StatusCode,
+    force: bool,
+}
+
+/// A rule that matched a request.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Found {
+    /// Where to send the request, with placeholders filled in.
+    pub to: String,
+    pub status: StatusCode,
+    /// Whether the rule applies even if there's a file at the path.
+    pub force: bool,
+}
+
+impl Found {
+    /// Whether this sends the client elsewhere, rather than serving the
+    /// destination in place of the original.
+    pub fn is_redirect(&self) -> bool {
+        self.status.is_redirection()
+    }
+}
+
+impl Rule {
+    /// Checks `path`, a sanitized request path, against the rule.
+    fn find(&self, path: &str, query: Option<&str>) -> Option<Found> {
+        let params = self.from.matches(path)?;
+        let mut to = substitute(&self.to, &params);
+        // Redirects keep the query, unless the rule gives its own.
+        if let (Some(query), true) = (query, self.status.is_redirection()) {
+            if !to.contains('?') {
+                to.push('?');
+                to.push_str(query);
+            }
+        }
+        Some(Found {
+            to,
+            status: self.status,
+            force: self.force,
+        })
+    }
+}
+
+/// Replaces each `:name` in `to` with its value from `params`, encoded again
+/// for a URL. Names start with a letter, so port numbers are left alone.
+fn substitute(to: &str, params: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(to.len());
+    let mut rest = to;
+    while let Some(i) = rest.find(':') {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let len = after
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(after.len());
+        let name = &after[..len];
+        match params.iter().find(|(n, _)| *n == name) {
+            Some((_, value))
+                if name.starts_with(|c: char| c.is_ascii_alphabetic()) =>
+            {
+                out.push_str(&crate::percent::encode_path(value));
+                rest = &after[len..];
+            }
+            _ => {
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Parses the file, returning the rules and a complaint about each line that
+/// had to be skipped.
+pub fn parse(text: &str) -> (Vec<Rule>, Vec<String>) {
+    let mut rules = vec![];
+    let mut skipped = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_rule(line) {
+            Ok(rule) => rules.push(rule),
+            Err(why) => skipped.push(format!("line {}: {}", n + 1, why)),
+        }
+    }
+    (rules, skipped)
+}
+
+fn parse_rule(line: &str) -> Result<Rule, String> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let (from, to, rest) = match &fields[..] {
+        [from, to, rest @ ..] => (*from, *to, rest),
+        _ => return Err("expected FROM TO [STATUS]".to_string()),
+    };
+    if !to.starts_with('/') && !to.contains("://") {
+        return Err(if to.contains('=') {
+            "query parameter matching isn't supported".to_string()
+        } else {
+            format!("bad destination {}", to)
+        });
+    }
+    let (status, force) = match rest {
+        [] => ("301", false),
+        [status] => match status.strip_suffix('!') {
+            Some(status) => (status, true),
+            None => (*status, false),
+        },
+        _ => return Err("conditions aren't supported".to_string()),
+    };
+    let status = status
+        .parse::<u16>()
+        .ok()
+        .and_then(|s| StatusCode::from_u16(s).ok())
+        .filter(|s| {
+            matches!(s.as_u16(), 200 | 301 | 302 | 303 | 307 | 308 | 404)
+        })
+        .ok_or_else(|| format!("unsupported status {}", status))?;
+    if !status.is_redirection() && !to.starts_with('/') {
+        return Err("proxying isn't supported".to_string());
+    }
+    Ok(Rule {
+        from: from.parse()?,
+        to: to.to_string(),
+        status,
+        force,
+    })
+}
+
+/// The rules currently in effect.
+pub struct Redirects(Watched<Vec<Rule>>);
+
+impl Default for Redirects {
+    fn default() -> Self {
+        Redirects(Watched::new(FILE))
+    }
+}
+
+impl Redirects {
+    /// Finds the first rule matching a request for the sanitized path `path`
+    /// with `query`.
+    pub fn find(&self, path: &str, query: Option<&str>) -> Option<Found> {
+        let rules = self.0.get();
+        rules.iter().find_map(|rule| rule.find(path, query))
+    }
+
+    /// Re-reads the file from `root` if it has changed since last time. If it
+    /// has gone, so do the rules.
+    pub async fn reload(&self, log: &slog::Logger, root: &dyn ContentSource) {
+        let rules = self
+            .0
+            .reload(log, root, |text| {
+                let (rules, skipped) = parse(text);
+                for why in skipped {
+                    slog::warn!(log, "skipping redirect: {}", why);
+                }
+                rules
+            })
+            .await;
+        if let Some(rules) = rules {
+            slog::info!(log, "redirects"; "rules" => rules.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let (rules, skipped) = parse(
+            "# comment\n\
+             /old /new\n\
+             /blog/* /posts/:splat 302\n\
+             /news/:year/:id /archive/:year/:id?from=news 301\n\
+             /app/* /app/index.html 200\n\
+             /moved/ https://example.com:8443/ 308!\n\
+             /store id=:id /blog/:id\n\
+             /proxy https://example.com/ 200\n\
+             /geo /uk 302 Country=gb\n\
+             /.well-known/* /wk/:splat 302\n",
+        );
+        assert_eq!(skipped.len(), 3);
+        assert!(skipped[0].starts_with("line 7: "));
+        // Paths come sanitized, as the server matches them.
+        let find = |path: &str, query: Option<&str>| {
+            let path = crate::traversal::sanitize(path);
+            rules
+                .iter()
+                .find_map(|rule| rule.find(&path, query))
+                .map(|f| (f.to, f.status.as_u16(), f.force))
+        };
+        assert_eq!(
+            find("/old/", Some("a=1")),
+            Some(("/new?a=1".into(), 301, false))
+        );
+        assert_eq!(find("/old/more", None), None);
+        assert_eq!(
+            find("/blog/2024/x", None),
+            Some(("/posts/2024/x".into(), 302, false))
+        );
+        assert_eq!(
+            find("/news/2024/7", Some("a=1")),
+            Some(("/archive/2024/7?from=news".into(), 301, false))
+        );
+        assert_eq!(find("/news/2024", None), None);
+        assert_eq!(
+            find("/app/settings", Some("a=1")),
+            Some(("/app/index.html".into(), 200, false))
+        );
+        assert_eq!(
+            find("/moved", None),
+            Some(("https://example.com:8443/".into(), 308, true))
+        );
+        assert_eq!(find("/", None), None);
+        assert_eq!(
+            find("/.well-known/x", None),
+            Some(("/wk/x".into(), 302, false))
+        );
+        // Values go back into a URL encoded, as they came.
+        assert_eq!(
+            find("/blog/a b?/%", None),
+            Some(("/posts/a%20b%3F/%25".into(), 302, false))
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! A `robots.txt` written from the configuration.
+//!
+//! With `--robots-disallow` or `--robots-crawl-delay`, a request for
+//! `/robots.txt` that finds no file is answered with one made from those
+//! options, so crawl policy can be kept with the rest of the server's
+//! configuration. A real file, if there is one, wins.
+
+/// The sanitized path the synthesized file answers for.
+pub const PATH: &str = "./robots.txt";
+
+/// Writes a `robots.txt` with one group, for every crawler, disallowing the
+/// path prefixes in `disallow` and asking for `crawl_delay` seconds between
+/// requests. Returns `None` if there's nothing to say.
+pub fn render(disallow: &[String], crawl_delay: Option<u64>) -> Option<String> {
+    if disallow.is_empty() && crawl_delay.is_none() {
+        return None;
+    }
+    let mut text = "User-agent: *\n".to_string();
+    for prefix in disallow {
+        text.push_str(&format!("Disallow: {}\n", prefix));
+    }
+    if disallow.is_empty() {
+        // A group needs a rule; an empty Disallow allows everything.
+        text.push_str("Disallow:\n");
+    }
+    if let Some(delay) = crawl_delay {
+        text.push_str(&format!("Crawl-delay: {}\n", delay));
+    }
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendering() {
+        assert_eq!(render(&[], None), None);
+        assert_eq!(
+            render(&["/private/".into(), "/tmp".into()], Some(10)).unwrap(),
+            "User-agent: *\n\
+             Disallow: /private/\n\
+             Disallow: /tmp\n\
+             Crawl-delay: 10\n"
+        );
+        assert_eq!(
+            render(&[], Some(5)).unwrap(),
+            "User-agent: *\nDisallow:\nCrawl-delay: 5\n"
+        );
+    }
+}
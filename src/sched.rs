@@ -0,0 +1,80 @@
+//! Process settings for sharing a host politely: the umask, and CPU and I/O
+//! scheduling priority.
+//!
+//! On Linux, niceness and I/O priority belong to threads, not processes, and
+//! new threads inherit them from the thread that creates them. So these have
+//! to be set on the main thread before the runtime (or the logger) starts any
+//! others.
+
+use std::io;
+
+use nix::sys::stat::umask;
+
+use crate::args::{CommonArgs, IoClass};
+
+/// Applies the settings in `args` to this thread and its future children.
+pub fn apply(args: &CommonArgs) -> io::Result<()> {
+    if let Some(mask) = args.umask {
+        umask(mask);
+    }
+    if let Some(nice) = args.nice {
+        // Safety: this takes no pointers.
+        let r = unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(class) = args.io_class {
+        set_io_priority(class, args.io_priority)?;
+    }
+    Ok(())
+}
+
+/// Encodes an I/O class and level the way `ioprio_set` wants them.
+fn ioprio(class: IoClass, level: u8) -> libc::c_int {
+    const CLASS_SHIFT: libc::c_int = 13;
+    let (class, level) = match class {
+        IoClass::Realtime => (1, level),
+        IoClass::BestEffort => (2, level),
+        IoClass::Idle => (3, 0),
+    };
+    class << CLASS_SHIFT | libc::c_int::from(level)
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_priority(class: IoClass, level: u8) -> io::Result<()> {
+    const WHO_PROCESS: libc::c_int = 1;
+    // Safety: this takes no pointers.
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            WHO_PROCESS,
+            0,
+            ioprio(class, level),
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_io_priority(_class: IoClass, _level: u8) -> io::Result<()> {
+    Err(io::Error::other(
+        "--io-class isn't supported on this system",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_priorities() {
+        // As in linux/ioprio.h.
+        assert_eq!(ioprio(IoClass::Realtime, 0), 0x2000);
+        assert_eq!(ioprio(IoClass::BestEffort, 7), 0x4007);
+        assert_eq!(ioprio(IoClass::Idle, 7), 0x6000);
+    }
+}
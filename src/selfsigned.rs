@@ -0,0 +1,282 @@
+//! Self-signed certificates, for getting started.
+//!
+//! `--gen-cert NAME` makes a new ECDSA P-256 key, and a certificate for NAME
+//! signed with that same key, so that a first local run doesn't need an
+//! `openssl` incantation beforehand. No client will trust the certificate
+//! unless told to.
+//!
+//! There's no X.509 library among our dependencies, so the certificate is put
+//! together here, in DER, with just the fields TLS clients look at.
+
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use ring::error::Unspecified;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+/// How long certificates are good for.
+pub const VALIDITY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// A private key and a certificate for it, in PEM.
+pub struct Generated {
+    pub key_pem: String,
+    pub cert_pem: String,
+}
+
+/// Makes a key and a certificate naming each of `names`, which may be host
+/// names or IP addresses, valid for `VALIDITY` from `now`.
+pub fn generate(
+    names: &[String],
+    now: SystemTime,
+) -> Result<Generated, Unspecified> {
+    let rng = SystemRandom::new();
+    let alg = &ECDSA_P256_SHA256_ASN1_SIGNING;
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng)?;
+    let key = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng)?;
+    let mut serial = [0; 16];
+    rng.fill(&mut serial)?;
+    // Serial numbers are positive.
+    serial[0] &= 0x7f;
+
+    let tbs = tbs_certificate(&key, &serial, names, now);
+    let signature = key.sign(&rng, &tbs)?;
+    let cert = der(
+        SEQUENCE,
+        &[tbs, ecdsa_with_sha256(), bit_string(signature.as_ref())].concat(),
+    );
+    Ok(Generated {
+        key_pem: pem("PRIVATE KEY", pkcs8.as_ref()),
+        cert_pem: pem("CERTIFICATE", &cert),
+    })
+}
+
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+
+/// The certificate's contents, as signed.
+fn tbs_certificate(
+    key: &EcdsaKeyPair,
+    serial: &[u8],
+    names: &[String],
+    now: SystemTime,
+) -> Vec<u8> {
+    let name = der(
+        SEQUENCE,
+        &der(
+            SET,
+            &der(
+                SEQUENCE,
+                &[
+                    // commonName
+                    der(OID, &[0x55, 0x04, 0x03]),
+                    der(UTF8_STRING, names[0].as_bytes()),
+                ]
+                .concat(),
+            ),
+        ),
+    );
+    let validity = der(SEQUENCE, &[time(now), time(now + VALIDITY)].concat());
+    let public_key = der(
+        SEQUENCE,
+        &[
+            der(
+                SEQUENCE,
+                &[
+                    // id-ecPublicKey, prime256v1
+                    der(OID, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01]),
+                    der(OID, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07]),
+                ]
+                .concat(),
+            ),
+            bit_string(key.public_key().as_ref()),
+        ]
+        .concat(),
+    );
+    let alt_names = names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            // iPAddress and dNSName, tagged implicitly.
+            Ok(IpAddr::V4(ip)) => der(0x87, &ip.octets()),
+            Ok(IpAddr::V6(ip)) => der(0x87, &ip.octets()),
+            Err(_) => der(0x82, name.as_bytes()),
+        })
+        .collect::<Vec<_>>()
+        .concat();
+    let extensions = der(
+        SEQUENCE,
+        &der(
+            SEQUENCE,
+            &[
+                // subjectAltName
+                der(OID, &[0x55, 0x1d, 0x11]),
+                der(OCTET_STRING, &der(SEQUENCE, &alt_names)),
+            ]
+            .concat(),
+        ),
+    );
+    der(
+        SEQUENCE,
+        &[
+            // Version 3, as needed for extensions.
+            der(0xa0, &der(INTEGER, &[0x02])),
+            der(INTEGER, serial),
+            ecdsa_with_sha256(),
+            name.clone(),
+            validity,
+            name,
+            public_key,
+            der(0xa3, &extensions),
+        ]
+        .concat(),
+    )
+}
+
+/// The AlgorithmIdentifier for ecdsa-with-SHA256.
+fn ecdsa_with_sha256() -> Vec<u8> {
+    der(
+        SEQUENCE,
+        &der(OID, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02]),
+    )
+}
+
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    // No unused bits.
+    der(BIT_STRING, &[&[0][..], bytes].concat())
+}
+
+/// Encodes `time` as certificates want it: UTCTime through 2049, and
+/// GeneralizedTime after.
+fn time(time: SystemTime) -> Vec<u8> {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's civil_from_days.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    let rest = format!(
+        "{:02}{:02}{:02}{:02}{:02}Z",
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    if year < 2050 {
+        der(UTC_TIME, format!("{:02}{}", year % 100, rest).as_bytes())
+    } else {
+        der(GENERALIZED_TIME, format!("{:04}{}", year, rest).as_bytes())
+    }
+}
+
+/// Encodes a DER element with `tag` around `contents`.
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+/// Wraps `der` in PEM armor with `label`.
+fn pem(label: &str, der: &[u8]) -> String {
+    let b64 = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut out = format!("-----BEGIN {}-----\n", label);
+    for line in b64.as_bytes().chunks(64) {
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", label));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use rustls::{ServerConfig, ServerConnection};
+
+    #[test]
+    fn handshake() {
+        let now = SystemTime::now();
+        let generated =
+            generate(&["localhost".into(), "127.0.0.1".into()], now).unwrap();
+        let chain = rustls_pemfile::certs(&mut generated.cert_pem.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key =
+            rustls_pemfile::private_key(&mut generated.key_pem.as_bytes())
+                .unwrap()
+                .unwrap();
+        let expiry = crate::certs::not_after(&chain[0]).unwrap();
+        assert!((now + VALIDITY).duration_since(expiry).unwrap().as_secs() < 1);
+
+        // A client that trusts the certificate should accept it for each
+        // name, and only those.
+        let mut roots = RootCertStore::empty();
+        roots.add(chain[0].clone()).unwrap();
+        let client = Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        let server = Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(chain, key)
+                .unwrap(),
+        );
+        let connect = |name: &str| {
+            let name = ServerName::try_from(name.to_string()).unwrap();
+            let mut client =
+                ClientConnection::new(client.clone(), name).unwrap();
+            let mut server = ServerConnection::new(server.clone()).unwrap();
+            client.writer().write_all(b"hi").unwrap();
+            while client.wants_write() || server.wants_write() {
+                let mut buf = vec![];
+                client.write_tls(&mut buf).unwrap();
+                server.read_tls(&mut &buf[..]).unwrap();
+                server.process_new_packets().unwrap();
+                let mut buf = vec![];
+                server.write_tls(&mut buf).unwrap();
+                client.read_tls(&mut &buf[..]).unwrap();
+                client.process_new_packets()?;
+            }
+            let mut got = [0; 2];
+            server.reader().read_exact(&mut got).unwrap();
+            Ok::<_, rustls::Error>(got)
+        };
+        assert_eq!(connect("localhost").unwrap(), *b"hi");
+        assert_eq!(connect("127.0.0.1").unwrap(), *b"hi");
+        assert!(connect("example.com").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! Counting what was actually sent.
+//!
+//! A `response` record gives the length of the body the server meant to send;
+//! a client that goes away partway through gets less. Bodies are wrapped in
+//! `Counted`, which counts the bytes hyper takes from them. When hyper is done
+//! with the body, having sent all of it or not, it logs a `sent` record with
+//! that count and adds it to `bytes_served`, and to its host's traffic if the
+//! host is being counted.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+
+use crate::err::ServeError;
+use crate::log::ACCESS;
+use crate::serve::BoxBody;
+use crate::stats::Stats;
+
+/// A response body, counting the bytes taken from it.
+pub struct Counted {
+    inner: BoxBody,
+    /// The length the body was meant to have.
+    len: u64,
+    sent: u64,
+    log: slog::Logger,
+    stats: Arc<Stats>,
+    /// The host the body is for, as `Traffic::find` has it.
+    host: Option<usize>,
+}
+
+impl Counted {
+    /// Wraps `inner`, which should be `len` bytes long, reporting to `log` and
+    /// `stats`, for `host`, once it's dropped.
+    pub fn new(
+        inner: BoxBody,
+        len: u64,
+        log: slog::Logger,
+        stats: Arc<Stats>,
+        host: Option<usize>,
+    ) -> Self {
+        Counted {
+            inner,
+            len,
+            sent: 0,
+            log,
+            stats,
+            host,
+        }
+    }
+}
+
+impl Body for Counted {
+    type Data = Bytes;
+    type Error = ServeError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, ServeError>>> {
+        let frame = futures::ready!(self.inner.as_mut().poll_frame(cx));
+        if let Some(data) =
+            frame.as_ref().and_then(|f| f.as_ref().ok()?.data_ref())
+        {
+            self.sent += data.len() as u64;
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.stats.record_sent(self.sent);
+        if let Some(host) = self.host {
+            self.stats.traffic.record_sent(host, self.sent, SystemTime::now());
+        }
+        slog::info!(
+            self.log,
+            #ACCESS,
+            "sent";
+            "bytes" => self.sent,
+            "aborted" => self.sent < self.len,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+    use crate::traffic::Traffic;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn counting() {
+        let stats = Arc::new(Stats::with_traffic(Traffic::new(
+            &["example.com".to_string()],
+            &[],
+        )));
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let body = |text: &'static str| -> BoxBody {
+            Box::pin(Full::new(Bytes::from(text)).map_err(|r| match r {}))
+        };
+
+        let mut counted =
+            Counted::new(body("hello"), 5, log.clone(), stats.clone(), Some(0));
+        while counted.frame().await.is_some() {}
+        drop(counted);
+        assert_eq!(stats.bytes_served.load(Ordering::Relaxed), 5);
+
+        // A body dropped unread counts for nothing.
+        drop(Counted::new(body("hello"), 5, log, stats.clone(), None));
+        assert_eq!(stats.bytes_served.load(Ordering::Relaxed), 5);
+        assert!(stats
+            .traffic
+            .snapshot(SystemTime::now())
+            .contains(&("host.example.com.bytes_served".to_string(), 5)));
+    }
+}
@@ -1,37 +1,138 @@
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::collections::hash_map::DefaultHasher;
 use std::ffi::OsStr;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::pin::Pin;
+use std::time::{Duration, SystemTime};
 
 use bytes::Bytes;
 use futures::stream::StreamExt;
 
 use hyper::body::{Body, Frame};
 use hyper::header::HeaderValue;
-use hyper::{body::Incoming, Method, Request, Response, StatusCode};
-use http_body_util::{StreamBody, BodyExt};
+use hyper::{body::Incoming, Method, Request, Response, StatusCode, Uri};
+use http_body_util::{BodyExt, Full, StreamBody};
 
-use tokio_util::codec::{self, Decoder};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::args::{HasCommonArgs, CommonArgs};
+use crate::args::{
+    BlockAction, HasCommonArgs, CommonArgs, DirectoryIndex, EtagSource, TrailingSlash, UnknownHost,
+    Validators,
+};
+use crate::blocklist::UserAgentBlocklist;
+use crate::buffers::FileStream;
+use crate::bulk::Queued;
+use crate::digest::{self, Wanted};
+use crate::encoding::{self, Encoding};
 use crate::err::ServeError;
-use crate::log::OptionKV;
+use crate::etag::TagCache;
+use crate::fadvise;
+use crate::glob::Glob;
+use crate::headers::{self, SiteHeaders};
+use crate::image::{self, ImageFormat};
+use crate::log::{self, OptionKV, ACCESS};
+use crate::mount::Mounts;
+use crate::jwt::{Bearer, Refusal};
+use crate::oidc::{Gate, Oidc};
+use crate::redirects::{self, Redirects};
+use crate::sent::Counted;
 use crate::picky::{self, File};
-use crate::{percent, traversal};
+use crate::priority::{Priority, Schedule, Scheduled};
+use crate::signed::{Rejection, UrlSigner};
+use crate::source::ContentSource;
+use crate::stats::Stats;
+use crate::sync::{RequestLimit, SharedSemaphore};
+use crate::upload::{Refused, Spool, Stored};
+use crate::{host, percent, proxy, robots, strict, trace, traversal};
 
-fn empty() -> Pin<Box<dyn Body<Data = Bytes, Error = ServeError> + Send>> {
+/// Type-erased response body used throughout the server.
+pub type BoxBody = Pin<Box<dyn Body<Data = Bytes, Error = ServeError> + Send>>;
+
+fn empty() -> BoxBody {
     Box::pin(http_body_util::Empty::new().map_err(|r| match r {}))
 }
 
-/// Attempts to serve a file in response to `req`.
+/// State shared by every request, beyond the arguments.
+pub struct Shared {
+    /// Directories served alongside the root.
+    pub mounts: Mounts,
+    /// Entity tags computed from file contents.
+    pub tags: TagCache,
+    /// Server-wide counters.
+    pub stats: Arc<Stats>,
+    /// Checks links to `--signed` paths, if there are any.
+    pub signer: Option<UrlSigner>,
+    /// Where uploads go, if they're allowed.
+    pub spool: Option<Spool>,
+    /// User-Agents to refuse, if any.
+    pub user_agents: Option<Arc<UserAgentBlocklist>>,
+    /// Requests being worked on.
+    pub requests: RequestLimit,
+    /// Turns at sending large bodies, if they're limited.
+    pub bulk: Option<SharedSemaphore>,
+    /// Rules from `_redirects`, if they're in use.
+    pub redirects: Option<Arc<Redirects>>,
+    /// Headers from `_headers`, if they're in use.
+    pub site_headers: Option<Arc<SiteHeaders>>,
+    /// The identity provider for `--oidc-protect`, if there is one.
+    pub oidc: Option<Oidc>,
+    /// The keys for `--jwt-protect`, if any paths need a bearer token.
+    pub bearer: Option<Bearer>,
+}
+
+impl Shared {
+    /// Whether `path`, sanitized and relative to the root, is one of the files
+    /// in use for configuring the server, which aren't served.
+    fn is_site_file(&self, path: &str) -> bool {
+        (self.redirects.is_some() && path == redirects::FILE)
+            || (self.site_headers.is_some() && path == headers::SITE_FILE)
+    }
+}
+
+/// Attempts to serve a file in response to `req`, which came from `peer` on
+/// a connection whose bodies follow `schedule`, if it has one.
 pub async fn files(
     args: Arc<impl HasCommonArgs>,
+    shared: Arc<Shared>,
+    peer: SocketAddr,
+    schedule: Option<Arc<Schedule>>,
     log: slog::Logger,
     req: Request<Incoming>,
-) -> Result<Response<Pin<Box<dyn Body<Data = Bytes, Error = ServeError> + Send>>>, ServeError> {
-    // We log all requests, whether or not they will be served.
+) -> Result<Response<BoxBody>, ServeError> {
+    // Anything in the response that depends on the current time is computed
+    // from this, so that the headers agree with each other.
+    let now = SystemTime::now();
+
+    // Only uploads have a body we care about, so set it aside.
+    let (parts, body) = req.into_parts();
+    let req = Request::from_parts(parts, ());
+
+    // A trusted proxy may tell us who the client is, and what host and
+    // scheme it asked for.
+    let forwarded = proxy::forwarded(
+        &args.common().trusted_proxies,
+        peer.ip(),
+        req.headers(),
+    );
+    let host = forwarded.host.clone().or_else(|| host::request_host(&req));
+
+    // We log all requests, whether or not they will be served, and to the
+    // host's own log if it has one.
+    let log = log::for_host(args.common(), log, host.clone());
     let method = req.method();
     let uri = req.uri();
+    // The peer was already logged at connect; only mention the client if a
+    // trusted proxy has told us it's someone else.
+    let client = if forwarded.client != peer.ip().to_canonical() {
+        Some(slog::o!("client" => forwarded.client.to_string()))
+    } else {
+        None
+    };
+    let proto = forwarded.proto.as_ref().map(|p| slog::o!("proto" => p.clone()));
     let ua = if args.common().log_user_agent {
         req.headers().get(hyper::header::USER_AGENT).map(|v| {
             // Use HeaderValue's Debug impl to safely print attacker-controlled
@@ -49,13 +150,18 @@ pub async fn files(
     } else {
         None
     };
+    let trace = trace::log_fields(req.headers());
     slog::info!(
         log,
+        #ACCESS,
         "{}", method;
         "uri" => %uri,
         "version" => ?req.version(),
+        OptionKV::from(client),
+        OptionKV::from(proto),
         OptionKV::from(ua),
         OptionKV::from(rfr),
+        OptionKV::from(trace),
     );
 
     // Other than logging, we defer work to the latest reasonable point, to
@@ -64,62 +170,276 @@ pub async fn files(
     // side-channel that opens should be the ability to probe what public files
     // exist on the filesystem ... which is exactly what the HTTP server is for.
 
-    let mut accept_gzip = false;
-    let (mut response, mut response_info) = match (method, uri.path()) {
-        (&Method::GET, path) | (&Method::HEAD, path) => {
-            // Sanitize the path using a derivative of publicfile's algorithm.
-            // It appears that Hyper blocks non-ASCII characters.
-            let mut sanitized = sanitize_path(path);
-
-            // Scan the request headers to see if gzip compressed responses are
-            // OK. We need to do this before consulting the filesystem, but it's
-            // fairly quick.
-            if req
-                .headers()
-                .get_all(hyper::header::ACCEPT_ENCODING)
-                .iter()
-                .filter_map(|list| list.to_str().ok())
-                .any(|list| list.split(',').any(|item| item.trim() == "gzip"))
-            {
-                accept_gzip = true;
-            }
+    // How urgently the client wants the response, for when it's sent.
+    let priority = Priority::of(req.headers());
 
-            // Now, see what the path yields.
-            let open_result = picky_open_with_redirect_and_gzip(
-                &log,
-                &mut sanitized,
-                accept_gzip,
+    // So are requests that are suspiciously unusual, if we're being strict.
+    let invalid = if args.common().strict {
+        strict::check(&req).err()
+    } else {
+        None
+    };
+
+    // Known-bad clients are sent away before any real work is done.
+    let blocked = shared.user_agents.as_ref().is_some_and(|list| {
+        let ua = req.headers().get(hyper::header::USER_AGENT);
+        list.blocks(ua.map_or(b"", HeaderValue::as_bytes))
+    });
+    if blocked && args.common().blocked_user_agent_action == BlockAction::Close {
+        slog::info!(log, #ACCESS, "dropped"; "cause" => "blocked user agent");
+        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "blocked user agent").into());
+    }
+
+    // Hosts with their own traffic count may also have used up their quota.
+    let counted = shared.stats.traffic.find(host.as_deref());
+    let over_quota = counted.and_then(|i| shared.stats.traffic.over_quota(i, now));
+
+    // Requests for hosts we don't serve are redirected or turned away before
+    // anything else.
+    let scheme = forwarded.proto.as_deref().unwrap_or("https");
+    let host_check = check_host(args.common(), &req, host, scheme);
+
+    // Scanners probing for well-known vulnerable paths are kept waiting. The
+    // patterns apply to the path as requested, since that's what scanners
+    // aim at.
+    let tarpitted = {
+        let sanitized = sanitize_path(uri.path());
+        args.common().tarpit.iter().any(|p| p.matches(&sanitized))
+    };
+
+    // Under overload, requests are turned away at once, rather than queueing
+    // for threads and dragging out the wait for everyone. Requests that
+    // we've already decided to refuse don't count.
+    let permit = if invalid.is_some() || blocked || tarpitted || over_quota.is_some() {
+        None
+    } else {
+        Some(shared.requests.try_acquire())
+    };
+    let shed = matches!(permit, Some(None));
+
+    // Some paths need a bearer token or a login first, and the login's
+    // callback is ours to answer.
+    let needs_token = protected_by(args.common(), &args.common().jwt_protect, uri.path());
+    let needs_login = protected_by(args.common(), &args.common().oidc_protect, uri.path());
+    let mut protected = needs_token || needs_login;
+    let admitted = invalid.is_none()
+        && matches!(host_check, HostCheck::Ok)
+        && !blocked
+        && !tarpitted
+        && !shed
+        && over_quota.is_none();
+    let login = if admitted {
+        gate(&shared, &log, &req, needs_token, needs_login, now).await
+    } else {
+        None
+    };
+
+    let mut encodings = vec![];
+    let (mut response, mut response_info) = match (invalid, host_check, method, login) {
+        (Some(reason), _, _, _) => (
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(empty())
+                .unwrap(),
+            ResponseInfo::Error(ErrorContext::Fixed(reason), None),
+        ),
+        _ if blocked => (
+            Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(empty())
+                .unwrap(),
+            ResponseInfo::Error(ErrorContext::Fixed("blocked user agent"), None),
+        ),
+        _ if tarpitted => {
+            shared.stats.tarpitted.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(args.common().tarpit_delay).await;
+            (
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(empty())
+                    .unwrap(),
+                ResponseInfo::Error(ErrorContext::Fixed("tarpit"), None),
             )
-            .await;
+        }
+        _ if shed => {
+            shared.stats.shed.fetch_add(1, Ordering::Relaxed);
+            (
+                Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(hyper::header::RETRY_AFTER, args.common().retry_after)
+                    .body(empty())
+                    .unwrap(),
+                ResponseInfo::Error(ErrorContext::Fixed("overloaded"), None),
+            )
+        }
+        (_, HostCheck::Redirect(location), _, _) => (
+            Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(hyper::header::LOCATION, location)
+                .body(empty())
+                .unwrap(),
+            ResponseInfo::Success(None),
+        ),
+        (_, HostCheck::Reject(status), _, _) => (
+            Response::builder()
+                .status(status)
+                .body(empty())
+                .unwrap(),
+            ResponseInfo::Error(ErrorContext::Fixed("unknown host"), None),
+        ),
+        _ if over_quota.is_some() => (
+            Response::builder()
+                .status(StatusCode::from_u16(509).unwrap())
+                .header(hyper::header::RETRY_AFTER, over_quota.unwrap_or_default())
+                .body(empty())
+                .unwrap(),
+            ResponseInfo::Error(ErrorContext::Fixed("over quota"), None),
+        ),
+        (_, _, _, Some(login)) => login,
+        (_, HostCheck::Ok, &Method::GET, None) | (_, HostCheck::Ok, &Method::HEAD, None) => {
+            // Scan the request headers to see which compressed responses are
+            // OK, and in what order to try them. We need to do this before
+            // consulting the filesystem, but it's fairly quick.
+            encodings = encoding::negotiate(
+                &args.common().encodings,
+                req.headers().get_all(hyper::header::ACCEPT_ENCODING).iter(),
+            );
+            let images = image::negotiate(
+                &args.common().image_formats,
+                req.headers().get_all(hyper::header::ACCEPT).iter(),
+            );
 
-            match open_result {
-                Ok((file, enc)) => {
+            // Now, see what the path yields.
+            let redirected =
+                lookup_redirected(args.common(), &shared, &log, now, &req, &encodings, &images)
+                    .await;
+            let rewritten_status = redirected.status;
+            protected |= redirected.protected;
+
+            match redirected.lookup {
+                Lookup::Found(mut file, enc, validators) => {
                     // Collect the caller's cache date, if present. Because the
                     // date format is fixed as of HTTP/1.1, and because caches
                     // send the *exact* previous date in if-modified-since, we
                     // can get away with doing an exact bytewise date comparison
                     // rather than parsing.
-                    let if_modified_since = req
-                        .headers()
-                        .get(hyper::header::IF_MODIFIED_SINCE)
-                        .and_then(|value| value.to_str().ok());
+                    let header = |name| {
+                        req.headers()
+                            .get(name)
+                            .and_then(|value: &HeaderValue| value.to_str().ok())
+                    };
+                    // A page served with an error status isn't something a
+                    // client would have cached.
+                    let conditions = if rewritten_status.is_some() {
+                        Conditions::default()
+                    } else {
+                        Conditions {
+                            if_modified_since: header(hyper::header::IF_MODIFIED_SINCE),
+                            if_none_match: header(hyper::header::IF_NONE_MATCH),
+                        }
+                    };
 
-                    let (resp, srv) = serve_file(
+                    let validation = validation(
+                        args.common(),
+                        &shared.tags,
+                        validators,
+                        &mut file,
+                        enc,
+                    )
+                    .await;
+                    let wanted = if args.common().digests {
+                        Wanted::from_headers(req.headers())
+                    } else {
+                        Wanted::default()
+                    };
+                    let file_digest = if wanted.any() {
+                        shared.tags.digest(&mut file).await.ok()
+                    } else {
+                        None
+                    };
+                    let (mut resp, srv) = serve_file(
                         args.common(),
+                        now,
                         file,
                         enc,
-                        if_modified_since,
+                        validation,
+                        conditions,
                         method == Method::GET,
                     );
+                    if let Some(status) = rewritten_status {
+                        *resp.status_mut() = status;
+                    }
+                    if let Some(file_digest) = file_digest {
+                        let value = digest::header_value(&file_digest);
+                        // Only a full body is the same as the representation.
+                        if wanted.content && srv.is_some() {
+                            resp.headers_mut().insert(digest::CONTENT_DIGEST, value.clone());
+                        }
+                        if wanted.repr {
+                            resp.headers_mut().insert(digest::REPR_DIGEST, value);
+                        }
+                    }
                     (resp, ResponseInfo::Success(srv))
                 }
-                Err(e) => (
+                Lookup::Redirect(status, location) => (
+                    Response::builder()
+                        .status(status)
+                        .header(hyper::header::LOCATION, location)
+                        .body(empty())
+                        .unwrap(),
+                    ResponseInfo::Success(None),
+                ),
+                Lookup::Missing(ctx) => match synthesized_robots(args.common(), now, uri, method == Method::GET) {
+                    Some(response) => response,
+                    None => (
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(empty())
+                            .unwrap(),
+                        ResponseInfo::Error(ctx, None),
+                    ),
+                },
+                Lookup::Forbidden(ctx) => (
+                    Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(empty())
+                        .unwrap(),
+                    ResponseInfo::Error(ctx, None),
+                ),
+                Lookup::Refused(response, info) => (response, info),
+            }
+        }
+        (_, HostCheck::Ok, &Method::PUT, None) if shared.spool.is_some() => {
+            let spool = shared.spool.as_ref().unwrap();
+            let stored = match upload_name(args.common(), spool, uri.path()) {
+                None => Err(Refused(StatusCode::NOT_IMPLEMENTED, "bad method")),
+                Some(Err(refused)) => Err(refused),
+                Some(Ok(_)) if !spool.authorized(req.headers()) => {
+                    Err(Refused(StatusCode::UNAUTHORIZED, "not authorized"))
+                }
+                Some(Ok(name)) => spool.receive(&log, req.headers(), &name, body).await,
+            };
+            match stored {
+                Ok(stored) => (
                     Response::builder()
-                        .status(StatusCode::NOT_FOUND)
+                        .status(match stored {
+                            Stored::Created => StatusCode::CREATED,
+                            Stored::Replaced => StatusCode::NO_CONTENT,
+                        })
                         .body(empty())
                         .unwrap(),
-                    ResponseInfo::Error(ErrorContext::Error(e), None),
+                    ResponseInfo::Success(None),
                 ),
+                Err(Refused(status, reason)) => {
+                    let mut response = Response::builder().status(status);
+                    if status == StatusCode::UNAUTHORIZED {
+                        response = response.header(hyper::header::WWW_AUTHENTICATE, "Bearer");
+                    }
+                    (
+                        response.body(empty()).unwrap(),
+                        ResponseInfo::Error(ErrorContext::Fixed(reason), None),
+                    )
+                }
             }
         }
         // Any other request method falls here.
@@ -132,28 +452,112 @@ pub async fn files(
         ),
     };
 
-    if let ResponseInfo::Error(_, srv) = &mut response_info {
+    if let (ResponseInfo::Error(ctx, _), true) = (&response_info, args.common().dev) {
+        // Tell the developer what went wrong, rather than hiding it.
+        let reason = match ctx {
+            ErrorContext::Fixed(s) => s.to_string(),
+            ErrorContext::Error(e) => e.to_string(),
+        };
+        let body = format!(
+            "{}\nreason: {}\npath: {}\n",
+            response.status(),
+            reason,
+            sanitize_path(uri.path()),
+        );
+        let headers = response.headers_mut();
+        headers.insert(
+            hyper::header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        headers.insert(hyper::header::CONTENT_LENGTH, body.len().into());
+        headers.insert(hyper::header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        if method != Method::HEAD {
+            *response.body_mut() = Box::pin(Full::new(Bytes::from(body)).map_err(|r| match r {}));
+        }
+    } else if shed {
+        // An error page is more work than we have time for.
+    } else if let ResponseInfo::Error(_, srv) = &mut response_info {
         // Attempt to present the user with an error page.
         slog::debug!(log, "searching for error page");
 
-        let mut redirect =
-            format!("./errors/{:03}.html", response.status().as_u16());
-        // TODO: it would be nice to break the picky combinators out, so I could
-        // have picky_open_with_gzip (no redirect) here.
-        let err_result =
-            picky_open_with_redirect_and_gzip(&log, &mut redirect, accept_gzip)
-                .await;
-        if let Ok((error_page, enc)) = err_result {
-            let (mut r, s) = serve_file(args.common(), error_page, enc, None, true);
+        let nearest = if response.status() == StatusCode::NOT_FOUND {
+            nearest_not_found_page(args.common(), &shared, &log, uri, &encodings).await
+        } else {
+            None
+        };
+        let err_result = match nearest {
+            Some(found) => Ok(found),
+            None => {
+                let mut redirect =
+                    format!("./errors/{:03}.html", response.status().as_u16());
+                // TODO: it would be nice to break the picky combinators out, so I could
+                // have picky_open_with_encoding (no redirect) here.
+                picky_open_with_redirect_and_encoding(&log, shared.mounts.root(), &mut redirect, true, &encodings, &[])
+                    .await
+            }
+        };
+        if let Ok((mut error_page, enc)) = err_result {
+            let validation = validation(
+                args.common(),
+                &shared.tags,
+                args.common().validators,
+                &mut error_page,
+                enc,
+            )
+            .await;
+            let (mut r, s) = serve_file(
+                args.common(),
+                now,
+                error_page,
+                enc,
+                validation,
+                Conditions::default(),
+                true,
+            );
             *r.status_mut() = response.status();
             response = r;
             *srv = s;
         }
     }
 
+    // Nothing behind a login belongs in a shared cache.
+    if protected && response.status().is_success() {
+        let headers = response.headers_mut();
+        let private = match headers.get(hyper::header::CACHE_CONTROL) {
+            Some(value) => format!("private, {}", value.to_str().unwrap_or("")),
+            None => "private".to_string(),
+        };
+        headers.insert(hyper::header::CACHE_CONTROL, HeaderValue::from_str(&private).unwrap());
+    }
+
+    if let Some(server) = &args.common().server_header {
+        response.headers_mut().insert(hyper::header::SERVER, server.clone());
+    }
+    // The site's own rules are about its paths, and the operator's about the
+    // whole of what's requested.
+    if let Some(site_headers) = &shared.site_headers {
+        if let Some(path) = site_path(args.common(), uri.path()) {
+            site_headers.apply(&path, response.headers_mut());
+        }
+    }
+    if !args.common().header_rules.is_empty() {
+        headers::apply(
+            &args.common().header_rules,
+            &sanitize_path(uri.path()),
+            response.headers_mut(),
+        );
+    }
+    if args.common().normalize_headers {
+        headers::normalize(response.headers_mut());
+    }
+
     let log_kv = slog::o!("status" => response.status().as_u16());
     let srv_kv = match &response_info {
         ResponseInfo::Error(_, os) | ResponseInfo::Success(os) => {
+            shared.stats.record_response(response.status());
+            if let Some(i) = counted {
+                shared.stats.traffic.record_request(i);
+            }
             os.as_ref().map(|s| {
                 slog::o!(
                     "len" => s.len,
@@ -162,9 +566,24 @@ pub async fn files(
             })
         }
     };
+    let (ResponseInfo::Error(_, served) | ResponseInfo::Success(served)) = &response_info;
+    if let Some(served) = served {
+        // Count what actually goes out, and say so once it has. Bodies may
+        // have to wait for more urgent ones on the same connection, and large
+        // ones for a turn, first.
+        let mut body = std::mem::replace(response.body_mut(), empty());
+        if let Some(schedule) = &schedule {
+            body = Box::pin(Scheduled::new(body, schedule, priority));
+        }
+        if let Some(turns) = shared.bulk.as_ref().filter(|_| served.len >= args.common().bulk_size) {
+            body = Box::pin(Queued::new(body, turns));
+        }
+        *response.body_mut() = Box::pin(Counted::new(body, served.len, log.clone(), shared.stats.clone(), counted));
+    }
     match response_info {
         ResponseInfo::Error(ErrorContext::Fixed(ctx), _) => slog::info!(
             log,
+            #ACCESS,
             "response";
             log_kv,
             "err" => ctx,
@@ -172,6 +591,7 @@ pub async fn files(
         ),
         ResponseInfo::Error(ErrorContext::Error(e), _) => slog::info!(
             log,
+            #ACCESS,
             "response";
             log_kv,
             "err" => %e,
@@ -179,6 +599,7 @@ pub async fn files(
         ),
         ResponseInfo::Success(_) => slog::info!(
             log,
+            #ACCESS,
             "response";
             log_kv,
             OptionKV::from(srv_kv),
@@ -188,6 +609,363 @@ pub async fn files(
     Ok(response)
 }
 
+/// Checks that `req` has the bearer token and login its path `needs`, and
+/// answers the login's callback. Returns the response to send instead, if
+/// it's stopped.
+async fn gate(
+    shared: &Shared,
+    log: &slog::Logger,
+    req: &Request<()>,
+    needs_token: bool,
+    needs_login: bool,
+    now: SystemTime,
+) -> Option<(Response<BoxBody>, ResponseInfo)> {
+    if let (Some(bearer), true) = (&shared.bearer, needs_token) {
+        if let Err(refusal) = bearer.check(log, req.headers(), now) {
+            return Some(refusal_response(refusal));
+        }
+    }
+    match &shared.oidc {
+        Some(oidc) => login_response(oidc.gate(log, req, needs_login, now).await),
+        None => None,
+    }
+}
+
+/// The response for a request the login gate stopped, if it did.
+fn login_response(gate: Gate) -> Option<(Response<BoxBody>, ResponseInfo)> {
+    Some(match gate {
+        Gate::Pass => return None,
+        Gate::Redirect(location, cookies) => {
+            let mut response = Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(hyper::header::LOCATION, location)
+                .header(hyper::header::CACHE_CONTROL, "no-store");
+            for cookie in cookies {
+                response = response.header(hyper::header::SET_COOKIE, cookie);
+            }
+            (response.body(empty()).unwrap(), ResponseInfo::Success(None))
+        }
+        Gate::Refuse(status, reason) => (
+            Response::builder().status(status).body(empty()).unwrap(),
+            ResponseInfo::Error(ErrorContext::Fixed(reason), None),
+        ),
+    })
+}
+
+/// The response to a request refused for want of a good bearer token.
+fn refusal_response(refusal: Refusal) -> (Response<BoxBody>, ResponseInfo) {
+    let mut response = Response::builder().status(refusal.status);
+    if let Some(challenge) = refusal.challenge {
+        response = response.header(hyper::header::WWW_AUTHENTICATE, challenge);
+    }
+    (
+        response.body(empty()).unwrap(),
+        ResponseInfo::Error(ErrorContext::Fixed(refusal.reason), None),
+    )
+}
+
+/// Outcome of checking the host a request is addressed to.
+enum HostCheck {
+    /// Proceed with the request.
+    Ok,
+    /// Redirect to this location on the canonical host.
+    Redirect(HeaderValue),
+    /// Refuse the request with this status.
+    Reject(StatusCode),
+}
+
+/// Checks `host`, which `req` is addressed to, against the configured
+/// redirects and known hosts. Redirects keep the `scheme` the client used.
+fn check_host<B>(
+    args: &CommonArgs,
+    req: &Request<B>,
+    host: Option<String>,
+    scheme: &str,
+) -> HostCheck {
+    if args.hosts.is_empty() && args.host_redirects.is_empty() {
+        return HostCheck::Ok;
+    }
+
+    let redirect = args
+        .host_redirects
+        .iter()
+        .find(|r| host.as_deref() == Some(&*r.from));
+    if let Some(redirect) = redirect {
+        let path = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        // Both parts have been validated as URI components, so this can't
+        // contain anything that's illegal in a header.
+        let location =
+            HeaderValue::from_str(&format!("{}://{}{}", scheme, redirect.to, path))
+                .unwrap();
+        return HostCheck::Redirect(location);
+    }
+
+    if args.hosts.is_empty()
+        || host.is_some_and(|h| args.hosts.contains(&h))
+    {
+        return HostCheck::Ok;
+    }
+    match args.unknown_host {
+        UnknownHost::Serve => HostCheck::Ok,
+        UnknownHost::Misdirected => {
+            HostCheck::Reject(StatusCode::MISDIRECTED_REQUEST)
+        }
+        UnknownHost::NotFound => HostCheck::Reject(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Outcome of resolving a request path.
+enum Lookup {
+    /// A file to serve, with its alternate encoding if any, and the validators
+    /// to send with it.
+    Found(File, Option<Encoding>, Validators),
+    /// The content lives at this other location.
+    Redirect(StatusCode, HeaderValue),
+    /// There's nothing here.
+    Missing(ErrorContext),
+    /// There's something here, but we won't serve it.
+    Forbidden(ErrorContext),
+    /// What's here needs a token or login the request doesn't have; this is
+    /// the response to send instead.
+    Refused(Response<BoxBody>, ResponseInfo),
+}
+
+/// Outcome of a lookup, once the `_redirects` rules have had their say.
+struct Redirected {
+    lookup: Lookup,
+    /// The status to send it with, if a rule calls for one other than the
+    /// usual.
+    status: Option<StatusCode>,
+    /// Whether a rewrite served a path that needs a token or login.
+    protected: bool,
+}
+
+impl From<Lookup> for Redirected {
+    fn from(lookup: Lookup) -> Self {
+        Redirected {
+            lookup,
+            status: None,
+            protected: false,
+        }
+    }
+}
+
+/// Applies the `_redirects` rules, if there are any, around `lookup` of the
+/// path of `req`. The rules are about the site, so they match the path as the
+/// site sees it, and their destinations on the site are under the prefix. A
+/// rewrite's destination is held to the same --jwt-protect and --oidc-protect
+/// patterns as the path asked for, since it's served under that path's name.
+async fn lookup_redirected(
+    args: &CommonArgs,
+    shared: &Shared,
+    log: &slog::Logger,
+    now: SystemTime,
+    req: &Request<()>,
+    encodings: &[Encoding],
+    images: &[ImageFormat],
+) -> Redirected {
+    let uri = req.uri();
+    let redirects = match &shared.redirects {
+        Some(redirects) => redirects,
+        None => return lookup(args, shared, log, now, uri, encodings, images).await.into(),
+    };
+    let found = site_path(args, uri.path()).and_then(|path| redirects.find(&path, uri.query()));
+    let found = match found {
+        Some(found) => found,
+        None => return lookup(args, shared, log, now, uri, encodings, images).await.into(),
+    };
+    // Unless forced, rules only fill in for missing files.
+    if !found.force {
+        let result = lookup(args, shared, log, now, uri, encodings, images).await;
+        if !matches!(result, Lookup::Missing(_)) {
+            return result.into();
+        }
+    }
+    slog::debug!(log, "redirect rule"; "to" => &found.to, "status" => found.status.as_u16());
+    // Destinations on this site are paths on the site, under the prefix.
+    let to = if found.to.starts_with('/') {
+        request_path(args, &found.to)
+    } else {
+        found.to.clone()
+    };
+    if found.is_redirect() {
+        return match HeaderValue::from_str(&to) {
+            Ok(location) => Lookup::Redirect(found.status, location).into(),
+            Err(_) => Lookup::Missing(ErrorContext::Fixed("bad redirect")).into(),
+        };
+    }
+    let to = match to.parse::<Uri>() {
+        Ok(to) => to,
+        Err(_) => return Lookup::Missing(ErrorContext::Fixed("bad rewrite")).into(),
+    };
+    let needs_token = protected_by(args, &args.jwt_protect, to.path());
+    let needs_login = protected_by(args, &args.oidc_protect, to.path());
+    if let Some(refused) = gate(shared, log, req, needs_token, needs_login, now).await {
+        let (response, info) = refused;
+        return Lookup::Refused(response, info).into();
+    }
+    Redirected {
+        lookup: lookup(args, shared, log, now, &to, encodings, images).await,
+        status: Some(found.status).filter(|&s| s != StatusCode::OK),
+        protected: needs_token || needs_login,
+    }
+}
+
+/// With `--not-found-page`, looks for the page in each directory above the
+/// path of `uri`, nearest first, up to the root (or the prefix).
+async fn nearest_not_found_page(
+    args: &CommonArgs,
+    shared: &Shared,
+    log: &slog::Logger,
+    uri: &Uri,
+    encodings: &[Encoding],
+) -> Option<(File, Option<Encoding>)> {
+    let name = args.not_found_page.as_ref()?;
+    let sanitized = site_path(args, uri.path())?;
+    let mut dir = sanitized.as_str();
+    while let Some(i) = dir.rfind('/') {
+        dir = &dir[..i];
+        let page = format!("{}/{}", dir, name);
+        // A page behind a signature isn't for everyone who misses.
+        if args.signed.iter().any(|p| p.matches(&page)) {
+            continue;
+        }
+        let (source, mut path) = match shared.mounts.resolve(&page) {
+            Some((source, rest)) => (source, rest),
+            None => (shared.mounts.root(), page),
+        };
+        slog::debug!(log, "checking for not-found page"; "path" => &path);
+        let found =
+            picky_open_with_redirect_and_encoding(log, source, &mut path, false, encodings, &[])
+                .await;
+        if let Ok(found) = found {
+            return Some(found);
+        }
+    }
+    None
+}
+
+/// Resolves the path of `uri` to a file, taking the prefix and mount options
+/// into account.
+async fn lookup(
+    args: &CommonArgs,
+    shared: &Shared,
+    log: &slog::Logger,
+    now: SystemTime,
+    uri: &Uri,
+    encodings: &[Encoding],
+    images: &[ImageFormat],
+) -> Lookup {
+    let path = uri.path();
+
+    // With --strip-prefix, paths outside the prefix don't correspond to
+    // anything on disk.
+    let sanitized = match site_path(args, path) {
+        Some(sanitized) => sanitized,
+        None => return Lookup::Missing(ErrorContext::Fixed("outside prefix")),
+    };
+
+    // Some paths are only available through a signed link. Signatures cover
+    // the path as it was sent, since that's what the signer had to work from.
+    if args.signed.iter().any(|p| p.matches(&sanitized)) {
+        let checked = match &shared.signer {
+            Some(signer) => signer.check(path, uri.query(), now),
+            None => Err(Rejection::Unsigned),
+        };
+        if let Err(rejection) = checked {
+            return Lookup::Forbidden(ErrorContext::Fixed(rejection.reason()));
+        }
+    }
+
+    // Content-type overrides apply to the path as the site sees it, before it
+    // is mapped onto a mount.
+    let content_type = args
+        .content_types
+        .iter()
+        .find(|rule| rule.pattern.matches(&sanitized))
+        .map(|rule| rule.content_type);
+    let validators = args
+        .path_validators
+        .iter()
+        .find(|rule| rule.pattern.matches(&sanitized))
+        .map_or(args.validators, |rule| rule.validators);
+
+    // Paths under a mount prefix are served from the mount's directory
+    // instead of the root.
+    // Files that configure the server are for it, not for visitors.
+    let (dir, mut sanitized) = match shared.mounts.resolve(&sanitized) {
+        Some((dir, rest)) => (dir, rest),
+        None if shared.is_site_file(&sanitized) => {
+            return Lookup::Missing(ErrorContext::Fixed("site file"));
+        }
+        None => (shared.mounts.root(), sanitized),
+    };
+    let requested = sanitized.clone();
+
+    // A content-type override says what the file is, whatever its extension,
+    // so it isn't swapped for an image alternate.
+    let images = if content_type.is_some() { &[][..] } else { images };
+
+    let index = args.directory_index == DirectoryIndex::IndexHtml;
+    match picky_open_with_redirect_and_encoding(log, dir, &mut sanitized, index, encodings, images)
+        .await
+    {
+        Ok((file, enc)) => Lookup::Found(
+            File {
+                content_type: content_type.unwrap_or(file.content_type),
+                ..file
+            },
+            enc,
+            validators,
+        ),
+        // A trailing slash after something that isn't a directory. If it's a
+        // file, we may want to send the client to its real name.
+        Err(picky::Error::NotDirectory)
+            if args.file_trailing_slash == TrailingSlash::Redirect
+                && requested.ends_with('/') =>
+        {
+            let file = requested.trim_end_matches('/');
+            match dir.open(log, Path::new(file), &map_content_type, &map_cache_ttl)
+                .await
+            {
+                Ok(_) => {
+                    let location = without_trailing_slash(path, uri.query());
+                    // This is a piece of a valid URI, so it's a valid header.
+                    Lookup::Redirect(
+                        StatusCode::MOVED_PERMANENTLY,
+                        HeaderValue::from_str(&location).unwrap(),
+                    )
+                }
+                Err(_) => {
+                    Lookup::Missing(ErrorContext::Error(picky::Error::NotDirectory))
+                }
+            }
+        }
+        Err(picky::Error::Directory)
+            if args.directory_index == DirectoryIndex::Forbidden =>
+        {
+            Lookup::Forbidden(ErrorContext::Error(picky::Error::Directory))
+        }
+        Err(e) if (args.distinguish_forbidden || args.dev) && e.is_forbidden() => {
+            Lookup::Forbidden(ErrorContext::Error(e))
+        }
+        Err(e) => Lookup::Missing(ErrorContext::Error(e)),
+    }
+}
+
+/// Where to send a request for `path`, which ends in a slash, to drop it.
+/// Leading slashes are collapsed to one, so that a request for
+/// `//example.com/` isn't sent off to another site.
+fn without_trailing_slash(path: &str, query: Option<&str>) -> String {
+    let path = path.trim_end_matches('/').trim_start_matches(['/', '\\']);
+    let mut location = format!("/{}", path);
+    if let Some(query) = query {
+        location.push('?');
+        location.push_str(query);
+    }
+    location
+}
+
 enum ErrorContext {
     Fixed(&'static str),
     Error(picky::Error),
@@ -198,6 +976,13 @@ enum ResponseInfo {
     Success(Option<Served>),
 }
 
+/// Conditional request headers that bear on whether to send a file.
+#[derive(Default)]
+struct Conditions<'a> {
+    if_modified_since: Option<&'a str>,
+    if_none_match: Option<&'a str>,
+}
+
 struct Served {
     len: u64,
     encoding: &'static str,
@@ -207,18 +992,22 @@ struct Served {
 ///
 /// `args` is used to customize generation of some headers.
 ///
-/// `len`, `content_type`, and `modified` are metadata of the file being served.
+/// `now` is the time the request is being handled, for headers that need it.
+///
+/// `len`, `content_type`, and `modified` are metadata of the file being served;
+/// `modified` is `None` if Last-Modified should not be sent.
 ///
 /// `enc` gives the content-encoding of the file, if it is not being served
 /// plain.
 fn start_response(
     args: &CommonArgs,
+    now: SystemTime,
     len: u64,
     content_type: &'static str,
-    modified: &str,
+    modified: Option<&str>,
     ttl: Option<usize>,
     enc: Option<Encoding>,
-) -> Response<Pin<Box<dyn Body<Data = Bytes, Error = ServeError> + Send>>> {
+) -> Response<BoxBody> {
     let mut response = Response::new(empty());
 
     let headers = response.headers_mut();
@@ -228,17 +1017,37 @@ fn start_response(
         hyper::header::CONTENT_TYPE,
         HeaderValue::from_static(content_type),
     );
-    headers.insert(
-        hyper::header::VARY,
-        HeaderValue::from_name(hyper::header::ACCEPT_ENCODING),
-    );
+    // Images may have been picked by Accept, when there are image alternates
+    // to pick from.
+    let vary = if !args.image_formats.is_empty() && content_type.starts_with("image/") {
+        "accept-encoding, accept"
+    } else {
+        "accept-encoding"
+    };
+    headers.insert(hyper::header::VARY, HeaderValue::from_static(vary));
+    let ttl = ttl.unwrap_or(args.default_max_age);
     headers.insert(hyper::header::CACHE_CONTROL,
-        HeaderValue::from_str(&format!("max-age={}", ttl.unwrap_or(args.default_max_age))).unwrap()
-    );
-    headers.insert(
-        hyper::header::LAST_MODIFIED,
-        HeaderValue::from_str(modified).unwrap(),
+        HeaderValue::from_str(&format!("max-age={}", ttl)).unwrap()
     );
+    if args.expires {
+        // Set the date ourselves, rather than letting Hyper do it from its own
+        // clock, so that it's consistent with the expiry.
+        headers.insert(
+            hyper::header::DATE,
+            HeaderValue::from_str(&httpdate::fmt_http_date(now)).unwrap(),
+        );
+        let expires = now + Duration::from_secs(ttl as u64);
+        headers.insert(
+            hyper::header::EXPIRES,
+            HeaderValue::from_str(&httpdate::fmt_http_date(expires)).unwrap(),
+        );
+    }
+    if let Some(modified) = modified {
+        headers.insert(
+            hyper::header::LAST_MODIFIED,
+            HeaderValue::from_str(modified).unwrap(),
+        );
+    }
     if let Some(enc) = enc {
         headers.insert(hyper::header::CONTENT_ENCODING, enc.into());
     }
@@ -265,91 +1074,153 @@ fn start_response(
 /// `picky_open` to search for an `index.html` file within that directory. If
 /// the `index.html` has the appropriate permissions and is a regular file, the
 /// open operation succeeds, returning its contents.
+///
+/// If `index` is false, no retry happens, and directories produce
+/// `picky::Error::Directory` whether or not the path ends in a slash.
 async fn picky_open_with_redirect(
     log: &slog::Logger,
+    dir: &dyn ContentSource,
     path: &mut String,
+    index: bool,
 ) -> Result<File, picky::Error> {
     // Performance optimization: if the path is *syntactically* a directory,
     // i.e. it ends in a slash, pre-append the `index.html`. This reduces
     // filesystem round trips (and thus the number of blocking operations
     // affecting the thread pool) by 1, and improved a particular load benchmark
     // by 18% at the time of writing.
-    let trailing_slash = path.ends_with('/');
+    let trailing_slash = index && path.ends_with('/');
     if trailing_slash {
         path.push_str("index.html");
     }
 
-    match picky::open(log, Path::new(path), map_content_type, map_cache_ttl).await {
-        Err(picky::Error::Directory) if !trailing_slash => {
+    match dir.open(log, Path::new(path), &map_content_type, &map_cache_ttl).await {
+        Err(picky::Error::Directory) if index && !trailing_slash => {
             slog::debug!(log, "--> index.html");
             path.push_str("/index.html");
-            picky::open(log, Path::new(path), map_content_type, map_cache_ttl).await
+            dir.open(log, Path::new(path), &map_content_type, &map_cache_ttl).await
         }
         r => r,
     }
 }
 
-/// Extends `picky_open_with_redirect` with selection of precompressed
-/// alternate files.
+/// Extends `picky_open_with_redirect` with selection of image and
+/// precompressed alternate files.
+///
+/// If the file at `path` is a picture that can have image alternates, those
+/// in `images` are tried first, by appending each format's extension. An
+/// image alternate is served with its own content-type and never compressed,
+/// since image formats are compressed already.
 ///
 /// When `picky_open_with_redirect` finds a readable regular file at `path`,
 /// this routine will retry to search for a compressed version of the file with
-/// the same name and the `.gz` extension appended. If the compressed version
-/// exists, passes `picky_open`'s criteria, *and* has a last-modified date at
-/// least as recent as the original file, then it is substituted.
+/// the same name and the extension of each of `encodings` appended, in order.
+/// If a compressed version exists, passes `picky_open`'s criteria, *and* has a
+/// last-modified date at least as recent as the original file, then it is
+/// substituted.
 ///
 /// Importantly, the content-type judgment for the *original*, non-compressed
 /// file, is preserved.
 ///
 /// Returns the normal `File` result, plus an optional `Content-Encoding` value
 /// if an alternate encoding was selected.
-async fn picky_open_with_redirect_and_gzip(
+async fn picky_open_with_redirect_and_encoding(
     log: &slog::Logger,
+    dir: &dyn ContentSource,
     path: &mut String,
-    accept_gzip: bool,
+    index: bool,
+    encodings: &[Encoding],
+    images: &[ImageFormat],
 ) -> Result<(File, Option<Encoding>), picky::Error> {
-    let file = picky_open_with_redirect(log, path).await?;
+    let file = picky_open_with_redirect(log, dir, path, index).await?;
+
+    let file = if !images.is_empty() && image::convertible(file.content_type) {
+        match open_image_alternate(log, dir, path, file, images).await {
+            Ok(alt) => return Ok((alt, None)),
+            Err(file) => file,
+        }
+    } else {
+        file
+    };
 
-    if !accept_gzip {
+    if encodings.is_empty() {
         return Ok((file, None));
     }
 
-    open_precompressed(log, path, file).await
+    open_precompressed(log, dir, path, file, encodings).await
 }
 
 async fn open_precompressed(
     log: &slog::Logger,
+    dir: &dyn ContentSource,
     path: &mut String,
     file: File,
+    encodings: &[Encoding],
 ) -> Result<(File, Option<Encoding>), picky::Error> {
-    slog::debug!(log, "checking for precompressed alternate");
-    path.push_str(".gz");
-    // Note that we're "inferring" the old content-type.
-    match picky::open(log, Path::new(path), |_| file.content_type, |_| file.ttl).await {
-        Ok(gzfile) if gzfile.modified >= file.modified => {
-            slog::debug!(log, "serving gzip");
-            // Preserve mod date of original content.
-            Ok((
-                File {
-                    modified: file.modified,
-                    ..gzfile
-                },
-                Some(Encoding::Gzip),
-            ))
-        }
-        _ => {
+    let base_len = path.len();
+    let (content_type, ttl) = (file.content_type, file.ttl);
+    for &enc in encodings {
+        slog::debug!(log, "checking for precompressed alternate"; "enc" => enc.token());
+        path.truncate(base_len);
+        path.push_str(enc.extension());
+        // Note that we're "inferring" the old content-type.
+        match dir.open(log, Path::new(path), &|_| content_type, &|_| ttl).await {
+            Ok(altfile) if altfile.modified >= file.modified => {
+                slog::debug!(log, "serving {}", enc.token());
+                // Preserve mod date of original content.
+                return Ok((
+                    File {
+                        modified: file.modified,
+                        ..altfile
+                    },
+                    Some(enc),
+                ));
+            }
             // If the compressed alternative isn't available, or if it
             // predates the actual content, ignore it.
-            slog::debug!(log, "serving uncompressed");
-            Ok((file, None))
+            _ => (),
+        }
+    }
+    slog::debug!(log, "serving uncompressed");
+    path.truncate(base_len);
+    Ok((file, None))
+}
+
+/// Looks for an alternate of the image `file`, at `path`, in each of
+/// `images` in turn. Like precompressed alternates, one is only used if it's at
+/// least as recent as the original, and it keeps the original's modification
+/// date. Gives `file` back if there's none to use.
+async fn open_image_alternate(
+    log: &slog::Logger,
+    dir: &dyn ContentSource,
+    path: &mut String,
+    file: File,
+    images: &[ImageFormat],
+) -> Result<File, File> {
+    let base_len = path.len();
+    let ttl = file.ttl;
+    for &format in images {
+        slog::debug!(log, "checking for image alternate"; "type" => format.content_type());
+        path.truncate(base_len);
+        path.push_str(format.extension());
+        match dir.open(log, Path::new(path), &|_| format.content_type(), &|_| ttl).await {
+            Ok(altfile) if altfile.modified >= file.modified => {
+                slog::debug!(log, "serving {}", format.content_type());
+                return Ok(File {
+                    modified: file.modified,
+                    ..altfile
+                });
+            }
+            _ => (),
         }
     }
+    path.truncate(base_len);
+    Err(file)
 }
 
 /// Guesses the `Content-Type` of a file based on its path.
 ///
 /// Currently, this is hardcoded based on file extensions, like we're Windows.
-fn map_content_type(path: &Path) -> &'static str {
+pub fn map_content_type(path: &Path) -> &'static str {
     match path.extension().and_then(OsStr::to_str) {
         Some("html") => "text/html",
         Some("css") => "text/css",
@@ -378,44 +1249,162 @@ fn map_cache_ttl(path: &Path) -> Option<usize> {
     }
 }
 
+/// The request path `path` as the site sees it: sanitized, using a derivative
+/// of publicfile's algorithm, and with `--strip-prefix` removed. Paths outside
+/// the prefix aren't part of the site, and give `None`.
+fn site_path(args: &CommonArgs, path: &str) -> Option<String> {
+    // It appears that Hyper blocks non-ASCII characters.
+    let sanitized = sanitize_path(path);
+    match &args.strip_prefix {
+        Some(prefix) => traversal::strip_prefix(prefix, &sanitized),
+        None => Some(sanitized),
+    }
+}
+
+/// The request path for `path`, a path on the site: under the prefix, with
+/// `--strip-prefix`.
+fn request_path(args: &CommonArgs, path: &str) -> String {
+    match &args.strip_prefix {
+        // Sanitized, the prefix starts with a dot.
+        Some(prefix) => format!("{}{}", &prefix[1..], path),
+        None => path.to_string(),
+    }
+}
+
+/// Where the spool would store an upload to the request path `path`, if it's
+/// under the upload prefix, which, like a mount's, applies after
+/// `--strip-prefix`.
+fn upload_name(args: &CommonArgs, spool: &Spool, path: &str) -> Option<Result<String, Refused>> {
+    spool.file_name(&site_path(args, path)?)
+}
+
+/// Whether the request path `path` is protected by any of `patterns`, which,
+/// like other path patterns, apply after `--strip-prefix`. A pattern protects
+/// what it matches and everything beneath that; a directory counts as matched
+/// if its contents would, since its index is served under its own name.
+fn protected_by(args: &CommonArgs, patterns: &[Glob], path: &str) -> bool {
+    let path = match site_path(args, path) {
+        Some(path) => path,
+        None => return false,
+    };
+    let covered = |path: &str| patterns.iter().any(|p| p.matches(path));
+    // Each ancestor of the path, and the path itself, with and without a
+    // trailing slash.
+    let dir = format!("{}/", path.trim_end_matches('/'));
+    dir.match_indices('/').any(|(i, _)| covered(&dir[..i]) || covered(&dir[..=i]))
+}
+
+/// Percent-decodes and sanitizes a request path, in one pass and one
+/// allocation. Decoding never lengthens the path.
 fn sanitize_path(path: &str) -> String {
-    traversal::sanitize(percent::decode(path.chars())).collect()
+    let mut out = traversal::Sanitizer::with_capacity(path.len());
+    percent::decode(path, |c| out.push(c));
+    out.finish()
 }
 
-#[derive(Copy, Clone, Debug)]
-enum Encoding {
-    Gzip,
+/// Cache validators to send with a file.
+struct Validation {
+    /// Whether to send Last-Modified.
+    last_modified: bool,
+    /// The entity tag to send, if any.
+    etag: Option<String>,
 }
 
-impl From<Encoding> for HeaderValue {
-    fn from(e: Encoding) -> Self {
-        match e {
-            Encoding::Gzip => HeaderValue::from_static("gzip"),
+/// Works out the validators called for by `validators` for `file` as served
+/// with `encoding`.
+async fn validation(
+    args: &CommonArgs,
+    tags: &TagCache,
+    validators: Validators,
+    file: &mut File,
+    encoding: Option<Encoding>,
+) -> Validation {
+    let etag = if !validators.etag() {
+        None
+    } else {
+        match args.etag_source {
+            EtagSource::Metadata => Some(metadata_tag(file, encoding)),
+            // If the file can't be read now, it can't be served either, so
+            // the missing tag won't be missed.
+            EtagSource::Content => tags.get(file).await.ok(),
         }
+    };
+    Validation {
+        last_modified: validators.last_modified(),
+        etag,
     }
 }
 
+/// Computes an entity tag for `file` as served with `encoding` from its
+/// metadata.
+///
+/// The tag is a hash of the file's length and modification time, rather than
+/// the values themselves, so that it doesn't reveal the mtime to clients when
+/// Last-Modified is turned off. Alternates get distinct tags, since their bytes
+/// differ.
+fn metadata_tag(file: &File, encoding: Option<Encoding>) -> String {
+    let mut hasher = DefaultHasher::new();
+    file.len.hash(&mut hasher);
+    file.modified.hash(&mut hasher);
+    encoding.map(Encoding::token).hash(&mut hasher);
+    // Image alternates differ from the original by type instead.
+    file.content_type.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Checks whether `etag` is among the tags listed in an `If-None-Match` header,
+/// using the weak comparison that header calls for.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|tag| {
+        tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+    })
+}
+
 fn serve_file(
     args: &CommonArgs,
+    now: SystemTime,
     file: File,
     encoding: Option<Encoding>,
-    if_modified_since: Option<&str>,
+    validation: Validation,
+    conditions: Conditions<'_>,
     send_body: bool,
-) -> (Response<Pin<Box<dyn Body<Data = Bytes, Error = ServeError> + Send>>>, Option<Served>) {
+) -> (Response<BoxBody>, Option<Served>) {
+    let etag = validation.etag;
     // Go ahead and format the modification date as a string, since we'll need
     // it for the response headers and the if-modified-since check (where
     // relevant).
-    let modified = httpdate::fmt_http_date(file.modified);
+    let modified = if validation.last_modified {
+        Some(httpdate::fmt_http_date(file.modified))
+    } else {
+        None
+    };
 
-    // Check if-modified-since before handing off the modified string.
-    let cached = if_modified_since == Some(&*modified);
+    // If-None-Match takes precedence over If-Modified-Since when we're able
+    // to evaluate it.
+    let cached = match (&etag, conditions.if_none_match) {
+        (Some(etag), Some(inm)) => etag_matches(inm, etag),
+        _ => modified.is_some() && conditions.if_modified_since == modified.as_deref(),
+    };
 
     // Construct the basic response.
-    let mut response =
-        start_response(args, file.len, file.content_type, &*modified, file.ttl, encoding);
+    let mut response = start_response(
+        args,
+        now,
+        file.len,
+        file.content_type,
+        modified.as_deref(),
+        file.ttl,
+        encoding,
+    );
+    if let Some(etag) = etag {
+        // Hex digits in quotes are always a valid header value.
+        response
+            .headers_mut()
+            .insert(hyper::header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    }
 
-    // If a last-modified date was provided, and it matches, we want to
-    // uniformly return a 304 without a body to both GET and HEAD requests.
+    // If a validator was provided, and it matches, we want to uniformly
+    // return a 304 without a body to both GET and HEAD requests.
     if cached || !send_body {
         if cached {
             *response.status_mut() = StatusCode::NOT_MODIFIED;
@@ -423,27 +1412,76 @@ fn serve_file(
         (response, None)
     } else {
         // !cached && send_body
-        // A GET request without a matching last-modified.
-        *response.body_mut() = Box::pin(StreamBody::new(
-            codec::BytesCodec::new()
-                .framed(file.file)
-                .map(|b| b.map(bytes::BytesMut::freeze))
-                .map(|b| b.map(Frame::data))
-                .map(|r| r.map_err(ServeError::from))
-        ));
+        // A GET request without a matching validator.
+        let large = args.fadvise_threshold.is_some_and(|t| file.len >= t);
+        let fd = if large { file.file.fd() } else { None };
+        if let Some(fd) = fd {
+            fadvise::sequential(&fd);
+        }
+        *response.body_mut() = match fd {
+            Some(fd) if args.fadvise_dontneed => {
+                file_body(fadvise::DropCache::new(fd, file.file))
+            }
+            _ if file.len <= SMALL_FILE => small_file_body(file.file, file.len),
+            _ => file_body(file.file),
+        };
         (
             response,
             Some(Served {
                 len: file.len,
-                encoding: match encoding {
-                    None => "raw",
-                    Some(Encoding::Gzip) => "gzip",
-                },
+                encoding: encoding.map_or("raw", Encoding::token),
             }),
         )
     }
 }
 
+/// Makes up a `robots.txt` from the configuration, if `uri` asks for one and
+/// the options call for it.
+fn synthesized_robots(
+    args: &CommonArgs,
+    now: SystemTime,
+    uri: &Uri,
+    send_body: bool,
+) -> Option<(Response<BoxBody>, ResponseInfo)> {
+    if sanitize_path(uri.path()) != robots::PATH {
+        return None;
+    }
+    let text = robots::render(&args.robots_disallow, args.robots_crawl_delay)?;
+    let len = text.len() as u64;
+    let mut response = start_response(args, now, len, "text/plain; charset=utf-8", None, None, None);
+    if !send_body {
+        return Some((response, ResponseInfo::Success(None)));
+    }
+    *response.body_mut() = Box::pin(Full::new(Bytes::from(text)).map_err(|r| match r {}));
+    Some((response, ResponseInfo::Success(Some(Served { len, encoding: "raw" }))))
+}
+
+/// Files up to this size are read whole, rather than streamed.
+const SMALL_FILE: u64 = 64 * 1024;
+
+/// Reads the `len` bytes of `file` into a body of one frame. For small files,
+/// this takes one buffer and one read, where streaming takes several of each.
+fn small_file_body(
+    file: impl AsyncRead + Send + Unpin + 'static,
+    len: u64,
+) -> BoxBody {
+    let read = async move {
+        let mut buf = Vec::with_capacity(len as usize);
+        file.take(len).read_to_end(&mut buf).await?;
+        Ok(Frame::data(Bytes::from(buf)))
+    };
+    Box::pin(StreamBody::new(futures::stream::once(read)))
+}
+
+/// Streams the contents of `file` as a response body.
+fn file_body(file: impl AsyncRead + Send + Unpin + 'static) -> BoxBody {
+    Box::pin(StreamBody::new(
+        FileStream::new(file)
+            .map(|b| b.map(Frame::data))
+            .map(|r| r.map_err(ServeError::from))
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -455,4 +1493,142 @@ mod tests {
         assert_eq!(sanitize_path("%2f%2e%2e"), "./:.");
         assert_eq!(sanitize_path("%2f%2e%2e%00"), "./:._");
     }
+
+    #[test]
+    fn etag_matching() {
+        let etag = "\"0123456789abcdef\"";
+        assert!(etag_matches("\"0123456789abcdef\"", etag));
+        assert!(etag_matches("W/\"0123456789abcdef\"", etag));
+        assert!(etag_matches("\"x\", \"0123456789abcdef\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"x\"", etag));
+        assert!(!etag_matches("0123456789abcdef", etag));
+    }
+
+    #[test]
+    fn prefixed_paths() {
+        use clap::Parser;
+
+        let args = CommonArgs::parse_from(["httpd2", "--strip-prefix=/site/", "root"]);
+        assert_eq!(site_path(&args, "/site/a/%2e%2e").as_deref(), Some("./a/:."));
+        assert_eq!(site_path(&args, "/other/a"), None);
+        assert_eq!(request_path(&args, "/a?b"), "/site/a?b");
+        let args = CommonArgs::parse_from(["httpd2", "--strip-prefix=/", "root"]);
+        assert_eq!(request_path(&args, "/a"), "/a");
+    }
+
+    #[test]
+    fn prefixed_uploads() {
+        use clap::Parser;
+
+        let dir = std::env::temp_dir();
+        let token = dir.join(format!("httpd2-serve-upload-test-{}", std::process::id()));
+        std::fs::write(&token, "s3cret").unwrap();
+        let args = CommonArgs::parse_from([
+            "httpd2",
+            "--strip-prefix=/site",
+            &format!("--upload=/drop={}", dir.display()),
+            &format!("--upload-token={}", token.display()),
+            "root",
+        ]);
+        let spool = Spool::open(args.upload.as_ref().unwrap(), &token, 10).unwrap();
+        std::fs::remove_file(&token).unwrap();
+        // An upload lands where the same URL would be served from.
+        let name = |path| upload_name(&args, &spool, path).and_then(Result::ok);
+        assert_eq!(name("/site/drop/a.tar").as_deref(), Some("a.tar"));
+        assert_eq!(name("/drop/a.tar"), None);
+        assert_eq!(name("/site/a.tar"), None);
+    }
+
+    #[test]
+    fn protected_paths() {
+        use clap::Parser;
+
+        let args = CommonArgs::parse_from([
+            "httpd2",
+            "--strip-prefix=/site",
+            "--jwt-keys=keys.json",
+            "--jwt-protect=/private/*",
+            "--oidc-issuer=https://id.example.com",
+            "--oidc-client-id=docs",
+            "--oidc-client-secret=docs.secret",
+            "--oidc-redirect-uri=https://example.com/callback",
+            "--oidc-protect=/docs/*",
+            "root",
+        ]);
+        let protected = |path| protected_by(&args, &args.jwt_protect, path);
+        assert!(protected("/site/private/a"));
+        assert!(protected("/site//private/%61"));
+        assert!(!protected("/site/public/a"));
+        // A pattern covers the whole tree, and the directory itself, which
+        // serves its index.
+        assert!(protected("/site/private"));
+        assert!(protected("/site/private/"));
+        assert!(protected("/site/private/a/b"));
+        assert!(protected("/site/private/a/b/"));
+        assert!(!protected("/site/privately"));
+        assert!(!protected("/site/"));
+        // Outside the prefix, nothing is served, so nothing is protected.
+        assert!(!protected("/private/a"));
+        let login = |path| protected_by(&args, &args.oidc_protect, path);
+        assert!(login("/site/docs"));
+        assert!(login("/site/docs/"));
+        assert!(login("/site/docs/a"));
+        assert!(login("/site/docs/a/b"));
+        assert!(!login("/site/docsy"));
+        assert!(!login("/docs/a"));
+    }
+
+    #[test]
+    fn host_checks() {
+        use clap::Parser;
+
+        let args = CommonArgs::parse_from([
+            "httpd2",
+            "--host=example.com",
+            "--redirect-host=www.example.com=example.com",
+            "root",
+        ]);
+        let check = |host: &str, uri: &str| {
+            let req = Request::get(uri).header("host", host).body(()).unwrap();
+            match check_host(&args, &req, host::request_host(&req), "https") {
+                HostCheck::Ok => "ok".to_string(),
+                HostCheck::Redirect(loc) => loc.to_str().unwrap().to_string(),
+                HostCheck::Reject(status) => status.as_str().to_string(),
+            }
+        };
+        assert_eq!(check("example.com", "/"), "ok");
+        assert_eq!(check("Example.com:443", "/"), "ok");
+        assert_eq!(check("other.com", "/"), "421");
+        assert_eq!(check("www.example.com", "/a?b"), "https://example.com/a?b");
+        assert_eq!(
+            check("other.com", "https://www.example.com/"),
+            "https://example.com/"
+        );
+        // Behind a proxy, the client may have used plain HTTP.
+        let req = Request::get("/").body(()).unwrap();
+        assert!(matches!(
+            check_host(&args, &req, Some("www.example.com".into()), "http"),
+            HostCheck::Redirect(loc) if loc == "http://example.com/"
+        ));
+    }
+
+    #[test]
+    fn trailing_slash_redirects() {
+        assert_eq!(without_trailing_slash("/a/page.html/", None), "/a/page.html");
+        assert_eq!(without_trailing_slash("/page.html//", Some("x=1")), "/page.html?x=1");
+        // Never somewhere else.
+        assert_eq!(without_trailing_slash("//example.com/", None), "/example.com");
+        assert_eq!(without_trailing_slash("/\\example.com/", None), "/example.com");
+    }
+
+    #[tokio::test]
+    async fn small_files() {
+        // A file that has grown since it was measured is cut off at the
+        // length already promised.
+        let mut body = small_file_body(&b"hello, world"[..], 5);
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "hello");
+        assert!(body.frame().await.is_none());
+    }
 }
@@ -0,0 +1,207 @@
+//! Private-key operations done by another process.
+//!
+//! With `--key-signer`, the server never holds its private key. Instead it
+//! starts a helper command, before dropping privileges, and asks it for each
+//! signature the TLS handshake needs. The helper can keep the key wherever it
+//! likes -- a file only root can read, a PKCS#11 token, an HSM -- and a bug in
+//! the server can at worst get things signed while it runs, not steal the key.
+//!
+//! The helper reads requests on stdin, one per line:
+//!
+//! ```text
+//! sign SCHEME HEX
+//! ```
+//!
+//! where `SCHEME` is a TLS signature scheme name as rustls spells it (say,
+//! `ECDSA_NISTP256_SHA256` or `RSA_PSS_SHA256`) and `HEX` is the message to
+//! sign. It answers each with a line holding the signature in hex, or with a
+//! line starting `error` if it can't.
+//!
+//! rustls asks for signatures synchronously, so each one holds up the worker
+//! thread it runs on for a round trip to the helper, and requests are handled
+//! one at a time.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use rustls::sign::{Signer, SigningKey};
+use rustls::{SignatureAlgorithm, SignatureScheme};
+
+use crate::signed::parse_hex;
+
+/// The kind of key the helper holds, which decides the signature schemes we
+/// offer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeyType {
+    /// RSA, with PSS or PKCS#1 v1.5 padding.
+    Rsa,
+    /// ECDSA on the P-256 curve.
+    EcdsaP256,
+    /// ECDSA on the P-384 curve.
+    EcdsaP384,
+    /// Ed25519.
+    Ed25519,
+}
+
+impl KeyType {
+    /// Signature schemes this kind of key can produce, most preferred first.
+    fn schemes(self) -> &'static [SignatureScheme] {
+        match self {
+            KeyType::Rsa => &[
+                SignatureScheme::RSA_PSS_SHA512,
+                SignatureScheme::RSA_PSS_SHA384,
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA512,
+                SignatureScheme::RSA_PKCS1_SHA384,
+                SignatureScheme::RSA_PKCS1_SHA256,
+            ],
+            KeyType::EcdsaP256 => &[SignatureScheme::ECDSA_NISTP256_SHA256],
+            KeyType::EcdsaP384 => &[SignatureScheme::ECDSA_NISTP384_SHA384],
+            KeyType::Ed25519 => &[SignatureScheme::ED25519],
+        }
+    }
+
+    fn algorithm(self) -> SignatureAlgorithm {
+        match self {
+            KeyType::Rsa => SignatureAlgorithm::RSA,
+            KeyType::EcdsaP256 | KeyType::EcdsaP384 => {
+                SignatureAlgorithm::ECDSA
+            }
+            KeyType::Ed25519 => SignatureAlgorithm::ED25519,
+        }
+    }
+}
+
+/// A private key held by a helper process.
+#[derive(Debug)]
+pub struct ExternalKey {
+    key_type: KeyType,
+    helper: Arc<Mutex<Helper>>,
+}
+
+#[derive(Debug)]
+struct Helper {
+    // Held so the helper isn't reaped until we're done with it.
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl ExternalKey {
+    /// Starts `command`, with the shell, as the helper for a key of type
+    /// `key_type`.
+    pub fn spawn(command: &str, key_type: KeyType) -> io::Result<Self> {
+        let mut child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(ExternalKey {
+            key_type,
+            helper: Arc::new(Mutex::new(Helper {
+                _child: child,
+                stdin,
+                stdout,
+            })),
+        })
+    }
+}
+
+impl SigningKey for ExternalKey {
+    fn choose_scheme(
+        &self,
+        offered: &[SignatureScheme],
+    ) -> Option<Box<dyn Signer>> {
+        let scheme = *self
+            .key_type
+            .schemes()
+            .iter()
+            .find(|s| offered.contains(s))?;
+        Some(Box::new(ExternalSigner {
+            scheme,
+            helper: self.helper.clone(),
+        }))
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.key_type.algorithm()
+    }
+}
+
+#[derive(Debug)]
+struct ExternalSigner {
+    scheme: SignatureScheme,
+    helper: Arc<Mutex<Helper>>,
+}
+
+impl Signer for ExternalSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        let mut helper = self.helper.lock().unwrap();
+        helper
+            .request(self.scheme, message)
+            .map_err(|e| rustls::Error::General(format!("key signer: {}", e)))
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+impl Helper {
+    fn request(
+        &mut self,
+        scheme: SignatureScheme,
+        message: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        self.stdin.write_all(request(scheme, message).as_bytes())?;
+        self.stdin.flush()?;
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        parse_hex(line)
+            .filter(|sig| !sig.is_empty())
+            .ok_or_else(|| io::Error::other(line.to_string()))
+    }
+}
+
+/// Forms the line asking for `message` to be signed with `scheme`.
+fn request(scheme: SignatureScheme, message: &[u8]) -> String {
+    let hex = message
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("sign {:?} {}\n", scheme, hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(
+            request(SignatureScheme::ECDSA_NISTP256_SHA256, b"\x01\xab"),
+            "sign ECDSA_NISTP256_SHA256 01ab\n"
+        );
+
+        // A helper that answers one request, then quits.
+        let key =
+            ExternalKey::spawn("read line; echo c0ff", KeyType::Rsa).unwrap();
+        assert!(key.choose_scheme(&[SignatureScheme::ED25519]).is_none());
+        let signer = key
+            .choose_scheme(&[
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::RSA_PSS_SHA256,
+            ])
+            .unwrap();
+        assert_eq!(signer.scheme(), SignatureScheme::RSA_PSS_SHA256);
+        assert_eq!(signer.sign(b"hello").unwrap(), b"\xc0\xff");
+        assert!(signer.sign(b"hello").is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Configuration that travels with the site.
+//!
+//! Some settings can live in the content directory, so that they're deployed
+//! along with the pages they're about: `_redirects` and `_headers`, in the
+//! formats Netlify uses. The files are read through the root's
+//! `ContentSource`, and so have to pass the same checks as anything being
+//! served, but aren't served themselves. They're checked for changes every
+//! couple of seconds, and reread when they change.
+//!
+//! Both formats match request paths against the same kind of pattern, where
+//! `:name` stands for one path segment and a final `*` for the rest.
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio::io::AsyncReadExt;
+
+use crate::source::ContentSource;
+
+/// How often to check the files for changes.
+pub const INTERVAL: Duration = Duration::from_secs(2);
+
+/// Largest file we'll read.
+const MAX_LEN: u64 = 1024 * 1024;
+
+/// What identifies a version of a file: its identity, modification time and
+/// length.
+type Version = ((u64, u64), SystemTime, u64);
+
+/// What was parsed from a file, and the version of the file it came from.
+pub struct Watched<T> {
+    path: &'static str,
+    value: RwLock<Arc<T>>,
+    version: Mutex<Option<Version>>,
+}
+
+impl<T: Default> Watched<T> {
+    /// Watches the file at `path`, a sanitized path relative to the root.
+    /// Until it's loaded, the value is the default.
+    pub fn new(path: &'static str) -> Self {
+        Watched {
+            path,
+            value: RwLock::default(),
+            version: Mutex::default(),
+        }
+    }
+
+    /// The file's path, relative to the root.
+    pub fn path(&self) -> &'static str {
+        self.path
+    }
+
+    /// The value as of the last load.
+    pub fn get(&self) -> Arc<T> {
+        self.value.read().unwrap().clone()
+    }
+
+    /// Rereads the file from `root`, with `parse`, if it has changed since
+    /// last time. If it has gone, the value goes back to the default. Returns
+    /// the new value, if there is one.
+    pub async fn reload(
+        &self,
+        log: &slog::Logger,
+        root: &dyn ContentSource,
+        parse: impl FnOnce(&str) -> T,
+    ) -> Option<Arc<T>> {
+        let file = root
+            .open(log, Path::new(self.path), &|_| "text/plain", &|_| None)
+            .await;
+        let mut file = match file {
+            Ok(file) => file,
+            Err(_) => {
+                self.version.lock().unwrap().take()?;
+                let value = Arc::<T>::default();
+                *self.value.write().unwrap() = value.clone();
+                return Some(value);
+            }
+        };
+        let version = Some((file.id, file.modified, file.len));
+        if *self.version.lock().unwrap() == version {
+            return None;
+        }
+        let mut text = String::new();
+        let read = (&mut file.file)
+            .take(MAX_LEN)
+            .read_to_string(&mut text)
+            .await;
+        if let Err(e) = read {
+            slog::warn!(log, "can't read {}: {}", self.path, e);
+            return None;
+        }
+        let value = Arc::new(parse(&text));
+        *self.value.write().unwrap() = value.clone();
+        *self.version.lock().unwrap() = version;
+        Some(value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+/// A path pattern, like `/blog/:year/*`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+    splat: bool,
+}
+
+impl Pattern {
+    /// Checks `path`, a sanitized request path, against the pattern. If it
+    /// matches, returns the value of each `:name`, and of the `*` as `splat`.
+    /// Trailing slashes don't matter.
+    pub fn matches<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> Option<Vec<(&'a str, String)>> {
+        let path = path.strip_suffix('/').unwrap_or(path);
+        let mut segments = path.split('/').skip(1).filter(|s| !s.is_empty());
+        let mut params = vec![];
+        for pattern in &self.segments {
+            let segment = segments.next()?;
+            match pattern {
+                Segment::Literal(l) if l == segment => (),
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => {
+                    params.push((name.as_str(), segment.to_string()))
+                }
+            }
+        }
+        let rest = segments.collect::<Vec<_>>().join("/");
+        if self.splat {
+            params.push(("splat", rest));
+        } else if !rest.is_empty() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') {
+            return Err(format!("{} isn't a path", s));
+        }
+        let s = s.strip_suffix('/').unwrap_or(s);
+        let (s, splat) = match s.strip_suffix("/*") {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
+        let segments = s
+            .split('/')
+            .skip(1)
+            .filter(|s| !s.is_empty())
+            .map(|s| match s.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                // Sanitizing turns a leading dot into a colon, so a literal
+                // has to be compared in the same form.
+                None => match s.strip_prefix('.') {
+                    Some(rest) => Segment::Literal(format!(":{}", rest)),
+                    None => Segment::Literal(s.to_string()),
+                },
+            })
+            .collect();
+        Ok(Pattern { segments, splat })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        let matches = |pattern: &str, path: &str| {
+            let pattern = pattern.parse::<Pattern>().unwrap();
+            let params = pattern.matches(path)?;
+            let params = params.iter().map(|(n, v)| format!("{}={}", n, v));
+            Some(params.collect::<Vec<_>>())
+        };
+        assert_eq!(matches("/a/b", "/a/b/"), Some(vec![]));
+        assert_eq!(matches("/a/b/", "/a/b"), Some(vec![]));
+        assert_eq!(matches("/a/b", "/a/b/c"), None);
+        assert_eq!(matches("/a/*", "/a/b/c"), Some(vec!["splat=b/c".into()]));
+        assert_eq!(matches("/a/*", "/a"), Some(vec!["splat=".into()]));
+        assert_eq!(matches("/*", "/"), Some(vec!["splat=".into()]));
+        assert_eq!(
+            matches("/:x/b/*", "/a/b/c"),
+            Some(vec!["x=a".into(), "splat=c".into()])
+        );
+        assert_eq!(matches("/:x", "/"), None);
+        assert_eq!(matches("/a/:x", "./a/b"), Some(vec!["x=b".into()]));
+        assert_eq!(matches("/*", "./"), Some(vec!["splat=".into()]));
+        let well_known = crate::traversal::sanitize("/.well-known/x");
+        assert_eq!(
+            matches("/.well-known/:x", &well_known),
+            Some(vec!["x=x".into()])
+        );
+        assert!("a/b".parse::<Pattern>().is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Where served content comes from.
+//!
+//! `serve` finds files through a `ContentSource` rather than going to the
+//! filesystem itself, so that content can come from somewhere other than a
+//! local directory without changing how requests are handled. The sources so
+//! far are `picky::Dir`, `archive::Archive`, for a tar archive on disk or
+//! built in, and `Layered`, which stacks other sources; another one
+//! has to give the same guarantees `picky::open` does -- paths can't lead
+//! outside it, and only what's meant to be public is found -- and report
+//! directories the same way, so that index and trailing-slash handling keep
+//! working.
+
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncSeek};
+
+use crate::picky::{self, Dir, File};
+
+/// Guesses a file's content type from its path.
+pub type InferContentType<'a> = &'a (dyn Fn(&Path) -> &'static str + Sync);
+
+/// Picks a file's cache TTL from its path.
+pub type ChooseTtl<'a> = &'a (dyn Fn(&Path) -> Option<usize> + Sync);
+
+/// A place files can be served from.
+pub trait ContentSource: Debug + Send + Sync {
+    /// Opens `path`, relative to the source's root, if it's there to be
+    /// served, with the rules and errors of `picky::open`.
+    fn open<'a>(
+        &'a self,
+        log: &'a slog::Logger,
+        path: &'a Path,
+        infer_content_type: InferContentType<'a>,
+        choose_ttl: ChooseTtl<'a>,
+    ) -> BoxFuture<'a, Result<File, picky::Error>>;
+
+    /// Lists the names in the directory `path`, relative to the source's
+    /// root, for walking the source at startup. Sources that can't be listed
+    /// return nothing.
+    fn list<'a>(
+        &'a self,
+        _path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Vec<OsString>>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+}
+
+impl ContentSource for Dir {
+    fn open<'a>(
+        &'a self,
+        log: &'a slog::Logger,
+        path: &'a Path,
+        infer_content_type: InferContentType<'a>,
+        choose_ttl: ChooseTtl<'a>,
+    ) -> BoxFuture<'a, Result<File, picky::Error>> {
+        Box::pin(picky::open(log, self, path, infer_content_type, choose_ttl))
+    }
+
+    fn list<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Vec<OsString>>> {
+        let dir = self.clone();
+        let path = path.to_owned();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || dir.list(&path)).await?
+        })
+    }
+}
+
+/// Sources stacked on one another: a path is opened from the first that has it
+/// to serve. One that doesn't -- the path is missing, or isn't public -- passes
+/// it to the next. A directory stops the search, so a directory in an upper
+/// source hides a file of the same name below, but its `index.html` is again
+/// looked for in each.
+#[derive(Debug)]
+pub struct Layered(pub Vec<Box<dyn ContentSource>>);
+
+impl ContentSource for Layered {
+    fn open<'a>(
+        &'a self,
+        log: &'a slog::Logger,
+        path: &'a Path,
+        infer_content_type: InferContentType<'a>,
+        choose_ttl: ChooseTtl<'a>,
+    ) -> BoxFuture<'a, Result<File, picky::Error>> {
+        Box::pin(async move {
+            // Having found nothing, say why the first source that had
+            // something refused it, if one did.
+            let mut refusal = None;
+            let mut missing = None;
+            for (i, source) in self.0.iter().enumerate() {
+                match source.open(log, path, infer_content_type, choose_ttl).await
+                {
+                    Err(e) if e.is_forbidden() => {
+                        slog::debug!(log, "layer refused"; "layer" => i);
+                        refusal.get_or_insert(e);
+                    }
+                    Err(e) if is_missing(&e) => {
+                        missing.get_or_insert(e);
+                    }
+                    r => return r,
+                }
+            }
+            Err(refusal.or(missing).unwrap_or(picky::Error::Io(
+                io::ErrorKind::NotFound.into(),
+            )))
+        })
+    }
+
+    fn list<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Vec<OsString>>> {
+        Box::pin(async move {
+            let mut names = vec![];
+            for source in &self.0 {
+                for name in source.list(path).await.unwrap_or_default() {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+            Ok(names)
+        })
+    }
+}
+
+/// Checks whether `e` means there's nothing at the path, rather than something
+/// that can't be served.
+fn is_missing(e: &picky::Error) -> bool {
+    match e {
+        picky::Error::NotDirectory => true,
+        picky::Error::Io(e) => e.kind() == io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+/// The bytes of an open file.
+pub trait Content: AsyncRead + AsyncSeek + Debug + Send + Unpin {
+    /// The file descriptor the bytes are read from, if there is one, for
+    /// page cache hints.
+    fn fd(&self) -> Option<RawFd>;
+}
+
+impl Content for tokio::fs::File {
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn dir_source() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let source: Box<dyn ContentSource> =
+            Box::new(Dir::open(Path::new("src"), false).unwrap());
+        let file = source
+            .open(&log, Path::new("lib.rs"), &|_| "text/x-rust", &|_| Some(7))
+            .await
+            .unwrap();
+        assert_eq!(file.content_type, "text/x-rust");
+        assert_eq!(file.ttl, Some(7));
+        assert!(file.file.fd().is_some());
+        assert!(matches!(
+            source
+                .open(&log, Path::new("bin"), &|_| "", &|_| None)
+                .await,
+            Err(picky::Error::Directory)
+        ));
+    }
+
+    #[tokio::test]
+    async fn layered_source() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let layers = Layered(vec![
+            Box::new(Dir::open(Path::new("src/bin"), false).unwrap()),
+            Box::new(Dir::open(Path::new("src"), false).unwrap()),
+        ]);
+        let open = |path: &'static str| {
+            layers.open(&log, Path::new(path), &|_| "", &|_| None)
+        };
+        // Found in the top layer, and, missing there, in the one below.
+        assert!(open("httpd2.rs").await.is_ok());
+        assert!(open("lib.rs").await.is_ok());
+        assert!(matches!(
+            open("nothing.rs").await,
+            Err(picky::Error::Io(e)) if e.kind() == io::ErrorKind::NotFound
+        ));
+
+        let names = layers.list(Path::new(".")).await.unwrap();
+        assert!(names.contains(&"httpd2.rs".into()));
+        assert!(names.contains(&"lib.rs".into()));
+        // Names in more than one layer are listed once.
+        let twice = Layered(vec![
+            Box::new(Dir::open(Path::new("src"), false).unwrap()),
+            Box::new(Dir::open(Path::new("src"), false).unwrap()),
+        ]);
+        let names = twice.list(Path::new(".")).await.unwrap();
+        assert_eq!(names.iter().filter(|n| *n == "lib.rs").count(), 1);
+    }
+}
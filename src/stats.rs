@@ -0,0 +1,550 @@
+//! Server-wide counters.
+//!
+//! These are kept with relaxed atomics, so a snapshot taken while the server is
+//! busy may be slightly inconsistent (a request counted before its connection,
+//! say), which is fine for the purpose: giving an operator a rough picture of
+//! what the server has been up to.
+//!
+//! Connections that end badly are sorted by `Failure` into a handful of
+//! causes, which are counted here and named in the log, so that (say) a port
+//! scanner speaking plain HTTP can be told apart from clients that reject our
+//! certificate.
+//!
+//! Successful handshakes are counted too: by whether they resumed an earlier
+//! session, by TLS version, cipher suite and application protocol, and by how
+//! long they took, to show whether session resumption is working, what clients
+//! are really negotiating, and whether handshakes are slow.
+
+use std::error::Error;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use hyper::StatusCode;
+use rustls::{CipherSuite, ProtocolVersion, ServerConnection};
+
+use crate::traffic::Traffic;
+
+/// Counters describing the server's activity since startup.
+#[derive(Default)]
+pub struct Stats {
+    /// Connections accepted.
+    pub connections: AtomicU64,
+    /// Connections currently open.
+    pub active: AtomicU64,
+    /// Connections dropped because the TLS handshake failed.
+    pub handshake_failures: AtomicU64,
+    /// Completed TLS handshakes, indexed by `Handshake`.
+    handshakes: [AtomicU64; Handshake::ALL.len()],
+    /// Handshakes that negotiated TLS 1.2 and 1.3.
+    pub by_version: [AtomicU64; 2],
+    /// Handshakes by cipher suite, indexed as in `CIPHERS`, with one more for
+    /// any suite not listed there.
+    by_cipher: [AtomicU64; CIPHERS.len() + 1],
+    /// Handshakes that negotiated h2, http/1.1, and no protocol.
+    by_alpn: [AtomicU64; 3],
+    /// Handshakes by how long they took, indexed as in `HANDSHAKE_TIMES`,
+    /// with one more for those slower than all of them.
+    handshake_times: [AtomicU64; HANDSHAKE_TIMES.len() + 1],
+    /// Connections closed because their client already had as many as it's
+    /// allowed.
+    pub refused_per_ip: AtomicU64,
+    /// Times accepting a connection failed, usually for want of file
+    /// descriptors.
+    pub accept_errors: AtomicU64,
+    /// Connections closed unserved to keep the listen queue moving while out
+    /// of file descriptors.
+    pub accept_dropped: AtomicU64,
+    /// Connections that ended badly, indexed by `Failure`.
+    failures: [AtomicU64; Failure::ALL.len()],
+    /// Requests answered.
+    pub requests: AtomicU64,
+    /// Requests answered with 304 Not Modified, meaning the client's cached
+    /// copy was still good.
+    pub not_modified: AtomicU64,
+    /// Responses by status class: 2xx, 3xx, 4xx, and 5xx.
+    pub by_class: [AtomicU64; 4],
+    /// Responses with 404 Not Found, which tend to spike when a deploy loses
+    /// files.
+    pub not_found: AtomicU64,
+    /// Responses with 429 Too Many Requests.
+    pub too_many_requests: AtomicU64,
+    /// Requests stalled by the tarpit.
+    pub tarpitted: AtomicU64,
+    /// Requests turned away by --max-requests.
+    pub shed: AtomicU64,
+    /// Bytes of file content sent, not counting headers or encoding overhead.
+    /// A transfer the client cut short counts for what it got.
+    pub bytes_served: AtomicU64,
+    /// Requests and bytes for each host being counted.
+    pub traffic: Traffic,
+}
+
+impl Stats {
+    /// Counters, with `traffic` for counting hosts.
+    pub fn with_traffic(traffic: Traffic) -> Self {
+        Stats {
+            traffic,
+            ..Stats::default()
+        }
+    }
+
+    /// Records a response with `status`.
+    pub fn record_response(&self, status: StatusCode) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(class) = (status.as_u16() / 100).checked_sub(2) {
+            if let Some(c) = self.by_class.get(usize::from(class)) {
+                c.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let specific = match status {
+            StatusCode::NOT_MODIFIED => Some(&self.not_modified),
+            StatusCode::NOT_FOUND => Some(&self.not_found),
+            StatusCode::TOO_MANY_REQUESTS => Some(&self.too_many_requests),
+            _ => None,
+        };
+        if let Some(c) = specific {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records `len` bytes of file content sent.
+    pub fn record_sent(&self, len: u64) {
+        self.bytes_served.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Records a handshake on `conn` that completed in `time`, returning its
+    /// kind.
+    pub fn record_handshake(
+        &self,
+        conn: &ServerConnection,
+        time: Duration,
+    ) -> Handshake {
+        let kind = Handshake::of(conn);
+        self.handshakes[kind as usize].fetch_add(1, Ordering::Relaxed);
+        let version = match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => Some(0),
+            Some(ProtocolVersion::TLSv1_3) => Some(1),
+            _ => None,
+        };
+        if let Some(v) = version {
+            self.by_version[v].fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(suite) = conn.negotiated_cipher_suite() {
+            let i = CIPHERS
+                .iter()
+                .position(|&(s, _)| s == suite.suite())
+                .unwrap_or(CIPHERS.len());
+            self.by_cipher[i].fetch_add(1, Ordering::Relaxed);
+        }
+        let alpn = match conn.alpn_protocol() {
+            Some(b"h2") => 0,
+            Some(_) => 1,
+            None => 2,
+        };
+        self.by_alpn[alpn].fetch_add(1, Ordering::Relaxed);
+        let i = HANDSHAKE_TIMES
+            .iter()
+            .position(|&(limit, _)| time < limit)
+            .unwrap_or(HANDSHAKE_TIMES.len());
+        self.handshake_times[i].fetch_add(1, Ordering::Relaxed);
+        kind
+    }
+
+    /// Records a connection that ended because of `failure`.
+    pub fn record_failure(&self, failure: Failure) {
+        self.failures[failure as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reads all the counters, with their names.
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut counters = vec![
+            ("connections_accepted", get(&self.connections)),
+            ("connections_active", get(&self.active)),
+            ("handshake_failures", get(&self.handshake_failures)),
+            ("handshakes_full", get(&self.handshakes[0])),
+            ("handshakes_resumed", get(&self.handshakes[1])),
+            ("handshakes_unknown", get(&self.handshakes[2])),
+            ("tls12", get(&self.by_version[0])),
+            ("tls13", get(&self.by_version[1])),
+            ("alpn_h2", get(&self.by_alpn[0])),
+            ("alpn_http11", get(&self.by_alpn[1])),
+            ("alpn_none", get(&self.by_alpn[2])),
+            ("refused_per_ip", get(&self.refused_per_ip)),
+            ("accept_errors", get(&self.accept_errors)),
+            ("accept_dropped", get(&self.accept_dropped)),
+            ("requests", get(&self.requests)),
+            ("status_2xx", get(&self.by_class[0])),
+            ("status_3xx", get(&self.by_class[1])),
+            ("status_4xx", get(&self.by_class[2])),
+            ("status_5xx", get(&self.by_class[3])),
+            ("not_modified", get(&self.not_modified)),
+            ("not_found", get(&self.not_found)),
+            ("too_many_requests", get(&self.too_many_requests)),
+            ("tarpitted", get(&self.tarpitted)),
+            ("shed", get(&self.shed)),
+            ("bytes_served", get(&self.bytes_served)),
+        ];
+        for (i, &(_, name)) in CIPHERS.iter().enumerate() {
+            counters.push((name, get(&self.by_cipher[i])));
+        }
+        counters.push(("cipher_other", get(&self.by_cipher[CIPHERS.len()])));
+        for (i, &(_, name)) in HANDSHAKE_TIMES.iter().enumerate() {
+            counters.push((name, get(&self.handshake_times[i])));
+        }
+        counters.push((
+            "handshake_over_1s",
+            get(&self.handshake_times[HANDSHAKE_TIMES.len()]),
+        ));
+        for failure in Failure::ALL {
+            counters.push((
+                failure.counter_name(),
+                get(&self.failures[failure as usize]),
+            ));
+        }
+        counters
+    }
+}
+
+/// Cipher suites we count separately, with their counter names: those rustls
+/// supports, strongest first.
+const CIPHERS: [(CipherSuite, &str); 9] = [
+    (CipherSuite::TLS13_AES_256_GCM_SHA384, "cipher_tls13_aes_256_gcm"),
+    (CipherSuite::TLS13_AES_128_GCM_SHA256, "cipher_tls13_aes_128_gcm"),
+    (
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+        "cipher_tls13_chacha20_poly1305",
+    ),
+    (
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        "cipher_ecdhe_ecdsa_aes_256_gcm",
+    ),
+    (
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        "cipher_ecdhe_ecdsa_aes_128_gcm",
+    ),
+    (
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+        "cipher_ecdhe_ecdsa_chacha20_poly1305",
+    ),
+    (
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        "cipher_ecdhe_rsa_aes_256_gcm",
+    ),
+    (
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        "cipher_ecdhe_rsa_aes_128_gcm",
+    ),
+    (
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        "cipher_ecdhe_rsa_chacha20_poly1305",
+    ),
+];
+
+/// Upper bounds of the handshake time buckets, with their counter names. A
+/// handshake includes a round trip or two to the client, so these are mostly
+/// a measure of distance; a pile-up at the slow end can also be a client
+/// holding handshakes open on purpose.
+const HANDSHAKE_TIMES: [(Duration, &str); 4] = [
+    (Duration::from_millis(10), "handshake_under_10ms"),
+    (Duration::from_millis(50), "handshake_under_50ms"),
+    (Duration::from_millis(250), "handshake_under_250ms"),
+    (Duration::from_secs(1), "handshake_under_1s"),
+];
+
+/// Whether a TLS handshake set up a new session or resumed an old one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Handshake {
+    /// A new session, with the certificate and a key exchange.
+    Full,
+    /// An earlier session picked up again, from our session cache or a
+    /// ticket.
+    Resumed,
+    /// A TLS 1.2 handshake. rustls doesn't tell us whether those resumed.
+    Unknown,
+}
+
+impl Handshake {
+    const ALL: [Handshake; 3] =
+        [Handshake::Full, Handshake::Resumed, Handshake::Unknown];
+
+    /// Classifies the handshake `conn` completed.
+    pub fn of(conn: &ServerConnection) -> Self {
+        if conn.protocol_version() != Some(ProtocolVersion::TLSv1_3) {
+            Handshake::Unknown
+        } else if conn.received_resumption_data().is_some() {
+            // In TLS 1.3, this is set whenever a session is resumed, whether
+            // or not anything was stored with it.
+            Handshake::Resumed
+        } else {
+            Handshake::Full
+        }
+    }
+
+    /// The name used for this kind of handshake in the log.
+    pub fn name(self) -> &'static str {
+        match self {
+            Handshake::Full => "full",
+            Handshake::Resumed => "resumed",
+            Handshake::Unknown => "unknown",
+        }
+    }
+}
+
+impl slog::Value for Handshake {
+    fn serialize(
+        &self,
+        _record: &slog::Record,
+        key: slog::Key,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        serializer.emit_str(key, self.name())
+    }
+}
+
+/// Why a connection ended badly.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The client isn't speaking TLS at all -- usually plain HTTP sent to the
+    /// HTTPS port.
+    NotTls,
+    /// The client speaks TLS, but we have no version, cipher suite, or
+    /// protocol in common.
+    Incompatible,
+    /// The client sent a TLS alert, often because it didn't like our
+    /// certificate.
+    Alert,
+    /// The client went away: a reset, or a hangup partway through.
+    Reset,
+    /// The client broke the rules of TLS, HTTP, or the PROXY protocol.
+    Protocol,
+    /// The connection ran out of time.
+    Timeout,
+    /// Something we don't have a category for.
+    Other,
+}
+
+impl Failure {
+    const ALL: [Failure; 7] = [
+        Failure::NotTls,
+        Failure::Incompatible,
+        Failure::Alert,
+        Failure::Reset,
+        Failure::Protocol,
+        Failure::Timeout,
+        Failure::Other,
+    ];
+
+    /// Classifies an error from the TLS handshake.
+    pub fn of_handshake(e: &io::Error) -> Self {
+        use rustls::InvalidMessage;
+
+        match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+            Some(rustls::Error::InvalidMessage(
+                InvalidMessage::InvalidContentType,
+            ))
+            | Some(rustls::Error::PeerSentOversizedRecord) => Failure::NotTls,
+            Some(rustls::Error::PeerIncompatible(_))
+            | Some(rustls::Error::NoApplicationProtocol) => {
+                Failure::Incompatible
+            }
+            Some(rustls::Error::AlertReceived(_)) => Failure::Alert,
+            Some(rustls::Error::InvalidMessage(_))
+            | Some(rustls::Error::PeerMisbehaved(_))
+            | Some(rustls::Error::DecryptError) => Failure::Protocol,
+            Some(_) => Failure::Other,
+            None => Failure::of_io(e),
+        }
+    }
+
+    /// Classifies an error from serving HTTP on an established connection.
+    pub fn of_connection(e: &(dyn Error + 'static)) -> Self {
+        // Any I/O error underneath says more than hyper's summary of it.
+        let mut source = Some(e);
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                return Failure::of_io(e);
+            }
+            source = e.source();
+        }
+        match e.downcast_ref::<hyper::Error>() {
+            Some(e) if e.is_parse() => Failure::Protocol,
+            Some(e) if e.is_incomplete_message() => Failure::Reset,
+            Some(e) if e.is_timeout() => Failure::Timeout,
+            _ => Failure::Other,
+        }
+    }
+
+    /// Classifies a plain I/O error.
+    pub fn of_io(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => Failure::Reset,
+            io::ErrorKind::TimedOut => Failure::Timeout,
+            io::ErrorKind::InvalidData => Failure::Protocol,
+            _ => Failure::Other,
+        }
+    }
+
+    /// The name used for this failure in the log.
+    pub fn name(self) -> &'static str {
+        match self {
+            Failure::NotTls => "not-tls",
+            Failure::Incompatible => "incompatible",
+            Failure::Alert => "alert",
+            Failure::Reset => "reset",
+            Failure::Protocol => "protocol",
+            Failure::Timeout => "timeout",
+            Failure::Other => "error",
+        }
+    }
+
+    fn counter_name(self) -> &'static str {
+        match self {
+            Failure::NotTls => "failed_not_tls",
+            Failure::Incompatible => "failed_incompatible",
+            Failure::Alert => "failed_alert",
+            Failure::Reset => "failed_reset",
+            Failure::Protocol => "failed_protocol",
+            Failure::Timeout => "failed_timeout",
+            Failure::Other => "failed_other",
+        }
+    }
+}
+
+impl slog::Value for Failure {
+    fn serialize(
+        &self,
+        _record: &slog::Record,
+        key: slog::Key,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        serializer.emit_str(key, self.name())
+    }
+}
+
+impl std::fmt::Display for Stats {
+    /// Formats the counters as `name value` lines.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (name, value) in self.snapshot() {
+            writeln!(f, "{} {}", name, value)?;
+        }
+        for (name, value) in self.traffic.snapshot(SystemTime::now()) {
+            writeln!(f, "{} {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl slog::KV for Stats {
+    fn serialize(
+        &self,
+        _record: &slog::Record,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        // slog prints pairs in the reverse of the order they're emitted.
+        for (name, value) in self.snapshot().into_iter().rev() {
+            serializer.emit_u64(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses() {
+        let stats = Stats::default();
+        stats.record_response(StatusCode::OK);
+        stats.record_sent(100);
+        stats.record_response(StatusCode::NOT_MODIFIED);
+        stats.record_response(StatusCode::NOT_FOUND);
+        stats.record_sent(20);
+        assert_eq!(
+            stats.to_string(),
+            "connections_accepted 0\n\
+             connections_active 0\n\
+             handshake_failures 0\n\
+             handshakes_full 0\n\
+             handshakes_resumed 0\n\
+             handshakes_unknown 0\n\
+             tls12 0\n\
+             tls13 0\n\
+             alpn_h2 0\n\
+             alpn_http11 0\n\
+             alpn_none 0\n\
+             refused_per_ip 0\n\
+             accept_errors 0\n\
+             accept_dropped 0\n\
+             requests 3\n\
+             status_2xx 1\n\
+             status_3xx 1\n\
+             status_4xx 1\n\
+             status_5xx 0\n\
+             not_modified 1\n\
+             not_found 1\n\
+             too_many_requests 0\n\
+             tarpitted 0\n\
+             shed 0\n\
+             bytes_served 120\n\
+             cipher_tls13_aes_256_gcm 0\n\
+             cipher_tls13_aes_128_gcm 0\n\
+             cipher_tls13_chacha20_poly1305 0\n\
+             cipher_ecdhe_ecdsa_aes_256_gcm 0\n\
+             cipher_ecdhe_ecdsa_aes_128_gcm 0\n\
+             cipher_ecdhe_ecdsa_chacha20_poly1305 0\n\
+             cipher_ecdhe_rsa_aes_256_gcm 0\n\
+             cipher_ecdhe_rsa_aes_128_gcm 0\n\
+             cipher_ecdhe_rsa_chacha20_poly1305 0\n\
+             cipher_other 0\n\
+             handshake_under_10ms 0\n\
+             handshake_under_50ms 0\n\
+             handshake_under_250ms 0\n\
+             handshake_under_1s 0\n\
+             handshake_over_1s 0\n\
+             failed_not_tls 0\n\
+             failed_incompatible 0\n\
+             failed_alert 0\n\
+             failed_reset 0\n\
+             failed_protocol 0\n\
+             failed_timeout 0\n\
+             failed_other 0\n"
+        );
+    }
+
+    #[test]
+    fn failures() {
+        let tls = |e: rustls::Error| {
+            Failure::of_handshake(&io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        assert_eq!(
+            tls(rustls::Error::InvalidMessage(
+                rustls::InvalidMessage::InvalidContentType
+            )),
+            Failure::NotTls
+        );
+        assert_eq!(
+            tls(rustls::Error::AlertReceived(
+                rustls::AlertDescription::BadCertificate
+            )),
+            Failure::Alert
+        );
+        assert_eq!(
+            Failure::of_handshake(&io::ErrorKind::UnexpectedEof.into()),
+            Failure::Reset
+        );
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(Failure::of_connection(&reset), Failure::Reset);
+
+        let stats = Stats::default();
+        stats.record_failure(Failure::Alert);
+        stats.record_failure(Failure::Alert);
+        assert!(stats.to_string().contains("failed_alert 2\n"));
+    }
+}
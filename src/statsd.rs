@@ -0,0 +1,210 @@
+//! Pushing counters to a StatsD server.
+//!
+//! Every interval, the counters in `Stats` are sent over UDP as StatsD
+//! metrics: those that only go up as counts of how much they rose since the
+//! last push, and those that go up and down as gauges. Tags, if any, are added
+//! in the DogStatsD `|#tag,tag` style, which plain StatsD servers don't
+//! understand, so leave them off for those. Traffic for each counted host
+//! goes along too, with its month's bytes and quota as gauges.
+//!
+//! UDP being what it is, a push that can't be sent is logged and dropped;
+//! counts in it aren't lost, since the next push starts from what was last
+//! sent successfully.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::net::UdpSocket;
+
+use crate::stats::Stats;
+
+/// Counters that go down as well as up, sent as gauges.
+const GAUGES: &[&str] = &["connections_active"];
+
+/// Endings of per-host counters that are sent as gauges.
+const HOST_GAUGES: &[&str] = &[".month_bytes", ".quota"];
+
+/// Largest datagram we send, small enough to cross most networks without
+/// fragmenting.
+const MAX_DATAGRAM: usize = 1432;
+
+/// Sends counters to a StatsD server.
+pub struct Pusher {
+    socket: UdpSocket,
+    prefix: String,
+    tags: String,
+    /// Counter values as of the last successful push.
+    last: Vec<u64>,
+}
+
+impl Pusher {
+    /// Sets up pushing to `addr`, naming each metric `prefix.counter` and
+    /// attaching `tags`.
+    pub async fn new(
+        addr: SocketAddr,
+        prefix: &str,
+        tags: &[String],
+    ) -> std::io::Result<Self> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(Pusher {
+            socket,
+            prefix: prefix.to_string(),
+            tags: if tags.is_empty() {
+                String::new()
+            } else {
+                format!("|#{}", tags.join(","))
+            },
+            last: vec![],
+        })
+    }
+
+    /// Pushes `stats` every `interval`, forever.
+    pub async fn run(
+        mut self,
+        stats: Arc<Stats>,
+        interval: Duration,
+        log: slog::Logger,
+    ) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let mut snapshot: Vec<(String, u64)> = stats
+                .snapshot()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect();
+            snapshot.extend(stats.traffic.snapshot(SystemTime::now()));
+            let lines = self.lines(&snapshot);
+            match self.send(&lines).await {
+                Ok(()) => {
+                    self.last = snapshot.iter().map(|&(_, v)| v).collect();
+                }
+                Err(e) => slog::warn!(log, "can't push stats: {}", e),
+            }
+        }
+    }
+
+    /// Formats the metrics for `snapshot`, one per line.
+    fn lines<N: AsRef<str>>(&self, snapshot: &[(N, u64)]) -> Vec<String> {
+        snapshot
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (name, value))| {
+                let (name, value) = (name.as_ref(), *value);
+                if GAUGES.contains(&name)
+                    || (name.starts_with("host.")
+                        && HOST_GAUGES.iter().any(|g| name.ends_with(g)))
+                {
+                    return Some(format!(
+                        "{}.{}:{}|g{}",
+                        self.prefix, name, value, self.tags
+                    ));
+                }
+                let delta = value
+                    .saturating_sub(self.last.get(i).copied().unwrap_or(0));
+                // Nothing happened, so there's nothing to count.
+                if delta == 0 {
+                    return None;
+                }
+                Some(format!(
+                    "{}.{}:{}|c{}",
+                    self.prefix, name, delta, self.tags
+                ))
+            })
+            .collect()
+    }
+
+    async fn send(&self, lines: &[String]) -> std::io::Result<()> {
+        for datagram in pack(lines) {
+            self.socket.send(datagram.as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Joins `lines` into as few datagrams as will hold them.
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut datagrams = vec![];
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM
+        {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn deltas_and_gauges() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tags = ["env:test".to_string()];
+        let mut pusher =
+            Pusher::new(receiver.local_addr().unwrap(), "web", &tags)
+                .await
+                .unwrap();
+
+        let snapshot = [("requests", 10), ("connections_active", 3)];
+        assert_eq!(
+            pusher.lines(&snapshot),
+            [
+                "web.requests:10|c|#env:test",
+                "web.connections_active:3|g|#env:test"
+            ]
+        );
+        pusher.last = vec![10, 3];
+        let snapshot = [("requests", 12), ("connections_active", 1)];
+        assert_eq!(
+            pusher.lines(&snapshot),
+            [
+                "web.requests:2|c|#env:test",
+                "web.connections_active:1|g|#env:test"
+            ]
+        );
+        pusher.last = vec![12, 1];
+        assert_eq!(
+            pusher.lines(&snapshot),
+            ["web.connections_active:1|g|#env:test"]
+        );
+        pusher.send(&pusher.lines(&snapshot)).await.unwrap();
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"web.connections_active:1|g|#env:test");
+
+        let hosts = [
+            ("host.a.com.requests".to_string(), 4),
+            ("host.a.com.month_bytes".to_string(), 4),
+        ];
+        pusher.last = vec![3, 5];
+        assert_eq!(
+            pusher.lines(&hosts),
+            [
+                "web.host.a.com.requests:1|c|#env:test",
+                "web.host.a.com.month_bytes:4|g|#env:test"
+            ]
+        );
+
+        let lines = vec!["x".repeat(1000), "y".repeat(1000), "z".to_string()];
+        let datagrams = pack(&lines);
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[1], format!("{}\nz", "y".repeat(1000)));
+    }
+}
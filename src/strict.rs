@@ -0,0 +1,203 @@
+//! Strict request validation.
+//!
+//! hyper accepts requests that are merely unusual, as it should, but some of
+//! what's unusual is also what request smuggling and cache poisoning are made
+//! of: framing headers that disagree with each other, a target that names a
+//! different host from `Host`, bytes in the path that different parsers read
+//! differently. With `--strict`, such requests are refused with a 400, and the
+//! reason, which is different for each check, goes in the log.
+
+use hyper::header::{HeaderMap, HeaderName};
+use hyper::{Request, Version};
+
+use crate::host;
+
+/// Checks `req`, returning why it's refused, if it is.
+pub fn check<B>(req: &Request<B>) -> Result<(), &'static str> {
+    let headers = req.headers();
+    let h1 = req.version() < Version::HTTP_2;
+
+    let lengths = all(headers, &hyper::header::CONTENT_LENGTH);
+    let digits =
+        |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match &lengths[..] {
+        [] => (),
+        [length] if digits(length) => (),
+        [_] => return Err("malformed content-length"),
+        _ => return Err("duplicate content-length"),
+    }
+    let codings = all(headers, &hyper::header::TRANSFER_ENCODING);
+    if !codings.is_empty() {
+        if !lengths.is_empty() {
+            return Err("content-length with transfer-encoding");
+        }
+        // chunked is the only coding hyper will undo, and HTTP/1.0 and
+        // HTTP/2 have no transfer codings at all.
+        let chunked = match &codings[..] {
+            [coding] => coding.eq_ignore_ascii_case("chunked"),
+            _ => false,
+        };
+        if !chunked || !h1 || req.version() == Version::HTTP_10 {
+            return Err("unsupported transfer-encoding");
+        }
+    }
+
+    let hosts = all(headers, &hyper::header::HOST);
+    match &hosts[..] {
+        [] if h1 && req.version() != Version::HTTP_10 => {
+            return Err("missing host")
+        }
+        [] => (),
+        [value] if host::normalize(value).is_some() => (),
+        [_] => return Err("malformed host"),
+        _ => return Err("duplicate host"),
+    }
+    if h1 && req.uri().scheme().is_some() {
+        return Err("absolute-form target");
+    }
+    if let (Some(authority), [value]) = (req.uri().authority(), &hosts[..]) {
+        if host::normalize(authority.as_str()) != host::normalize(value) {
+            return Err("host disagrees with authority");
+        }
+    }
+
+    check_chars(req.uri().path(), b":@/").map_err(|bad| match bad {
+        Bad::Char => "invalid character in path",
+        Bad::Escape => "malformed percent-encoding in path",
+        Bad::Nul => "encoded NUL in path",
+    })?;
+    if let Some(query) = req.uri().query() {
+        check_chars(query, b":@/?").map_err(|bad| match bad {
+            Bad::Char => "invalid character in query",
+            Bad::Escape => "malformed percent-encoding in query",
+            Bad::Nul => "encoded NUL in query",
+        })?;
+    }
+    Ok(())
+}
+
+/// The values of every `name` header, as text. A value that isn't text is
+/// empty, so it's never well-formed.
+fn all<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Vec<&'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .map(|v| v.to_str().unwrap_or("").trim())
+        .collect()
+}
+
+/// What's wrong with part of a target.
+enum Bad {
+    Char,
+    Escape,
+    Nul,
+}
+
+/// Checks that `s` has only unreserved characters, sub-delims, percent
+/// escapes, and `extra`, as RFC 3986 allows in a path or query.
+fn check_chars(s: &str, extra: &[u8]) -> Result<(), Bad> {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).ok_or(Bad::Escape)?;
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(Bad::Escape);
+                }
+                if hex == b"00" {
+                    return Err(Bad::Nul);
+                }
+                i += 3;
+                continue;
+            }
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (),
+            b'-' | b'.' | b'_' | b'~' => (),
+            b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b','
+            | b';' | b'=' => (),
+            b if extra.contains(&b) => (),
+            _ => return Err(Bad::Char),
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks() {
+        let get = |uri: &str| Request::get(uri).header("host", "example.com");
+        let ok =
+            |req: hyper::http::request::Builder| check(&req.body(()).unwrap());
+        assert_eq!(ok(get("/a/b;c=d?e=f&g=%20/h")), Ok(()));
+        assert_eq!(ok(get("/").header("transfer-encoding", "chunked")), Ok(()));
+        assert_eq!(
+            ok(Request::get("https://example.com/").version(Version::HTTP_2)),
+            Ok(())
+        );
+        // HTTP/1.0 didn't require Host.
+        assert_eq!(ok(Request::get("/").version(Version::HTTP_10)), Ok(()));
+
+        for (req, reason) in [
+            (
+                get("/")
+                    .header("content-length", "1")
+                    .header("content-length", "1"),
+                "duplicate content-length",
+            ),
+            (
+                get("/").header("content-length", "+1"),
+                "malformed content-length",
+            ),
+            (
+                get("/")
+                    .header("content-length", "1")
+                    .header("transfer-encoding", "chunked"),
+                "content-length with transfer-encoding",
+            ),
+            (
+                get("/").header("transfer-encoding", "gzip, chunked"),
+                "unsupported transfer-encoding",
+            ),
+            (
+                get("/")
+                    .version(Version::HTTP_10)
+                    .header("transfer-encoding", "chunked"),
+                "unsupported transfer-encoding",
+            ),
+            (Request::get("/"), "missing host"),
+            (get("/").header("host", "example.com"), "duplicate host"),
+            (Request::get("/").header("host", "a b"), "malformed host"),
+            (get("http://example.com/"), "absolute-form target"),
+            (
+                Request::get("https://other.com/")
+                    .version(Version::HTTP_2)
+                    .header("host", "example.com"),
+                "host disagrees with authority",
+            ),
+            (get("/a%zz"), "malformed percent-encoding in path"),
+            (get("/a%2"), "malformed percent-encoding in path"),
+            (get("/a%00b"), "encoded NUL in path"),
+            (get("/a{b}"), "invalid character in path"),
+            (get("/a\\b"), "invalid character in path"),
+            (get("/a?b=c|d"), "invalid character in query"),
+            (get("/a?b=%g0"), "malformed percent-encoding in query"),
+            (get("/a?b=%00"), "encoded NUL in query"),
+            (
+                get("/").header("content-length", "1 2"),
+                "malformed content-length",
+            ),
+            (
+                get("/")
+                    .version(Version::HTTP_2)
+                    .header("transfer-encoding", "chunked"),
+                "unsupported transfer-encoding",
+            ),
+        ] {
+            assert_eq!(ok(req), Err(reason));
+        }
+    }
+}
@@ -1,6 +1,10 @@
 //! Synchronization primitive add-ons.
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use tokio::sync::Semaphore;
 
@@ -23,6 +27,13 @@ impl SharedSemaphore {
         }
     }
 
+    /// Resolves once all `permits` permits have been returned, meaning that
+    /// nothing holds one.
+    pub async fn drain(&self, permits: usize) {
+        let permits = u32::try_from(permits).unwrap_or(u32::MAX);
+        self.inner.acquire_many(permits).await.unwrap().forget();
+    }
+
     /// Acquires one permit, resolving when it's acquired.
     pub async fn acquire(&self) -> SharedPermit {
         self.inner.acquire().await.unwrap().forget();
@@ -42,3 +53,140 @@ impl Drop for SharedPermit {
         self.inner.add_permits(1);
     }
 }
+
+/// Caps the number of simultaneous connections from any one client address.
+///
+/// IPv6 addresses are counted by their /64 network, since that's what a single
+/// customer is normally given, and counting each address separately would let
+/// one client pick as many as it liked.
+pub struct PerIpLimit {
+    limit: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PerIpLimit {
+    /// Creates a limit of `limit` connections per address.
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            counts: Mutex::default(),
+        })
+    }
+
+    /// Counts a connection from `addr`, unless that address already has as
+    /// many as it's allowed.
+    pub fn try_acquire(self: &Arc<Self>, addr: IpAddr) -> Option<PerIpPermit> {
+        let key = match addr.to_canonical() {
+            IpAddr::V6(v6) => {
+                let net = u128::from(v6) & !(u128::MAX >> 64);
+                IpAddr::V6(Ipv6Addr::from(net))
+            }
+            v4 => v4,
+        };
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(PerIpPermit {
+            limit: Arc::clone(self),
+            key,
+        })
+    }
+}
+
+/// RAII representation of one connection counted by a `PerIpLimit`.
+pub struct PerIpPermit {
+    limit: Arc<PerIpLimit>,
+    key: IpAddr,
+}
+
+impl Drop for PerIpPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limit.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Caps the number of requests being worked on at once, so that excess
+/// requests can be turned away rather than left to queue.
+pub struct RequestLimit {
+    limit: usize,
+    current: AtomicUsize,
+}
+
+impl RequestLimit {
+    /// Creates a limit of `limit` requests at once, or no limit.
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit: limit.unwrap_or(usize::MAX),
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts a request, unless there are already as many as allowed.
+    pub fn try_acquire(&self) -> Option<RequestPermit<'_>> {
+        self.current
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < self.limit).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| RequestPermit { limit: self })
+    }
+}
+
+/// RAII representation of one request counted by a `RequestLimit`.
+pub struct RequestPermit<'a> {
+    limit: &'a RequestLimit,
+}
+
+impl Drop for RequestPermit<'_> {
+    fn drop(&mut self) {
+        self.limit.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_ip_limit() {
+        let limit = PerIpLimit::new(2);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let a = limit.try_acquire(ip("192.0.2.1")).unwrap();
+        let b = limit.try_acquire(ip("::ffff:192.0.2.1")).unwrap();
+        assert!(limit.try_acquire(ip("192.0.2.1")).is_none());
+        assert!(limit.try_acquire(ip("192.0.2.2")).is_some());
+        drop(a);
+        assert!(limit.try_acquire(ip("192.0.2.1")).is_some());
+        drop(b);
+
+        let _c = limit.try_acquire(ip("2001:db8::1")).unwrap();
+        let _d = limit.try_acquire(ip("2001:db8::2")).unwrap();
+        assert!(limit.try_acquire(ip("2001:db8::ffff:3")).is_none());
+        assert!(limit.try_acquire(ip("2001:db8:0:1::1")).is_some());
+        assert_eq!(limit.counts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn request_limit() {
+        let limit = RequestLimit::new(Some(2));
+        let a = limit.try_acquire().unwrap();
+        let _b = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        drop(a);
+        assert!(limit.try_acquire().is_some());
+
+        let unlimited = RequestLimit::new(None);
+        let permits: Vec<_> = (0..1000).map(|_| unlimited.try_acquire()).collect();
+        assert!(permits.iter().all(Option::is_some));
+    }
+}
@@ -0,0 +1,276 @@
+//! TLS session ticket encryption, with key rotation.
+//!
+//! Session tickets let a returning client resume a TLS session without a full
+//! handshake, by handing back state the server encrypted for it earlier. The
+//! catch is that anyone holding the ticket key can decrypt every session it
+//! protected, so keys must be rotated and erased; a ticket key that lives
+//! forever undoes forward secrecy.
+//!
+//! Keys either come from the system RNG, in which case we rotate them
+//! ourselves, or from a key file maintained by the operator, which lets several
+//! servers (or successive runs of one server) accept each other's tickets. The
+//! file is opened before chroot and re-read through the open descriptor, so
+//! rotating it is up to whoever writes it.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::server::ProducesTickets;
+
+/// Length of a ticket key in bytes.
+const KEY_LEN: usize = 32;
+
+/// How often to re-read a key file.
+const FILE_REFRESH: Duration = Duration::from_secs(60);
+
+/// Encrypts and decrypts session tickets using rotating keys.
+pub struct Ticketer {
+    /// Lifetime of tickets, in seconds.
+    lifetime: u32,
+    /// Key file, if keys aren't generated internally.
+    file: Option<File>,
+    log: slog::Logger,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Keys that may decrypt tickets. The first one also encrypts them.
+    keys: Vec<LessSafeKey>,
+    /// When to next rotate or reload the keys.
+    refresh_at: Instant,
+}
+
+impl Ticketer {
+    /// Creates a ticketer with random keys, rotated so that none is kept for
+    /// longer than `lifetime` seconds.
+    pub fn random(lifetime: u32, log: slog::Logger) -> io::Result<Self> {
+        Ok(Self::new(lifetime, None, vec![random_key()?], log))
+    }
+
+    /// Creates a ticketer using the keys in `file`, which is re-read
+    /// periodically to pick up changes.
+    pub fn from_file(
+        lifetime: u32,
+        file: File,
+        log: slog::Logger,
+    ) -> io::Result<Self> {
+        let keys = read_keys(&file)?;
+        Ok(Self::new(lifetime, Some(file), keys, log))
+    }
+
+    fn new(
+        lifetime: u32,
+        file: Option<File>,
+        keys: Vec<LessSafeKey>,
+        log: slog::Logger,
+    ) -> Self {
+        let mut ticketer = Ticketer {
+            lifetime,
+            file,
+            log,
+            state: Mutex::new(State {
+                keys,
+                refresh_at: Instant::now(),
+            }),
+        };
+        let refresh_at = Instant::now() + ticketer.interval();
+        ticketer.state.get_mut().unwrap().refresh_at = refresh_at;
+        ticketer
+    }
+
+    /// Time between key rotations or reloads.
+    fn interval(&self) -> Duration {
+        match self.file {
+            // Each key encrypts for half the lifetime, then decrypts for the
+            // other half.
+            None => Duration::from_secs(u64::from(self.lifetime / 2).max(1)),
+            Some(_) => FILE_REFRESH,
+        }
+    }
+
+    /// Locks the key state, first rotating or reloading keys if it's time.
+    fn state(&self) -> Option<MutexGuard<'_, State>> {
+        let mut state = self.state.lock().ok()?;
+        let now = Instant::now();
+        if now < state.refresh_at {
+            return Some(state);
+        }
+        let interval = self.interval();
+        // If we've been idle for more than a whole interval, the previous key
+        // has outlived its lifetime too.
+        let stale = now >= state.refresh_at + interval;
+        state.refresh_at = now + interval;
+        match &self.file {
+            None => match random_key() {
+                Ok(key) => {
+                    state.keys.insert(0, key);
+                    state.keys.truncate(if stale { 1 } else { 2 });
+                }
+                Err(e) => {
+                    // Stop issuing tickets rather than keep using old keys.
+                    slog::warn!(self.log, "ticket key rotation failed"; "err" => %e);
+                    state.keys.clear();
+                }
+            },
+            Some(file) => match read_keys(file) {
+                Ok(keys) => state.keys = keys,
+                Err(e) => {
+                    // Keep the keys we have; the operator can fix the file.
+                    slog::warn!(self.log, "ticket key reload failed"; "err" => %e);
+                }
+            },
+        }
+        Some(state)
+    }
+}
+
+impl std::fmt::Debug for Ticketer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Keys deliberately omitted.
+        f.debug_struct("Ticketer")
+            .field("lifetime", &self.lifetime)
+            .field("file", &self.file)
+            .finish()
+    }
+}
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let state = self.state()?;
+        let key = state.keys.first()?;
+
+        // Random nonce, because a counter would let clients link tickets.
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+
+        let mut ticket = Vec::with_capacity(
+            NONCE_LEN + message.len() + key.algorithm().tag_len(),
+        );
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(message);
+        let tag = key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut ticket[NONCE_LEN..],
+            )
+            .ok()?;
+        ticket.extend_from_slice(tag.as_ref());
+        Some(ticket)
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        let nonce = ticket.get(..NONCE_LEN)?;
+        let ciphertext = ticket.get(NONCE_LEN..)?;
+        let state = self.state()?;
+        state.keys.iter().find_map(|key| {
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut out = ciphertext.to_vec();
+            let len = key.open_in_place(nonce, Aad::empty(), &mut out).ok()?.len();
+            out.truncate(len);
+            Some(out)
+        })
+    }
+}
+
+fn make_key(bytes: &[u8; KEY_LEN]) -> LessSafeKey {
+    // The length matches the algorithm, so this can't fail.
+    LessSafeKey::new(UnboundKey::new(&aead::CHACHA20_POLY1305, bytes).unwrap())
+}
+
+fn random_key() -> io::Result<LessSafeKey> {
+    let mut bytes = [0; KEY_LEN];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| io::Error::other("can't generate ticket key"))?;
+    Ok(make_key(&bytes))
+}
+
+/// Reads keys from the start of `file`, in the format accepted by
+/// `parse_keys`.
+fn read_keys(mut file: &File) -> io::Result<Vec<LessSafeKey>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    let keys = parse_keys(&text)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(keys.iter().map(make_key).collect())
+}
+
+/// Parses a key file: one key per line, written as 64 hex digits. Blank lines
+/// and lines starting with `#` are ignored. There must be at least one key.
+fn parse_keys(text: &str) -> Result<Vec<[u8; KEY_LEN]>, String> {
+    let keys = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut key = [0; KEY_LEN];
+            if line.len() != KEY_LEN * 2 || !line.is_ascii() {
+                return Err("ticket key must be 64 hex digits".to_string());
+            }
+            for (i, byte) in key.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&line[i * 2..i * 2 + 2], 16)
+                    .map_err(|_| "ticket key must be 64 hex digits".to_string())?;
+            }
+            Ok(key)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if keys.is_empty() {
+        return Err("no ticket keys in file".to_string());
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticketer(keys: &[[u8; KEY_LEN]]) -> Ticketer {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        Ticketer::new(3600, None, keys.iter().map(make_key).collect(), log)
+    }
+
+    #[test]
+    fn key_parsing() {
+        let a = "00".repeat(32);
+        let b = "aB".repeat(32);
+        let keys = parse_keys(&format!("# current\n{}\n\n{}\n", a, b)).unwrap();
+        assert_eq!(keys, [[0; KEY_LEN], [0xab; KEY_LEN]]);
+
+        assert!(parse_keys("").is_err());
+        assert!(parse_keys("# nothing\n").is_err());
+        assert!(parse_keys(&"00".repeat(31)).is_err());
+        assert!(parse_keys(&"zz".repeat(32)).is_err());
+        assert!(parse_keys(&"é".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn tickets() {
+        let old = ticketer(&[[1; KEY_LEN]]);
+        let ticket = old.encrypt(b"session").unwrap();
+        assert_eq!(old.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+
+        // A ticket from a key that has been demoted is still accepted...
+        let rotated = ticketer(&[[2; KEY_LEN], [1; KEY_LEN]]);
+        assert_eq!(rotated.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+        // ...but not once it's gone.
+        let new = ticketer(&[[2; KEY_LEN]]);
+        assert_eq!(new.decrypt(&ticket), None);
+
+        assert_eq!(old.decrypt(&ticket[..NONCE_LEN]), None);
+        assert_eq!(old.decrypt(b""), None);
+    }
+}
@@ -0,0 +1,115 @@
+//! W3C Trace Context.
+//!
+//! A request that's part of a distributed trace carries a `traceparent` header
+//! naming the trace and the span that made the request, and perhaps a
+//! `tracestate` with vendors' own data. There's nowhere for us to send spans,
+//! but putting the IDs in the request's log record lets it be found from the
+//! trace, and the trace from it.
+
+use hyper::header::HeaderMap;
+
+use crate::log::OptionKV;
+
+/// The most of a `tracestate` header that's worth keeping: the spec's limit.
+const MAX_STATE: usize = 512;
+
+/// What `traceparent` says, as found in a request.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TraceParent<'a> {
+    /// The trace, as 32 hex digits.
+    pub trace_id: &'a str,
+    /// The span the request was made from, as 16 hex digits.
+    pub parent_id: &'a str,
+    /// Whether the caller is recording the trace.
+    pub sampled: bool,
+}
+
+/// Parses a `traceparent` header value, returning `None` unless it's valid.
+/// Versions after 00 may add fields, which are ignored.
+pub fn parse(value: &str) -> Option<TraceParent<'_>> {
+    let mut fields = value.split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+    let hex = |s: &str, len: usize| {
+        s.len() == len
+            && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let zero = |s: &str| s.bytes().all(|b| b == b'0');
+    if !hex(version, 2)
+        || version == "ff"
+        || (version == "00" && fields.next().is_some())
+        || !hex(trace_id, 32)
+        || zero(trace_id)
+        || !hex(parent_id, 16)
+        || zero(parent_id)
+        || !hex(flags, 2)
+    {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some(TraceParent {
+        trace_id,
+        parent_id,
+        sampled: flags & 1 != 0,
+    })
+}
+
+/// Log fields for the trace context of a request with `headers`, if it has
+/// one.
+pub fn log_fields(headers: &HeaderMap) -> Option<slog::OwnedKV<impl slog::KV>> {
+    let parent = headers.get("traceparent")?.to_str().ok()?;
+    let parent = parse(parent)?;
+    // tracestate means nothing without traceparent, and multiple headers
+    // are one list.
+    let state = headers
+        .get_all("tracestate")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let state = if state.is_empty() || state.len() > MAX_STATE {
+        None
+    } else {
+        Some(slog::o!("tracestate" => state))
+    };
+    Some(slog::o!(
+        "trace_id" => parent.trace_id.to_string(),
+        "parent_id" => parent.parent_id.to_string(),
+        "sampled" => parent.sampled,
+        OptionKV::from(state),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let valid = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        assert_eq!(
+            parse(valid),
+            Some(TraceParent {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736",
+                parent_id: "00f067aa0ba902b7",
+                sampled: true,
+            })
+        );
+        // A later version may have more to say.
+        let later = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-x";
+        assert_eq!(parse(later).map(|p| p.sampled), Some(false));
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(parse(invalid), None, "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Traffic by host, and monthly quotas.
+//!
+//! For a server shared by several sites, the totals in `Stats` don't say who
+//! used what. Each host named with `--host` or `--host-quota` gets its own
+//! count of requests, bytes served since startup, and bytes served this
+//! calendar month (UTC), which is what a quota is measured against. Only those
+//! hosts are counted, so a client making up `Host` headers can't make the
+//! table grow.
+//!
+//! Counts live in memory, so a restart starts the month afresh.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use crate::args::HostQuota;
+use crate::date;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Counters for every host we're counting.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    hosts: Vec<Usage>,
+}
+
+#[derive(Debug)]
+struct Usage {
+    host: String,
+    /// Most bytes to serve in a month, if there's a limit.
+    quota: Option<u64>,
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    /// The month `month_bytes` is for, as months since 1970.
+    month: AtomicU64,
+    month_bytes: AtomicU64,
+}
+
+impl Traffic {
+    /// Counts `hosts`, and those with `quotas`, limiting the latter.
+    pub fn new(hosts: &[String], quotas: &[HostQuota]) -> Self {
+        let mut traffic = Traffic::default();
+        let names = hosts.iter().chain(quotas.iter().map(|q| &q.host));
+        for host in names {
+            if traffic.find(Some(host)).is_some() {
+                continue;
+            }
+            traffic.hosts.push(Usage {
+                host: host.clone(),
+                quota: quotas.iter().find(|q| q.host == *host).map(|q| q.bytes),
+                requests: AtomicU64::new(0),
+                bytes: AtomicU64::new(0),
+                month: AtomicU64::new(0),
+                month_bytes: AtomicU64::new(0),
+            });
+        }
+        traffic
+    }
+
+    /// Finds `host`, normalized, among those counted, returning a handle for
+    /// recording its traffic.
+    pub fn find(&self, host: Option<&str>) -> Option<usize> {
+        let host = host?;
+        self.hosts.iter().position(|u| u.host == host)
+    }
+
+    /// Records a request for the host `i`.
+    pub fn record_request(&self, i: usize) {
+        self.hosts[i].requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records `len` bytes served for the host `i` at `now`.
+    pub fn record_sent(&self, i: usize, len: u64, now: SystemTime) {
+        let usage = &self.hosts[i];
+        usage.bytes.fetch_add(len, Ordering::Relaxed);
+        let month = month_of(now);
+        // The first to notice a new month starts its count. Bytes sent by
+        // someone else at the same moment may land in either month.
+        if usage.month.swap(month, Ordering::Relaxed) != month {
+            usage.month_bytes.store(0, Ordering::Relaxed);
+        }
+        usage.month_bytes.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// If the host `i` has used its quota for the month of `now`, returns the
+    /// seconds until the next month begins.
+    pub fn over_quota(&self, i: usize, now: SystemTime) -> Option<u64> {
+        let usage = &self.hosts[i];
+        let quota = usage.quota?;
+        let month = month_of(now);
+        if usage.month_bytes(month) < quota {
+            return None;
+        }
+        let now = unix_secs(now);
+        Some(month_start(month + 1).saturating_sub(now).max(1))
+    }
+
+    /// Logs a `host stats` record for each host.
+    pub fn log(&self, log: &slog::Logger, now: SystemTime) {
+        let month = month_of(now);
+        for usage in &self.hosts {
+            let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+            slog::info!(
+                log,
+                "host stats";
+                "host" => &usage.host,
+                "requests" => get(&usage.requests),
+                "bytes_served" => get(&usage.bytes),
+                "month_bytes" => usage.month_bytes(month),
+                "quota" => usage.quota,
+            );
+        }
+    }
+
+    /// Reads all the counters, with their names.
+    pub fn snapshot(&self, now: SystemTime) -> Vec<(String, u64)> {
+        let month = month_of(now);
+        let mut counters = vec![];
+        for usage in &self.hosts {
+            let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+            let name = |counter| format!("host.{}.{}", usage.host, counter);
+            counters.push((name("requests"), get(&usage.requests)));
+            counters.push((name("bytes_served"), get(&usage.bytes)));
+            counters.push((name("month_bytes"), usage.month_bytes(month)));
+            if let Some(quota) = usage.quota {
+                counters.push((name("quota"), quota));
+            }
+        }
+        counters
+    }
+}
+
+impl Usage {
+    /// Bytes served in `month`, which is none if nothing has been yet.
+    fn month_bytes(&self, month: u64) -> u64 {
+        if self.month.load(Ordering::Relaxed) == month {
+            self.month_bytes.load(Ordering::Relaxed)
+        } else {
+            0
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The calendar month `time` falls in, UTC, counted from January 1970.
+fn month_of(time: SystemTime) -> u64 {
+    let (year, month, _) =
+        date::civil_from_days(unix_secs(time) / SECS_PER_DAY);
+    (year - 1970) * 12 + (month - 1)
+}
+
+/// When `month`, counted as by `month_of`, begins, in Unix seconds.
+fn month_start(month: u64) -> u64 {
+    let (year, month) = (1970 + month / 12, month % 12 + 1);
+    date::days_from_civil(year, month, 1) * SECS_PER_DAY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn quotas() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        // 2024-02-29 12:00 and 2024-03-01 00:00 UTC.
+        let (leap_day, march) = (1_709_208_000, 1_709_251_200);
+        assert_eq!(month_start(month_of(at(leap_day)) + 1), march);
+        assert_eq!(month_of(at(march)), month_of(at(leap_day)) + 1);
+        assert_eq!(month_start(0), 0);
+
+        let traffic = Traffic::new(
+            &["a.example".to_string(), "b.example".to_string()],
+            &[HostQuota {
+                host: "b.example".to_string(),
+                bytes: 100,
+            }],
+        );
+        assert_eq!(traffic.find(Some("c.example")), None);
+        assert_eq!(traffic.find(None), None);
+        let (a, b) = (
+            traffic.find(Some("a.example")).unwrap(),
+            traffic.find(Some("b.example")).unwrap(),
+        );
+
+        traffic.record_request(b);
+        traffic.record_sent(b, 60, at(leap_day));
+        assert_eq!(traffic.over_quota(b, at(leap_day)), None);
+        traffic.record_sent(b, 40, at(leap_day));
+        assert_eq!(traffic.over_quota(b, at(leap_day)), Some(43_200));
+        // No quota, no limit.
+        traffic.record_sent(a, 1000, at(leap_day));
+        assert_eq!(traffic.over_quota(a, at(leap_day)), None);
+        // A new month starts afresh.
+        assert_eq!(traffic.over_quota(b, at(march)), None);
+        traffic.record_sent(b, 10, at(march));
+
+        let snapshot = traffic.snapshot(at(march));
+        let get = |name: &str| {
+            snapshot.iter().find(|(n, _)| n == name).map(|&(_, v)| v)
+        };
+        assert_eq!(get("host.b.example.requests"), Some(1));
+        assert_eq!(get("host.b.example.bytes_served"), Some(110));
+        assert_eq!(get("host.b.example.month_bytes"), Some(10));
+        assert_eq!(get("host.b.example.quota"), Some(100));
+        assert_eq!(get("host.a.example.month_bytes"), Some(0));
+        assert_eq!(get("host.a.example.quota"), None);
+    }
+}
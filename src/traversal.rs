@@ -9,90 +9,78 @@
 //! - Contains no `"/."` sequences, preventing access to parent directories and
 //!   dotfiles.
 //!
-//! The sanitizer API is an `Iterator`. Use `sanitize` to get one.
+//! The sanitizer is fed one character at a time, so that it can be run over
+//! the output of another decoder without collecting that first. Use
+//! `sanitize` to sanitize a string.
 //!
 //! Note that path sanitization should be applied *last*, after any other decode
 //! steps, immediately before passing the path to the OS.
 
-/// Adapts `inner` to sanitize path names.
-pub fn sanitize(
-    inner: impl Iterator<Item = char>,
-) -> impl Iterator<Item = char> {
-    Sanitizer::from(inner)
+/// Sanitizes `path`.
+pub fn sanitize(path: &str) -> String {
+    let mut out = Sanitizer::with_capacity(path.len());
+    path.chars().for_each(|c| out.push(c));
+    out.finish()
 }
 
-struct Sanitizer<I> {
-    inner: I,
-    state: SanitizerState,
+/// Sanitizes a configured URL prefix, such as `/static/`, into the same form as
+/// a sanitized path but without any trailing slash (`./static`), so it can be
+/// compared against sanitized paths with `strip_prefix`.
+pub fn sanitize_prefix(prefix: &str) -> String {
+    let mut s = sanitize(prefix);
+    if s.ends_with('/') {
+        s.pop();
+    }
+    s
 }
 
-impl<I> From<I> for Sanitizer<I> {
-    fn from(inner: I) -> Self {
-        Self {
-            inner,
-            state: SanitizerState::EmitDot,
-        }
+/// Removes a prefix produced by `sanitize_prefix` from the front of the
+/// sanitized path `path`, if it matches on a path component boundary. The
+/// result is itself a sanitized path.
+pub fn strip_prefix(prefix: &str, path: &str) -> Option<String> {
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(format!(".{}", rest))
+    } else {
+        None
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-enum SanitizerState {
-    EmitDot,
-    EmitSlash,
-    Normal,
-    Slash,
+/// A path being sanitized.
+pub struct Sanitizer {
+    out: String,
+    /// Whether the last character written was a slash.
+    slash: bool,
 }
 
-impl<I: Iterator<Item = char>> Iterator for Sanitizer<I> {
-    type Item = char;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.state {
-            SanitizerState::EmitDot => {
-                self.state = SanitizerState::EmitSlash;
-                return Some('.');
-            }
-            SanitizerState::EmitSlash => {
-                self.state = SanitizerState::Slash;
-                return Some('/');
-            }
-            _ => (),
-        }
+impl Sanitizer {
+    /// Starts a sanitized path, with room for `len` more bytes of it.
+    pub fn with_capacity(len: usize) -> Self {
+        let mut out = String::with_capacity(len + 2);
+        out.push_str("./");
+        Sanitizer { out, slash: true }
+    }
 
-        loop {
-            match (self.state, self.inner.next()?) {
-                (_, '\0') => {
-                    self.state = SanitizerState::Normal;
-                    break Some('_');
-                }
-                (SanitizerState::Normal, '/') => {
-                    self.state = SanitizerState::Slash;
-                    break Some('/');
-                }
-                (SanitizerState::Slash, '/') => continue,
-                (SanitizerState::Slash, '.') => {
-                    self.state = SanitizerState::Normal;
-                    break Some(':');
-                }
-                (_, c) => {
-                    self.state = SanitizerState::Normal;
-                    break Some(c);
-                }
-            }
+    /// Appends `c` from the unsanitized path.
+    pub fn push(&mut self, c: char) {
+        match c {
+            '\0' => self.out.push('_'),
+            '/' if self.slash => return,
+            '.' if self.slash => self.out.push(':'),
+            c => self.out.push(c),
         }
+        self.slash = c == '/';
     }
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        // We alter the inner size-hint because it's possible that we discard
-        // all characters. The max length is extended by the initial dot-slash.
-        (0, self.inner.size_hint().1.map(|x| x + 2))
+    pub fn finish(self) -> String {
+        self.out
     }
 }
 
 #[cfg(test)]
 mod tests {
     fn san_str(s: &str) -> String {
-        super::sanitize(s.chars()).collect()
+        super::sanitize(s)
     }
 
     #[test]
@@ -107,4 +95,20 @@ mod tests {
 
         assert_eq!(san_str("//.././doc.pdf\0/"), "./:./:/doc.pdf_/");
     }
+
+    #[test]
+    fn prefixes() {
+        use super::{sanitize_prefix, strip_prefix};
+
+        assert_eq!(sanitize_prefix("/static/"), "./static");
+        assert_eq!(sanitize_prefix("//a/.b"), "./a/:b");
+        assert_eq!(sanitize_prefix("/"), ".");
+
+        let sp = |p, s| strip_prefix(p, s);
+        assert_eq!(sp("./static", "./static/x.css").as_deref(), Some("./x.css"));
+        assert_eq!(sp("./static", "./static").as_deref(), Some("."));
+        assert_eq!(sp("./static", "./staticky"), None);
+        assert_eq!(sp("./static", "./other"), None);
+        assert_eq!(sp(".", "./x").as_deref(), Some("./x"));
+    }
 }
@@ -0,0 +1,24 @@
+//! Unix domain socket helpers.
+
+use std::io;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+use nix::sys::stat::{umask, Mode};
+
+/// Creates a listening Unix socket at `path`, usable only by its owner,
+/// replacing any socket already there. The result is in nonblocking mode.
+pub fn listen_private(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    // Create the socket owner-only from the start, rather than fixing its
+    // permissions after someone may already have connected.
+    let old_mask = umask(Mode::from_bits_truncate(0o177));
+    let result = UnixListener::bind(path);
+    umask(old_mask);
+    let listener = result?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
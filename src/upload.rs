@@ -0,0 +1,302 @@
+//! Accepting files by PUT into a spool directory.
+//!
+//! The spool directory is opened at startup, before chroot, and held open, like
+//! a mount. Uploads are written to a temporary file in the spool and renamed
+//! into place once complete, so a reader of the directory sees either the
+//! whole file or nothing. Names come from the request path, sanitized as usual
+//! and limited to a single component, so the only thing an upload can do is
+//! create or replace one file directly inside the spool.
+//!
+//! Uploading requires a bearer token, read from a file at startup.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::{HeaderMap, StatusCode};
+use nix::fcntl::{AtFlags, OFlag};
+use nix::sys::stat::Mode;
+use tokio::io::AsyncWriteExt;
+
+use crate::args::Mount;
+use crate::traversal;
+
+/// An open spool directory and the rules for writing to it.
+pub struct Spool {
+    prefix: String,
+    dir: Arc<OwnedFd>,
+    token: Vec<u8>,
+    max_size: u64,
+    /// Distinguishes temporary files from concurrent uploads.
+    counter: AtomicU64,
+}
+
+/// What a successful upload did.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stored {
+    Created,
+    Replaced,
+}
+
+/// A temporary file in the spool, removed when this is dropped unless it has
+/// been kept. An upload whose request goes away partway through, because the
+/// client reset it or the connection was closed, leaves nothing behind.
+struct Temp {
+    dir: Arc<OwnedFd>,
+    name: String,
+    kept: bool,
+}
+
+impl Drop for Temp {
+    fn drop(&mut self) {
+        if !self.kept {
+            // There's no awaiting here, but unlinking one name is quick.
+            let _ = nix::unistd::unlinkat(
+                Some(self.dir.as_raw_fd()),
+                self.name.as_str(),
+                nix::unistd::UnlinkatFlags::NoRemoveDir,
+            );
+        }
+    }
+}
+
+/// Why an upload was refused, as a status and a reason for the log.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Refused(pub StatusCode, pub &'static str);
+
+impl Spool {
+    /// Opens the directory for `mount`, and reads the token from the file at
+    /// `token_path`, less any trailing newline.
+    ///
+    /// Relative paths are interpreted relative to the current working
+    /// directory, so this should be called before dropping privileges.
+    pub fn open(mount: &Mount, token_path: &Path, max_size: u64) -> io::Result<Self> {
+        let dir = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(&mount.dir)?;
+        let mut token = std::fs::read(token_path)?;
+        if token.ends_with(b"\n") {
+            token.pop();
+        }
+        if token.is_empty() {
+            return Err(io::Error::other("upload token file is empty"));
+        }
+        Ok(Spool {
+            prefix: mount.prefix.clone(),
+            dir: Arc::new(OwnedFd::from(dir)),
+            token,
+            max_size,
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Checks whether the sanitized path `path` falls under the upload prefix.
+    /// If so, returns the name to store the upload under, or why it can't be
+    /// used.
+    pub fn file_name(&self, path: &str) -> Option<Result<String, Refused>> {
+        let rest = traversal::strip_prefix(&self.prefix, path)?;
+        let name = rest.strip_prefix("./").unwrap_or("");
+        Some(if name.is_empty() || name.contains('/') {
+            Err(Refused(StatusCode::BAD_REQUEST, "bad upload name"))
+        } else {
+            Ok(name.to_string())
+        })
+    }
+
+    /// Checks that a request with `headers` carries our token.
+    pub fn authorized(&self, headers: &HeaderMap) -> bool {
+        let token = headers
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|v| v.as_bytes().strip_prefix(b"Bearer "));
+        match token {
+            Some(token) => {
+                ring::constant_time::verify_slices_are_equal(token, &self.token)
+                    .is_ok()
+            }
+            None => false,
+        }
+    }
+
+    /// Writes `body` to the spool under `name`, which should come from
+    /// `file_name`.
+    pub async fn receive(
+        &self,
+        log: &slog::Logger,
+        headers: &HeaderMap,
+        name: &str,
+        mut body: Incoming,
+    ) -> Result<Stored, Refused> {
+        let too_large = Refused(StatusCode::PAYLOAD_TOO_LARGE, "upload too large");
+        let declared = headers
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if declared.is_some_and(|len| len > self.max_size) {
+            return Err(too_large);
+        }
+
+        // Ready to go before the file is, in case we're dropped while it's
+        // being created.
+        let mut temp = Temp {
+            dir: Arc::clone(&self.dir),
+            name: format!(
+                ".upload-{}-{}",
+                std::process::id(),
+                self.counter.fetch_add(1, Ordering::Relaxed),
+            ),
+            kept: false,
+        };
+        let failed = |e: io::Error| {
+            slog::warn!(log, "error in upload: {}", e);
+            Refused(StatusCode::INTERNAL_SERVER_ERROR, "upload failed")
+        };
+        let mut file = self.create(&temp.name).await.map_err(failed)?;
+
+        // Copy the body across, keeping an eye on its size, since the
+        // declared length (if any) is only a promise.
+        let mut len = 0;
+        let copied = loop {
+            let data = match body.frame().await {
+                None => break Ok(()),
+                Some(Err(e)) => {
+                    slog::debug!(log, "upload interrupted: {}", e);
+                    break Err(Refused(StatusCode::BAD_REQUEST, "upload interrupted"));
+                }
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => data,
+                    // Trailers carry nothing we want.
+                    Err(_) => continue,
+                },
+            };
+            len += data.len() as u64;
+            if len > self.max_size {
+                break Err(too_large);
+            }
+            if let Err(e) = file.write_all(&data).await {
+                break Err(failed(e));
+            }
+        };
+        copied?;
+        file.sync_all().await.map_err(failed)?;
+        drop(file);
+
+        let stored = self.commit(&temp.name, name).await.map_err(failed)?;
+        temp.kept = true;
+        Ok(stored)
+    }
+
+    /// Creates the temporary file `name` in the spool.
+    async fn create(&self, name: &str) -> io::Result<tokio::fs::File> {
+        let dir = Arc::clone(&self.dir);
+        let name = name.to_owned();
+        let fd = tokio::task::spawn_blocking(move || {
+            nix::fcntl::openat(
+                dir.as_raw_fd(),
+                name.as_str(),
+                OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_CLOEXEC,
+                Mode::from_bits_truncate(0o644),
+            )
+        })
+        .await??;
+        // Safety: openat has just given us this fd, and nobody else has it.
+        let file = unsafe { std::fs::File::from_raw_fd(fd) };
+        Ok(tokio::fs::File::from_std(file))
+    }
+
+    /// Renames the temporary file `temp` to `name`, replacing anything there.
+    async fn commit(&self, temp: &str, name: &str) -> io::Result<Stored> {
+        let dir = Arc::clone(&self.dir);
+        let (temp, name) = (temp.to_owned(), name.to_owned());
+        tokio::task::spawn_blocking(move || {
+            let dir = dir.as_raw_fd();
+            // This is only used to pick the response status, so it doesn't
+            // matter that the answer could change before the rename.
+            let existed = nix::sys::stat::fstatat(
+                dir,
+                name.as_str(),
+                AtFlags::AT_SYMLINK_NOFOLLOW,
+            )
+            .is_ok();
+            nix::fcntl::renameat(Some(dir), temp.as_str(), Some(dir), name.as_str())?;
+            Ok(if existed { Stored::Replaced } else { Stored::Created })
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_tokens() {
+        let dir = std::env::temp_dir();
+        let token_path = dir.join(format!("httpd2-upload-test-{}", std::process::id()));
+        std::fs::write(&token_path, "s3cret\n").unwrap();
+        let mount = Mount {
+            prefix: traversal::sanitize_prefix("/drop/"),
+            dir: dir.clone(),
+        };
+        let spool = Spool::open(&mount, &token_path, 10).unwrap();
+        std::fs::remove_file(&token_path).unwrap();
+
+        let name = |path: &str| {
+            spool.file_name(&traversal::sanitize(path))
+        };
+        assert_eq!(name("/drop/build.tar.gz"), Some(Ok("build.tar.gz".to_string())));
+        assert_eq!(
+            name("/drop/a/b"),
+            Some(Err(Refused(StatusCode::BAD_REQUEST, "bad upload name")))
+        );
+        assert_eq!(
+            name("/drop/"),
+            Some(Err(Refused(StatusCode::BAD_REQUEST, "bad upload name")))
+        );
+        assert_eq!(name("/dropped"), None);
+        assert_eq!(name("/other/x"), None);
+
+        let headers = |auth: &'static str| {
+            let mut h = HeaderMap::new();
+            h.insert(hyper::header::AUTHORIZATION, auth.parse().unwrap());
+            h
+        };
+        assert!(spool.authorized(&headers("Bearer s3cret")));
+        assert!(!spool.authorized(&headers("Bearer s3cre")));
+        assert!(!spool.authorized(&headers("Basic s3cret")));
+        assert!(!spool.authorized(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn abandoned_uploads() {
+        let dir = std::env::temp_dir()
+            .join(format!("httpd2-upload-temp-test-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let token_path = dir.join("token");
+        std::fs::write(&token_path, "s3cret").unwrap();
+        let mount = Mount {
+            prefix: traversal::sanitize_prefix("/drop/"),
+            dir: dir.clone(),
+        };
+        let spool = Spool::open(&mount, &token_path, 10).unwrap();
+
+        // A temporary file goes when it's dropped, unless it's kept.
+        for kept in [false, true] {
+            let mut temp = Temp {
+                dir: Arc::clone(&spool.dir),
+                name: format!(".upload-{}", kept),
+                kept: false,
+            };
+            spool.create(&temp.name).await.unwrap();
+            temp.kept = kept;
+            drop(temp);
+            assert_eq!(dir.join(format!(".upload-{}", kept)).exists(), kept);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Looking over the content at startup.
+//!
+//! With `--warm-up`, once the server has dropped privileges, it walks the root
+//! and each mount, opening files just as it would to serve them. Files it
+//! won't serve -- because of their permissions, their type, or a symlink it
+//! won't follow -- are logged then, rather than going unnoticed until someone
+//! asks for one. With `--etag-source content`, the tags of the files it opens
+//! are computed too, so the first requests for them don't wait on hashing.
+//!
+//! The walk is bounded by a count of files and, for hashing, of bytes. It
+//! runs alongside serving, so a large root doesn't hold up startup.
+
+use std::collections::VecDeque;
+use std::path::Path;
+
+use crate::etag::TagCache;
+use crate::mount::Mounts;
+use crate::picky;
+
+/// How far to go.
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    /// Most files (and directories) to open.
+    pub files: usize,
+    /// Most bytes to read for hashing.
+    pub bytes: u64,
+}
+
+/// What the walk found.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Files that can be served.
+    pub files: usize,
+    /// Paths that can't be.
+    pub refused: usize,
+    /// Bytes hashed for tags.
+    pub hashed: u64,
+    /// Whether the walk stopped at `Limits::files`.
+    pub truncated: bool,
+}
+
+/// Walks `mounts`, hashing files into `tags` if given, and logs what it found.
+pub async fn warm_up(
+    log: &slog::Logger,
+    mounts: &Mounts,
+    tags: Option<&TagCache>,
+    limits: Limits,
+) {
+    let summary = walk(log, mounts, tags, limits).await;
+    slog::info!(
+        log,
+        "warmed up";
+        "files" => summary.files,
+        "refused" => summary.refused,
+        "hashed" => summary.hashed,
+        "truncated" => summary.truncated,
+    );
+}
+
+async fn walk(
+    log: &slog::Logger,
+    mounts: &Mounts,
+    tags: Option<&TagCache>,
+    limits: Limits,
+) -> Summary {
+    let mut summary = Summary::default();
+    let mut opened = 0;
+    for (prefix, source) in mounts.sources() {
+        let mut dirs = VecDeque::from([".".to_string()]);
+        while let Some(dir) = dirs.pop_front() {
+            let names = match source.list(Path::new(&dir)).await {
+                Ok(names) => names,
+                Err(e) => {
+                    slog::warn!(log, "can't list"; "path" => site_path(prefix, &dir), "err" => %e);
+                    continue;
+                }
+            };
+            let mut names = names
+                .into_iter()
+                // Names that aren't UTF-8 can't be asked for.
+                .filter_map(|name| name.into_string().ok())
+                // Nor can dotfiles, which sanitizing renames.
+                .filter(|name| !name.starts_with('.'))
+                .collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                let path = format!("{}/{}", dir, name);
+                // Paths under a mount are served from the mount, not the root.
+                if prefix == "." && mounts.resolve(&path).is_some() {
+                    continue;
+                }
+                if opened == limits.files {
+                    summary.truncated = true;
+                    return summary;
+                }
+                opened += 1;
+
+                let shown = site_path(prefix, &path);
+                match source
+                    .open(log, Path::new(&path), &|_| "", &|_| None)
+                    .await
+                {
+                    Ok(mut file) => {
+                        summary.files += 1;
+                        if let Some(tags) = tags {
+                            if summary.hashed + file.len <= limits.bytes {
+                                summary.hashed += file.len;
+                                if let Err(e) = tags.get(&mut file).await {
+                                    slog::warn!(log, "can't hash"; "path" => shown, "err" => %e);
+                                }
+                            }
+                        }
+                    }
+                    Err(picky::Error::Directory) => dirs.push_back(path),
+                    Err(e) => {
+                        summary.refused += 1;
+                        slog::warn!(log, "won't serve"; "path" => shown, "err" => %e);
+                    }
+                }
+            }
+        }
+    }
+    summary
+}
+
+/// Puts the source-relative `path` back under the source's `prefix`, as it
+/// would appear in a request's sanitized path.
+fn site_path(prefix: &str, path: &str) -> String {
+    format!("{}{}", prefix, &path[1..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn walk_root() {
+        let root = std::env::temp_dir()
+            .join(format!("httpd2-warm-test-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let write = |name: &str, mode: u32| {
+            let path = root.join(name);
+            std::fs::write(&path, "hello\n").unwrap();
+            std::fs::set_permissions(&path, PermissionsExt::from_mode(mode))
+                .unwrap();
+        };
+        write("a.html", 0o644);
+        write("sub/b.html", 0o644);
+        write("sub/private", 0o600);
+        write(".hidden", 0o600);
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mounts = Mounts::open(&root, &[], &[], false).unwrap();
+        let tags = TagCache::default();
+        let limits = Limits {
+            files: 100,
+            bytes: 6,
+        };
+        assert_eq!(
+            walk(&log, &mounts, Some(&tags), limits).await,
+            Summary {
+                files: 2,
+                refused: 1,
+                hashed: 6,
+                truncated: false,
+            }
+        );
+        let limits = Limits { files: 2, bytes: 0 };
+        assert_eq!(
+            walk(&log, &mounts, None, limits).await,
+            Summary {
+                files: 1,
+                refused: 0,
+                hashed: 0,
+                truncated: true,
+            }
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
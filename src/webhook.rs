@@ -0,0 +1,403 @@
+//! Telling someone when something happens, by HTTP POST.
+//!
+//! Each event is sent as a small JSON object to every `--webhook` URL. This
+//! is meant for simple alerting -- a chat channel, a pager's incoming hook --
+//! so there's no queue and no retrying: a delivery that fails is logged and
+//! forgotten.
+//!
+//! Webhook hosts are looked up, and the CA certificates for HTTPS loaded,
+//! at startup, since neither can be done after chroot. A host that moves
+//! needs a restart to follow it.
+
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use hyper::Uri;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::stats::Stats;
+
+/// How long a delivery may take, start to finish.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something worth telling someone about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The server is up and accepting connections.
+    Startup,
+    /// The server stopped accepting connections and finished those it had.
+    Shutdown,
+    /// The private key and certificate were reloaded.
+    TlsReloaded,
+    /// Reloading the private key and certificate failed, with this error.
+    TlsReloadFailed(String),
+    /// Too many requests ended in 5xx or 429 over a window of `secs` seconds.
+    Errors {
+        status_5xx: u64,
+        too_many_requests: u64,
+        secs: u64,
+    },
+}
+
+impl Event {
+    /// The event's name in the payload.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Startup => "startup",
+            Event::Shutdown => "shutdown",
+            Event::TlsReloaded => "tls-reloaded",
+            Event::TlsReloadFailed(_) => "tls-reload-failed",
+            Event::Errors { .. } => "errors",
+        }
+    }
+
+    /// Forms the JSON payload, for a server listening on `addr`, at `time`
+    /// in seconds since the epoch.
+    fn payload(&self, addr: SocketAddr, time: u64) -> String {
+        let mut json = format!(
+            "{{\"event\":{},\"addr\":{},\"time\":{}",
+            quote(self.name()),
+            quote(&addr.to_string()),
+            time
+        );
+        match self {
+            Event::TlsReloadFailed(error) => {
+                let _ = write!(json, ",\"error\":{}", quote(error));
+            }
+            Event::Errors {
+                status_5xx,
+                too_many_requests,
+                secs,
+            } => {
+                let _ = write!(
+                    json,
+                    ",\"status_5xx\":{},\"too_many_requests\":{},\"secs\":{}",
+                    status_5xx, too_many_requests, secs
+                );
+            }
+            _ => (),
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Quotes `s` as a JSON string.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Where to send events.
+pub struct Webhooks {
+    targets: Vec<Target>,
+    tls: Option<TlsConnector>,
+    /// The server's own address, to say which server an event came from.
+    addr: SocketAddr,
+    log: slog::Logger,
+}
+
+struct Target {
+    uri: Uri,
+    host: String,
+    addrs: Vec<SocketAddr>,
+    https: bool,
+}
+
+impl Webhooks {
+    /// Looks up the hosts in `urls`, and, if any use HTTPS, loads the CA
+    /// certificates in `ca`. Events will say they're from `addr`.
+    pub async fn new(
+        urls: &[String],
+        ca: &Path,
+        addr: SocketAddr,
+        log: slog::Logger,
+    ) -> io::Result<Self> {
+        let mut targets = vec![];
+        for url in urls {
+            let bad = |why: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("bad webhook URL {}: {}", url, why),
+                )
+            };
+            let uri: Uri = url.parse().map_err(|_| bad("can't parse"))?;
+            let https = match uri.scheme_str() {
+                Some("http") => false,
+                Some("https") => true,
+                _ => return Err(bad("not http or https")),
+            };
+            let host = uri.host().ok_or_else(|| bad("no host"))?.to_string();
+            let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+            // IPv6 literals come with brackets, which lookup doesn't want.
+            let name = host.trim_start_matches('[').trim_end_matches(']');
+            let addrs = tokio::net::lookup_host((name, port)).await?.collect();
+            targets.push(Target {
+                uri,
+                host,
+                addrs,
+                https,
+            });
+        }
+        let tls = if targets.iter().any(|t| t.https) {
+            Some(connector(ca)?)
+        } else {
+            None
+        };
+        Ok(Webhooks {
+            targets,
+            tls,
+            addr,
+            log,
+        })
+    }
+
+    /// Sends `event` to every webhook, waiting until they've all answered or
+    /// timed out.
+    pub async fn send(&self, event: Event) {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let payload = event.payload(self.addr, time);
+        let (event, payload) = (&event, &payload);
+        let deliveries = self.targets.iter().map(|target| async move {
+            let result =
+                tokio::time::timeout(TIMEOUT, self.deliver(target, payload))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "timed out",
+                        ))
+                    });
+            match result {
+                Ok(()) => slog::debug!(
+                    self.log,
+                    "webhook";
+                    "event" => event.name(),
+                    "url" => %target.uri,
+                ),
+                Err(e) => slog::warn!(
+                    self.log,
+                    "webhook failed: {}", e;
+                    "event" => event.name(),
+                    "url" => %target.uri,
+                ),
+            }
+        });
+        futures::future::join_all(deliveries).await;
+    }
+
+    /// Sends `event` in the background.
+    pub fn fire(self: &Arc<Self>, event: Event) {
+        let hooks = self.clone();
+        tokio::spawn(async move { hooks.send(event).await });
+    }
+
+    async fn deliver(&self, target: &Target, payload: &str) -> io::Result<()> {
+        let stream = TcpStream::connect(&target.addrs[..]).await?;
+        match (&self.tls, target.https) {
+            (Some(tls), true) => {
+                let name =
+                    ServerName::try_from(target.host.clone()).map_err(|e| {
+                        io::Error::new(io::ErrorKind::InvalidInput, e)
+                    })?;
+                let stream = tls.connect(name, stream).await?;
+                post(stream, target, payload).await
+            }
+            _ => post(stream, target, payload).await,
+        }
+    }
+}
+
+/// Makes a TLS client trusting the CA certificates in the PEM file `ca`.
+pub fn connector(ca: &Path) -> io::Result<TlsConnector> {
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(
+        std::fs::File::open(ca)?,
+    ))
+    .collect::<Result<Vec<_>, _>>()?;
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(certs);
+    if added == 0 {
+        return Err(io::Error::other(format!(
+            "no CA certificates in {}",
+            ca.display()
+        )));
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// POSTs `payload` to `target` over `stream`, and checks the response
+/// status.
+async fn post<S>(
+    mut stream: S,
+    target: &Target,
+    payload: &str,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let path = target.uri.path_and_query().map_or("/", |p| p.as_str());
+    let request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         User-Agent: httpd2\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        path,
+        target
+            .uri
+            .authority()
+            .map_or(&target.host[..], |a| a.as_str()),
+        payload.len(),
+        payload
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+    // All we want is the status line, which shouldn't be long.
+    let mut status = String::new();
+    BufReader::new(stream.take(1024))
+        .read_line(&mut status)
+        .await?;
+    let code = status.split_whitespace().nth(1).unwrap_or("");
+    if code.starts_with('2') {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("got {}", status.trim_end())))
+    }
+}
+
+/// Sends `Event::Errors` when, over a window of `window`, at least
+/// `threshold` requests end in a 5xx or 429 response. Once it has fired, it
+/// waits for a window below the threshold before firing again, so a long
+/// outage is one alert rather than one a minute.
+pub async fn watch_errors(
+    hooks: Arc<Webhooks>,
+    stats: Arc<Stats>,
+    threshold: u64,
+    window: Duration,
+) {
+    use std::sync::atomic::Ordering;
+
+    let count = || {
+        (
+            stats.by_class[3].load(Ordering::Relaxed),
+            stats.too_many_requests.load(Ordering::Relaxed),
+        )
+    };
+    let mut last = count();
+    let mut firing = false;
+    loop {
+        tokio::time::sleep(window).await;
+        let now = count();
+        let (status_5xx, too_many_requests) = (now.0 - last.0, now.1 - last.1);
+        last = now;
+        let over = status_5xx + too_many_requests >= threshold;
+        if over && !firing {
+            hooks
+                .send(Event::Errors {
+                    status_5xx,
+                    too_many_requests,
+                    secs: window.as_secs(),
+                })
+                .await;
+        }
+        firing = over;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn delivery() {
+        let addr: SocketAddr = ([192, 0, 2, 1], 443).into();
+        assert_eq!(
+            Event::TlsReloadFailed("no \"key\"\n".to_string()).payload(addr, 7),
+            r#"{"event":"tls-reload-failed","addr":"192.0.2.1:443","time":7,"error":"no \"key\"\n"}"#
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook?x=1", listener.local_addr().unwrap());
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let hooks = Webhooks::new(&[url], Path::new("/nonexistent"), addr, log)
+            .await
+            .unwrap();
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+            for status in ["204 No Content", "500 Oops"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let mut len = 0;
+                // Read until the body, which comes last, has arrived.
+                while !request[..len].ends_with(b"}") {
+                    len += stream.read(&mut request[len..]).await.unwrap();
+                }
+                let response = format!("HTTP/1.1 {}\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+                requests.push(String::from_utf8(request[..len].to_vec()));
+            }
+            requests
+        });
+        let target = &hooks.targets[0];
+        hooks.deliver(target, "{}").await.unwrap();
+        // Anything but a 2xx is a failure.
+        let e = hooks.deliver(target, "{}").await.unwrap_err();
+        assert_eq!(e.to_string(), "got HTTP/1.1 500 Oops");
+        let request = server.await.unwrap().remove(0).unwrap();
+        assert!(request.starts_with("POST /hook?x=1 HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nContent-Length: 2\r\n"));
+    }
+
+    #[test]
+    fn payloads() {
+        let addr: SocketAddr = ([127, 0, 0, 1], 443).into();
+        let errors = Event::Errors {
+            status_5xx: 3,
+            too_many_requests: 4,
+            secs: 60,
+        };
+        assert_eq!(
+            errors.payload(addr, 7),
+            r#"{"event":"errors","addr":"127.0.0.1:443","time":7,"status_5xx":3,"too_many_requests":4,"secs":60}"#
+        );
+        assert_eq!(
+            Event::Startup.payload(addr, 7),
+            r#"{"event":"startup","addr":"127.0.0.1:443","time":7}"#
+        );
+        assert_eq!(quote("a\\b\t\u{7f}é"), r#""a\\b\u0009\u007fé""#);
+    }
+}